@@ -0,0 +1,961 @@
+use crate::client::{Client, ClientStream};
+use crate::connection::{build_resp_command, dispatch_command, EXEC_LOCK};
+use crate::parse::{parse_command, parse_request, Args};
+use crate::persistence;
+use crate::rdb::{RdbData, RdbReadError, RdbReader, RdbWriter};
+use crate::store::{self, Data, Database};
+use crate::utils::{build_resp_array_raw, build_resp_bulk};
+use crate::{unix_millis, Command, RestoreOptions, CONFIG};
+use once_cell::sync::Lazy;
+use std::collections::HashMap;
+use std::io::Cursor;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+use thiserror::Error;
+use tokio::fs::{File, OpenOptions};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::Mutex;
+
+#[derive(Error, Debug)]
+pub enum AofError {
+    #[error("Bad file format reading the append only file at offset {0}")]
+    BadFormat(usize),
+
+    #[error("Unexpected end of file reading the append only file at offset {0}. Truncate it to the last valid command or set aof-load-truncated yes")]
+    Truncated(usize),
+
+    #[error("Unexpected MULTI inside a transaction at offset {0}")]
+    UnexpectedMulti(usize),
+
+    #[error("Unexpected EXEC without MULTI at offset {0}")]
+    UnexpectedExec(usize),
+
+    #[error("Reached EOF before reading EXEC for the MULTI at offset {0}")]
+    UnclosedMulti(usize),
+
+    #[error("Unknown command '{0}' reading the append only file")]
+    UnknownCommand(String),
+
+    #[error("Error reading the RDB preamble of the append only file: {0}")]
+    Preamble(#[from] RdbReadError),
+
+    #[error("The AOF manifest has an invalid line: {0}")]
+    InvalidManifest(String),
+
+    #[error("The AOF file {0} listed in the manifest doesn't exist")]
+    MissingFile(String),
+
+    #[error("Background append only file rewriting already in progress")]
+    RewriteInProgress,
+
+    #[error("Append only file is disabled, turn on appendonly to rewrite it")]
+    Disabled,
+
+    #[error("IO Error: {0}")]
+    IoError(#[from] std::io::Error),
+}
+
+/// When the AOF is flushed to disk, set by `appendfsync`.
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum AppendFsync {
+    /// After every write, before the client gets its reply.
+    Always,
+    /// At most once per second, from the background task.
+    EverySec,
+    /// Whenever the operating system decides.
+    No,
+}
+
+impl AppendFsync {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.to_lowercase().as_str() {
+            "always" => Some(Self::Always),
+            "everysec" => Some(Self::EverySec),
+            "no" => Some(Self::No),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Always => "always",
+            Self::EverySec => "everysec",
+            Self::No => "no",
+        }
+    }
+}
+
+/// Kind of a file listed in the AOF manifest.
+#[derive(PartialEq, Debug, Clone, Copy)]
+enum AofFileType {
+    /// A snapshot of the dataset, in RDB or command form.
+    Base,
+    /// Commands run after the base was taken.
+    Incr,
+    /// A file replaced by a rewrite, waiting to be deleted.
+    History,
+}
+
+impl AofFileType {
+    fn parse(value: &str) -> Option<Self> {
+        match value {
+            "b" => Some(Self::Base),
+            "i" => Some(Self::Incr),
+            "h" => Some(Self::History),
+            _ => None,
+        }
+    }
+
+    fn describe(&self) -> &'static str {
+        match self {
+            Self::Base => "base",
+            Self::Incr => "incr",
+            Self::History => "history",
+        }
+    }
+
+    fn as_str(&self) -> &'static str {
+        match self {
+            Self::Base => "b",
+            Self::Incr => "i",
+            Self::History => "h",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct AofFile {
+    name: String,
+    seq: u64,
+    kind: AofFileType,
+}
+
+/// The files making up the AOF, as in Redis 7's multi-part AOF: a base file, then
+/// incremental files replayed in order on top of it.
+#[derive(Debug, Clone, Default)]
+struct Manifest {
+    base: Option<AofFile>,
+    incrs: Vec<AofFile>,
+    history: Vec<AofFile>,
+}
+
+impl Manifest {
+    /// Parses lines of `file <name> seq <n> type <b|i|h>`; keys may come in any order.
+    fn parse(text: &str) -> Result<Self, AofError> {
+        let mut manifest = Self::default();
+        for line in text.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let invalid = || AofError::InvalidManifest(line.to_owned());
+            let tokens = line.split_whitespace().collect::<Vec<_>>();
+            if tokens.len() % 2 != 0 {
+                return Err(invalid());
+            }
+            let (mut name, mut seq, mut kind) = (None, None, None);
+            for pair in tokens.chunks(2) {
+                match pair[0] {
+                    "file" => name = Some(pair[1].to_owned()),
+                    "seq" => seq = pair[1].parse::<u64>().ok(),
+                    "type" => kind = AofFileType::parse(pair[1]),
+                    _ => {}
+                }
+            }
+            let (Some(name), Some(seq), Some(kind)) = (name, seq, kind) else {
+                return Err(invalid());
+            };
+            if name.contains('/') {
+                return Err(invalid());
+            }
+            let file = AofFile { name, seq, kind };
+            match kind {
+                AofFileType::Base if manifest.base.is_some() => return Err(invalid()),
+                AofFileType::Base => manifest.base = Some(file),
+                AofFileType::Incr => {
+                    if manifest.incrs.last().is_some_and(|last| last.seq >= seq) {
+                        return Err(invalid());
+                    }
+                    manifest.incrs.push(file);
+                }
+                AofFileType::History => manifest.history.push(file),
+            }
+        }
+        Ok(manifest)
+    }
+
+    fn to_text(&self) -> String {
+        self.base
+            .iter()
+            .chain(&self.history)
+            .chain(&self.incrs)
+            .map(|file| {
+                format!(
+                    "file {} seq {} type {}\n",
+                    file.name,
+                    file.seq,
+                    file.kind.as_str()
+                )
+            })
+            .collect()
+    }
+
+    fn next_base(&self, filename: &str, rdb: bool) -> AofFile {
+        let seq = self.base.as_ref().map_or(1, |base| base.seq + 1);
+        let extension = if rdb { "rdb" } else { "aof" };
+        AofFile {
+            name: format!("{}.{}.base.{}", filename, seq, extension),
+            seq,
+            kind: AofFileType::Base,
+        }
+    }
+
+    fn next_incr(&self, filename: &str) -> AofFile {
+        let seq = self.incrs.last().map_or(1, |incr| incr.seq + 1);
+        AofFile {
+            name: format!("{}.{}.incr.aof", filename, seq),
+            seq,
+            kind: AofFileType::Incr,
+        }
+    }
+
+    /// The base then the incremental files, in the order they are replayed.
+    fn files(&self) -> impl Iterator<Item = &AofFile> {
+        self.base.iter().chain(&self.incrs)
+    }
+}
+
+struct AofState {
+    /// The incremental file commands are currently appended to.
+    file: File,
+    dir: PathBuf,
+    filename: String,
+    manifest: Manifest,
+    /// Database the file last SELECTed; `None` forces a SELECT before the next command.
+    selected_db: Option<usize>,
+    /// Whether anything was written since the last fsync.
+    unsynced: bool,
+    last_fsync: Instant,
+    /// Total size of the files in the manifest.
+    current_size: u64,
+    /// `current_size` right after the last rewrite or startup, for auto-aof-rewrite-percentage.
+    base_size: u64,
+}
+
+/// The open AOF, or `None` while appendonly is off or the file is being loaded.
+static AOF: Lazy<Mutex<Option<AofState>>> = Lazy::new(|| Mutex::new(None));
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static LAST_WRITE_OK: AtomicBool = AtomicBool::new(true);
+static REWRITE_IN_PROGRESS: AtomicBool = AtomicBool::new(false);
+static LAST_REWRITE_OK: AtomicBool = AtomicBool::new(true);
+/// Set while the AOF is replayed at startup.
+static LOADING: AtomicBool = AtomicBool::new(false);
+
+/// Appends a write command that ran against `db_id` to the AOF, if it is enabled.
+pub async fn feed(db_id: usize, command: &Command) {
+    let fsync = CONFIG.read().await.appendfsync;
+    let mut aof = AOF.lock().await;
+    let Some(state) = aof.as_mut() else {
+        return;
+    };
+    let mut buff = Vec::new();
+    if state.selected_db != Some(db_id) {
+        buff.extend_from_slice(&build_resp_command(&Command::Select(db_id)));
+        state.selected_db = Some(db_id);
+    }
+    buff.extend_from_slice(&build_resp_command(command));
+
+    let result = async {
+        state.file.write_all(&buff).await?;
+        state.file.flush().await?;
+        state.current_size += buff.len() as u64;
+        if fsync == AppendFsync::Always {
+            state.file.sync_data().await?;
+            state.last_fsync = Instant::now();
+        } else {
+            state.unsynced = true;
+        }
+        Ok::<_, std::io::Error>(())
+    }
+    .await;
+    if let Err(e) = result.as_ref() {
+        println!("Error writing to the AOF file: {}", e);
+    }
+    LAST_WRITE_OK.store(result.is_ok(), Ordering::Relaxed);
+}
+
+/// Flushes the AOF to disk if `appendfsync everysec` is due. Called periodically from
+/// the server's background task.
+pub async fn fsync_if_needed() {
+    if CONFIG.read().await.appendfsync != AppendFsync::EverySec {
+        return;
+    }
+    let file = {
+        let mut aof = AOF.lock().await;
+        let Some(state) = aof.as_mut() else {
+            return;
+        };
+        if !state.unsynced || state.last_fsync.elapsed() < Duration::from_secs(1) {
+            return;
+        }
+        state.unsynced = false;
+        state.last_fsync = Instant::now();
+        state.file.try_clone().await
+    };
+    // Sync through a second handle so writers aren't held up by the disk.
+    if let Err(e) = async { file?.sync_data().await }.await {
+        println!("Error fsyncing the AOF file: {}", e);
+    }
+}
+
+/// Flushes the AOF to disk regardless of the policy, before the server exits.
+pub async fn fsync() {
+    if let Some(state) = AOF.lock().await.as_mut() {
+        if let Err(e) = state.file.sync_data().await {
+            println!("Error fsyncing the AOF file: {}", e);
+        }
+    }
+}
+
+/// Rebuilds the dataset from the files listed in the AOF manifest. Returns false,
+/// loading nothing, if there is no AOF yet.
+pub async fn load() -> Result<bool, AofError> {
+    let (dir, filename, legacy_path, load_truncated, verify_checksum) = {
+        let config = CONFIG.read().await;
+        (
+            config.aof_dir(),
+            config.appendfilename.clone(),
+            config.aof_path(),
+            config.aof_load_truncated,
+            config.rdb_checksum,
+        )
+    };
+    let manifest = match read_manifest(&dir, &filename).await? {
+        Some(manifest) => manifest,
+        None if tokio::fs::try_exists(&legacy_path).await? => {
+            upgrade(&dir, &filename, &legacy_path).await?
+        }
+        None => return Ok(false),
+    };
+
+    LOADING.store(true, Ordering::Relaxed);
+    let result = load_files(&dir, &manifest, load_truncated, verify_checksum).await;
+    LOADING.store(false, Ordering::Relaxed);
+    result?;
+    persistence::clear_dirty();
+    Ok(true)
+}
+
+/// Replays the files of `manifest` in order.
+async fn load_files(
+    dir: &Path,
+    manifest: &Manifest,
+    load_truncated: bool,
+    verify_checksum: bool,
+) -> Result<(), AofError> {
+    let files = manifest.files().collect::<Vec<_>>();
+    for (i, file) in files.iter().enumerate() {
+        let path = dir.join(&file.name);
+        let contents = match tokio::fs::read(&path).await {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                return Err(AofError::MissingFile(file.name.clone()))
+            }
+            Err(e) => return Err(e.into()),
+        };
+        // Only the file being appended to when the server stopped can have a torn tail.
+        let is_last = i + 1 == files.len();
+        let valid_len = replay(&contents, load_truncated && is_last, verify_checksum).await?;
+        if valid_len < contents.len() {
+            let file = OpenOptions::new().write(true).open(&path).await?;
+            file.set_len(valid_len as u64).await?;
+            println!(
+                "AOF loaded anyway because aof-load-truncated is enabled, truncated {:?} to {} bytes",
+                path, valid_len
+            );
+        }
+        println!("DB loaded from {} file {:?}", file.kind.describe(), path);
+    }
+    Ok(())
+}
+
+async fn read_manifest(dir: &Path, filename: &str) -> Result<Option<Manifest>, AofError> {
+    let path = dir.join(format!("{}.manifest", filename));
+    match tokio::fs::read_to_string(&path).await {
+        Ok(text) => Manifest::parse(&text).map(Some),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Replaces the manifest atomically, so a crash leaves either the old or the new one.
+async fn write_manifest(dir: &Path, filename: &str, manifest: &Manifest) -> std::io::Result<()> {
+    let path = dir.join(format!("{}.manifest", filename));
+    let temp_path = dir.join(format!("temp-{}.manifest", filename));
+    let mut file = File::create(&temp_path).await?;
+    file.write_all(manifest.to_text().as_bytes()).await?;
+    file.sync_all().await?;
+    tokio::fs::rename(&temp_path, &path).await
+}
+
+/// Moves a single-file AOF from before manifests existed into `dir`, as the base file.
+async fn upgrade(dir: &Path, filename: &str, legacy_path: &Path) -> Result<Manifest, AofError> {
+    tokio::fs::create_dir_all(dir).await?;
+    tokio::fs::rename(legacy_path, dir.join(filename)).await?;
+    let manifest = Manifest {
+        base: Some(AofFile {
+            name: filename.to_owned(),
+            seq: 1,
+            kind: AofFileType::Base,
+        }),
+        ..Default::default()
+    };
+    write_manifest(dir, filename, &manifest).await?;
+    println!(
+        "Successfully migrated an old-style AOF {:?} into the AOF directory {:?}",
+        legacy_path, dir
+    );
+    Ok(manifest)
+}
+
+async fn files_size(dir: &Path, manifest: &Manifest) -> std::io::Result<u64> {
+    let mut size = 0;
+    for file in manifest.files() {
+        size += tokio::fs::metadata(dir.join(&file.name)).await?.len();
+    }
+    Ok(size)
+}
+
+/// Runs every command of `contents` and returns how many leading bytes are valid. A
+/// truncated tail, or a MULTI block never closed, is left out if `load_truncated`.
+async fn replay(
+    contents: &[u8],
+    load_truncated: bool,
+    verify_checksum: bool,
+) -> Result<usize, AofError> {
+    let mut offset = 0;
+    if let Some((data, len)) = read_preamble(contents, verify_checksum).await? {
+        store::db_replace(data).await;
+        offset = len;
+    }
+
+    // Replies to replayed commands have nowhere to go.
+    let stream: ClientStream = Arc::new(Mutex::new(Box::new(tokio::io::sink())));
+    let mut client = Client::new(stream);
+    let mut valid_len = offset;
+    let mut multi_start = None;
+    while offset < contents.len() {
+        let (args, len) = match read_record(contents, offset)? {
+            Some(record) => record,
+            None => {
+                if !load_truncated {
+                    return Err(AofError::Truncated(offset));
+                }
+                println!(
+                    "!!! Warning: short read while loading the AOF file at offset {}!!!",
+                    offset
+                );
+                break;
+            }
+        };
+        let name = String::from_utf8_lossy(&args[0]).into_owned();
+        let command = parse_command(args).map_err(|_| AofError::UnknownCommand(name))?;
+        match command {
+            Command::Multi => multi_start = Some(offset),
+            Command::Exec => multi_start = None,
+            _ => {}
+        }
+        dispatch_command(Arc::clone(&client.handle.stream), &mut client, command).await;
+        offset += len;
+        if multi_start.is_none() {
+            valid_len = offset;
+        }
+    }
+
+    if let Some(multi_start) = multi_start {
+        if !load_truncated {
+            return Err(AofError::Truncated(multi_start));
+        }
+        println!("Revert incomplete MULTI/EXEC transaction in AOF file");
+    }
+    Ok(valid_len)
+}
+
+/// Decodes the RDB preamble `contents` starts with, if any, returning it with its length.
+async fn read_preamble(
+    contents: &[u8],
+    verify_checksum: bool,
+) -> Result<Option<(RdbData, usize)>, AofError> {
+    if !contents.starts_with(b"REDIS") {
+        return Ok(None);
+    }
+    let mut preamble = Cursor::new(contents);
+    let data = RdbReader::read_from(&mut preamble, verify_checksum).await?;
+    Ok(Some((data, preamble.position() as usize)))
+}
+
+/// Parses the command at `offset`, returning its arguments and length, or `None` if the
+/// file ends before it does.
+fn read_record(contents: &[u8], offset: usize) -> Result<Option<(Args, usize)>, AofError> {
+    if contents[offset] != b'*' {
+        return Err(AofError::BadFormat(offset));
+    }
+    match parse_request(&contents[offset..]) {
+        Ok(Some((args, len))) if !args.is_empty() => Ok(Some((args, len))),
+        Ok(Some(_)) | Err(_) => Err(AofError::BadFormat(offset)),
+        Ok(None) => Ok(None),
+    }
+}
+
+/// What `check` found in an AOF file.
+pub struct AofCheck {
+    /// Whether the file starts with an RDB preamble.
+    pub preamble: bool,
+    /// Whole commands after the preamble, up to the first error.
+    pub commands: usize,
+    /// Length of the leading part made of whole commands, outside any MULTI block.
+    pub valid_len: usize,
+    /// Why the rest of the file isn't valid.
+    pub error: Option<AofError>,
+}
+
+/// Verifies every record of an AOF file without running anything, as
+/// `altredis-check-aof` does.
+pub async fn check(contents: &[u8], verify_checksum: bool) -> AofCheck {
+    let mut result = AofCheck {
+        preamble: contents.starts_with(b"REDIS"),
+        commands: 0,
+        valid_len: 0,
+        error: None,
+    };
+    let mut offset = match read_preamble(contents, verify_checksum).await {
+        Ok(Some((_, len))) => len,
+        Ok(None) => 0,
+        Err(e) => {
+            result.error = Some(e);
+            return result;
+        }
+    };
+    result.valid_len = offset;
+    let mut multi_start = None;
+    while offset < contents.len() {
+        let (args, len) = match read_record(contents, offset) {
+            Ok(Some(record)) => record,
+            Ok(None) => {
+                result.error = Some(AofError::Truncated(offset));
+                return result;
+            }
+            Err(e) => {
+                result.error = Some(e);
+                return result;
+            }
+        };
+        match args[0].to_ascii_uppercase().as_slice() {
+            b"MULTI" if multi_start.is_some() => {
+                result.error = Some(AofError::UnexpectedMulti(offset));
+                return result;
+            }
+            b"MULTI" => multi_start = Some(offset),
+            b"EXEC" if multi_start.is_none() => {
+                result.error = Some(AofError::UnexpectedExec(offset));
+                return result;
+            }
+            b"EXEC" => multi_start = None,
+            _ => {}
+        }
+        result.commands += 1;
+        offset += len;
+        if multi_start.is_none() {
+            result.valid_len = offset;
+        }
+    }
+    if let Some(multi_start) = multi_start {
+        result.error = Some(AofError::UnclosedMulti(multi_start));
+    }
+    result
+}
+
+/// Paths of the files listed in the manifest at `path`, in replay order.
+pub async fn manifest_files(path: &Path) -> Result<Vec<PathBuf>, AofError> {
+    let text = tokio::fs::read_to_string(path).await?;
+    let manifest = Manifest::parse(&text)?;
+    let dir = path.parent().unwrap_or(Path::new("."));
+    Ok(manifest.files().map(|file| dir.join(&file.name)).collect())
+}
+
+/// How a base file is written, copied out of the config.
+#[derive(Clone, Copy)]
+struct BaseSettings {
+    use_rdb_preamble: bool,
+    compression: bool,
+    checksum: bool,
+}
+
+/// Writes `databases` as base file of the manifest, returning its entry.
+async fn write_base(
+    databases: HashMap<usize, Database>,
+    dir: &Path,
+    filename: &str,
+    manifest: &Manifest,
+    settings: BaseSettings,
+) -> anyhow::Result<AofFile> {
+    let (contents, rdb) = tokio::task::spawn_blocking(move || {
+        if settings.use_rdb_preamble {
+            let snapshot =
+                RdbWriter::write_snapshot(&databases, settings.compression, settings.checksum);
+            (snapshot, true)
+        } else {
+            (encode_commands(&databases, settings.compression), false)
+        }
+    })
+    .await?;
+    let base = manifest.next_base(filename, rdb);
+    RdbWriter::write_file(dir.join(&base.name), &contents).await?;
+    Ok(base)
+}
+
+/// The commands recreating `databases`. Strings are written as SET; the other types have
+/// no commands to build them here (sorted sets only come from GEOADD, lists, sets,
+/// hashes and streams only from RDB files), so they are written as RESTORE with their
+/// DUMP payload, which replays every type.
+fn encode_commands(databases: &HashMap<usize, Database>, compression: bool) -> Vec<u8> {
+    let now = SystemTime::now();
+    let mut db_ids = databases.keys().copied().collect::<Vec<_>>();
+    db_ids.sort();
+    let mut buff = Vec::new();
+    for db_id in db_ids {
+        let mut selected = false;
+        for (key, entry) in &databases[&db_id] {
+            if entry.expiry.is_some_and(|expiry| expiry < now) {
+                continue;
+            }
+            if !selected {
+                buff.extend(build_resp_command(&Command::Select(db_id)));
+                selected = true;
+            }
+            let Data::String(value) = &entry.value else {
+                let options = RestoreOptions {
+                    payload: RdbWriter::dump_value(&entry.value, compression),
+                    expiry: entry.expiry,
+                    replace: false,
+                    access: entry.access,
+                };
+                buff.extend(build_resp_command(&Command::Restore(key.clone(), options)));
+                continue;
+            };
+            let mut args = vec![
+                build_resp_bulk(b"SET"),
+                build_resp_bulk(key),
+                build_resp_bulk(value),
+            ];
+            if let Some(expiry) = entry.expiry {
+                args.push(build_resp_bulk(b"PXAT"));
+                args.push(build_resp_bulk(unix_millis(expiry).to_string().as_bytes()));
+            }
+            buff.extend(build_resp_array_raw(args));
+        }
+    }
+    buff
+}
+
+async fn base_settings() -> BaseSettings {
+    let config = CONFIG.read().await;
+    BaseSettings {
+        use_rdb_preamble: config.aof_use_rdb_preamble,
+        compression: config.rdb_compression,
+        checksum: config.rdb_checksum,
+    }
+}
+
+async fn open_incr(dir: &Path, incr: &AofFile) -> std::io::Result<File> {
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(dir.join(&incr.name))
+        .await
+}
+
+/// Starts appending write commands to the AOF. If there is none yet, its base file is
+/// written from the current dataset, so turning appendonly on keeps existing data.
+pub async fn open() -> anyhow::Result<()> {
+    let (dir, filename) = {
+        let config = CONFIG.read().await;
+        (config.aof_dir(), config.appendfilename.clone())
+    };
+    tokio::fs::create_dir_all(&dir).await?;
+    let mut manifest = match read_manifest(&dir, &filename).await? {
+        Some(manifest) => manifest,
+        None => {
+            let mut manifest = Manifest::default();
+            let databases = {
+                let _exclusive = EXEC_LOCK.write().await;
+                store::db_snapshot().await
+            };
+            let settings = base_settings().await;
+            manifest.base =
+                Some(write_base(databases, &dir, &filename, &manifest, settings).await?);
+            println!(
+                "Created AOF base file from the current dataset in {:?}",
+                dir
+            );
+            manifest
+        }
+    };
+    let incr = match manifest.incrs.last() {
+        Some(incr) => incr.clone(),
+        None => {
+            let incr = manifest.next_incr(&filename);
+            manifest.incrs.push(incr.clone());
+            incr
+        }
+    };
+    let file = open_incr(&dir, &incr).await?;
+    write_manifest(&dir, &filename, &manifest).await?;
+    let size = files_size(&dir, &manifest).await?;
+    *AOF.lock().await = Some(AofState {
+        file,
+        dir,
+        filename,
+        manifest,
+        selected_db: None,
+        unsynced: false,
+        last_fsync: Instant::now(),
+        current_size: size,
+        base_size: size,
+    });
+    ENABLED.store(true, Ordering::Relaxed);
+    Ok(())
+}
+
+/// Starts a BGREWRITEAOF. New commands go to a fresh incremental file from then on,
+/// while a snapshot of the dataset is written as the new base file in the background;
+/// once it is, the manifest drops the old base and incremental files.
+pub async fn bgrewriteaof() -> Result<(), AofError> {
+    if !enabled() {
+        return Err(AofError::Disabled);
+    }
+    if REWRITE_IN_PROGRESS.swap(true, Ordering::SeqCst) {
+        return Err(AofError::RewriteInProgress);
+    }
+    let settings = base_settings().await;
+    // Switching files waits for running commands, the caller's included, so it can't
+    // happen before replying.
+    tokio::spawn(rewrite(settings));
+    Ok(())
+}
+
+/// Rewrites the AOF and waits for the new base file, first letting a running rewrite
+/// finish, so the base ends up with the dataset as it is now rather than an older
+/// snapshot. The caller must not hold `EXEC_LOCK`.
+pub async fn rewrite_after_running() -> anyhow::Result<()> {
+    if !enabled() {
+        return Err(AofError::Disabled.into());
+    }
+    while REWRITE_IN_PROGRESS.swap(true, Ordering::SeqCst) {
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    let settings = base_settings().await;
+    rewrite(settings).await
+}
+
+/// Runs a rewrite claimed by setting `REWRITE_IN_PROGRESS`, and releases it.
+async fn rewrite(settings: BaseSettings) -> anyhow::Result<()> {
+    let result = async {
+        let (databases, first_incr_seq) = start_rewrite().await?;
+        finish_rewrite(databases, first_incr_seq, settings).await
+    }
+    .await;
+    match result.as_ref() {
+        Ok(()) => println!("Background AOF rewrite finished successfully"),
+        Err(e) => println!("Background AOF rewrite failed: {}", e),
+    }
+    LAST_REWRITE_OK.store(result.is_ok(), Ordering::Relaxed);
+    REWRITE_IN_PROGRESS.store(false, Ordering::SeqCst);
+    result
+}
+
+/// Switches to a new incremental file and snapshots the dataset at that same point.
+async fn start_rewrite() -> Result<(HashMap<usize, Database>, u64), AofError> {
+    // No command may run while switching, so each write lands either in the snapshot
+    // or in the new incremental file, never both or neither.
+    let _exclusive = EXEC_LOCK.write().await;
+    let mut aof = AOF.lock().await;
+    let state = aof.as_mut().ok_or(AofError::Disabled)?;
+    state.file.flush().await?;
+    state.file.sync_data().await?;
+    let incr = state.manifest.next_incr(&state.filename);
+    let file = open_incr(&state.dir, &incr).await?;
+    let mut manifest = state.manifest.clone();
+    manifest.incrs.push(incr.clone());
+    write_manifest(&state.dir, &state.filename, &manifest).await?;
+    state.manifest = manifest;
+    state.file = file;
+    state.selected_db = None;
+    state.unsynced = false;
+    Ok((store::db_snapshot().await, incr.seq))
+}
+
+async fn finish_rewrite(
+    databases: HashMap<usize, Database>,
+    first_incr_seq: u64,
+    settings: BaseSettings,
+) -> anyhow::Result<()> {
+    let (dir, filename, manifest) = {
+        let aof = AOF.lock().await;
+        let state = aof.as_ref().ok_or(AofError::Disabled)?;
+        (
+            state.dir.clone(),
+            state.filename.clone(),
+            state.manifest.clone(),
+        )
+    };
+    // Only this task changes the base, so the manifest read above still has it.
+    let base = write_base(databases, &dir, &filename, &manifest, settings).await?;
+
+    let mut aof = AOF.lock().await;
+    let state = aof.as_mut().ok_or(AofError::Disabled)?;
+    let mut manifest = state.manifest.clone();
+    let mut replaced = manifest.base.replace(base).into_iter().collect::<Vec<_>>();
+    replaced.extend(
+        manifest
+            .incrs
+            .iter()
+            .filter(|incr| incr.seq < first_incr_seq)
+            .cloned(),
+    );
+    manifest.incrs.retain(|incr| incr.seq >= first_incr_seq);
+    replaced.append(&mut manifest.history);
+    write_manifest(&dir, &filename, &manifest).await?;
+    state.manifest = manifest;
+    for file in replaced {
+        if let Err(e) = tokio::fs::remove_file(dir.join(&file.name)).await {
+            println!("Error removing old AOF file {}: {}", file.name, e);
+        }
+    }
+    state.current_size = files_size(&dir, &state.manifest).await?;
+    state.base_size = state.current_size;
+    Ok(())
+}
+
+/// Starts a rewrite once the AOF grew by `auto-aof-rewrite-percentage` since the last
+/// one. Called periodically from the server's background task.
+pub async fn rewrite_if_needed() {
+    let (percentage, min_size) = {
+        let config = CONFIG.read().await;
+        (
+            config.auto_aof_rewrite_percentage,
+            config.auto_aof_rewrite_min_size,
+        )
+    };
+    if percentage == 0 || REWRITE_IN_PROGRESS.load(Ordering::SeqCst) {
+        return;
+    }
+    let growth = {
+        let aof = AOF.lock().await;
+        let Some(state) = aof.as_ref() else {
+            return;
+        };
+        if state.current_size < min_size {
+            return;
+        }
+        (state.current_size * 100 / state.base_size.max(1)).saturating_sub(100)
+    };
+    if growth >= percentage {
+        println!("Starting automatic rewriting of AOF on {}% growth", growth);
+        if let Err(e) = bgrewriteaof().await {
+            println!("Error starting the AOF rewrite: {}", e);
+        }
+    }
+}
+
+pub fn enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+pub fn last_write_ok() -> bool {
+    LAST_WRITE_OK.load(Ordering::Relaxed)
+}
+
+pub fn rewrite_in_progress() -> bool {
+    REWRITE_IN_PROGRESS.load(Ordering::SeqCst)
+}
+
+pub fn last_rewrite_ok() -> bool {
+    LAST_REWRITE_OK.load(Ordering::Relaxed)
+}
+
+/// Whether the AOF is being replayed, so commands must not trigger saves.
+pub fn loading() -> bool {
+    LOADING.load(Ordering::Relaxed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn manifest_round_trips_and_names_the_next_files() {
+        let text = "file appendonly.aof.1.base.rdb seq 1 type b\n\
+                    file appendonly.aof.1.incr.aof seq 1 type i\n\
+                    file appendonly.aof.2.incr.aof seq 2 type i\n";
+        let manifest = Manifest::parse(text).unwrap();
+        assert_eq!(manifest.to_text(), text);
+        assert_eq!(
+            manifest
+                .files()
+                .map(|file| file.name.as_str())
+                .collect::<Vec<_>>(),
+            [
+                "appendonly.aof.1.base.rdb",
+                "appendonly.aof.1.incr.aof",
+                "appendonly.aof.2.incr.aof"
+            ]
+        );
+        assert_eq!(
+            manifest.next_base("appendonly.aof", false).name,
+            "appendonly.aof.2.base.aof"
+        );
+        assert_eq!(
+            manifest.next_incr("appendonly.aof").name,
+            "appendonly.aof.3.incr.aof"
+        );
+        assert_eq!(
+            Manifest::default().next_base("appendonly.aof", true).name,
+            "appendonly.aof.1.base.rdb"
+        );
+    }
+
+    #[test]
+    fn manifest_accepts_redis_key_order_and_comments() {
+        let manifest = Manifest::parse(
+            "# written by Redis\n\
+             seq 3 type h file old.aof\n\
+             type b file base.rdb seq 4\n",
+        )
+        .unwrap();
+        assert_eq!(manifest.base.unwrap().name, "base.rdb");
+        assert_eq!(manifest.history[0].kind, AofFileType::History);
+        assert!(manifest.incrs.is_empty());
+    }
+
+    #[test]
+    fn manifest_rejects_invalid_lines() {
+        for text in [
+            "file a seq 1",
+            "file a seq one type i",
+            "file a seq 1 type x",
+            "file ../a seq 1 type i",
+            "file a seq 1 type b\nfile b seq 2 type b",
+            "file a seq 2 type i\nfile b seq 1 type i",
+            "file a seq 1 type",
+        ] {
+            assert!(
+                matches!(Manifest::parse(text), Err(AofError::InvalidManifest(_))),
+                "{:?} was accepted",
+                text
+            );
+        }
+    }
+}
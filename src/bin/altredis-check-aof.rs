@@ -0,0 +1,131 @@
+//! Verifies an AOF file, or every file listed in an AOF manifest, without starting a
+//! server, and with `--fix` truncates a damaged file to its last valid command.
+
+use altredis::aof::{self, AofCheck};
+use std::env::args;
+use std::path::{Path, PathBuf};
+use std::process::exit;
+use tokio::fs::OpenOptions;
+
+const USAGE: &str = "Usage: altredis-check-aof [--fix] [--no-checksum] <file.aof|file.manifest>";
+
+struct Options {
+    path: PathBuf,
+    fix: bool,
+    verify_checksum: bool,
+}
+
+fn parse_options() -> Option<Options> {
+    let mut path = None;
+    let mut fix = false;
+    let mut verify_checksum = true;
+    for arg in args().skip(1) {
+        match arg.as_str() {
+            "--fix" => fix = true,
+            "--no-checksum" => verify_checksum = false,
+            _ if path.is_none() && !arg.starts_with("--") => path = Some(PathBuf::from(arg)),
+            _ => return None,
+        }
+    }
+    Some(Options {
+        path: path?,
+        fix,
+        verify_checksum,
+    })
+}
+
+/// Checks one file and prints the analysis, returning what was found and its size.
+async fn check_file(path: &Path, verify_checksum: bool) -> (AofCheck, usize) {
+    let contents = match tokio::fs::read(path).await {
+        Ok(contents) => contents,
+        Err(e) => {
+            println!("Cannot read {}: {}", path.display(), e);
+            exit(1);
+        }
+    };
+    let check = aof::check(&contents, verify_checksum).await;
+    println!(
+        "AOF analyzed: filename={}, size={}, ok_up_to={}, commands={}{}, diff={}",
+        path.display(),
+        contents.len(),
+        check.valid_len,
+        check.commands,
+        if check.preamble {
+            ", rdb_preamble=yes"
+        } else {
+            ""
+        },
+        contents.len() - check.valid_len
+    );
+    (check, contents.len())
+}
+
+async fn truncate(path: &Path, len: usize) -> std::io::Result<()> {
+    let file = OpenOptions::new().write(true).open(path).await?;
+    file.set_len(len as u64).await?;
+    file.sync_all().await
+}
+
+#[tokio::main]
+async fn main() {
+    let Some(options) = parse_options() else {
+        println!("{}", USAGE);
+        exit(1);
+    };
+    let files = if options
+        .path
+        .extension()
+        .is_some_and(|ext| ext == "manifest")
+    {
+        println!("Start checking Multi Part AOF");
+        match aof::manifest_files(&options.path).await {
+            Ok(files) => files,
+            Err(e) => {
+                println!("Invalid manifest {}: {}", options.path.display(), e);
+                exit(1);
+            }
+        }
+    } else {
+        println!("Start checking Old-Style AOF");
+        vec![options.path.clone()]
+    };
+
+    for (i, path) in files.iter().enumerate() {
+        let (check, size) = check_file(path, options.verify_checksum).await;
+        let Some(error) = check.error else {
+            println!("AOF {} is valid", path.display());
+            continue;
+        };
+        println!("AOF {} is not valid: {}", path.display(), error);
+        if !options.fix {
+            println!("Use the --fix option to truncate it to the last valid command");
+            exit(1);
+        }
+        // Commands in later files depend on this one, so only the last file can lose
+        // its tail without corrupting the dataset.
+        if i + 1 < files.len() {
+            println!(
+                "Only the last file of the manifest can be fixed; {} is followed by other files",
+                path.display()
+            );
+            exit(1);
+        }
+        if check.preamble && check.valid_len == 0 {
+            println!("The RDB preamble is damaged, so there is nothing valid to keep");
+            exit(1);
+        }
+        if let Err(e) = truncate(path, check.valid_len).await {
+            println!("Failed to truncate {}: {}", path.display(), e);
+            exit(1);
+        }
+        println!(
+            "Successfully truncated AOF {} from {} to {} bytes",
+            path.display(),
+            size,
+            check.valid_len
+        );
+    }
+    if files.is_empty() {
+        println!("The manifest lists no files");
+    }
+}
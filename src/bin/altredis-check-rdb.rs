@@ -0,0 +1,339 @@
+//! Inspects an RDB file without starting a server: validates it and reports its
+//! version, aux fields, key counts per database and type, the biggest keys and the
+//! keys with an expiry, optionally exporting the report as JSON.
+
+use altredis::rdb::{RdbData, RdbReader};
+use altredis::store::Data;
+use std::collections::BTreeMap;
+use std::env::args;
+use std::fmt::Write;
+use std::process::exit;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::fs::File;
+use tokio::io::BufReader;
+
+const USAGE: &str =
+    "Usage: altredis-check-rdb <rdb-file> [--top <n>] [--json <output-file>] [--no-checksum]";
+
+struct Options {
+    path: String,
+    top: usize,
+    json: Option<String>,
+    verify_checksum: bool,
+}
+
+/// One key of the file, as listed in the report.
+struct KeyInfo {
+    db: usize,
+    key: String,
+    kind: &'static str,
+    /// Bytes for strings, elements for everything else.
+    size: usize,
+    expiry_ms: Option<u128>,
+}
+
+#[derive(Default)]
+struct DbStats {
+    keys: usize,
+    expires: usize,
+    types: BTreeMap<&'static str, usize>,
+}
+
+fn parse_options() -> Option<Options> {
+    let mut options = Options {
+        path: String::new(),
+        top: 10,
+        json: None,
+        verify_checksum: true,
+    };
+    let mut path = None;
+    let mut iter = args().skip(1);
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "--top" => options.top = iter.next()?.parse().ok()?,
+            "--json" => options.json = Some(iter.next()?),
+            "--no-checksum" => options.verify_checksum = false,
+            "-h" | "--help" => return None,
+            _ if path.is_none() && !arg.starts_with("--") => path = Some(arg),
+            _ => return None,
+        }
+    }
+    options.path = path?;
+    Some(options)
+}
+
+fn type_name(data: &Data) -> &'static str {
+    match data {
+        Data::String(_) => "string",
+        Data::List(_) => "list",
+        Data::Set(_) => "set",
+        Data::Hash(_) => "hash",
+        Data::SortedSet(_) => "zset",
+        Data::Stream(_) => "stream",
+    }
+}
+
+fn size_of(data: &Data) -> usize {
+    match data {
+        Data::String(value) => value.len(),
+        Data::List(list) => list.len(),
+        Data::Set(set) => set.len(),
+        Data::Hash(hash) => hash.len(),
+        Data::SortedSet(sorted_set) => sorted_set.len(),
+        Data::Stream(stream) => stream.entries.len(),
+    }
+}
+
+fn unix_millis(time: SystemTime) -> u128 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis()
+}
+
+/// Flattens the file into one entry per key, ordered by database then key.
+fn collect_keys(data: &RdbData) -> Vec<KeyInfo> {
+    let mut keys = Vec::new();
+    for (db, database) in &data.databases {
+        let expirations = data.expirations.get(db);
+        for (key, value) in database {
+            keys.push(KeyInfo {
+                db: *db,
+                key: String::from_utf8_lossy(key).into_owned(),
+                kind: type_name(value),
+                size: size_of(value),
+                expiry_ms: expirations
+                    .and_then(|expirations| expirations.get(key))
+                    .map(|expiry| unix_millis(*expiry)),
+            });
+        }
+    }
+    keys.sort_by(|a, b| (a.db, &a.key).cmp(&(b.db, &b.key)));
+    keys
+}
+
+fn db_stats(keys: &[KeyInfo]) -> BTreeMap<usize, DbStats> {
+    let mut stats = BTreeMap::<usize, DbStats>::new();
+    for key in keys {
+        let db = stats.entry(key.db).or_default();
+        db.keys += 1;
+        db.expires += key.expiry_ms.is_some() as usize;
+        *db.types.entry(key.kind).or_default() += 1;
+    }
+    stats
+}
+
+/// The `top` biggest keys of each type, biggest first.
+fn biggest_keys(keys: &[KeyInfo], top: usize) -> Vec<&KeyInfo> {
+    let mut by_type = BTreeMap::<&str, Vec<&KeyInfo>>::new();
+    for key in keys {
+        by_type.entry(key.kind).or_default().push(key);
+    }
+    by_type
+        .into_values()
+        .flat_map(|mut keys| {
+            keys.sort_by_key(|key| std::cmp::Reverse(key.size));
+            keys.truncate(top);
+            keys
+        })
+        .collect()
+}
+
+fn size_unit(kind: &str) -> &'static str {
+    match kind {
+        "string" => "bytes",
+        "list" | "set" => "items",
+        "hash" => "fields",
+        "zset" => "members",
+        _ => "entries",
+    }
+}
+
+fn print_report(data: &RdbData, keys: &[KeyInfo], options: &Options, now_ms: u128) {
+    println!("RDB version: {}", data.rdb_version);
+    let aux = data.metadata.iter().collect::<BTreeMap<_, _>>();
+    for (name, value) in aux {
+        println!("AUX {} = '{}'", name, value);
+    }
+    if !data.functions.is_empty() {
+        println!("Function libraries: {}", data.functions.len());
+    }
+
+    let stats = db_stats(keys);
+    for (db, stats) in &stats {
+        let types = stats
+            .types
+            .iter()
+            .map(|(kind, count)| format!("{}: {}", kind, count))
+            .collect::<Vec<_>>()
+            .join(", ");
+        println!(
+            "DB {}: {} keys, {} with an expiry ({})",
+            db, stats.keys, stats.expires, types
+        );
+    }
+    if stats.is_empty() {
+        println!("No keys");
+    }
+
+    let biggest = biggest_keys(keys, options.top);
+    if !biggest.is_empty() {
+        println!("Biggest keys:");
+        for key in biggest {
+            println!(
+                "  db {} {} '{}': {} {}",
+                key.db,
+                key.kind,
+                key.key,
+                key.size,
+                size_unit(key.kind)
+            );
+        }
+    }
+
+    let mut expiring = keys
+        .iter()
+        .filter(|key| key.expiry_ms.is_some())
+        .collect::<Vec<_>>();
+    expiring.sort_by_key(|key| key.expiry_ms);
+    if !expiring.is_empty() {
+        println!("Expirations (soonest first):");
+        for key in expiring.iter().take(options.top) {
+            let expiry_ms = key.expiry_ms.unwrap_or_default();
+            let when = if expiry_ms <= now_ms {
+                "already expired".to_owned()
+            } else {
+                format!("in {}s", (expiry_ms - now_ms) / 1000)
+            };
+            println!(
+                "  db {} '{}' at {} ms ({})",
+                key.db, key.key, expiry_ms, when
+            );
+        }
+        if expiring.len() > options.top {
+            println!("  ... and {} more", expiring.len() - options.top);
+        }
+    }
+}
+
+fn json_string(text: &str) -> String {
+    let mut out = String::with_capacity(text.len() + 2);
+    out.push('"');
+    for c in text.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+fn json_key(key: &KeyInfo) -> String {
+    let expiry = key
+        .expiry_ms
+        .map_or("null".to_owned(), |expiry| expiry.to_string());
+    format!(
+        "{{\"db\":{},\"key\":{},\"type\":\"{}\",\"size\":{},\"expiry_ms\":{}}}",
+        key.db,
+        json_string(&key.key),
+        key.kind,
+        key.size,
+        expiry
+    )
+}
+
+/// The report as a JSON document, with every key listed under `keys`.
+fn json_report(data: &RdbData, keys: &[KeyInfo], top: usize, error: Option<String>) -> String {
+    let aux = data
+        .metadata
+        .iter()
+        .collect::<BTreeMap<_, _>>()
+        .into_iter()
+        .map(|(name, value)| format!("{}:{}", json_string(name), json_string(value)))
+        .collect::<Vec<_>>();
+    let databases = db_stats(keys)
+        .into_iter()
+        .map(|(db, stats)| {
+            let types = stats
+                .types
+                .iter()
+                .map(|(kind, count)| format!("\"{}\":{}", kind, count))
+                .collect::<Vec<_>>();
+            format!(
+                "{{\"db\":{},\"keys\":{},\"expires\":{},\"types\":{{{}}}}}",
+                db,
+                stats.keys,
+                stats.expires,
+                types.join(",")
+            )
+        })
+        .collect::<Vec<_>>();
+    let biggest = biggest_keys(keys, top)
+        .into_iter()
+        .map(json_key)
+        .collect::<Vec<_>>();
+    let all_keys = keys.iter().map(json_key).collect::<Vec<_>>();
+    format!(
+        "{{\"valid\":{},\"error\":{},\"rdb_version\":{},\"aux\":{{{}}},\"functions\":{},\"databases\":[{}],\"biggest_keys\":[{}],\"keys\":[{}]}}\n",
+        error.is_none(),
+        error.map_or("null".to_owned(), |e| json_string(&e)),
+        data.rdb_version,
+        aux.join(","),
+        data.functions.len(),
+        databases.join(","),
+        biggest.join(","),
+        all_keys.join(",")
+    )
+}
+
+#[tokio::main]
+async fn main() {
+    let Some(options) = parse_options() else {
+        println!("{}", USAGE);
+        exit(1);
+    };
+    println!("[offset 0] Checking RDB file {}", options.path);
+    let file = match File::open(&options.path).await {
+        Ok(file) => file,
+        Err(e) => {
+            println!("Cannot open {}: {}", options.path, e);
+            exit(1);
+        }
+    };
+    // Report whatever could be read before an error, as that is usually what helps.
+    let (data, error) =
+        RdbReader::read_partial_from(BufReader::new(file), options.verify_checksum).await;
+    let keys = collect_keys(&data);
+    print_report(&data, &keys, &options, unix_millis(SystemTime::now()));
+
+    if let Some(path) = options.json.as_deref() {
+        let json = json_report(
+            &data,
+            &keys,
+            options.top,
+            error.as_ref().map(|e| e.to_string()),
+        );
+        if let Err(e) = tokio::fs::write(path, json).await {
+            println!("Cannot write {}: {}", path, e);
+            exit(1);
+        }
+        println!("JSON report written to {}", path);
+    }
+
+    match error {
+        None => println!("\\o/ RDB looks OK! \\o/"),
+        Some(e) => {
+            println!("--- RDB ERROR DETECTED ---");
+            println!("{}", e);
+            println!("Keys read before the error: {}", keys.len());
+            exit(1);
+        }
+    }
+}
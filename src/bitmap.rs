@@ -0,0 +1,620 @@
+use std::fmt;
+use thiserror::Error;
+
+/// Largest addressable bit, matching the 512MB limit on string values.
+pub const MAX_BIT_OFFSET: u64 = 512 * 1024 * 1024 * 8 - 1;
+
+#[derive(Error, Debug)]
+pub enum BitmapError {
+    #[error("bit offset is not an integer or out of range")]
+    InvalidBitOffset,
+
+    #[error("bit is not an integer or out of range")]
+    InvalidBit,
+
+    #[error("value is not an integer or out of range")]
+    NotAnInteger,
+
+    #[error("syntax error")]
+    Syntax,
+
+    #[error("Invalid bitfield type. Use something like i16 u8. Note that u64 is not supported but i64 is.")]
+    InvalidBitFieldType,
+
+    #[error("Invalid OVERFLOW type specified")]
+    InvalidOverflowType,
+
+    #[error("BITFIELD_RO only supports the GET subcommand")]
+    ReadOnlySubcommand,
+
+    #[error("BITOP NOT must be called with a single source key.")]
+    BitOpNotSingleSource,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BitUnit {
+    Byte,
+    Bit,
+}
+
+impl fmt::Display for BitUnit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BitUnit::Byte => write!(f, "BYTE"),
+            BitUnit::Bit => write!(f, "BIT"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BitOperation {
+    And,
+    Or,
+    Xor,
+    Not,
+}
+
+impl fmt::Display for BitOperation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BitOperation::And => write!(f, "AND"),
+            BitOperation::Or => write!(f, "OR"),
+            BitOperation::Xor => write!(f, "XOR"),
+            BitOperation::Not => write!(f, "NOT"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Overflow {
+    Wrap,
+    Sat,
+    Fail,
+}
+
+impl fmt::Display for Overflow {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Overflow::Wrap => write!(f, "WRAP"),
+            Overflow::Sat => write!(f, "SAT"),
+            Overflow::Fail => write!(f, "FAIL"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BitFieldType {
+    pub signed: bool,
+    pub bits: u32,
+}
+
+impl fmt::Display for BitFieldType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let sign = if self.signed { "i" } else { "u" };
+        write!(f, "{}{}", sign, self.bits)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum BitFieldOp {
+    Get {
+        ty: BitFieldType,
+        offset: u64,
+    },
+    Set {
+        ty: BitFieldType,
+        offset: u64,
+        value: i64,
+        overflow: Overflow,
+    },
+    IncrBy {
+        ty: BitFieldType,
+        offset: u64,
+        increment: i64,
+        overflow: Overflow,
+    },
+}
+
+impl BitFieldOp {
+    pub fn is_write(&self) -> bool {
+        !matches!(self, BitFieldOp::Get { .. })
+    }
+
+    /// The subcommand's arguments. Writes repeat the OVERFLOW mode they were parsed under.
+    pub fn to_args(&self) -> Vec<String> {
+        match self {
+            BitFieldOp::Get { ty, offset } => {
+                vec!["GET".to_owned(), ty.to_string(), offset.to_string()]
+            }
+            BitFieldOp::Set {
+                ty,
+                offset,
+                value,
+                overflow,
+            } => vec![
+                "OVERFLOW".to_owned(),
+                overflow.to_string(),
+                "SET".to_owned(),
+                ty.to_string(),
+                offset.to_string(),
+                value.to_string(),
+            ],
+            BitFieldOp::IncrBy {
+                ty,
+                offset,
+                increment,
+                overflow,
+            } => vec![
+                "OVERFLOW".to_owned(),
+                overflow.to_string(),
+                "INCRBY".to_owned(),
+                ty.to_string(),
+                offset.to_string(),
+                increment.to_string(),
+            ],
+        }
+    }
+}
+
+pub fn parse_bit_offset(arg: &str) -> Result<u64, BitmapError> {
+    arg.parse::<u64>()
+        .ok()
+        .filter(|offset| *offset <= MAX_BIT_OFFSET)
+        .ok_or(BitmapError::InvalidBitOffset)
+}
+
+pub fn parse_bitfield_type(arg: &str) -> Result<BitFieldType, BitmapError> {
+    let signed = match arg.chars().next() {
+        Some('i') | Some('I') => true,
+        Some('u') | Some('U') => false,
+        _ => return Err(BitmapError::InvalidBitFieldType),
+    };
+    let bits = arg[1..]
+        .parse::<u32>()
+        .map_err(|_| BitmapError::InvalidBitFieldType)?;
+    let max_bits = if signed { 64 } else { 63 };
+    if bits < 1 || bits > max_bits {
+        return Err(BitmapError::InvalidBitFieldType);
+    }
+    Ok(BitFieldType { signed, bits })
+}
+
+/// Parses a BITFIELD offset, where a `#` prefix means "multiply by the type width".
+pub fn parse_bitfield_offset(arg: &str, ty: BitFieldType) -> Result<u64, BitmapError> {
+    let (multiplied, digits) = match arg.strip_prefix('#') {
+        Some(digits) => (true, digits),
+        None => (false, arg),
+    };
+    let offset = digits
+        .parse::<u64>()
+        .map_err(|_| BitmapError::InvalidBitOffset)?;
+    let offset = if multiplied {
+        offset
+            .checked_mul(ty.bits as u64)
+            .ok_or(BitmapError::InvalidBitOffset)?
+    } else {
+        offset
+    };
+    // The whole field has to fit, as in Redis, not just its first bit.
+    if offset > MAX_BIT_OFFSET + 1 - ty.bits as u64 {
+        return Err(BitmapError::InvalidBitOffset);
+    }
+    Ok(offset)
+}
+
+pub fn get_bit(bytes: &[u8], offset: u64) -> u8 {
+    let byte = (offset >> 3) as usize;
+    let shift = 7 - (offset & 7);
+    bytes.get(byte).map_or(0, |b| (b >> shift) & 1)
+}
+
+/// Sets a bit, growing the string with zero bytes as needed, and returns the previous bit.
+pub fn set_bit(bytes: &mut Vec<u8>, offset: u64, bit: u8) -> u8 {
+    let byte = (offset >> 3) as usize;
+    let shift = 7 - (offset & 7);
+    if bytes.len() <= byte {
+        bytes.resize(byte + 1, 0);
+    }
+    let previous = (bytes[byte] >> shift) & 1;
+    if bit == 1 {
+        bytes[byte] |= 1 << shift;
+    } else {
+        bytes[byte] &= !(1 << shift);
+    }
+    previous
+}
+
+/// Resolves Redis style (possibly negative) start/end indexes against a length,
+/// returning `None` when the range is empty.
+fn normalize_range(start: i64, end: i64, len: i64) -> Option<(i64, i64)> {
+    let start = if start < 0 {
+        (len + start).max(0)
+    } else {
+        start
+    };
+    let end = if end < 0 { (len + end).max(0) } else { end };
+    let end = end.min(len - 1);
+    if len == 0 || start > end {
+        None
+    } else {
+        Some((start, end))
+    }
+}
+
+/// Converts a range into inclusive bit positions, or `None` if it selects nothing.
+fn bit_range(bytes: &[u8], range: Option<(i64, i64, BitUnit)>) -> Option<(u64, u64)> {
+    let byte_len = bytes.len() as i64;
+    match range {
+        None => normalize_range(0, -1, byte_len).map(|(s, e)| (s as u64 * 8, e as u64 * 8 + 7)),
+        Some((start, end, BitUnit::Byte)) => {
+            normalize_range(start, end, byte_len).map(|(s, e)| (s as u64 * 8, e as u64 * 8 + 7))
+        }
+        Some((start, end, BitUnit::Bit)) => {
+            normalize_range(start, end, byte_len * 8).map(|(s, e)| (s as u64, e as u64))
+        }
+    }
+}
+
+pub fn bit_count(bytes: &[u8], range: Option<(i64, i64, BitUnit)>) -> u64 {
+    let Some((mut start, end)) = bit_range(bytes, range) else {
+        return 0;
+    };
+
+    let mut count = 0u64;
+    while start <= end {
+        let byte = bytes[(start >> 3) as usize];
+        if start & 7 == 0 && end - start >= 7 {
+            count += byte.count_ones() as u64;
+            start += 8;
+        } else {
+            count += ((byte >> (7 - (start & 7))) & 1) as u64;
+            start += 1;
+        }
+    }
+    count
+}
+
+/// Returns the position of the first bit set to `bit`, following BITPOS semantics
+/// for missing ranges: a clear bit is assumed just past the string when no end is given.
+pub fn bit_pos(bytes: &[u8], bit: u8, start: Option<i64>, end: Option<i64>, unit: BitUnit) -> i64 {
+    if bytes.is_empty() {
+        return if bit == 1 { -1 } else { 0 };
+    }
+
+    let range = (start.unwrap_or(0), end.unwrap_or(-1), unit);
+    let Some((mut position, last)) = bit_range(bytes, Some(range)) else {
+        return -1;
+    };
+
+    let skip = if bit == 1 { 0x00 } else { 0xFF };
+    while position <= last {
+        let byte = bytes[(position >> 3) as usize];
+        if position & 7 == 0 && last - position >= 7 && byte == skip {
+            position += 8;
+            continue;
+        }
+        if get_bit(bytes, position) == bit {
+            return position as i64;
+        }
+        position += 1;
+    }
+
+    if bit == 0 && end.is_none() {
+        (last + 1) as i64
+    } else {
+        -1
+    }
+}
+
+pub fn bit_op(operation: BitOperation, sources: &[Vec<u8>]) -> Vec<u8> {
+    let len = sources.iter().map(|s| s.len()).max().unwrap_or(0);
+    let byte_at = |source: &Vec<u8>, i: usize| source.get(i).copied().unwrap_or(0);
+    (0..len)
+        .map(|i| match operation {
+            BitOperation::Not => !byte_at(&sources[0], i),
+            BitOperation::And => sources.iter().fold(0xFF, |acc, s| acc & byte_at(s, i)),
+            BitOperation::Or => sources.iter().fold(0x00, |acc, s| acc | byte_at(s, i)),
+            BitOperation::Xor => sources.iter().fold(0x00, |acc, s| acc ^ byte_at(s, i)),
+        })
+        .collect()
+}
+
+fn get_unsigned(bytes: &[u8], offset: u64, bits: u32) -> u64 {
+    (0..bits as u64).fold(0u64, |value, i| {
+        (value << 1) | get_bit(bytes, offset + i) as u64
+    })
+}
+
+fn get_signed(bytes: &[u8], offset: u64, bits: u32) -> i64 {
+    let mut value = get_unsigned(bytes, offset, bits);
+    if bits < 64 && value & (1 << (bits - 1)) != 0 {
+        value |= u64::MAX << bits;
+    }
+    value as i64
+}
+
+fn set_unsigned(bytes: &mut Vec<u8>, offset: u64, bits: u32, value: u64) {
+    for i in 0..bits {
+        let bit = ((value >> (bits - 1 - i)) & 1) as u8;
+        set_bit(bytes, offset + i as u64, bit);
+    }
+}
+
+/// Applies `increment` to an unsigned field, returning the stored value or `None`
+/// if the result overflows under the FAIL policy.
+fn unsigned_overflow(value: u64, increment: i64, bits: u32, overflow: Overflow) -> Option<u64> {
+    let max = (1u64 << bits) - 1;
+    let wrapped = value.wrapping_add(increment as u64) & max;
+    let overflowed = if value > max || (increment > 0 && increment as u64 > max - value) {
+        Some(max)
+    } else if increment < 0 && increment.unsigned_abs() > value {
+        Some(0)
+    } else {
+        None
+    };
+    match (overflowed, overflow) {
+        (None, _) => Some(wrapped),
+        (Some(_), Overflow::Wrap) => Some(wrapped),
+        (Some(limit), Overflow::Sat) => Some(limit),
+        (Some(_), Overflow::Fail) => None,
+    }
+}
+
+/// Signed counterpart of [`unsigned_overflow`].
+fn signed_overflow(value: i64, increment: i64, bits: u32, overflow: Overflow) -> Option<i64> {
+    let max = if bits == 64 {
+        i64::MAX
+    } else {
+        (1i64 << (bits - 1)) - 1
+    };
+    let min = -max - 1;
+    let wrapped = {
+        let sum = (value as u64).wrapping_add(increment as u64);
+        if bits < 64 {
+            let mask = u64::MAX << bits;
+            if sum & (1 << (bits - 1)) != 0 {
+                (sum | mask) as i64
+            } else {
+                (sum & !mask) as i64
+            }
+        } else {
+            sum as i64
+        }
+    };
+    let overflowed = match value.checked_add(increment) {
+        Some(sum) if sum > max => Some(max),
+        Some(sum) if sum < min => Some(min),
+        Some(_) => None,
+        None if increment > 0 => Some(max),
+        None => Some(min),
+    };
+    match (overflowed, overflow) {
+        (None, _) => Some(wrapped),
+        (Some(_), Overflow::Wrap) => Some(wrapped),
+        (Some(limit), Overflow::Sat) => Some(limit),
+        (Some(_), Overflow::Fail) => None,
+    }
+}
+
+/// Executes a single BITFIELD operation, returning the reply value (`None` is a nil
+/// reply caused by the FAIL overflow policy).
+pub fn bit_field(bytes: &mut Vec<u8>, op: &BitFieldOp) -> Option<i64> {
+    if let BitFieldOp::Set { ty, offset, .. } | BitFieldOp::IncrBy { ty, offset, .. } = *op {
+        // Writes create the field even if the FAIL policy ends up rejecting the value.
+        let needed = ((offset + ty.bits as u64 - 1) >> 3) as usize + 1;
+        if bytes.len() < needed {
+            bytes.resize(needed, 0);
+        }
+    }
+
+    match *op {
+        BitFieldOp::Get { ty, offset } => Some(if ty.signed {
+            get_signed(bytes, offset, ty.bits)
+        } else {
+            get_unsigned(bytes, offset, ty.bits) as i64
+        }),
+        BitFieldOp::Set {
+            ty,
+            offset,
+            value,
+            overflow,
+        } => {
+            if ty.signed {
+                let old = get_signed(bytes, offset, ty.bits);
+                let new = signed_overflow(value, 0, ty.bits, overflow)?;
+                set_unsigned(bytes, offset, ty.bits, new as u64);
+                Some(old)
+            } else {
+                let old = get_unsigned(bytes, offset, ty.bits);
+                let new = unsigned_overflow(value as u64, 0, ty.bits, overflow)?;
+                set_unsigned(bytes, offset, ty.bits, new);
+                Some(old as i64)
+            }
+        }
+        BitFieldOp::IncrBy {
+            ty,
+            offset,
+            increment,
+            overflow,
+        } => {
+            if ty.signed {
+                let old = get_signed(bytes, offset, ty.bits);
+                let new = signed_overflow(old, increment, ty.bits, overflow)?;
+                set_unsigned(bytes, offset, ty.bits, new as u64);
+                Some(new)
+            } else {
+                let old = get_unsigned(bytes, offset, ty.bits);
+                let new = unsigned_overflow(old, increment, ty.bits, overflow)?;
+                set_unsigned(bytes, offset, ty.bits, new);
+                Some(new as i64)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn field(spec: &str) -> BitFieldType {
+        parse_bitfield_type(spec).unwrap()
+    }
+
+    #[test]
+    fn set_bit_grows_and_returns_previous() {
+        let mut bytes = Vec::new();
+        assert_eq!(set_bit(&mut bytes, 7, 1), 0);
+        assert_eq!(bytes, b"\x01");
+        assert_eq!(set_bit(&mut bytes, 7, 1), 1);
+        assert_eq!(set_bit(&mut bytes, 100, 1), 0);
+        assert_eq!(bytes.len(), 13);
+        assert_eq!(get_bit(&bytes, 100), 1);
+        assert_eq!(get_bit(&bytes, 1000), 0);
+        assert_eq!(set_bit(&mut bytes, 7, 0), 1);
+        assert_eq!(bytes[0], 0);
+    }
+
+    #[test]
+    fn bit_count_ranges() {
+        // The examples from the BITCOUNT documentation.
+        assert_eq!(bit_count(b"foobar", None), 26);
+        assert_eq!(bit_count(b"foobar", Some((0, 0, BitUnit::Byte))), 4);
+        assert_eq!(bit_count(b"foobar", Some((1, 1, BitUnit::Byte))), 6);
+        assert_eq!(bit_count(b"foobar", Some((1, 1, BitUnit::Bit))), 1);
+        assert_eq!(bit_count(b"foobar", Some((5, 30, BitUnit::Bit))), 17);
+        assert_eq!(bit_count(b"foobar", Some((-2, -1, BitUnit::Byte))), 7);
+        assert_eq!(bit_count(b"foobar", Some((4, 2, BitUnit::Byte))), 0);
+        assert_eq!(bit_count(b"", None), 0);
+    }
+
+    #[test]
+    fn bit_pos_follows_redis_semantics() {
+        assert_eq!(bit_pos(b"\xff\xf0\x00", 0, None, None, BitUnit::Byte), 12);
+        assert_eq!(bit_pos(b"\x00\xff\xf0", 1, Some(0), None, BitUnit::Byte), 8);
+        assert_eq!(
+            bit_pos(b"\x00\xff\xf0", 1, Some(2), None, BitUnit::Byte),
+            16
+        );
+        assert_eq!(
+            bit_pos(b"\x00\xff\xf0", 1, Some(2), Some(-1), BitUnit::Byte),
+            16
+        );
+        assert_eq!(
+            bit_pos(b"\x00\xff\xf0", 1, Some(7), Some(15), BitUnit::Bit),
+            8
+        );
+        assert_eq!(bit_pos(b"\x00\x00\x00", 1, None, None, BitUnit::Byte), -1);
+        assert_eq!(bit_pos(b"", 0, None, None, BitUnit::Byte), 0);
+        assert_eq!(bit_pos(b"", 1, None, None, BitUnit::Byte), -1);
+        // Without an end, the clear bits past the string count; with one they don't.
+        assert_eq!(bit_pos(b"\xff\xff\xff", 0, None, None, BitUnit::Byte), 24);
+        assert_eq!(
+            bit_pos(b"\xff\xff\xff", 0, Some(0), Some(-1), BitUnit::Byte),
+            -1
+        );
+    }
+
+    #[test]
+    fn bit_op_pads_shorter_sources_with_zeros() {
+        let sources = [b"\xff\x0f\xf0".to_vec(), b"\x0f\xff".to_vec()];
+        assert_eq!(bit_op(BitOperation::And, &sources), b"\x0f\x0f\x00");
+        assert_eq!(bit_op(BitOperation::Or, &sources), b"\xff\xff\xf0");
+        assert_eq!(bit_op(BitOperation::Xor, &sources), b"\xf0\xf0\xf0");
+        assert_eq!(bit_op(BitOperation::Not, &sources[1..]), b"\xf0\x00");
+        assert!(bit_op(BitOperation::Or, &[Vec::new()]).is_empty());
+    }
+
+    #[test]
+    fn bit_field_get_and_set() {
+        let mut bytes = Vec::new();
+        let set = BitFieldOp::Set {
+            ty: field("i8"),
+            offset: 0,
+            value: 200,
+            overflow: Overflow::Wrap,
+        };
+        assert_eq!(bit_field(&mut bytes, &set), Some(0));
+        assert_eq!(bytes, b"\xc8");
+        let get = |ty, offset| BitFieldOp::Get {
+            ty: field(ty),
+            offset,
+        };
+        assert_eq!(bit_field(&mut bytes, &get("i8", 0)), Some(-56));
+        assert_eq!(bit_field(&mut bytes, &get("u8", 0)), Some(200));
+        assert_eq!(bit_field(&mut bytes, &get("u4", 4)), Some(8));
+        // Reads past the end see zeros and don't grow the string.
+        assert_eq!(bit_field(&mut bytes, &get("u16", 8)), Some(0));
+        assert_eq!(bytes.len(), 1);
+    }
+
+    #[test]
+    fn bit_field_overflow_policies() {
+        let incr = |overflow| BitFieldOp::IncrBy {
+            ty: field("u2"),
+            offset: 100,
+            increment: 1,
+            overflow,
+        };
+        // The u2 counter example from the BITFIELD documentation.
+        let (mut wrap, mut sat) = (Vec::new(), Vec::new());
+        let wrapped = (0..4)
+            .map(|_| bit_field(&mut wrap, &incr(Overflow::Wrap)))
+            .collect::<Vec<_>>();
+        let saturated = (0..4)
+            .map(|_| bit_field(&mut sat, &incr(Overflow::Sat)))
+            .collect::<Vec<_>>();
+        assert_eq!(wrapped, [Some(1), Some(2), Some(3), Some(0)]);
+        assert_eq!(saturated, [Some(1), Some(2), Some(3), Some(3)]);
+
+        let mut bytes = b"\x7f".to_vec();
+        let incr_i8 = |increment, overflow| BitFieldOp::IncrBy {
+            ty: field("i8"),
+            offset: 0,
+            increment,
+            overflow,
+        };
+        assert_eq!(bit_field(&mut bytes, &incr_i8(1, Overflow::Fail)), None);
+        assert_eq!(bytes, b"\x7f");
+        assert_eq!(bit_field(&mut bytes, &incr_i8(1, Overflow::Sat)), Some(127));
+        assert_eq!(
+            bit_field(&mut bytes, &incr_i8(1, Overflow::Wrap)),
+            Some(-128)
+        );
+        assert_eq!(
+            bit_field(&mut bytes, &incr_i8(-1, Overflow::Sat)),
+            Some(-128)
+        );
+    }
+
+    #[test]
+    fn parses_types_and_offsets() {
+        assert_eq!(
+            field("i64"),
+            BitFieldType {
+                signed: true,
+                bits: 64
+            }
+        );
+        assert!(parse_bitfield_type("u64").is_err());
+        assert!(parse_bitfield_type("i0").is_err());
+        assert!(parse_bitfield_type("x8").is_err());
+        assert_eq!(parse_bitfield_offset("#3", field("u8")).unwrap(), 24);
+        assert_eq!(parse_bitfield_offset("3", field("u8")).unwrap(), 3);
+        let last = MAX_BIT_OFFSET - 63;
+        assert_eq!(
+            parse_bitfield_offset(&last.to_string(), field("i64")).unwrap(),
+            last
+        );
+        assert!(parse_bitfield_offset(&(last + 1).to_string(), field("i64")).is_err());
+        assert_eq!(
+            parse_bitfield_offset(&(last + 1).to_string(), field("u63")).unwrap(),
+            last + 1
+        );
+        assert!(parse_bitfield_offset(&(last + 2).to_string(), field("u63")).is_err());
+        let last_field = (MAX_BIT_OFFSET + 1) / 64 - 1;
+        assert!(parse_bitfield_offset(&format!("#{}", last_field), field("i64")).is_ok());
+        assert!(parse_bitfield_offset(&format!("#{}", last_field + 1), field("i64")).is_err());
+        assert!(parse_bit_offset("-1").is_err());
+        assert!(parse_bit_offset(&(MAX_BIT_OFFSET + 1).to_string()).is_err());
+    }
+}
@@ -0,0 +1,215 @@
+use crate::pubsub::{self, SubscriptionKind};
+use crate::store;
+use crate::tracking::{self, TrackingOptions};
+use crate::Command;
+use once_cell::sync::Lazy;
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use tokio::io::AsyncWrite;
+use tokio::sync::{mpsc, Mutex};
+
+/// Where replies and pushes for a connection are written. Commands replayed from the AOF
+/// have no connection and write to a sink.
+pub type ClientStream = Arc<Mutex<Box<dyn AsyncWrite + Send + Unpin>>>;
+
+static NEXT_CLIENT_ID: AtomicU64 = AtomicU64::new(1);
+
+/// Most bytes of pushes a client may leave unread, as the pubsub hard limit of Redis'
+/// default `client-output-buffer-limit`. A client going over it is disconnected.
+const PUSH_BUFFER_LIMIT: usize = 32 * 1024 * 1024;
+
+/// Handles of every connected client by id, e.g. for CLIENT TRACKING REDIRECT.
+static CLIENTS: Lazy<std::sync::Mutex<HashMap<u64, Arc<ClientHandle>>>> =
+    Lazy::new(|| std::sync::Mutex::new(HashMap::new()));
+
+/// The part of a client's state other connections may need in order to write to it.
+pub struct ClientHandle {
+    pub stream: ClientStream,
+    pub resp3: AtomicBool,
+    /// Whether the client is in subscriber mode.
+    pub subscribed: AtomicBool,
+    /// Messages, invalidations and other pushes for the client, written out by its own
+    /// connection task, so a client that stops reading never holds up the one sending.
+    outbox: mpsc::UnboundedSender<Vec<u8>>,
+    /// Bytes in `outbox` not written out yet.
+    queued: AtomicUsize,
+    /// Set once `queued` went over `PUSH_BUFFER_LIMIT`; later pushes are dropped.
+    overflowed: AtomicBool,
+}
+
+impl ClientHandle {
+    /// Queues `frame` to be written to the client after the reply it is working on.
+    pub fn push(&self, frame: Vec<u8>) {
+        if self.overflowed.load(Ordering::Relaxed) {
+            return;
+        }
+        let queued = self.queued.fetch_add(frame.len(), Ordering::Relaxed) + frame.len();
+        if queued > PUSH_BUFFER_LIMIT {
+            self.overflowed.store(true, Ordering::Relaxed);
+            return;
+        }
+        let _ = self.outbox.send(frame);
+    }
+
+    /// Whether the client left too many pushes unread and has to be disconnected.
+    pub fn overflowed(&self) -> bool {
+        self.overflowed.load(Ordering::Relaxed)
+    }
+}
+
+impl std::fmt::Debug for ClientHandle {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ClientHandle")
+            .field("resp3", &self.resp3)
+            .field("subscribed", &self.subscribed)
+            .finish_non_exhaustive()
+    }
+}
+
+pub fn lookup_client(id: u64) -> Option<Arc<ClientHandle>> {
+    CLIENTS.lock().unwrap().get(&id).cloned()
+}
+
+/// Commands queued between MULTI and EXEC.
+#[derive(Debug, Default)]
+pub struct Transaction {
+    pub queued: Vec<Command>,
+    /// Set when a command fails to queue, which makes EXEC abort the transaction.
+    pub aborted: bool,
+}
+
+/// A key registered with WATCH and the version it had at that moment.
+#[derive(Debug)]
+pub struct WatchedKey {
+    pub db: usize,
+    pub key: Vec<u8>,
+    pub version: u64,
+    /// Whether the key was already logically expired when it was watched.
+    pub expired: bool,
+}
+
+/// Per-connection state kept by `handle_client` for the lifetime of a connection.
+#[derive(Debug)]
+pub struct Client {
+    pub id: u64,
+    pub handle: Arc<ClientHandle>,
+    /// Receiving end of the handle's `outbox`.
+    pub outbox: mpsc::UnboundedReceiver<Vec<u8>>,
+    /// RESP protocol version negotiated with HELLO.
+    pub protocol: u8,
+    /// Database selected with SELECT.
+    pub db: usize,
+    pub transaction: Option<Transaction>,
+    pub watched: Vec<WatchedKey>,
+    /// Write commands collected while EXEC runs, with the database they ran against,
+    /// propagated as one MULTI/EXEC block.
+    pub propagation_batch: Option<Vec<(usize, Command)>>,
+    pub channels: HashSet<Vec<u8>>,
+    pub patterns: HashSet<Vec<u8>>,
+    pub shard_channels: HashSet<Vec<u8>>,
+    pub tracking: Option<TrackingOptions>,
+    /// Set by CLIENT CACHING for the next command in OPTIN/OPTOUT tracking modes.
+    pub caching: Option<bool>,
+}
+
+impl Client {
+    pub fn new(stream: ClientStream) -> Self {
+        let id = NEXT_CLIENT_ID.fetch_add(1, Ordering::Relaxed);
+        let (sender, outbox) = mpsc::unbounded_channel();
+        let handle = Arc::new(ClientHandle {
+            stream,
+            resp3: AtomicBool::new(false),
+            subscribed: AtomicBool::new(false),
+            outbox: sender,
+            queued: AtomicUsize::new(0),
+            overflowed: AtomicBool::new(false),
+        });
+        CLIENTS.lock().unwrap().insert(id, Arc::clone(&handle));
+        Self {
+            id,
+            handle,
+            outbox,
+            protocol: 2,
+            db: 0,
+            transaction: None,
+            watched: Vec::new(),
+            propagation_batch: None,
+            channels: HashSet::new(),
+            patterns: HashSet::new(),
+            shard_channels: HashSet::new(),
+            tracking: None,
+            caching: None,
+        }
+    }
+
+    /// Mirrors the protocol and subscriber mode into the shared handle.
+    pub fn refresh_handle(&self) {
+        self.handle.resp3.store(self.resp3(), Ordering::Relaxed);
+        self.handle
+            .subscribed
+            .store(self.is_subscribed(), Ordering::Relaxed);
+    }
+
+    pub fn resp3(&self) -> bool {
+        self.protocol == 3
+    }
+
+    /// Takes the pushes queued so far, after `first` if one was already received.
+    pub fn take_pushes(&mut self, first: Option<Vec<u8>>) -> Vec<Vec<u8>> {
+        let mut frames = first.into_iter().collect::<Vec<_>>();
+        while let Ok(frame) = self.outbox.try_recv() {
+            frames.push(frame);
+        }
+        let bytes = frames.iter().map(Vec::len).sum();
+        self.handle.queued.fetch_sub(bytes, Ordering::Relaxed);
+        frames
+    }
+
+    pub fn subscriptions_mut(&mut self, kind: SubscriptionKind) -> &mut HashSet<Vec<u8>> {
+        match kind {
+            SubscriptionKind::Channel => &mut self.channels,
+            SubscriptionKind::Pattern => &mut self.patterns,
+            SubscriptionKind::ShardChannel => &mut self.shard_channels,
+        }
+    }
+
+    /// Count reported in (un)subscribe replies: sharded channels are counted on their own,
+    /// channels and patterns together.
+    pub fn subscription_count(&self, kind: SubscriptionKind) -> usize {
+        match kind {
+            SubscriptionKind::ShardChannel => self.shard_channels.len(),
+            _ => self.channels.len() + self.patterns.len(),
+        }
+    }
+
+    /// Whether the connection is in subscriber mode.
+    pub fn is_subscribed(&self) -> bool {
+        !self.channels.is_empty() || !self.patterns.is_empty() || !self.shard_channels.is_empty()
+    }
+
+    pub fn unwatch_all(&mut self) {
+        for watched in self.watched.drain(..) {
+            store::unwatch_key(watched.db, &watched.key);
+        }
+    }
+}
+
+impl Drop for Client {
+    fn drop(&mut self) {
+        self.unwatch_all();
+        CLIENTS.lock().unwrap().remove(&self.id);
+        if self.tracking.is_some() {
+            tracking::disable(self.id);
+        }
+        for (kind, names) in [
+            (SubscriptionKind::Channel, &self.channels),
+            (SubscriptionKind::Pattern, &self.patterns),
+            (SubscriptionKind::ShardChannel, &self.shard_channels),
+        ] {
+            for name in names.iter() {
+                pubsub::unsubscribe(kind, self.id, name);
+            }
+        }
+    }
+}
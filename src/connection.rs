@@ -1,93 +1,569 @@
-use crate::store::{self, db_get, db_set};
-use crate::utils::{build_resp_array, build_resp_string, get_bulk_string};
+use crate::aof::{self, AppendFsync};
+use crate::bitmap;
+use crate::client::{Client, ClientStream, Transaction, WatchedKey};
+use crate::geo::{self, GeoMatch, GeoSearch};
+use crate::hyperloglog::{HllError, HyperLogLog};
+use crate::migrate::{self, MigrateError, Migrated};
+use crate::notify;
+use crate::persistence;
+use crate::pubsub::{self, SubscriptionKind};
+use crate::rdb::{RdbReadError, RdbReader, RdbWriter};
+use crate::sorted_set::SortedSet;
+use crate::store::{
+    self, db_get, db_set, db_update, db_update_if_changed, db_view, CorruptRdbPolicy, Data,
+    StoreError,
+};
+use crate::tracking;
+use crate::utils::{
+    build_resp_array, build_resp_array_raw, build_resp_bulk, build_resp_error,
+    build_resp_error_raw, build_resp_integer, build_resp_map, build_resp_null_array,
+    build_resp_simple_string, build_resp_string, get_bulk_string, glob_match, parse_memory,
+    parse_yes_no, yes_no,
+};
 use crate::CRLF;
-use crate::{utils::EMPTY_RDB_HEX, Command, CONFIG};
+use crate::{Command, CONFIG, SERVER_VERSION};
 use bytes::BufMut;
+use once_cell::sync::Lazy;
 use std::result::Result::Ok;
 use std::sync::Arc;
+use std::time::SystemTime;
 use std::vec;
 use tokio::io::AsyncWriteExt;
-use tokio::net::tcp::OwnedWriteHalf;
-use tokio::sync::Mutex;
+use tokio::sync::{Mutex, RwLock};
 
-pub async fn propagate_command(command: &Command) -> anyhow::Result<()> {
+/// Commands run under the read side; EXEC takes the write side so a transaction
+/// executes without interleaving with other clients. An AOF rewrite also takes the
+/// write side while it switches files, and so does anything snapshotting the dataset
+/// for an RDB file, so no command is caught halfway.
+pub static EXEC_LOCK: Lazy<RwLock<()>> = Lazy::new(|| RwLock::new(()));
+
+/// Held by a write from the moment it changes the store until it has been fed to the AOF
+/// and replicas, so both get writes in the order they were applied. Always taken after
+/// the read side of `EXEC_LOCK`.
+static WRITE_LOCK: Lazy<Mutex<()>> = Lazy::new(|| Mutex::new(()));
+
+/// Database the replication stream last SELECTed; `None` forces a SELECT before the
+/// next propagated command, e.g. after a replica attaches.
+static PROPAGATED_DB: Lazy<Mutex<Option<usize>>> = Lazy::new(|| Mutex::new(None));
+
+/// A replica still receiving its snapshot, with the writes propagated since it was taken.
+struct PendingSync {
+    client_id: u64,
+    buffer: Vec<u8>,
+}
+
+/// Only changed while holding `Config::replicas`, so no write is missed or sent twice
+/// when a replica goes live.
+static PENDING_SYNCS: Lazy<std::sync::Mutex<Vec<PendingSync>>> =
+    Lazy::new(|| std::sync::Mutex::new(Vec::new()));
+
+pub async fn propagate_command(db_id: usize, command: &Command) -> anyhow::Result<()> {
     let config = CONFIG.read().await;
     let replicas = config.replicas.lock().await;
-    let msg = build_resp_array(command.to_string().as_str());
-    println!("Propagating command to {} replicas", replicas.len());
+    let mut propagated_db = PROPAGATED_DB.lock().await;
+    let mut msg = Vec::new();
+    if *propagated_db != Some(db_id) {
+        msg.extend_from_slice(&build_resp_command(&Command::Select(db_id)));
+        *propagated_db = Some(db_id);
+    }
+    msg.extend_from_slice(&build_resp_command(command));
+    for pending in PENDING_SYNCS.lock().unwrap().iter_mut() {
+        pending.buffer.extend_from_slice(&msg);
+    }
     for stream in replicas.iter() {
         let stream_clone: Arc<_> = Arc::clone(stream);
         let mut stream_lock = stream_clone.lock().await;
-        println!("Sending command to replica {:?}", msg);
         stream_lock.write_all(&msg).await?;
         stream_lock.flush().await?;
     }
     Ok(())
 }
 
-pub async fn handle_connection(
-    stream: Arc<Mutex<OwnedWriteHalf>>,
-    command: &Command,
+pub fn build_resp_command(command: &Command) -> Vec<u8> {
+    build_resp_array_raw(
+        command
+            .to_args()
+            .iter()
+            .map(|arg| build_resp_bulk(arg))
+            .collect(),
+    )
+}
+
+async fn propagate_if_master(client: &mut Client, command: &Command) {
+    if let Some(batch) = client.propagation_batch.as_mut() {
+        batch.push((client.db, command.clone()));
+        return;
+    }
+    propagate_to_replicas(client.db, command).await;
+}
+
+async fn propagate_to_replicas(db_id: usize, command: &Command) {
+    // A key the command found expired is gone before the command ran.
+    propagate_lazily_expired().await;
+    propagate(db_id, command, command.is_logged()).await;
+}
+
+/// Propagates a DEL for every key removed because it was found expired when accessed,
+/// as the active expire cycle does for the keys it removes. Callers hold `WRITE_LOCK`
+/// or the write side of `EXEC_LOCK`.
+async fn propagate_lazily_expired() {
+    for (db_id, key) in store::take_lazily_expired() {
+        propagate(db_id, &Command::Del(vec![key]), true).await;
+    }
+}
+
+/// Sends `command` to the replicas, and to the AOF too if `log` is set.
+async fn propagate(db_id: usize, command: &Command, log: bool) {
+    // Replicas log the writes they get from their master too.
+    if log {
+        aof::feed(db_id, command).await;
+    }
+    let mode = CONFIG.read().await.mode;
+    if mode == store::ServerMode::Master {
+        if let Err(e) = propagate_command(db_id, command).await {
+            println!("Error propagating command: {:?}", e);
+        }
+    }
+}
+
+/// Answers PSYNC with a full resync: replies +FULLRESYNC, sends an RDB snapshot of the
+/// dataset as it was when the command ran, then the writes buffered while it was sent,
+/// after which the replica gets writes as they happen.
+async fn full_resync(stream: &ClientStream, client_id: u64) -> anyhow::Result<()> {
+    let (databases, replid, offset, compression, checksum) = {
+        // No command runs meanwhile, so every write is either in the snapshot or buffered.
+        let _exclusive = EXEC_LOCK.write().await;
+        let settings = {
+            let config = CONFIG.read().await;
+            let _replicas = config.replicas.lock().await;
+            PENDING_SYNCS.lock().unwrap().push(PendingSync {
+                client_id,
+                buffer: Vec::new(),
+            });
+            // The buffer has to start with a SELECT.
+            *PROPAGATED_DB.lock().await = None;
+            (
+                config.master_replid.clone(),
+                config.master_repl_offset,
+                config.rdb_compression,
+                config.rdb_checksum,
+            )
+        };
+        let (replid, offset, compression, checksum) = settings;
+        (
+            store::db_snapshot().await,
+            replid,
+            offset,
+            compression,
+            checksum,
+        )
+    };
+
+    let result = async {
+        let rdb = tokio::task::spawn_blocking(move || {
+            RdbWriter::write_snapshot(&databases, compression, checksum)
+        })
+        .await?;
+        println!(
+            "Starting full resync with replica, sending {} bytes of RDB",
+            rdb.len()
+        );
+        let mut stream = stream.lock().await;
+        let header = format!("+FULLRESYNC {} {}\r\n${}\r\n", replid, offset, rdb.len());
+        stream.write_all(header.as_bytes()).await?;
+        stream.write_all(&rdb).await?;
+        stream.flush().await?;
+        Ok::<_, anyhow::Error>(())
+    }
+    .await;
+
+    let config = CONFIG.read().await;
+    let mut replicas = config.replicas.lock().await;
+    let buffered = {
+        let mut pending = PENDING_SYNCS.lock().unwrap();
+        let position = pending.iter().position(|sync| sync.client_id == client_id);
+        position.map(|position| pending.remove(position).buffer)
+    };
+    result?;
+    let mut stream_lock = stream.lock().await;
+    stream_lock.write_all(&buffered.unwrap_or_default()).await?;
+    stream_lock.flush().await?;
+    drop(stream_lock);
+    replicas.push(Arc::clone(stream));
+    println!("Synchronization with replica succeeded");
+    Ok(())
+}
+
+/// Removes expired keys in the background, as Redis' active expire cycle, so expired
+/// notifications fire without the keys being accessed. Replicas wait for the DEL
+/// propagated by their master instead.
+pub async fn expire_keys() {
+    if CONFIG.read().await.mode != store::ServerMode::Master {
+        return;
+    }
+    let _shared = EXEC_LOCK.read().await;
+    let _ordered = WRITE_LOCK.lock().await;
+    for (db_id, key) in store::db_remove_expired().await {
+        propagate_to_replicas(db_id, &Command::Del(vec![key])).await;
+    }
+}
+
+/// Runs `command` for `client`, queueing it instead while a MULTI block is open.
+pub async fn dispatch_command(
+    stream: ClientStream,
+    client: &mut Client,
+    command: Command,
 ) -> Vec<Vec<u8>> {
-    let selected_db = 0;
+    // CLIENT CACHING applies to the next command, or to a whole MULTI/EXEC block.
+    let sets_caching = matches!(command, Command::ClientCaching(_));
+    let responses = dispatch(stream, client, command).await;
+    if !sets_caching && client.transaction.is_none() {
+        client.caching = None;
+    }
+    responses
+}
+
+async fn dispatch(stream: ClientStream, client: &mut Client, command: Command) -> Vec<Vec<u8>> {
     match command {
-        Command::Ping => vec![build_resp_string("PONG")],
-        Command::Echo(ref s) => vec![s.as_bytes().to_vec()],
-        Command::ReplConf(args) => {
-            if args == "listening-port" {
-                let config = CONFIG.read().await;
-                config.replicas.lock().await.push(stream.clone());
+        Command::Multi => {
+            if client.transaction.is_some() {
+                return vec![build_resp_error("MULTI calls can not be nested")];
             }
-            vec![build_resp_string("OK")]
+            client.transaction = Some(Transaction::default());
+            vec![build_resp_simple_string("OK")]
         }
-        Command::ReplConfAck => vec![build_resp_string("REPLCONF ACK 0")],
-        Command::Psync(ref args) => {
-            let _replication_id = args.get(0);
-            let _offset = args.get(1);
-            let master_repl_id = CONFIG.read().await.master_replid.clone();
-            let master_repl_offset = CONFIG.read().await.master_repl_offset.clone();
-            let bytes: Vec<u8> = (0..EMPTY_RDB_HEX.len())
-                .step_by(2)
-                .map(|i| u8::from_str_radix(&EMPTY_RDB_HEX[i..i + 2], 16).unwrap())
-                .collect();
-            let response = format!("FULLRESYNC {} {}", master_repl_id, master_repl_offset);
-            let mut bytes_res = format!("${}{}", bytes.len(), CRLF).as_bytes().to_vec();
-            bytes_res.extend_from_slice(&bytes);
+        Command::Discard => match client.transaction.take() {
+            Some(_) => {
+                client.unwatch_all();
+                vec![build_resp_simple_string("OK")]
+            }
+            None => vec![build_resp_error("DISCARD without MULTI")],
+        },
+        Command::Watch(keys) => {
+            if client.transaction.is_some() {
+                return vec![build_resp_error("WATCH inside MULTI is not allowed")];
+            }
+            for key in keys {
+                let db = client.db;
+                if client
+                    .watched
+                    .iter()
+                    .any(|watched| watched.db == db && watched.key == key)
+                {
+                    continue;
+                }
+                let version = store::watch_key(db, &key);
+                let expired = store::db_is_expired(db, &key).await;
+                client.watched.push(WatchedKey {
+                    db,
+                    key,
+                    version,
+                    expired,
+                });
+            }
+            vec![build_resp_simple_string("OK")]
+        }
+        Command::Exec => {
+            let Some(transaction) = client.transaction.take() else {
+                return vec![build_resp_error("EXEC without MULTI")];
+            };
+            if transaction.aborted {
+                client.unwatch_all();
+                return vec![build_resp_error_raw(
+                    "EXECABORT Transaction discarded because of previous errors.",
+                )];
+            }
 
-            vec![build_resp_string(response.as_str()), bytes_res]
+            // Holding the write side keeps every other client out until the batch is done.
+            let _exclusive = EXEC_LOCK.write().await;
+            let touched = watched_keys_touched(client).await;
+            client.unwatch_all();
+            if touched {
+                return vec![build_resp_null_array(client.resp3())];
+            }
+            client.propagation_batch = Some(Vec::new());
+            let mut replies = Vec::with_capacity(transaction.queued.len());
+            for queued in transaction.queued.iter() {
+                // Commands such as SUBSCRIBE reply with one frame per channel, and the
+                // array header has to count every frame sent.
+                let responses = execute(client, queued).await;
+                replies.extend(responses);
+            }
+            let batch = client.propagation_batch.take().unwrap_or_default();
+            if let (Some((first_db, _)), Some((last_db, _))) = (batch.first(), batch.last()) {
+                propagate_lazily_expired().await;
+                // A batch of PUBLISHes alone leaves nothing for the AOF to wrap.
+                let log = batch.iter().any(|(_, write)| write.is_logged());
+                propagate(*first_db, &Command::Multi, log).await;
+                for (db_id, write) in batch.iter() {
+                    propagate(*db_id, write, write.is_logged()).await;
+                }
+                propagate(*last_db, &Command::Exec, log).await;
+            } else {
+                propagate_lazily_expired().await;
+            }
+            vec![build_resp_array_raw(replies)]
         }
-        Command::Get(ref key) => {
-            let value = db_get(selected_db, &key).await;
-            println!("Received GET command {:?} {:?}", key, value);
-            match value {
-                Ok(Some(value)) => {
-                    let mut response_buff = Vec::with_capacity(256).writer();
-                    let _ = get_bulk_string(&mut response_buff, value.as_bytes());
-                    vec![response_buff.into_inner()]
+        Command::Psync(_) if client.transaction.is_none() => {
+            if let Err(e) = full_resync(&stream, client.id).await {
+                println!("Full resync with replica failed: {}", e);
+            }
+            vec![]
+        }
+        command => {
+            if let Some(transaction) = client.transaction.as_mut() {
+                transaction.queued.push(command);
+                return vec![build_resp_simple_string("QUEUED")];
+            }
+            if command.snapshots_dataset() {
+                let _exclusive = EXEC_LOCK.write().await;
+                return execute(client, &command).await;
+            }
+            let _shared = EXEC_LOCK.read().await;
+            if command.is_write() {
+                let _ordered = WRITE_LOCK.lock().await;
+                return execute(client, &command).await;
+            }
+            let responses = execute(client, &command).await;
+            if store::has_lazily_expired() {
+                let _ordered = WRITE_LOCK.lock().await;
+                propagate_lazily_expired().await;
+            }
+            responses
+        }
+    }
+}
+
+/// Whether any key WATCHed by `client` was modified, or has expired, since WATCH.
+async fn watched_keys_touched(client: &Client) -> bool {
+    for watched in client.watched.iter() {
+        if store::watched_key_version(watched.db, &watched.key) != watched.version {
+            return true;
+        }
+        if !watched.expired && store::db_is_expired(watched.db, &watched.key).await {
+            return true;
+        }
+    }
+    false
+}
+
+/// Reply sent for each channel or pattern named in a (un)subscribe command.
+fn subscription_reply(client: &Client, reply: &str, name: Option<&[u8]>, count: usize) -> Vec<u8> {
+    pubsub::build_frame(
+        client.resp3(),
+        vec![
+            build_resp_bulk(reply.as_bytes()),
+            match name {
+                Some(name) => build_resp_bulk(name),
+                None => build_resp_string(""),
+            },
+            build_resp_integer(count as i64),
+        ],
+    )
+}
+
+fn subscribe(client: &mut Client, kind: SubscriptionKind, names: &[Vec<u8>]) -> Vec<Vec<u8>> {
+    names
+        .iter()
+        .map(|name| {
+            if client.subscriptions_mut(kind).insert(name.clone()) {
+                pubsub::subscribe(kind, client.id, name, Arc::clone(&client.handle));
+                client.refresh_handle();
+            }
+            let count = client.subscription_count(kind);
+            subscription_reply(client, kind.subscribe_reply(), Some(name), count)
+        })
+        .collect()
+}
+
+/// Unsubscribes from `names`, or from every subscription of `kind` when none are given.
+fn unsubscribe(client: &mut Client, kind: SubscriptionKind, names: &[Vec<u8>]) -> Vec<Vec<u8>> {
+    let names = if names.is_empty() {
+        client.subscriptions_mut(kind).iter().cloned().collect()
+    } else {
+        names.to_vec()
+    };
+    if names.is_empty() {
+        let count = client.subscription_count(kind);
+        return vec![subscription_reply(
+            client,
+            kind.unsubscribe_reply(),
+            None,
+            count,
+        )];
+    }
+    names
+        .iter()
+        .map(|name| {
+            if client.subscriptions_mut(kind).remove(name) {
+                pubsub::unsubscribe(kind, client.id, name);
+                client.refresh_handle();
+            }
+            let count = client.subscription_count(kind);
+            subscription_reply(client, kind.unsubscribe_reply(), Some(name), count)
+        })
+        .collect()
+}
+
+fn bit_field_reply(results: Vec<Option<i64>>) -> Vec<u8> {
+    build_resp_array_raw(
+        results
+            .into_iter()
+            .map(|result| match result {
+                Some(value) => build_resp_integer(value),
+                None => build_resp_string(""),
+            })
+            .collect(),
+    )
+}
+
+/// Error replies keep the code carried by typed errors (e.g. `WRONGTYPE`) and use
+/// the generic `ERR` prefix otherwise.
+fn error_reply(e: &anyhow::Error) -> Vec<u8> {
+    if e.is::<StoreError>() || e.is::<HllError>() {
+        build_resp_error_raw(&e.to_string())
+    } else {
+        build_resp_error(&e.to_string())
+    }
+}
+
+/// Loads the HyperLogLogs stored under `keys`, treating missing keys as empty.
+async fn load_hyperloglogs(db_id: usize, keys: &[Vec<u8>]) -> anyhow::Result<Vec<HyperLogLog>> {
+    let mut hlls = Vec::with_capacity(keys.len());
+    for key in keys {
+        if let Some(bytes) = db_get(db_id, key).await? {
+            hlls.push(HyperLogLog::from_bytes(&bytes)?);
+        }
+    }
+    Ok(hlls)
+}
+
+fn geo_search_reply(matches: &[GeoMatch], search: &GeoSearch) -> Vec<u8> {
+    let detailed = search.with_coord || search.with_dist || search.with_hash;
+    build_resp_array_raw(
+        matches
+            .iter()
+            .map(|found| {
+                if !detailed {
+                    return build_resp_bulk(&found.member);
+                }
+                let mut item = vec![build_resp_bulk(&found.member)];
+                if search.with_dist {
+                    let distance = found.distance / search.unit.to_meters();
+                    item.push(build_resp_string(&format!("{:.4}", distance)));
                 }
-                _ => {
-                    vec![build_resp_string("")]
+                if search.with_hash {
+                    item.push(build_resp_integer(found.score as i64));
                 }
+                if search.with_coord {
+                    item.push(build_resp_array_raw(vec![
+                        build_resp_string(&geo::format_coordinate(found.longitude)),
+                        build_resp_string(&geo::format_coordinate(found.latitude)),
+                    ]));
+                }
+                build_resp_array_raw(item)
+            })
+            .collect(),
+    )
+}
+
+/// Runs `command` and remembers the keys it read if the client tracks keys for its cache.
+async fn execute(client: &mut Client, command: &Command) -> Vec<Vec<u8>> {
+    let keys = command.read_keys();
+    let remember = match client.tracking.as_ref() {
+        Some(options) if !options.bcast && !keys.is_empty() => {
+            if options.optin {
+                client.caching == Some(true)
+            } else if options.optout {
+                client.caching != Some(false)
+            } else {
+                true
             }
         }
-        Command::Set(ref key, ref value_str, expiry) => {
+        _ => false,
+    };
+    // The keys are remembered before the read, so a write racing it still sends an
+    // invalidation, and again after it, since such a write uses up the first entry
+    // while the value read may already be the new one. Invalidations queued meanwhile
+    // reach the client after the reply.
+    if remember {
+        tracking::remember_keys(client.id, &keys);
+    }
+    let responses = tracking::CURRENT_CLIENT
+        .scope(client.id, handle_connection(client, command))
+        .await;
+    if remember {
+        tracking::remember_keys(client.id, &keys);
+    }
+    responses
+}
+
+pub async fn handle_connection(client: &mut Client, command: &Command) -> Vec<Vec<u8>> {
+    let selected_db = client.db;
+    match command {
+        Command::Hello(protocol) => {
+            match protocol {
+                Some(protocol @ (2 | 3)) => {
+                    client.protocol = *protocol as u8;
+                    client.refresh_handle();
+                }
+                Some(_) => {
+                    return vec![build_resp_error_raw("NOPROTO unsupported protocol version")]
+                }
+                None => {}
+            }
+            let config = CONFIG.read().await;
+            let role = match config.mode {
+                store::ServerMode::Master => "master",
+                store::ServerMode::Replica => "replica",
+            };
+            let field = |name: &str| build_resp_bulk(name.as_bytes());
+            vec![build_resp_map(
+                vec![
+                    (field("server"), field("redis")),
+                    (field("version"), field(SERVER_VERSION)),
+                    (field("proto"), build_resp_integer(client.protocol as i64)),
+                    (field("id"), build_resp_integer(client.id as i64)),
+                    (field("mode"), field("standalone")),
+                    (field("role"), field(role)),
+                    (field("modules"), build_resp_array_raw(Vec::new())),
+                ],
+                client.resp3(),
+            )]
+        }
+        Command::Ping => {
+            if client.is_subscribed() && !client.resp3() {
+                return vec![build_resp_array_raw(vec![
+                    build_resp_bulk(b"pong"),
+                    build_resp_bulk(b""),
+                ])];
+            }
+            vec![build_resp_string("PONG")]
+        }
+        Command::Echo(ref message) => vec![build_resp_bulk(message)],
+        // A replica only starts getting writes once PSYNC has sent it a snapshot.
+        Command::ReplConf(_) => vec![build_resp_string("OK")],
+        Command::ReplConfAck => vec![build_resp_string("REPLCONF ACK 0")],
+        Command::Psync(_) => vec![build_resp_error("PSYNC is not allowed here")],
+        Command::Get(ref key) => match db_get(selected_db, key).await {
+            Ok(Some(value)) => {
+                let mut response_buff = Vec::with_capacity(256).writer();
+                let _ = get_bulk_string(&mut response_buff, &value);
+                vec![response_buff.into_inner()]
+            }
+            Ok(None) => {
+                vec![build_resp_string("")]
+            }
+            Err(e) => vec![error_reply(&e)],
+        },
+        Command::Set(ref key, ref value, expiry) => {
             let value = store::Value {
-                value: value_str.to_string(),
+                value: Data::String(value.clone()),
                 expiry: *expiry,
+                access: None,
             };
-            let _ = db_set(selected_db, key.to_string(), value).await;
-            println!("Set key to val in master");
-            let mode = CONFIG.read().await.mode;
-            if mode == store::ServerMode::Master {
-                match propagate_command(command).await {
-                    Ok(_) => {
-                        println!("Command propagated successfully {:?}", command)
-                    }
-                    Err(e) => {
-                        println!("Error propagating command: {:?}", e);
-                    }
-                }
-            }
+            let _ = db_set(selected_db, key.clone(), value, notify::SET).await;
+            propagate_if_master(client, command).await;
 
             vec![build_resp_string("OK")]
         }
@@ -118,40 +594,229 @@ pub async fn handle_connection(
                     }
                 }
             }
+            "notify-keyspace-events" => {
+                let flags = CONFIG.read().await.notify_keyspace_events;
+                vec![build_resp_array_raw(vec![
+                    build_resp_bulk(key.as_bytes()),
+                    build_resp_bulk(notify::flags_to_string(flags).as_bytes()),
+                ])]
+            }
+            "rdbchecksum" => {
+                let checksum = if CONFIG.read().await.rdb_checksum {
+                    "yes"
+                } else {
+                    "no"
+                };
+                vec![build_resp_array_raw(vec![
+                    build_resp_bulk(key.as_bytes()),
+                    build_resp_bulk(checksum.as_bytes()),
+                ])]
+            }
+            "appendonly"
+            | "aof-load-truncated"
+            | "appendfsync"
+            | "appendfilename"
+            | "appenddirname"
+            | "aof-use-rdb-preamble"
+            | "auto-aof-rewrite-percentage"
+            | "auto-aof-rewrite-min-size" => {
+                let value = {
+                    let config = CONFIG.read().await;
+                    match key.to_lowercase().as_str() {
+                        "appendonly" => yes_no(config.appendonly).to_owned(),
+                        "aof-load-truncated" => yes_no(config.aof_load_truncated).to_owned(),
+                        "appendfsync" => config.appendfsync.as_str().to_owned(),
+                        "appenddirname" => config.appenddirname.clone(),
+                        "aof-use-rdb-preamble" => yes_no(config.aof_use_rdb_preamble).to_owned(),
+                        "auto-aof-rewrite-percentage" => {
+                            config.auto_aof_rewrite_percentage.to_string()
+                        }
+                        "auto-aof-rewrite-min-size" => config.auto_aof_rewrite_min_size.to_string(),
+                        _ => config.appendfilename.clone(),
+                    }
+                };
+                vec![build_resp_array_raw(vec![
+                    build_resp_bulk(key.as_bytes()),
+                    build_resp_bulk(value.as_bytes()),
+                ])]
+            }
+            "rdb-corrupt-policy" => {
+                let policy = CONFIG.read().await.rdb_corrupt_policy;
+                vec![build_resp_array_raw(vec![
+                    build_resp_bulk(key.as_bytes()),
+                    build_resp_bulk(policy.as_str().as_bytes()),
+                ])]
+            }
+            "save" => {
+                let params = persistence::save_params_to_string(&CONFIG.read().await.save_params);
+                vec![build_resp_array_raw(vec![
+                    build_resp_bulk(key.as_bytes()),
+                    build_resp_bulk(params.as_bytes()),
+                ])]
+            }
             _ => {
                 vec![build_resp_string("")]
             }
         },
-        Command::Keys(ref pattern) => {
-            if pattern == "*" {
-                let keys = store::db_list_keys(selected_db).await;
-                match keys {
-                    Ok(keys) => {
-                        let mut response = String::new();
-                        response.push_str(format!("*{}{}", keys.len(), CRLF).as_str());
-                        for key in keys {
-                            response.push_str(format!("${}{}", key.len(), CRLF).as_str());
-                            response.push_str(key.as_str());
-                            response.push_str(CRLF);
+        Command::SetConfig(ref pairs) => {
+            // Validate every parameter before applying any, so a failed CONFIG SET has no effect.
+            let mut notify_flags = None;
+            let mut save_params = None;
+            let mut rdb_compression = None;
+            let mut rdb_checksum = None;
+            let mut rdb_corrupt_policy = None;
+            let mut appendfsync = None;
+            let mut aof_load_truncated = None;
+            let mut aof_use_rdb_preamble = None;
+            let mut auto_aof_rewrite_percentage = None;
+            let mut auto_aof_rewrite_min_size = None;
+            for (name, value) in pairs {
+                match name.as_str() {
+                    "rdbcompression" | "rdbchecksum" => match parse_yes_no(value) {
+                        Some(enabled) if name == "rdbcompression" => rdb_compression = Some(enabled),
+                        Some(enabled) => rdb_checksum = Some(enabled),
+                        None => {
+                            return vec![build_resp_error(&format!(
+                                "CONFIG SET failed (possibly related to argument '{}') - argument must be 'yes' or 'no'",
+                                name
+                            ))]
                         }
-                        vec![response.as_bytes().to_vec()]
-                    }
-                    Err(_e) => {
-                        vec![build_resp_string("")]
+                    },
+                    "aof-load-truncated" | "aof-use-rdb-preamble" => match parse_yes_no(value) {
+                        Some(enabled) if name == "aof-load-truncated" => {
+                            aof_load_truncated = Some(enabled)
+                        }
+                        Some(enabled) => aof_use_rdb_preamble = Some(enabled),
+                        None => {
+                            return vec![build_resp_error(&format!(
+                                "CONFIG SET failed (possibly related to argument '{}') - argument must be 'yes' or 'no'",
+                                name
+                            ))]
+                        }
+                    },
+                    "auto-aof-rewrite-percentage" => match value.parse::<u64>() {
+                        Ok(percentage) => auto_aof_rewrite_percentage = Some(percentage),
+                        Err(_) => {
+                            return vec![build_resp_error(&format!(
+                                "CONFIG SET failed (possibly related to argument '{}') - argument couldn't be parsed into an integer",
+                                name
+                            ))]
+                        }
+                    },
+                    "auto-aof-rewrite-min-size" => match parse_memory(value) {
+                        Some(size) => auto_aof_rewrite_min_size = Some(size),
+                        None => {
+                            return vec![build_resp_error(&format!(
+                                "CONFIG SET failed (possibly related to argument '{}') - argument must be a memory value",
+                                name
+                            ))]
+                        }
+                    },
+                    "appendfsync" => match AppendFsync::parse(value) {
+                        Some(fsync) => appendfsync = Some(fsync),
+                        None => {
+                            return vec![build_resp_error(&format!(
+                                "CONFIG SET failed (possibly related to argument '{}') - argument(s) must be one of the following: always, everysec, no",
+                                name
+                            ))]
+                        }
+                    },
+                    "rdb-corrupt-policy" => match CorruptRdbPolicy::parse(value) {
+                        Some(policy) => rdb_corrupt_policy = Some(policy),
+                        None => {
+                            return vec![build_resp_error(&format!(
+                                "CONFIG SET failed (possibly related to argument '{}') - argument(s) must be one of the following: refuse, empty, partial",
+                                name
+                            ))]
+                        }
+                    },
+                    "save" => match persistence::parse_save_params(value) {
+                        Ok(params) => save_params = Some(params),
+                        Err(e) => {
+                            return vec![build_resp_error(&format!(
+                                "CONFIG SET failed (possibly related to argument '{}') - {}",
+                                name, e
+                            ))]
+                        }
+                    },
+                    "notify-keyspace-events" => match notify::parse_flags(value) {
+                        Ok(flags) => notify_flags = Some(flags),
+                        Err(e) => {
+                            return vec![build_resp_error(&format!(
+                                "CONFIG SET failed (possibly related to argument '{}') - {}",
+                                name, e
+                            ))]
+                        }
+                    },
+                    "dir" | "dbfilename" => {}
+                    _ => {
+                        return vec![build_resp_error(&format!(
+                            "Unknown option or number of arguments for CONFIG SET - '{}'",
+                            name
+                        ))]
                     }
                 }
-            } else {
-                vec![build_resp_string("")]
             }
+            let mut config = CONFIG.write().await;
+            for (name, value) in pairs {
+                match name.as_str() {
+                    "dir" => config.dir = Some(value.clone()),
+                    "dbfilename" => config.dbfilename = Some(value.clone()),
+                    _ => {}
+                }
+            }
+            if let Some(flags) = notify_flags {
+                config.notify_keyspace_events = flags;
+            }
+            if let Some(params) = save_params {
+                config.save_params = params;
+            }
+            if let Some(compression) = rdb_compression {
+                config.rdb_compression = compression;
+            }
+            if let Some(checksum) = rdb_checksum {
+                config.rdb_checksum = checksum;
+            }
+            if let Some(policy) = rdb_corrupt_policy {
+                config.rdb_corrupt_policy = policy;
+            }
+            if let Some(fsync) = appendfsync {
+                config.appendfsync = fsync;
+            }
+            if let Some(enabled) = aof_load_truncated {
+                config.aof_load_truncated = enabled;
+            }
+            if let Some(enabled) = aof_use_rdb_preamble {
+                config.aof_use_rdb_preamble = enabled;
+            }
+            if let Some(percentage) = auto_aof_rewrite_percentage {
+                config.auto_aof_rewrite_percentage = percentage;
+            }
+            if let Some(size) = auto_aof_rewrite_min_size {
+                config.auto_aof_rewrite_min_size = size;
+            }
+            vec![build_resp_simple_string("OK")]
         }
+        Command::Keys(ref pattern) => match store::db_list_keys(selected_db).await {
+            Ok(keys) => vec![build_resp_array_raw(
+                keys.iter()
+                    .filter(|key| glob_match(pattern, key, false))
+                    .map(|key| build_resp_bulk(key))
+                    .collect(),
+            )],
+            Err(_e) => {
+                vec![build_resp_string("")]
+            }
+        },
         Command::Info(ref arg) => match arg.to_lowercase().as_str() {
+            "persistence" => vec![build_resp_bulk(persistence::info().as_bytes())],
             "replication" => {
                 let masterhost = CONFIG.read().await.masterhost.clone();
                 let master_replid = CONFIG.read().await.master_replid.clone();
-                let master_repl_offset = CONFIG.read().await.master_repl_offset.clone();
+                let master_repl_offset = CONFIG.read().await.master_repl_offset;
                 let role = match masterhost {
-                    Some(_) => format!("role:slave"),
-                    None => format!("role:master"),
+                    Some(_) => "role:slave".to_string(),
+                    None => "role:master".to_string(),
                 };
                 let master_repl_id_string = format!("master_replid:{}", master_replid);
                 let master_repl_offset_string =
@@ -167,5 +832,594 @@ pub async fn handle_connection(
                 vec![build_resp_string("")]
             }
         },
+        Command::SetBit(ref key, offset, bit) => {
+            let previous = db_update(selected_db, key, Some(notify::SETBIT), |entry| {
+                let entry = entry.get_or_insert_with(|| store::Value::string(Vec::new()));
+                Ok(bitmap::set_bit(entry.value.as_string_mut()?, *offset, *bit))
+            })
+            .await;
+            match previous {
+                Ok(previous) => {
+                    propagate_if_master(client, command).await;
+                    vec![build_resp_integer(previous as i64)]
+                }
+                Err(e) => vec![error_reply(&e)],
+            }
+        }
+        Command::GetBit(ref key, offset) => match db_get(selected_db, key).await {
+            Ok(value) => {
+                let bit = bitmap::get_bit(value.as_deref().unwrap_or_default(), *offset);
+                vec![build_resp_integer(bit as i64)]
+            }
+            Err(e) => vec![error_reply(&e)],
+        },
+        Command::BitCount(ref key, range) => match db_get(selected_db, key).await {
+            Ok(value) => {
+                let count = bitmap::bit_count(value.as_deref().unwrap_or_default(), *range);
+                vec![build_resp_integer(count as i64)]
+            }
+            Err(e) => vec![error_reply(&e)],
+        },
+        Command::BitPos(ref key, bit, start, end, unit) => match db_get(selected_db, key).await {
+            Ok(value) => {
+                let position = bitmap::bit_pos(
+                    value.as_deref().unwrap_or_default(),
+                    *bit,
+                    *start,
+                    *end,
+                    *unit,
+                );
+                vec![build_resp_integer(position)]
+            }
+            Err(e) => vec![error_reply(&e)],
+        },
+        Command::BitOp(operation, ref dest, ref keys) => {
+            let mut sources = Vec::with_capacity(keys.len());
+            for key in keys {
+                match db_get(selected_db, key).await {
+                    Ok(value) => sources.push(value.unwrap_or_default()),
+                    Err(e) => return vec![error_reply(&e)],
+                }
+            }
+            let result = bitmap::bit_op(*operation, &sources);
+            let len = result.len();
+            let stored = db_update(selected_db, dest, Some(notify::SET), |entry| {
+                *entry = (!result.is_empty()).then(|| store::Value::string(result));
+                Ok(())
+            })
+            .await;
+            match stored {
+                Ok(_) => {
+                    propagate_if_master(client, command).await;
+                    vec![build_resp_integer(len as i64)]
+                }
+                Err(e) => vec![error_reply(&e)],
+            }
+        }
+        Command::BitField(ref key, ref ops) => {
+            let writes = ops.iter().any(|op| op.is_write());
+            let results = if writes {
+                db_update(selected_db, key, Some(notify::SETBIT), |entry| {
+                    let bytes = entry
+                        .get_or_insert_with(|| store::Value::string(Vec::new()))
+                        .value
+                        .as_string_mut()?;
+                    Ok(ops
+                        .iter()
+                        .map(|op| bitmap::bit_field(bytes, op))
+                        .collect::<Vec<_>>())
+                })
+                .await
+            } else {
+                // Only GETs: leave the key untouched so WATCH is not invalidated.
+                db_view(selected_db, key, |entry| {
+                    let mut bytes = match entry {
+                        Some(entry) => entry.value.as_string()?.clone(),
+                        None => Vec::new(),
+                    };
+                    Ok(ops
+                        .iter()
+                        .map(|op| bitmap::bit_field(&mut bytes, op))
+                        .collect::<Vec<_>>())
+                })
+                .await
+            };
+            match results {
+                Ok(results) => {
+                    if writes {
+                        propagate_if_master(client, command).await;
+                    }
+                    vec![bit_field_reply(results)]
+                }
+                Err(e) => vec![error_reply(&e)],
+            }
+        }
+        Command::BitFieldRo(ref key, ref ops) => match db_get(selected_db, key).await {
+            Ok(value) => {
+                let mut value = value.unwrap_or_default();
+                let results = ops
+                    .iter()
+                    .map(|op| bitmap::bit_field(&mut value, op))
+                    .collect::<Vec<_>>();
+                vec![bit_field_reply(results)]
+            }
+            Err(e) => vec![error_reply(&e)],
+        },
+        Command::PfAdd(ref key, ref elements) => {
+            let updated = db_update_if_changed(selected_db, key, Some(notify::PFADD), |entry| {
+                let (mut hll, mut updated) = match entry {
+                    Some(entry) => (HyperLogLog::from_bytes(entry.value.as_string()?)?, false),
+                    None => (HyperLogLog::new(), true),
+                };
+                for element in elements {
+                    updated |= hll.add(element);
+                }
+                if updated {
+                    let expiry = entry.as_ref().and_then(|entry| entry.expiry);
+                    let access = entry.as_ref().and_then(|entry| entry.access);
+                    *entry = Some(store::Value {
+                        value: Data::String(hll.to_bytes()),
+                        expiry,
+                        access,
+                    });
+                }
+                Ok((updated, updated))
+            })
+            .await;
+            match updated {
+                Ok(updated) => {
+                    if updated {
+                        propagate_if_master(client, command).await;
+                    }
+                    vec![build_resp_integer(updated as i64)]
+                }
+                Err(e) => vec![error_reply(&e)],
+            }
+        }
+        Command::PfCount(ref keys) => {
+            if let [key] = keys.as_slice() {
+                // A single key may use the cardinality cached in its header, and only
+                // takes the write path when that cache has to be refreshed.
+                let cached = db_view(selected_db, key, |entry| {
+                    let Some(entry) = entry else {
+                        return Ok(Some(0));
+                    };
+                    Ok(HyperLogLog::from_bytes(entry.value.as_string()?)?.cached_count())
+                })
+                .await;
+                let count = match cached {
+                    Ok(Some(count)) => Ok(count),
+                    Ok(None) => {
+                        // Refreshing the cached cardinality isn't an event.
+                        db_update_if_changed(selected_db, key, None, |entry| {
+                            let Some(entry) = entry else {
+                                return Ok((0, false));
+                            };
+                            let bytes = entry.value.as_string_mut()?;
+                            let mut hll = HyperLogLog::from_bytes(bytes)?;
+                            if hll.cached_count().is_some() {
+                                return Ok((hll.count(), false));
+                            }
+                            let count = hll.count();
+                            *bytes = hll.to_bytes();
+                            Ok((count, true))
+                        })
+                        .await
+                    }
+                    Err(e) => Err(e),
+                };
+                return match count {
+                    Ok(count) => vec![build_resp_integer(count as i64)],
+                    Err(e) => vec![error_reply(&e)],
+                };
+            }
+
+            match load_hyperloglogs(selected_db, keys).await {
+                Ok(hlls) => {
+                    let mut merged = HyperLogLog::new();
+                    for hll in hlls.iter() {
+                        merged.merge(hll);
+                    }
+                    vec![build_resp_integer(merged.estimate() as i64)]
+                }
+                Err(e) => vec![error_reply(&e)],
+            }
+        }
+        Command::PfMerge(ref dest, ref keys) => {
+            let sources = match load_hyperloglogs(selected_db, keys).await {
+                Ok(sources) => sources,
+                Err(e) => return vec![error_reply(&e)],
+            };
+            let merged = db_update(selected_db, dest, Some(notify::PFADD), |entry| {
+                let mut hll = match entry {
+                    Some(entry) => HyperLogLog::from_bytes(entry.value.as_string()?)?,
+                    None => HyperLogLog::new(),
+                };
+                for source in sources.iter() {
+                    hll.merge(source);
+                }
+                let expiry = entry.as_ref().and_then(|entry| entry.expiry);
+                let access = entry.as_ref().and_then(|entry| entry.access);
+                *entry = Some(store::Value {
+                    value: Data::String(hll.to_bytes()),
+                    expiry,
+                    access,
+                });
+                Ok(())
+            })
+            .await;
+            match merged {
+                Ok(_) => {
+                    propagate_if_master(client, command).await;
+                    vec![build_resp_string("OK")]
+                }
+                Err(e) => vec![error_reply(&e)],
+            }
+        }
+        Command::GeoAdd(ref key, options, ref items) => {
+            let changed = db_update_if_changed(selected_db, key, Some(notify::ZADD), |entry| {
+                let zset = entry
+                    .get_or_insert_with(|| store::Value {
+                        value: Data::SortedSet(SortedSet::new()),
+                        expiry: None,
+                        access: None,
+                    })
+                    .value
+                    .as_sorted_set_mut()?;
+                let (mut added, mut updated) = (0, 0);
+                for (longitude, latitude, member) in items {
+                    let score = geo::encode(*longitude, *latitude)? as f64;
+                    let previous = zset.score(member);
+                    if (options.nx && previous.is_some()) || (options.xx && previous.is_none()) {
+                        continue;
+                    }
+                    zset.insert(member.clone(), score);
+                    match previous {
+                        None => added += 1,
+                        Some(previous) if previous != score => updated += 1,
+                        Some(_) => {}
+                    }
+                }
+                // GEOADD XX against a missing key must not leave an empty sorted set behind.
+                if zset.is_empty() {
+                    *entry = None;
+                }
+                Ok(((added, updated), added + updated > 0))
+            })
+            .await;
+            match changed {
+                Ok((added, updated)) => {
+                    if added + updated > 0 {
+                        propagate_if_master(client, command).await;
+                    }
+                    let changed = if options.ch { added + updated } else { added };
+                    vec![build_resp_integer(changed)]
+                }
+                Err(e) => vec![error_reply(&e)],
+            }
+        }
+        Command::GeoPos(ref key, ref members) => {
+            let positions = db_view(selected_db, key, |entry| {
+                let zset = entry.map(|entry| entry.value.as_sorted_set()).transpose()?;
+                Ok(members
+                    .iter()
+                    .map(|member| zset.and_then(|zset| zset.score(member)))
+                    .map(|score| match score {
+                        Some(score) => {
+                            let (longitude, latitude) = geo::decode(score as u64);
+                            build_resp_array_raw(vec![
+                                build_resp_string(&geo::format_coordinate(longitude)),
+                                build_resp_string(&geo::format_coordinate(latitude)),
+                            ])
+                        }
+                        None => b"*-1\r\n".to_vec(),
+                    })
+                    .collect::<Vec<_>>())
+            })
+            .await;
+            match positions {
+                Ok(positions) => vec![build_resp_array_raw(positions)],
+                Err(e) => vec![error_reply(&e)],
+            }
+        }
+        Command::GeoDist(ref key, ref member1, ref member2, unit) => {
+            let distance = db_view(selected_db, key, |entry| {
+                let Some(entry) = entry else {
+                    return Ok(None);
+                };
+                let zset = entry.value.as_sorted_set()?;
+                let (Some(score1), Some(score2)) = (zset.score(member1), zset.score(member2))
+                else {
+                    return Ok(None);
+                };
+                let (lon1, lat1) = geo::decode(score1 as u64);
+                let (lon2, lat2) = geo::decode(score2 as u64);
+                Ok(Some(
+                    geo::distance(lon1, lat1, lon2, lat2) / unit.to_meters(),
+                ))
+            })
+            .await;
+            match distance {
+                Ok(Some(distance)) => vec![build_resp_string(&format!("{:.4}", distance))],
+                Ok(None) => vec![build_resp_string("")],
+                Err(e) => vec![error_reply(&e)],
+            }
+        }
+        Command::GeoHash(ref key, ref members) => {
+            let hashes = db_view(selected_db, key, |entry| {
+                let zset = entry.map(|entry| entry.value.as_sorted_set()).transpose()?;
+                Ok(members
+                    .iter()
+                    .map(|member| match zset.and_then(|zset| zset.score(member)) {
+                        Some(score) => build_resp_string(&geo::geohash_string(score as u64)),
+                        None => build_resp_string(""),
+                    })
+                    .collect::<Vec<_>>())
+            })
+            .await;
+            match hashes {
+                Ok(hashes) => vec![build_resp_array_raw(hashes)],
+                Err(e) => vec![error_reply(&e)],
+            }
+        }
+        Command::GeoSearch(ref key, ref search) => {
+            let matches = db_view(selected_db, key, |entry| match entry {
+                Some(entry) => Ok(geo::search(entry.value.as_sorted_set()?, search)?),
+                None => Ok(Vec::new()),
+            })
+            .await;
+            match matches {
+                Ok(matches) => vec![geo_search_reply(&matches, search)],
+                Err(e) => vec![error_reply(&e)],
+            }
+        }
+        Command::GeoSearchStore(ref dest, ref src, ref search) => {
+            let matches = db_view(selected_db, src, |entry| match entry {
+                Some(entry) => Ok(geo::search(entry.value.as_sorted_set()?, search)?),
+                None => Ok(Vec::new()),
+            })
+            .await;
+            let matches = match matches {
+                Ok(matches) => matches,
+                Err(e) => return vec![error_reply(&e)],
+            };
+            let stored = matches.len();
+            let mut zset = SortedSet::new();
+            for found in matches {
+                let score = if search.store_dist {
+                    found.distance / search.unit.to_meters()
+                } else {
+                    found.score
+                };
+                zset.insert(found.member, score);
+            }
+            let result = db_update(selected_db, dest, Some(notify::GEOSEARCHSTORE), |entry| {
+                *entry = (!zset.is_empty()).then_some(store::Value {
+                    value: Data::SortedSet(zset),
+                    expiry: None,
+                    access: None,
+                });
+                Ok(())
+            })
+            .await;
+            match result {
+                Ok(_) => {
+                    propagate_if_master(client, command).await;
+                    vec![build_resp_integer(stored as i64)]
+                }
+                Err(e) => vec![error_reply(&e)],
+            }
+        }
+        Command::Del(ref keys) => {
+            let mut deleted = 0;
+            for key in keys {
+                match store::db_delete(selected_db, key).await {
+                    Ok(true) => deleted += 1,
+                    Ok(false) => {}
+                    Err(e) => return vec![error_reply(&e)],
+                }
+            }
+            if deleted > 0 {
+                propagate_if_master(client, command).await;
+            }
+            vec![build_resp_integer(deleted)]
+        }
+        Command::ClientId => vec![build_resp_integer(client.id as i64)],
+        Command::ClientTracking(on, options) => {
+            if !*on {
+                if client.tracking.take().is_some() {
+                    tracking::disable(client.id);
+                }
+                return vec![build_resp_simple_string("OK")];
+            }
+            match tracking::enable(client.id, Arc::clone(&client.handle), options.clone()) {
+                Ok(_) => {
+                    client.tracking = Some(options.clone());
+                    vec![build_resp_simple_string("OK")]
+                }
+                Err(e) => vec![build_resp_error(&e.to_string())],
+            }
+        }
+        Command::ClientCaching(yes) => {
+            let (optin, optout) = client
+                .tracking
+                .as_ref()
+                .map_or((false, false), |options| (options.optin, options.optout));
+            match (*yes, optin, optout) {
+                (true, true, _) | (false, _, true) => {
+                    client.caching = Some(*yes);
+                    vec![build_resp_simple_string("OK")]
+                }
+                (true, ..) => vec![build_resp_error(
+                    "CLIENT CACHING YES is only valid when tracking is enabled in OPTIN mode.",
+                )],
+                (false, ..) => vec![build_resp_error(
+                    "CLIENT CACHING NO is only valid when tracking is enabled in OPTOUT mode.",
+                )],
+            }
+        }
+        Command::ClientGetRedir => {
+            let redirect = match client.tracking.as_ref() {
+                Some(options) => options.redirect.map_or(0, |id| id as i64),
+                None => -1,
+            };
+            vec![build_resp_integer(redirect)]
+        }
+        Command::Save => match persistence::save().await {
+            Ok(_) => vec![build_resp_simple_string("OK")],
+            Err(e) => vec![error_reply(&e)],
+        },
+        Command::BgSave => match persistence::bgsave().await {
+            Ok(_) => vec![build_resp_simple_string("Background saving started")],
+            Err(e) => vec![build_resp_error(&e.to_string())],
+        },
+        Command::BgRewriteAof => match aof::bgrewriteaof().await {
+            Ok(_) => vec![build_resp_simple_string(
+                "Background append only file rewriting started",
+            )],
+            Err(e) => vec![build_resp_error(&e.to_string())],
+        },
+        Command::LastSave => vec![build_resp_integer(persistence::last_save() as i64)],
+        Command::Migrate(ref options) => match migrate::migrate(selected_db, options).await {
+            Migrated::NoKeys => vec![build_resp_simple_string("NOKEY")],
+            Migrated::Done { deleted, error } => {
+                if !deleted.is_empty() {
+                    propagate_if_master(client, &Command::Del(deleted)).await;
+                }
+                match error {
+                    None => vec![build_resp_simple_string("OK")],
+                    Some(e @ MigrateError::Io(_)) => vec![build_resp_error_raw(&e.to_string())],
+                    Some(e) => vec![build_resp_error(&e.to_string())],
+                }
+            }
+        },
+        Command::Dump(ref key) => {
+            let value = db_view(selected_db, key, |entry| Ok(entry.map(|e| e.value.clone()))).await;
+            match value {
+                Ok(Some(value)) => {
+                    let compression = CONFIG.read().await.rdb_compression;
+                    vec![build_resp_bulk(&RdbWriter::dump_value(&value, compression))]
+                }
+                Ok(None) => vec![build_resp_string("")],
+                Err(e) => vec![error_reply(&e)],
+            }
+        }
+        Command::Restore(ref key, ref options) => {
+            let value = match RdbReader::read_dump(&options.payload).await {
+                Ok(value) => value,
+                Err(e @ RdbReadError::DumpPayloadMismatch) => {
+                    return vec![build_resp_error(&e.to_string())]
+                }
+                Err(_) => return vec![build_resp_error("Bad data format")],
+            };
+            // An absolute TTL in the past restores nothing, but REPLACE still deletes.
+            let expired = options
+                .expiry
+                .is_some_and(|expiry| expiry <= SystemTime::now());
+            let result = db_update(selected_db, key, Some(notify::RESTORE), |entry| {
+                if entry.is_some() && !options.replace {
+                    return Err(StoreError::BusyKey.into());
+                }
+                let existed = entry.is_some();
+                *entry = (!expired).then(|| store::Value {
+                    value,
+                    expiry: options.expiry,
+                    access: options.access,
+                });
+                Ok(existed)
+            })
+            .await;
+            match result {
+                Ok(existed) => {
+                    if !expired {
+                        propagate_if_master(client, command).await;
+                    } else if existed {
+                        propagate_if_master(client, &Command::Del(vec![key.clone()])).await;
+                    }
+                    vec![build_resp_simple_string("OK")]
+                }
+                Err(e) => vec![error_reply(&e)],
+            }
+        }
+        Command::Shutdown(save) => match persistence::shutdown(*save).await {
+            Ok(_) => vec![],
+            Err(e) => vec![build_resp_error(&e.to_string())],
+        },
+        Command::Unwatch => {
+            client.unwatch_all();
+            vec![build_resp_simple_string("OK")]
+        }
+        Command::Select(index) => {
+            client.db = *index;
+            vec![build_resp_simple_string("OK")]
+        }
+        Command::FlushDb | Command::FlushAll => {
+            let flushed = match command {
+                Command::FlushDb => store::db_flush(selected_db).await,
+                _ => store::db_flush_all().await,
+            };
+            match flushed {
+                Ok(_) => {
+                    // Like Redis, FLUSHALL persists the now empty dataset when save rules are
+                    // set, but not while replaying the AOF, which would overwrite the RDB.
+                    // A background save still writing the old keys would bring them back
+                    // on restart, so it is superseded rather than waited for.
+                    if matches!(command, Command::FlushAll)
+                        && !aof::loading()
+                        && !CONFIG.read().await.save_params.is_empty()
+                    {
+                        if let Err(e) = persistence::save_superseding().await {
+                            println!("Failed to save after FLUSHALL: {:?}", e);
+                        }
+                    }
+                    propagate_if_master(client, command).await;
+                    vec![build_resp_simple_string("OK")]
+                }
+                Err(e) => vec![error_reply(&e)],
+            }
+        }
+        Command::Subscribe(names) => subscribe(client, SubscriptionKind::Channel, names),
+        Command::PSubscribe(names) => subscribe(client, SubscriptionKind::Pattern, names),
+        Command::SSubscribe(names) => subscribe(client, SubscriptionKind::ShardChannel, names),
+        Command::Unsubscribe(names) => unsubscribe(client, SubscriptionKind::Channel, names),
+        Command::PUnsubscribe(names) => unsubscribe(client, SubscriptionKind::Pattern, names),
+        Command::SUnsubscribe(names) => unsubscribe(client, SubscriptionKind::ShardChannel, names),
+        Command::Publish(ref channel, ref message) => {
+            let receivers = pubsub::publish(channel, message);
+            propagate_if_master(client, command).await;
+            vec![build_resp_integer(receivers as i64)]
+        }
+        Command::SPublish(ref channel, ref message) => {
+            let receivers = pubsub::spublish(channel, message);
+            propagate_if_master(client, command).await;
+            vec![build_resp_integer(receivers as i64)]
+        }
+        Command::PubSubChannels(kind, pattern) => {
+            let channels = pubsub::active_channels(*kind, pattern.as_deref());
+            vec![build_resp_array_raw(
+                channels
+                    .iter()
+                    .map(|channel| build_resp_bulk(channel))
+                    .collect(),
+            )]
+        }
+        Command::PubSubNumSub(kind, channels) => {
+            let pairs = channels
+                .iter()
+                .map(|channel| {
+                    (
+                        build_resp_bulk(channel),
+                        build_resp_integer(pubsub::subscriber_count(*kind, channel) as i64),
+                    )
+                })
+                .collect();
+            vec![build_resp_map(pairs, client.resp3())]
+        }
+        Command::PubSubNumPat => vec![build_resp_integer(pubsub::pattern_count() as i64)],
+        Command::Multi | Command::Exec | Command::Discard | Command::Watch(_) => {
+            vec![build_resp_error(
+                "MULTI, EXEC, DISCARD and WATCH are not allowed here",
+            )]
+        }
     }
 }
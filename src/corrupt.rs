@@ -0,0 +1,20 @@
+// Damaged inputs for the tests of the binary decoders (LZF, listpack, ziplist, RDB).
+
+/// Runs `decode` on every truncation of the valid `encoded`, each of which must be
+/// rejected, then on copies with one byte inverted, which only must not panic: some
+/// flips still leave a valid encoding. `decode` returns whether it accepted the input.
+pub fn assert_rejects_damage(encoded: &[u8], mut decode: impl FnMut(&[u8]) -> bool) {
+    for end in 0..encoded.len() {
+        assert!(
+            !decode(&encoded[..end]),
+            "accepted the first {} of {} bytes",
+            end,
+            encoded.len()
+        );
+    }
+    for i in 0..encoded.len() {
+        let mut corrupted = encoded.to_vec();
+        corrupted[i] ^= 0xFF;
+        decode(&corrupted);
+    }
+}
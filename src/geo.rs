@@ -0,0 +1,675 @@
+use crate::sorted_set::SortedSet;
+use crate::utils::word;
+use std::fmt;
+use thiserror::Error;
+
+// Constants and algorithms follow Redis' geohash.c / geohash_helper.c so that scores
+// and distances match what a Redis server would produce for the same input.
+const GEO_STEP: u32 = 26;
+const GEO_LAT_MIN: f64 = -85.051_128_78;
+const GEO_LAT_MAX: f64 = 85.051_128_78;
+const GEO_LONG_MIN: f64 = -180.0;
+const GEO_LONG_MAX: f64 = 180.0;
+const EARTH_RADIUS_IN_METERS: f64 = 6_372_797.560_856;
+const MERCATOR_MAX: f64 = 20_037_726.37;
+const GEO_ALPHABET: &[u8; 32] = b"0123456789bcdefghjkmnpqrstuvwxyz";
+
+#[derive(Error, Debug)]
+pub enum GeoError {
+    #[error("invalid longitude,latitude pair {0:.6},{1:.6}")]
+    InvalidCoordinates(f64, f64),
+
+    #[error("unsupported unit provided. please use M, KM, FT, MI")]
+    UnsupportedUnit,
+
+    #[error("could not decode requested zset member")]
+    MemberNotFound,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GeoUnit {
+    Meters,
+    Kilometers,
+    Feet,
+    Miles,
+}
+
+impl GeoUnit {
+    pub fn parse(unit: &str) -> Result<Self, GeoError> {
+        match unit.to_lowercase().as_str() {
+            "m" => Ok(GeoUnit::Meters),
+            "km" => Ok(GeoUnit::Kilometers),
+            "ft" => Ok(GeoUnit::Feet),
+            "mi" => Ok(GeoUnit::Miles),
+            _ => Err(GeoError::UnsupportedUnit),
+        }
+    }
+
+    pub fn to_meters(self) -> f64 {
+        match self {
+            GeoUnit::Meters => 1.0,
+            GeoUnit::Kilometers => 1000.0,
+            GeoUnit::Feet => 0.3048,
+            GeoUnit::Miles => 1609.34,
+        }
+    }
+}
+
+impl fmt::Display for GeoUnit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            GeoUnit::Meters => write!(f, "m"),
+            GeoUnit::Kilometers => write!(f, "km"),
+            GeoUnit::Feet => write!(f, "ft"),
+            GeoUnit::Miles => write!(f, "mi"),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum GeoOrigin {
+    Member(Vec<u8>),
+    LonLat(f64, f64),
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum GeoShape {
+    Radius(f64),
+    Box(f64, f64),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GeoSort {
+    Asc,
+    Desc,
+}
+
+/// Parsed GEOSEARCH / GEOSEARCHSTORE options. Shape dimensions are kept in `unit`.
+#[derive(Debug, Clone)]
+pub struct GeoSearch {
+    pub origin: GeoOrigin,
+    pub shape: GeoShape,
+    pub unit: GeoUnit,
+    pub sort: Option<GeoSort>,
+    pub count: Option<(usize, bool)>,
+    pub with_coord: bool,
+    pub with_dist: bool,
+    pub with_hash: bool,
+    pub store_dist: bool,
+}
+
+impl GeoSearch {
+    /// The search options as command arguments, following the key(s).
+    pub fn to_args(&self) -> Vec<Vec<u8>> {
+        let mut args = Vec::new();
+        match &self.origin {
+            GeoOrigin::Member(member) => args.extend([word("FROMMEMBER"), member.clone()]),
+            GeoOrigin::LonLat(lon, lat) => args.extend([word("FROMLONLAT"), word(lon), word(lat)]),
+        }
+        match self.shape {
+            GeoShape::Radius(radius) => {
+                args.extend([word("BYRADIUS"), word(radius), word(self.unit)])
+            }
+            GeoShape::Box(width, height) => {
+                args.extend([word("BYBOX"), word(width), word(height), word(self.unit)])
+            }
+        }
+        match self.sort {
+            Some(GeoSort::Asc) => args.push(word("ASC")),
+            Some(GeoSort::Desc) => args.push(word("DESC")),
+            None => {}
+        }
+        if let Some((count, any)) = self.count {
+            args.extend([word("COUNT"), word(count)]);
+            if any {
+                args.push(word("ANY"));
+            }
+        }
+        for (enabled, flag) in [
+            (self.with_coord, "WITHCOORD"),
+            (self.with_dist, "WITHDIST"),
+            (self.with_hash, "WITHHASH"),
+            (self.store_dist, "STOREDIST"),
+        ] {
+            if enabled {
+                args.push(word(flag));
+            }
+        }
+        args
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct GeoMatch {
+    pub member: Vec<u8>,
+    pub score: f64,
+    pub distance: f64,
+    pub longitude: f64,
+    pub latitude: f64,
+}
+
+/// Spreads the low 32 bits of `x` to the even bit positions of the result.
+fn spread(x: u32) -> u64 {
+    let mut x = x as u64;
+    x = (x | (x << 16)) & 0x0000_FFFF_0000_FFFF;
+    x = (x | (x << 8)) & 0x00FF_00FF_00FF_00FF;
+    x = (x | (x << 4)) & 0x0F0F_0F0F_0F0F_0F0F;
+    x = (x | (x << 2)) & 0x3333_3333_3333_3333;
+    (x | (x << 1)) & 0x5555_5555_5555_5555
+}
+
+/// Inverse of [`spread`]: gathers the even bit positions of `x`.
+fn squash(x: u64) -> u32 {
+    let mut x = x & 0x5555_5555_5555_5555;
+    x = (x | (x >> 1)) & 0x3333_3333_3333_3333;
+    x = (x | (x >> 2)) & 0x0F0F_0F0F_0F0F_0F0F;
+    x = (x | (x >> 4)) & 0x00FF_00FF_00FF_00FF;
+    x = (x | (x >> 8)) & 0x0000_FFFF_0000_FFFF;
+    ((x | (x >> 16)) & 0x0000_0000_FFFF_FFFF) as u32
+}
+
+fn encode_with_ranges(
+    longitude: f64,
+    latitude: f64,
+    (lat_min, lat_max): (f64, f64),
+    (long_min, long_max): (f64, f64),
+) -> u64 {
+    let lat_offset = (latitude - lat_min) / (lat_max - lat_min) * (1u64 << GEO_STEP) as f64;
+    let long_offset = (longitude - long_min) / (long_max - long_min) * (1u64 << GEO_STEP) as f64;
+    spread(lat_offset as u32) | (spread(long_offset as u32) << 1)
+}
+
+pub fn validate(longitude: f64, latitude: f64) -> Result<(), GeoError> {
+    if !(GEO_LONG_MIN..=GEO_LONG_MAX).contains(&longitude)
+        || !(GEO_LAT_MIN..=GEO_LAT_MAX).contains(&latitude)
+    {
+        return Err(GeoError::InvalidCoordinates(longitude, latitude));
+    }
+    Ok(())
+}
+
+/// Encodes a position as the 52-bit interleaved geohash used for sorted set scores.
+pub fn encode(longitude: f64, latitude: f64) -> Result<u64, GeoError> {
+    validate(longitude, latitude)?;
+    Ok(encode_with_ranges(
+        longitude,
+        latitude,
+        (GEO_LAT_MIN, GEO_LAT_MAX),
+        (GEO_LONG_MIN, GEO_LONG_MAX),
+    ))
+}
+
+/// Decodes a geohash score to the centre of its cell as `(longitude, latitude)`.
+pub fn decode(bits: u64) -> (f64, f64) {
+    let lat_cell = squash(bits) as f64;
+    let long_cell = squash(bits >> 1) as f64;
+    let cells = (1u64 << GEO_STEP) as f64;
+    let lat_scale = GEO_LAT_MAX - GEO_LAT_MIN;
+    let long_scale = GEO_LONG_MAX - GEO_LONG_MIN;
+
+    let lat_min = GEO_LAT_MIN + (lat_cell / cells) * lat_scale;
+    let lat_max = GEO_LAT_MIN + ((lat_cell + 1.0) / cells) * lat_scale;
+    let long_min = GEO_LONG_MIN + (long_cell / cells) * long_scale;
+    let long_max = GEO_LONG_MIN + ((long_cell + 1.0) / cells) * long_scale;
+
+    let longitude = ((long_min + long_max) / 2.0).clamp(GEO_LONG_MIN, GEO_LONG_MAX);
+    let latitude = ((lat_min + lat_max) / 2.0).clamp(GEO_LAT_MIN, GEO_LAT_MAX);
+    (longitude, latitude)
+}
+
+/// Renders a score as the standard 11 character base32 geohash, which uses the
+/// full [-90, 90] latitude range rather than the Mercator limits of the score.
+pub fn geohash_string(bits: u64) -> String {
+    let (longitude, latitude) = decode(bits);
+    let hash = encode_with_ranges(longitude, latitude, (-90.0, 90.0), (-180.0, 180.0));
+    (0..11)
+        .map(|i| {
+            let index = if i == 10 {
+                0
+            } else {
+                (hash >> (52 - (i + 1) * 5)) & 0x1F
+            };
+            GEO_ALPHABET[index as usize] as char
+        })
+        .collect()
+}
+
+fn latitude_distance(lat1: f64, lat2: f64) -> f64 {
+    EARTH_RADIUS_IN_METERS * (lat2.to_radians() - lat1.to_radians()).abs()
+}
+
+/// Haversine distance in meters.
+pub fn distance(lon1: f64, lat1: f64, lon2: f64, lat2: f64) -> f64 {
+    let v = ((lon2.to_radians() - lon1.to_radians()) / 2.0).sin();
+    if v == 0.0 {
+        return latitude_distance(lat1, lat2);
+    }
+    let lat1 = lat1.to_radians();
+    let lat2 = lat2.to_radians();
+    let u = ((lat2 - lat1) / 2.0).sin();
+    let a = u * u + lat1.cos() * lat2.cos() * v * v;
+    2.0 * EARTH_RADIUS_IN_METERS * a.sqrt().asin()
+}
+
+/// Returns the distance from the centre to a point if the point lies within the shape.
+fn distance_if_inside(
+    shape: GeoShape,
+    unit: GeoUnit,
+    center: (f64, f64),
+    point: (f64, f64),
+) -> Option<f64> {
+    let (x1, y1) = center;
+    let (x2, y2) = point;
+    match shape {
+        GeoShape::Radius(radius) => {
+            let d = distance(x1, y1, x2, y2);
+            (d <= radius * unit.to_meters()).then_some(d)
+        }
+        GeoShape::Box(width, height) => {
+            let width = width * unit.to_meters();
+            let height = height * unit.to_meters();
+            if latitude_distance(y2, y1) > height / 2.0 {
+                return None;
+            }
+            if distance(x2, y2, x1, y2) > width / 2.0 {
+                return None;
+            }
+            Some(distance(x1, y1, x2, y2))
+        }
+    }
+}
+
+/// A geohash cell `step` bits of precision per axis, as grid coordinates.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Cell {
+    step: u32,
+    long: u32,
+    lat: u32,
+}
+
+impl Cell {
+    fn containing(longitude: f64, latitude: f64, step: u32) -> Self {
+        let cells = (1u64 << step) as f64;
+        let offset = |value: f64, min: f64, max: f64| {
+            (((value - min) / (max - min) * cells) as u32).min((1 << step) - 1)
+        };
+        Cell {
+            step,
+            long: offset(longitude, GEO_LONG_MIN, GEO_LONG_MAX),
+            lat: offset(latitude, GEO_LAT_MIN, GEO_LAT_MAX),
+        }
+    }
+
+    /// The adjacent cell, wrapping around the edges of the grid like Redis does.
+    fn neighbor(self, long: i64, lat: i64) -> Self {
+        let cells = 1i64 << self.step;
+        Cell {
+            step: self.step,
+            long: (self.long as i64 + long).rem_euclid(cells) as u32,
+            lat: (self.lat as i64 + lat).rem_euclid(cells) as u32,
+        }
+    }
+
+    /// The `(min, max)` longitude and latitude covered by the cell.
+    fn area(self) -> ((f64, f64), (f64, f64)) {
+        let cells = (1u64 << self.step) as f64;
+        let bound = |cell: u32, min: f64, max: f64| {
+            (
+                min + cell as f64 / cells * (max - min),
+                min + (cell as f64 + 1.0) / cells * (max - min),
+            )
+        };
+        (
+            bound(self.long, GEO_LONG_MIN, GEO_LONG_MAX),
+            bound(self.lat, GEO_LAT_MIN, GEO_LAT_MAX),
+        )
+    }
+
+    /// The scores `min <= score < max` of the positions inside the cell.
+    fn scores(self) -> (f64, f64) {
+        let hash = spread(self.lat) | (spread(self.long) << 1);
+        let shift = 2 * (GEO_STEP - self.step);
+        ((hash << shift) as f64, ((hash + 1) << shift) as f64)
+    }
+}
+
+/// Number of bits per axis of the cells searched for a radius, as in Redis'
+/// geohashEstimateStepsByRadius.
+fn steps_for_radius(mut meters: f64, latitude: f64) -> u32 {
+    if meters == 0.0 {
+        return GEO_STEP;
+    }
+    let mut step: i32 = 1;
+    while meters < MERCATOR_MAX {
+        meters *= 2.0;
+        step += 1;
+    }
+    step -= 2;
+    // Cells get narrower towards the poles.
+    if latitude.abs() > 66.0 {
+        step -= 1;
+        if latitude.abs() > 80.0 {
+            step -= 1;
+        }
+    }
+    step.clamp(1, GEO_STEP as i32) as u32
+}
+
+/// The cells that can hold members inside the shape: the one containing the centre
+/// and those of its eight neighbours that overlap the shape's bounding box, following
+/// Redis' geohashCalculateAreasByShapeWGS84.
+fn search_cells(shape: GeoShape, unit: GeoUnit, (longitude, latitude): (f64, f64)) -> Vec<Cell> {
+    let (width, height, radius) = match shape {
+        GeoShape::Radius(radius) => (radius, radius, radius),
+        GeoShape::Box(width, height) => (width / 2.0, height / 2.0, width.hypot(height) / 2.0),
+    };
+    let (width, height) = (width * unit.to_meters(), height * unit.to_meters());
+    let lat_delta = (height / EARTH_RADIUS_IN_METERS).to_degrees();
+    let long_delta =
+        |latitude: f64| (width / EARTH_RADIUS_IN_METERS / latitude.to_radians().cos()).to_degrees();
+    // The edge closer to the pole is the wider one in degrees.
+    let long_delta = if latitude < 0.0 {
+        long_delta(latitude - lat_delta)
+    } else {
+        long_delta(latitude + lat_delta)
+    };
+    let (min_long, max_long) = (longitude - long_delta, longitude + long_delta);
+    let (min_lat, max_lat) = (latitude - lat_delta, latitude + lat_delta);
+
+    let mut step = steps_for_radius(radius * unit.to_meters(), latitude);
+    let mut center = Cell::containing(longitude, latitude, step);
+    // Near the edge of the centre cell a neighbour may not reach far enough to cover
+    // the shape, in which case the next coarser step is used.
+    let (_, (_, north)) = center.neighbor(0, 1).area();
+    let (_, (south, _)) = center.neighbor(0, -1).area();
+    let ((_, east), _) = center.neighbor(1, 0).area();
+    let ((west, _), _) = center.neighbor(-1, 0).area();
+    let too_small = north < max_lat || south > min_lat || east < max_long || west > min_long;
+    if step > 1 && too_small {
+        step -= 1;
+        center = Cell::containing(longitude, latitude, step);
+    }
+
+    let ((area_min_long, area_max_long), (area_min_lat, area_max_lat)) = center.area();
+    let mut cells: Vec<Cell> = Vec::with_capacity(9);
+    for (long, lat) in [
+        (0, 0),
+        (0, 1),
+        (0, -1),
+        (1, 0),
+        (-1, 0),
+        (1, 1),
+        (-1, 1),
+        (1, -1),
+        (-1, -1),
+    ] {
+        // Neighbours on a side the shape does not reach past are skipped.
+        if step >= 2
+            && ((lat < 0 && area_min_lat < min_lat)
+                || (lat > 0 && area_max_lat > max_lat)
+                || (long < 0 && area_min_long < min_long)
+                || (long > 0 && area_max_long > max_long))
+        {
+            continue;
+        }
+        let cell = center.neighbor(long, lat);
+        if !cells.contains(&cell) {
+            cells.push(cell);
+        }
+    }
+    cells
+}
+
+/// Finds the members of `zset` inside the search shape, applying ordering and COUNT.
+pub fn search(zset: &SortedSet, search: &GeoSearch) -> Result<Vec<GeoMatch>, GeoError> {
+    let center = match &search.origin {
+        GeoOrigin::Member(member) => {
+            decode(zset.score(member).ok_or(GeoError::MemberNotFound)? as u64)
+        }
+        GeoOrigin::LonLat(longitude, latitude) => (*longitude, *latitude),
+    };
+
+    // COUNT without ANY must look at every candidate to return the closest ones.
+    let sort = match (search.sort, search.count) {
+        (None, Some((_, false))) => Some(GeoSort::Asc),
+        (sort, _) => sort,
+    };
+    let limit = match search.count {
+        Some((count, true)) => count,
+        _ => usize::MAX,
+    };
+
+    let mut matches = Vec::new();
+    'cells: for cell in search_cells(search.shape, search.unit, center) {
+        let (min, max) = cell.scores();
+        for (member, score) in zset.range(min, max) {
+            let (longitude, latitude) = decode(score as u64);
+            if let Some(distance) =
+                distance_if_inside(search.shape, search.unit, center, (longitude, latitude))
+            {
+                matches.push(GeoMatch {
+                    member: member.to_owned(),
+                    score,
+                    distance,
+                    longitude,
+                    latitude,
+                });
+                if matches.len() >= limit {
+                    break 'cells;
+                }
+            }
+        }
+    }
+
+    match sort {
+        Some(GeoSort::Asc) => matches.sort_by(|a, b| a.distance.total_cmp(&b.distance)),
+        Some(GeoSort::Desc) => matches.sort_by(|a, b| b.distance.total_cmp(&a.distance)),
+        None => {}
+    }
+    if let Some((count, _)) = search.count {
+        matches.truncate(count);
+    }
+    Ok(matches)
+}
+
+/// Formats a coordinate like Redis' human readable long double replies.
+pub fn format_coordinate(value: f64) -> String {
+    let formatted = format!("{:.17}", value);
+    formatted
+        .trim_end_matches('0')
+        .trim_end_matches('.')
+        .to_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Coordinates from the Redis GEO documentation examples.
+    const PALERMO: (f64, f64) = (13.361389, 38.115556);
+    const CATANIA: (f64, f64) = (15.087269, 37.502669);
+
+    fn sicily() -> SortedSet {
+        let mut zset = SortedSet::new();
+        for (name, (longitude, latitude)) in [
+            ("Palermo", PALERMO),
+            ("Catania", CATANIA),
+            ("edge1", (12.758489, 38.788135)),
+            ("edge2", (17.241510, 38.788135)),
+        ] {
+            let score = encode(longitude, latitude).unwrap();
+            zset.insert(name.as_bytes().to_vec(), score as f64);
+        }
+        zset
+    }
+
+    fn search_from(origin: GeoOrigin, shape: GeoShape) -> GeoSearch {
+        GeoSearch {
+            origin,
+            shape,
+            unit: GeoUnit::Kilometers,
+            sort: Some(GeoSort::Asc),
+            count: None,
+            with_coord: false,
+            with_dist: false,
+            with_hash: false,
+            store_dist: false,
+        }
+    }
+
+    fn members(matches: &[GeoMatch]) -> Vec<&[u8]> {
+        matches
+            .iter()
+            .map(|found| found.member.as_slice())
+            .collect()
+    }
+
+    #[test]
+    fn scores_and_hashes_match_redis() {
+        let palermo = encode(PALERMO.0, PALERMO.1).unwrap();
+        let catania = encode(CATANIA.0, CATANIA.1).unwrap();
+        assert_eq!(palermo, 3479099956230698);
+        assert_eq!(catania, 3479447370796909);
+        assert_eq!(geohash_string(palermo), "sqc8b49rny0");
+        assert_eq!(geohash_string(catania), "sqdtr74hyu0");
+
+        let (longitude, latitude) = decode(palermo);
+        assert_eq!(format_coordinate(longitude), "13.36138933897018433");
+        assert_eq!(format_coordinate(latitude), "38.11555639549629859");
+    }
+
+    #[test]
+    fn rejects_positions_outside_the_mercator_limits() {
+        assert!(encode(181.0, 0.0).is_err());
+        assert!(encode(0.0, 85.06).is_err());
+        assert!(encode(-180.0, -85.05112878).is_ok());
+    }
+
+    #[test]
+    fn distance_between_members() {
+        let (palermo, catania) = (
+            decode(encode(PALERMO.0, PALERMO.1).unwrap()),
+            decode(encode(CATANIA.0, CATANIA.1).unwrap()),
+        );
+        let meters = distance(palermo.0, palermo.1, catania.0, catania.1);
+        assert_eq!(format!("{:.4}", meters), "166274.1516");
+        assert_eq!(
+            format!("{:.4}", meters / GeoUnit::Kilometers.to_meters()),
+            "166.2742"
+        );
+        assert_eq!(
+            format!("{:.4}", meters / GeoUnit::Miles.to_meters()),
+            "103.3182"
+        );
+        assert_eq!(distance(10.0, 20.0, 10.0, 20.0), 0.0);
+    }
+
+    #[test]
+    fn search_by_radius_and_box() {
+        let zset = sicily();
+        let origin = GeoOrigin::LonLat(15.0, 37.0);
+        let found = search(&zset, &search_from(origin.clone(), GeoShape::Radius(200.0))).unwrap();
+        assert_eq!(members(&found), [&b"Catania"[..], b"Palermo"]);
+        let distances = found
+            .iter()
+            .map(|found| format!("{:.4}", found.distance / 1000.0))
+            .collect::<Vec<_>>();
+        assert_eq!(distances, ["56.4413", "190.4424"]);
+
+        let found = search(&zset, &search_from(origin, GeoShape::Box(400.0, 400.0))).unwrap();
+        assert_eq!(
+            members(&found),
+            [&b"Catania"[..], b"Palermo", b"edge2", b"edge1"]
+        );
+    }
+
+    #[test]
+    fn search_from_member_with_count_and_order() {
+        let zset = sicily();
+        let mut options = search_from(
+            GeoOrigin::Member(b"Palermo".to_vec()),
+            GeoShape::Radius(500.0),
+        );
+        options.sort = Some(GeoSort::Desc);
+        let found = search(&zset, &options).unwrap();
+        assert_eq!(found.last().unwrap().member, b"Palermo");
+        assert_eq!(found.last().unwrap().distance, 0.0);
+
+        // COUNT without ANY returns the closest members even when no order was asked for.
+        options.sort = None;
+        options.count = Some((2, false));
+        let found = search(&zset, &options).unwrap();
+        assert_eq!(members(&found), [&b"Palermo"[..], b"edge1"]);
+
+        options.origin = GeoOrigin::Member(b"Messina".to_vec());
+        assert!(matches!(
+            search(&zset, &options),
+            Err(GeoError::MemberNotFound)
+        ));
+    }
+
+    #[test]
+    fn small_searches_scan_only_nearby_cells() {
+        let cells = search_cells(GeoShape::Radius(10.0), GeoUnit::Kilometers, PALERMO);
+        assert!(cells.len() <= 9);
+        assert!(cells.iter().all(|cell| cell.step > 8));
+        let palermo = encode(PALERMO.0, PALERMO.1).unwrap() as f64;
+        let catania = encode(CATANIA.0, CATANIA.1).unwrap() as f64;
+        let covers = |score: f64| {
+            cells.iter().any(|cell| {
+                let (min, max) = cell.scores();
+                (min..max).contains(&score)
+            })
+        };
+        assert!(covers(palermo));
+        assert!(!covers(catania));
+    }
+
+    #[test]
+    fn cell_search_matches_a_full_scan() {
+        let mut seed = 0x2545_f491_4f6c_dd1du64;
+        let mut next = |range: f64| {
+            seed = seed.wrapping_mul(6364136223846793005).wrapping_add(1);
+            (seed >> 11) as f64 / (1u64 << 53) as f64 * range
+        };
+        let mut zset = SortedSet::new();
+        for i in 0..2000 {
+            let (longitude, latitude) = if i % 2 == 0 {
+                (next(360.0) - 180.0, next(170.0) - 85.0)
+            } else {
+                (12.0 + next(6.0), 36.0 + next(4.0))
+            };
+            let score = encode(longitude, latitude).unwrap();
+            zset.insert(format!("m{}", i).into_bytes(), score as f64);
+        }
+
+        for shape in [
+            GeoShape::Radius(0.0),
+            GeoShape::Radius(15.0),
+            GeoShape::Radius(120.0),
+            GeoShape::Radius(3000.0),
+            GeoShape::Radius(25000.0),
+            GeoShape::Box(30.0, 80.0),
+            GeoShape::Box(500.0, 200.0),
+        ] {
+            for origin in [(15.0, 38.0), (13.5, 37.2), (179.9, 0.0), (-20.0, 84.0)] {
+                let options = search_from(GeoOrigin::LonLat(origin.0, origin.1), shape);
+                let mut found = members(&search(&zset, &options).unwrap())
+                    .into_iter()
+                    .map(<[u8]>::to_vec)
+                    .collect::<Vec<_>>();
+                let mut expected = zset
+                    .iter()
+                    .filter(|(_, score)| {
+                        distance_if_inside(shape, options.unit, origin, decode(*score as u64))
+                            .is_some()
+                    })
+                    .map(|(member, _)| member.to_vec())
+                    .collect::<Vec<_>>();
+                found.sort();
+                expected.sort();
+                assert_eq!(found, expected, "{:?} around {:?}", shape, origin);
+            }
+        }
+    }
+}
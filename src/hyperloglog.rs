@@ -0,0 +1,572 @@
+use thiserror::Error;
+
+// Layout and encodings follow Redis' hyperloglog.c so that values can be exchanged with
+// real Redis instances through RDB files and DUMP payloads.
+const HLL_P: u32 = 14;
+const HLL_Q: u32 = 64 - HLL_P;
+const HLL_REGISTERS: usize = 1 << HLL_P;
+const HLL_P_MASK: u64 = HLL_REGISTERS as u64 - 1;
+const HLL_BITS: usize = 6;
+const HLL_REGISTER_MAX: u8 = (1 << HLL_BITS) - 1;
+const HLL_HDR_SIZE: usize = 16;
+const HLL_DENSE_SIZE: usize = HLL_HDR_SIZE + (HLL_REGISTERS * HLL_BITS).div_ceil(8);
+const HLL_ALPHA_INF: f64 = 0.721_347_520_444_481_7;
+const HLL_MAGIC: &[u8; 4] = b"HYLL";
+const HLL_HASH_SEED: u64 = 0xadc8_3b19;
+
+const HLL_SPARSE_VAL_MAX_VALUE: u8 = 32;
+const HLL_SPARSE_VAL_MAX_LEN: usize = 4;
+const HLL_SPARSE_ZERO_MAX_LEN: usize = 64;
+const HLL_SPARSE_XZERO_MAX_LEN: usize = 16384;
+
+/// Sparse representations longer than this (header included) are promoted to dense,
+/// mirroring the `hll-sparse-max-bytes` default.
+pub const HLL_SPARSE_MAX_BYTES: usize = 3000;
+
+#[derive(Error, Debug)]
+pub enum HllError {
+    #[error("WRONGTYPE Key is not a valid HyperLogLog string value.")]
+    NotHyperLogLog,
+
+    #[error("INVALIDOBJ Corrupted HLL object detected")]
+    Corrupted,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Encoding {
+    Dense = 0,
+    Sparse = 1,
+}
+
+#[derive(Debug, Clone)]
+pub struct HyperLogLog {
+    encoding: Encoding,
+    /// Raw header cardinality bytes; the MSB of the last byte flags the cache as stale.
+    card: [u8; 8],
+    registers: Vec<u8>,
+    /// Length of the sparse opcodes for `registers`, kept up to date by `add` so that
+    /// adding an element doesn't re-encode every register. Unused once dense.
+    sparse_len: usize,
+}
+
+fn murmurhash64a(key: &[u8], seed: u64) -> u64 {
+    const M: u64 = 0xc6a4_a793_5bd1_e995;
+    const R: u32 = 47;
+    let mut h = seed ^ (key.len() as u64).wrapping_mul(M);
+
+    let mut chunks = key.chunks_exact(8);
+    for chunk in &mut chunks {
+        let mut k = u64::from_le_bytes(chunk.try_into().unwrap());
+        k = k.wrapping_mul(M);
+        k ^= k >> R;
+        k = k.wrapping_mul(M);
+        h ^= k;
+        h = h.wrapping_mul(M);
+    }
+
+    let tail = chunks.remainder();
+    if !tail.is_empty() {
+        for (i, byte) in tail.iter().enumerate().rev() {
+            h ^= (*byte as u64) << (8 * i);
+        }
+        h = h.wrapping_mul(M);
+    }
+
+    h ^= h >> R;
+    h = h.wrapping_mul(M);
+    h ^= h >> R;
+    h
+}
+
+/// Returns the register index for `element` and the length of its "000..1" pattern.
+fn pattern_len(element: &[u8]) -> (usize, u8) {
+    let hash = murmurhash64a(element, HLL_HASH_SEED);
+    let index = (hash & HLL_P_MASK) as usize;
+    let hash = (hash >> HLL_P) | (1 << HLL_Q);
+    (index, hash.trailing_zeros() as u8 + 1)
+}
+
+fn sigma(mut x: f64) -> f64 {
+    if x == 1.0 {
+        return f64::INFINITY;
+    }
+    let mut y = 1.0;
+    let mut z = x;
+    loop {
+        x *= x;
+        let z_prime = z;
+        z += x * y;
+        y += y;
+        if z_prime == z {
+            return z;
+        }
+    }
+}
+
+fn tau(mut x: f64) -> f64 {
+    if x == 0.0 || x == 1.0 {
+        return 0.0;
+    }
+    let mut y = 1.0;
+    let mut z = 1.0 - x;
+    loop {
+        x = x.sqrt();
+        let z_prime = z;
+        y *= 0.5;
+        z -= (1.0 - x).powi(2) * y;
+        if z_prime == z {
+            return z / 3.0;
+        }
+    }
+}
+
+fn decode_dense(packed: &[u8]) -> Vec<u8> {
+    (0..HLL_REGISTERS)
+        .map(|regnum| {
+            let byte = regnum * HLL_BITS / 8;
+            let fb = (regnum * HLL_BITS) & 7;
+            let b0 = packed[byte] as u16;
+            let b1 = packed.get(byte + 1).copied().unwrap_or(0) as u16;
+            (((b0 >> fb) | (b1 << (8 - fb))) as u8) & HLL_REGISTER_MAX
+        })
+        .collect()
+}
+
+fn encode_dense(registers: &[u8]) -> Vec<u8> {
+    let mut packed = vec![0u8; HLL_DENSE_SIZE - HLL_HDR_SIZE];
+    for (regnum, value) in registers.iter().enumerate() {
+        let byte = regnum * HLL_BITS / 8;
+        let fb = (regnum * HLL_BITS) & 7;
+        let value = *value as u16;
+        packed[byte] |= (value << fb) as u8;
+        if let Some(next) = packed.get_mut(byte + 1) {
+            *next |= (value >> (8 - fb)) as u8;
+        }
+    }
+    packed
+}
+
+fn decode_sparse(opcodes: &[u8]) -> Result<Vec<u8>, HllError> {
+    let mut registers = Vec::with_capacity(HLL_REGISTERS);
+    let mut i = 0;
+    while i < opcodes.len() {
+        let opcode = opcodes[i];
+        let (value, len) = if opcode & 0xC0 == 0x00 {
+            // ZERO: 00xxxxxx
+            i += 1;
+            (0, (opcode & 0x3F) as usize + 1)
+        } else if opcode & 0xC0 == 0x40 {
+            // XZERO: 01xxxxxx yyyyyyyy
+            let next = *opcodes.get(i + 1).ok_or(HllError::Corrupted)?;
+            i += 2;
+            (0, ((((opcode & 0x3F) as usize) << 8) | next as usize) + 1)
+        } else {
+            // VAL: 1vvvvvxx
+            i += 1;
+            (((opcode >> 2) & 0x1F) + 1, (opcode & 0x03) as usize + 1)
+        };
+        if registers.len() + len > HLL_REGISTERS {
+            return Err(HllError::Corrupted);
+        }
+        registers.resize(registers.len() + len, value);
+    }
+    if registers.len() != HLL_REGISTERS {
+        return Err(HllError::Corrupted);
+    }
+    Ok(registers)
+}
+
+/// Encodes registers as sparse opcodes, or `None` if a register is too large for VAL.
+fn encode_sparse(registers: &[u8]) -> Option<Vec<u8>> {
+    let mut opcodes = Vec::new();
+    let mut i = 0;
+    while i < registers.len() {
+        let value = registers[i];
+        let run = registers[i..].iter().take_while(|r| **r == value).count();
+        i += run;
+
+        let mut remaining = run;
+        if value == 0 {
+            while remaining > 0 {
+                let len = remaining.min(HLL_SPARSE_XZERO_MAX_LEN);
+                if len > HLL_SPARSE_ZERO_MAX_LEN {
+                    opcodes.push(0x40 | ((len - 1) >> 8) as u8);
+                    opcodes.push(((len - 1) & 0xFF) as u8);
+                } else {
+                    opcodes.push((len - 1) as u8);
+                }
+                remaining -= len;
+            }
+        } else {
+            if value > HLL_SPARSE_VAL_MAX_VALUE {
+                return None;
+            }
+            while remaining > 0 {
+                let len = remaining.min(HLL_SPARSE_VAL_MAX_LEN);
+                opcodes.push(0x80 | ((value - 1) << 2) | (len - 1) as u8);
+                remaining -= len;
+            }
+        }
+    }
+    Some(opcodes)
+}
+
+/// Bytes of sparse opcodes for a run of `len` registers holding `value`.
+fn sparse_run_len(value: u8, len: usize) -> usize {
+    if value != 0 {
+        return len.div_ceil(HLL_SPARSE_VAL_MAX_LEN);
+    }
+    let full = len / HLL_SPARSE_XZERO_MAX_LEN * 2;
+    match len % HLL_SPARSE_XZERO_MAX_LEN {
+        0 => full,
+        rest if rest <= HLL_SPARSE_ZERO_MAX_LEN => full + 1,
+        _ => full + 2,
+    }
+}
+
+/// Counts the registers equal to `value` from `start` on, in the direction of `step`,
+/// stopping at `limit`.
+fn run_from(registers: &[u8], start: usize, step: isize, value: u8, limit: usize) -> usize {
+    (1..)
+        .map(|n| start.checked_add_signed(step * n))
+        .take_while(|i| i.is_some_and(|i| registers.get(i) == Some(&value)))
+        .take(limit)
+        .count()
+}
+
+impl HyperLogLog {
+    pub fn new() -> Self {
+        Self {
+            encoding: Encoding::Sparse,
+            card: [0; 8],
+            registers: vec![0; HLL_REGISTERS],
+            // A single XZERO opcode covering every register.
+            sparse_len: 2,
+        }
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, HllError> {
+        if bytes.len() < HLL_HDR_SIZE || &bytes[..4] != HLL_MAGIC {
+            return Err(HllError::NotHyperLogLog);
+        }
+        let encoding = match bytes[4] {
+            0 if bytes.len() == HLL_DENSE_SIZE => Encoding::Dense,
+            1 => Encoding::Sparse,
+            _ => return Err(HllError::NotHyperLogLog),
+        };
+        let registers = match encoding {
+            Encoding::Dense => decode_dense(&bytes[HLL_HDR_SIZE..]),
+            Encoding::Sparse => decode_sparse(&bytes[HLL_HDR_SIZE..])?,
+        };
+        let mut card = [0u8; 8];
+        card.copy_from_slice(&bytes[8..HLL_HDR_SIZE]);
+
+        // Redis doesn't always pick the shortest opcodes, so the length is measured on
+        // the encoding this value will be written with.
+        let sparse_len = match encoding {
+            Encoding::Sparse => encode_sparse(&registers).map_or(0, |opcodes| opcodes.len()),
+            Encoding::Dense => 0,
+        };
+        Ok(Self {
+            encoding,
+            card,
+            registers,
+            sparse_len,
+        })
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        // `add` and `merge` promote registers that don't fit the sparse encoding, but
+        // should some not, the value is written dense rather than lost.
+        let (encoding, body) = match self.encoding {
+            Encoding::Sparse => match encode_sparse(&self.registers) {
+                Some(opcodes) => (Encoding::Sparse, opcodes),
+                None => (Encoding::Dense, encode_dense(&self.registers)),
+            },
+            Encoding::Dense => (Encoding::Dense, encode_dense(&self.registers)),
+        };
+        let mut bytes = Vec::with_capacity(HLL_HDR_SIZE + body.len());
+        bytes.extend_from_slice(HLL_MAGIC);
+        bytes.push(encoding as u8);
+        bytes.extend_from_slice(&[0; 3]);
+        bytes.extend_from_slice(&self.card);
+        bytes.extend_from_slice(&body);
+        bytes
+    }
+
+    fn invalidate_cache(&mut self) {
+        self.card[7] |= 1 << 7;
+    }
+
+    /// Switches to the dense encoding once the sparse form can no longer hold the registers
+    /// or grows beyond `HLL_SPARSE_MAX_BYTES`, measuring it by encoding every register.
+    /// Promotion is never undone.
+    fn promote_if_needed(&mut self) {
+        if self.encoding == Encoding::Sparse {
+            match encode_sparse(&self.registers) {
+                Some(opcodes) => self.sparse_len = opcodes.len(),
+                None => self.encoding = Encoding::Dense,
+            }
+            self.promote_if_too_long();
+        }
+    }
+
+    fn promote_if_too_long(&mut self) {
+        if HLL_HDR_SIZE + self.sparse_len > HLL_SPARSE_MAX_BYTES {
+            self.encoding = Encoding::Dense;
+        }
+    }
+
+    /// Updates `sparse_len` for register `index` changing to `value`, from the runs next
+    /// to it rather than the whole encoding. Zero runs only need to be followed past
+    /// `HLL_SPARSE_ZERO_MAX_LEN`: within a sparse value, longer ones all take an XZERO.
+    fn update_sparse_len(&mut self, index: usize, value: u8) {
+        let registers = &self.registers;
+        let old = registers[index];
+        let limit = |value| match value {
+            0 => HLL_SPARSE_ZERO_MAX_LEN + 1,
+            _ => HLL_REGISTERS,
+        };
+        let (left_old, right_old) = (
+            run_from(registers, index, -1, old, limit(old)),
+            run_from(registers, index, 1, old, limit(old)),
+        );
+        // Where the old run ends at `index`, the register joins the run beyond it if
+        // that already holds `value`.
+        let left_new = match left_old {
+            0 => run_from(registers, index, -1, value, HLL_REGISTERS),
+            _ => 0,
+        };
+        let right_new = match right_old {
+            0 => run_from(registers, index, 1, value, HLL_REGISTERS),
+            _ => 0,
+        };
+        let before = sparse_run_len(old, left_old + 1 + right_old)
+            + sparse_run_len(value, left_new)
+            + sparse_run_len(value, right_new);
+        let after = sparse_run_len(old, left_old)
+            + sparse_run_len(old, right_old)
+            + sparse_run_len(value, left_new + 1 + right_new);
+        self.sparse_len = self.sparse_len + after - before;
+    }
+
+    /// Adds an element, returning whether any register changed.
+    pub fn add(&mut self, element: &[u8]) -> bool {
+        let (index, count) = pattern_len(element);
+        if self.registers[index] >= count {
+            return false;
+        }
+        if self.encoding == Encoding::Sparse {
+            if count > HLL_SPARSE_VAL_MAX_VALUE {
+                self.encoding = Encoding::Dense;
+            } else {
+                self.update_sparse_len(index, count);
+                self.promote_if_too_long();
+            }
+        }
+        self.registers[index] = count;
+        self.invalidate_cache();
+        true
+    }
+
+    /// Folds `other` into this HyperLogLog by taking the maximum of every register.
+    pub fn merge(&mut self, other: &HyperLogLog) {
+        for (register, other) in self.registers.iter_mut().zip(other.registers.iter()) {
+            *register = (*register).max(*other);
+        }
+        if other.encoding == Encoding::Dense {
+            self.encoding = Encoding::Dense;
+        }
+        self.invalidate_cache();
+        self.promote_if_needed();
+    }
+
+    pub fn cached_count(&self) -> Option<u64> {
+        if self.card[7] & (1 << 7) == 0 {
+            Some(u64::from_le_bytes(self.card))
+        } else {
+            None
+        }
+    }
+
+    /// Estimates the cardinality and stores it in the header cache.
+    pub fn count(&mut self) -> u64 {
+        if let Some(cached) = self.cached_count() {
+            return cached;
+        }
+        let estimate = self.estimate();
+        self.card = estimate.to_le_bytes();
+        estimate
+    }
+
+    pub fn estimate(&self) -> u64 {
+        let m = HLL_REGISTERS as f64;
+        // Sized for every 6-bit value, as a crafted dense string can hold registers past
+        // HLL_Q + 1; those fall outside the estimate, like in Redis.
+        let mut histogram = [0u32; 1 << HLL_BITS];
+        for register in self.registers.iter() {
+            histogram[*register as usize] += 1;
+        }
+
+        let mut z = m * tau((m - histogram[HLL_Q as usize + 1] as f64) / m);
+        for j in (1..=HLL_Q as usize).rev() {
+            z += histogram[j] as f64;
+            z *= 0.5;
+        }
+        z += m * sigma(histogram[0] as f64 / m);
+        (HLL_ALPHA_INF * m * m / z).round() as u64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dense_registers_above_q_do_not_panic() {
+        let mut bytes = HLL_MAGIC.to_vec();
+        bytes.extend_from_slice(&[Encoding::Dense as u8, 0, 0, 0]);
+        bytes.extend_from_slice(&[0, 0, 0, 0, 0, 0, 0, 1 << 7]);
+        bytes.resize(HLL_DENSE_SIZE, 0xff);
+        let mut hll = HyperLogLog::from_bytes(&bytes).unwrap();
+        assert!(hll
+            .registers
+            .iter()
+            .all(|register| *register == HLL_REGISTER_MAX));
+        hll.count();
+        hll.merge(&HyperLogLog::new());
+        hll.estimate();
+    }
+
+    fn filled(elements: std::ops::Range<u32>) -> HyperLogLog {
+        let mut hll = HyperLogLog::new();
+        for i in elements {
+            hll.add(format!("element:{}", i).as_bytes());
+        }
+        hll
+    }
+
+    fn assert_close(estimate: u64, actual: u64) {
+        let error = (estimate as f64 - actual as f64).abs() / actual as f64;
+        assert!(
+            error < 0.03,
+            "estimated {} for {} elements",
+            estimate,
+            actual
+        );
+    }
+
+    #[test]
+    fn estimates_stay_within_the_standard_error() {
+        assert_eq!(HyperLogLog::new().estimate(), 0);
+        for n in [1, 10, 100, 1_000, 10_000, 100_000] {
+            assert_close(filled(0..n).estimate(), n as u64);
+        }
+    }
+
+    #[test]
+    fn add_reports_register_changes() {
+        let mut hll = HyperLogLog::new();
+        assert!(hll.add(b"a"));
+        assert!(!hll.add(b"a"));
+        assert_eq!(hll.count(), 1);
+        assert_eq!(hll.cached_count(), Some(1));
+        assert!(hll.add(b"b"));
+        assert_eq!(hll.cached_count(), None);
+        assert_eq!(hll.count(), 2);
+    }
+
+    #[test]
+    fn merge_estimates_the_union() {
+        let mut merged = filled(0..5_000);
+        merged.merge(&filled(2_500..10_000));
+        assert_close(merged.count(), 10_000);
+
+        let before = merged.registers.clone();
+        merged.merge(&filled(0..10_000));
+        assert_eq!(merged.registers, before);
+
+        let mut empty = HyperLogLog::new();
+        empty.merge(&HyperLogLog::new());
+        assert_eq!(empty.count(), 0);
+        assert_eq!(empty.encoding, Encoding::Sparse);
+    }
+
+    #[test]
+    fn promotes_to_dense_and_round_trips() {
+        let sparse = filled(0..100);
+        assert_eq!(sparse.encoding, Encoding::Sparse);
+        let bytes = sparse.to_bytes();
+        assert!(bytes.len() <= HLL_SPARSE_MAX_BYTES);
+        let decoded = HyperLogLog::from_bytes(&bytes).unwrap();
+        assert_eq!(decoded.registers, sparse.registers);
+
+        let mut dense = filled(0..20_000);
+        assert_eq!(dense.encoding, Encoding::Dense);
+        dense.count();
+        let bytes = dense.to_bytes();
+        assert_eq!(bytes.len(), HLL_DENSE_SIZE);
+        let decoded = HyperLogLog::from_bytes(&bytes).unwrap();
+        assert_eq!(decoded.encoding, Encoding::Dense);
+        assert_eq!(decoded.registers, dense.registers);
+        assert_eq!(decoded.cached_count(), dense.cached_count());
+
+        // Merging a dense value promotes a sparse one, like PFMERGE in Redis.
+        let mut promoted = sparse.clone();
+        promoted.merge(&dense);
+        assert_eq!(promoted.encoding, Encoding::Dense);
+    }
+
+    #[test]
+    fn tracks_the_sparse_length_without_re_encoding() {
+        let mut hll = HyperLogLog::new();
+        for i in 0.. {
+            hll.add(format!("element:{}", i).as_bytes());
+            if hll.encoding == Encoding::Dense {
+                break;
+            }
+            let opcodes = encode_sparse(&hll.registers).unwrap();
+            assert_eq!(hll.sparse_len, opcodes.len(), "after {} elements", i + 1);
+        }
+        assert!(encode_sparse(&hll.registers)
+            .is_none_or(|opcodes| HLL_HDR_SIZE + opcodes.len() > HLL_SPARSE_MAX_BYTES));
+
+        // Runs of equal values on both sides of a register, which merge when it changes.
+        let mut hll = HyperLogLog::new();
+        for (index, value) in [(10, 3), (11, 3), (13, 3), (14, 3), (15, 3), (12, 2), (12, 3)] {
+            hll.update_sparse_len(index, value);
+            hll.registers[index] = value;
+            assert_eq!(hll.sparse_len, encode_sparse(&hll.registers).unwrap().len());
+        }
+    }
+
+    #[test]
+    fn writes_registers_too_large_for_sparse_as_dense() {
+        let mut hll = HyperLogLog::new();
+        hll.registers[7] = HLL_SPARSE_VAL_MAX_VALUE + 1;
+        let bytes = hll.to_bytes();
+        assert_eq!(bytes.len(), HLL_DENSE_SIZE);
+        let decoded = HyperLogLog::from_bytes(&bytes).unwrap();
+        assert_eq!(decoded.encoding, Encoding::Dense);
+        assert_eq!(decoded.registers, hll.registers);
+    }
+
+    #[test]
+    fn rejects_values_that_are_not_hyperloglogs() {
+        assert!(matches!(
+            HyperLogLog::from_bytes(b"not a hyperloglog"),
+            Err(HllError::NotHyperLogLog)
+        ));
+        let mut dense = filled(0..20_000).to_bytes();
+        dense.pop();
+        assert!(HyperLogLog::from_bytes(&dense).is_err());
+        // A sparse body whose runs don't add up to every register.
+        let mut short = HyperLogLog::new().to_bytes();
+        short.truncate(HLL_HDR_SIZE);
+        short.push(0x00);
+        assert!(matches!(
+            HyperLogLog::from_bytes(&short),
+            Err(HllError::Corrupted)
+        ));
+    }
+}
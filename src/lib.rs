@@ -0,0 +1,941 @@
+//! The server, as a library so both binaries share its modules: `altredis` just calls
+//! `run`, and `altredis-check-rdb` decodes dump files with `rdb`. Everything here was in
+//! main.rs until the second binary needed it.
+
+pub mod aof;
+mod bitmap;
+mod client;
+mod connection;
+#[cfg(test)]
+mod corrupt;
+mod crc64;
+mod geo;
+mod hyperloglog;
+mod listpack;
+mod lzf;
+mod migrate;
+mod notify;
+mod parse;
+mod persistence;
+mod pubsub;
+pub mod rdb;
+mod replica;
+mod sorted_set;
+pub mod store;
+mod stream;
+mod tracking;
+mod utils;
+mod ziplist;
+use crate::{
+    bitmap::{BitFieldOp, BitOperation, BitUnit},
+    client::{Client, ClientStream},
+    connection::{dispatch_command, expire_keys, EXEC_LOCK},
+    geo::{GeoSearch, GeoUnit},
+    parse::parse_command,
+    pubsub::SubscriptionKind,
+    replica::Replica,
+    tracking::TrackingOptions,
+    utils::{build_resp_error, get_array, parse_memory, parse_yes_no, word},
+};
+use aof::AppendFsync;
+use bytes::BufMut;
+use once_cell::sync::Lazy;
+use parse::process_buff;
+use std::{
+    env::args,
+    result::Result::Ok,
+    str,
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use store::{AccessHint, Config, CorruptRdbPolicy};
+use thiserror::Error;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    sync::{Mutex, RwLock},
+};
+#[derive(Debug, Clone)]
+pub enum Command {
+    Ping,
+    Echo(Vec<u8>),
+    ReplConf(String),
+    ReplConfAck,
+    Psync(Vec<String>),
+    Get(Vec<u8>),
+    Set(Vec<u8>, Vec<u8>, Option<SystemTime>),
+    GetConfig(String),
+    SetConfig(Vec<(String, String)>),
+    Keys(Vec<u8>),
+    Info(String),
+    SetBit(Vec<u8>, u64, u8),
+    GetBit(Vec<u8>, u64),
+    BitCount(Vec<u8>, Option<(i64, i64, BitUnit)>),
+    BitPos(Vec<u8>, u8, Option<i64>, Option<i64>, BitUnit),
+    BitOp(BitOperation, Vec<u8>, Vec<Vec<u8>>),
+    BitField(Vec<u8>, Vec<BitFieldOp>),
+    BitFieldRo(Vec<u8>, Vec<BitFieldOp>),
+    PfAdd(Vec<u8>, Vec<Vec<u8>>),
+    PfCount(Vec<Vec<u8>>),
+    PfMerge(Vec<u8>, Vec<Vec<u8>>),
+    GeoAdd(Vec<u8>, GeoAddOptions, Vec<(f64, f64, Vec<u8>)>),
+    GeoPos(Vec<u8>, Vec<Vec<u8>>),
+    GeoDist(Vec<u8>, Vec<u8>, Vec<u8>, GeoUnit),
+    GeoHash(Vec<u8>, Vec<Vec<u8>>),
+    GeoSearch(Vec<u8>, GeoSearch),
+    GeoSearchStore(Vec<u8>, Vec<u8>, GeoSearch),
+    Multi,
+    Exec,
+    Discard,
+    Watch(Vec<Vec<u8>>),
+    Unwatch,
+    Select(usize),
+    FlushDb,
+    FlushAll,
+    Subscribe(Vec<Vec<u8>>),
+    Unsubscribe(Vec<Vec<u8>>),
+    PSubscribe(Vec<Vec<u8>>),
+    PUnsubscribe(Vec<Vec<u8>>),
+    Publish(Vec<u8>, Vec<u8>),
+    PubSubChannels(SubscriptionKind, Option<Vec<u8>>),
+    PubSubNumSub(SubscriptionKind, Vec<Vec<u8>>),
+    PubSubNumPat,
+    SSubscribe(Vec<Vec<u8>>),
+    SUnsubscribe(Vec<Vec<u8>>),
+    SPublish(Vec<u8>, Vec<u8>),
+    Hello(Option<i64>),
+    Del(Vec<Vec<u8>>),
+    ClientId,
+    ClientTracking(bool, TrackingOptions),
+    ClientCaching(bool),
+    ClientGetRedir,
+    Save,
+    BgSave,
+    BgRewriteAof,
+    LastSave,
+    Dump(Vec<u8>),
+    Restore(Vec<u8>, RestoreOptions),
+    Migrate(MigrateOptions),
+    Shutdown(Option<bool>),
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct GeoAddOptions {
+    pub nx: bool,
+    pub xx: bool,
+    pub ch: bool,
+}
+
+/// Arguments of RESTORE besides the key.
+#[derive(Debug, Clone)]
+pub struct RestoreOptions {
+    /// The serialized value, as produced by DUMP.
+    pub payload: Vec<u8>,
+    /// Expiry from the TTL argument, already made absolute.
+    pub expiry: Option<SystemTime>,
+    pub replace: bool,
+    /// From IDLETIME or FREQ.
+    pub access: Option<AccessHint>,
+}
+
+/// Arguments of MIGRATE.
+#[derive(Debug, Clone)]
+pub struct MigrateOptions {
+    pub host: String,
+    pub port: u16,
+    /// The key argument, or the keys after KEYS when it is empty.
+    pub keys: Vec<Vec<u8>>,
+    pub db: usize,
+    pub timeout: Duration,
+    pub copy: bool,
+    pub replace: bool,
+}
+
+impl Command {
+    /// The command name, the first argument of `to_args`.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Command::Ping => "PING",
+            Command::Echo(_) => "ECHO",
+            Command::ReplConf(_) | Command::ReplConfAck => "REPLCONF",
+            Command::Psync(_) => "PSYNC",
+            Command::Get(_) => "GET",
+            Command::Set(..) => "SET",
+            Command::GetConfig(_) | Command::SetConfig(_) => "CONFIG",
+            Command::Keys(_) => "KEYS",
+            Command::Info(_) => "INFO",
+            Command::SetBit(..) => "SETBIT",
+            Command::GetBit(..) => "GETBIT",
+            Command::BitCount(..) => "BITCOUNT",
+            Command::BitPos(..) => "BITPOS",
+            Command::BitOp(..) => "BITOP",
+            Command::BitField(..) => "BITFIELD",
+            Command::BitFieldRo(..) => "BITFIELD_RO",
+            Command::PfAdd(..) => "PFADD",
+            Command::PfCount(_) => "PFCOUNT",
+            Command::PfMerge(..) => "PFMERGE",
+            Command::GeoAdd(..) => "GEOADD",
+            Command::GeoPos(..) => "GEOPOS",
+            Command::GeoDist(..) => "GEODIST",
+            Command::GeoHash(..) => "GEOHASH",
+            Command::GeoSearch(..) => "GEOSEARCH",
+            Command::GeoSearchStore(..) => "GEOSEARCHSTORE",
+            Command::Multi => "MULTI",
+            Command::Exec => "EXEC",
+            Command::Discard => "DISCARD",
+            Command::Watch(_) => "WATCH",
+            Command::Unwatch => "UNWATCH",
+            Command::Select(_) => "SELECT",
+            Command::FlushDb => "FLUSHDB",
+            Command::FlushAll => "FLUSHALL",
+            Command::Subscribe(_) => "SUBSCRIBE",
+            Command::Unsubscribe(_) => "UNSUBSCRIBE",
+            Command::PSubscribe(_) => "PSUBSCRIBE",
+            Command::PUnsubscribe(_) => "PUNSUBSCRIBE",
+            Command::Publish(..) => "PUBLISH",
+            Command::PubSubChannels(..) | Command::PubSubNumSub(..) | Command::PubSubNumPat => {
+                "PUBSUB"
+            }
+            Command::SSubscribe(_) => "SSUBSCRIBE",
+            Command::SUnsubscribe(_) => "SUNSUBSCRIBE",
+            Command::SPublish(..) => "SPUBLISH",
+            Command::Hello(_) => "HELLO",
+            Command::Del(_) => "DEL",
+            Command::ClientId
+            | Command::ClientTracking(..)
+            | Command::ClientCaching(_)
+            | Command::ClientGetRedir => "CLIENT",
+            Command::Save => "SAVE",
+            Command::BgSave => "BGSAVE",
+            Command::BgRewriteAof => "BGREWRITEAOF",
+            Command::LastSave => "LASTSAVE",
+            Command::Dump(_) => "DUMP",
+            Command::Restore(..) => "RESTORE",
+            Command::Migrate(_) => "MIGRATE",
+            Command::Shutdown(_) => "SHUTDOWN",
+        }
+    }
+
+    /// Arguments as sent over the wire, used when propagating the command and logging it
+    /// to the AOF. Every argument is kept whole, so keys and values may contain spaces.
+    pub fn to_args(&self) -> Vec<Vec<u8>> {
+        let mut args = vec![word(self.name())];
+        match self {
+            Command::Ping
+            | Command::PubSubNumPat
+            | Command::Multi
+            | Command::Exec
+            | Command::Discard
+            | Command::Unwatch
+            | Command::FlushDb
+            | Command::FlushAll
+            | Command::Save
+            | Command::BgSave
+            | Command::BgRewriteAof
+            | Command::LastSave => {}
+            Command::Echo(message) => args.push(message.clone()),
+            Command::ReplConf(arg) => args.push(word(arg)),
+            Command::ReplConfAck => args.push(word("GETACK")),
+            Command::Get(key) | Command::Keys(key) | Command::Dump(key) => args.push(key.clone()),
+            Command::Info(section) => {
+                if !section.is_empty() {
+                    args.push(word(section));
+                }
+            }
+            Command::Set(key, value, expiry) => {
+                args.extend([key.clone(), value.clone()]);
+                // Propagated as an absolute time, so replicas and the AOF agree on it.
+                if let Some(expiry) = expiry {
+                    args.extend([word("PXAT"), word(unix_millis(*expiry))]);
+                }
+            }
+            Command::GetConfig(name) => args.extend([word("GET"), word(name)]),
+            Command::SetConfig(pairs) => {
+                args.push(word("SET"));
+                for (name, value) in pairs {
+                    args.extend([word(name), word(value)]);
+                }
+            }
+            Command::SetBit(key, offset, bit) => {
+                args.extend([key.clone(), word(offset), word(bit)]);
+            }
+            Command::GetBit(key, offset) => args.extend([key.clone(), word(offset)]),
+            Command::BitCount(key, range) => {
+                args.push(key.clone());
+                if let Some((start, end, unit)) = range {
+                    args.extend([word(start), word(end), word(unit)]);
+                }
+            }
+            Command::BitPos(key, bit, start, end, unit) => {
+                args.extend([key.clone(), word(bit)]);
+                args.extend(start.iter().map(word));
+                if let Some(end) = end {
+                    args.extend([word(end), word(unit)]);
+                }
+            }
+            Command::BitOp(operation, dest, keys) => {
+                args.extend([word(operation), dest.clone()]);
+                args.extend(keys.iter().cloned());
+            }
+            Command::BitField(key, ops) | Command::BitFieldRo(key, ops) => {
+                args.push(key.clone());
+                args.extend(ops.iter().flat_map(|op| op.to_args()).map(word));
+            }
+            Command::PfAdd(key, items)
+            | Command::PfMerge(key, items)
+            | Command::GeoPos(key, items)
+            | Command::GeoHash(key, items) => {
+                args.push(key.clone());
+                args.extend(items.iter().cloned());
+            }
+            Command::PfCount(items)
+            | Command::Watch(items)
+            | Command::Del(items)
+            | Command::Subscribe(items)
+            | Command::Unsubscribe(items)
+            | Command::PSubscribe(items)
+            | Command::PUnsubscribe(items)
+            | Command::SSubscribe(items)
+            | Command::SUnsubscribe(items) => args.extend(items.iter().cloned()),
+            Command::Psync(items) => args.extend(items.iter().map(word)),
+            Command::GeoAdd(key, options, items) => {
+                args.push(key.clone());
+                for (enabled, flag) in [(options.nx, "NX"), (options.xx, "XX"), (options.ch, "CH")]
+                {
+                    if enabled {
+                        args.push(word(flag));
+                    }
+                }
+                for (longitude, latitude, member) in items {
+                    args.extend([word(longitude), word(latitude), member.clone()]);
+                }
+            }
+            Command::GeoDist(key, member1, member2, unit) => {
+                args.extend([key.clone(), member1.clone(), member2.clone(), word(unit)]);
+            }
+            Command::GeoSearch(key, search) => {
+                args.push(key.clone());
+                args.extend(search.to_args());
+            }
+            Command::GeoSearchStore(dest, src, search) => {
+                args.extend([dest.clone(), src.clone()]);
+                args.extend(search.to_args());
+            }
+            Command::Select(index) => args.push(word(index)),
+            Command::Publish(channel, message) | Command::SPublish(channel, message) => {
+                args.extend([channel.clone(), message.clone()]);
+            }
+            Command::PubSubChannels(kind, pattern) => {
+                args.push(match kind {
+                    SubscriptionKind::ShardChannel => word("SHARDCHANNELS"),
+                    _ => word("CHANNELS"),
+                });
+                args.extend(pattern.iter().cloned());
+            }
+            Command::PubSubNumSub(kind, channels) => {
+                args.push(match kind {
+                    SubscriptionKind::ShardChannel => word("SHARDNUMSUB"),
+                    _ => word("NUMSUB"),
+                });
+                args.extend(channels.iter().cloned());
+            }
+            Command::Hello(protocol) => args.extend(protocol.iter().map(word)),
+            Command::ClientId => args.push(word("ID")),
+            Command::ClientTracking(on, options) => {
+                args.extend([word("TRACKING"), word(if *on { "ON" } else { "OFF" })]);
+                args.extend(options.to_args());
+            }
+            Command::ClientCaching(yes) => {
+                args.extend([word("CACHING"), word(if *yes { "YES" } else { "NO" })]);
+            }
+            Command::ClientGetRedir => args.push(word("GETREDIR")),
+            Command::Restore(key, options) => return options.to_args(key),
+            Command::Migrate(options) => {
+                args.extend([
+                    word(&options.host),
+                    word(options.port),
+                    Vec::new(),
+                    word(options.db),
+                    word(options.timeout.as_millis()),
+                ]);
+                for (enabled, flag) in [(options.copy, "COPY"), (options.replace, "REPLACE")] {
+                    if enabled {
+                        args.push(word(flag));
+                    }
+                }
+                args.push(word("KEYS"));
+                args.extend(options.keys.iter().cloned());
+            }
+            Command::Shutdown(save) => match save {
+                Some(true) => args.push(word("SAVE")),
+                Some(false) => args.push(word("NOSAVE")),
+                None => {}
+            },
+        }
+        args
+    }
+
+    /// Keys read by read-only commands, remembered for client-side caching.
+    pub fn read_keys(&self) -> Vec<&Vec<u8>> {
+        match self {
+            Command::Get(key)
+            | Command::GetBit(key, _)
+            | Command::BitCount(key, _)
+            | Command::BitPos(key, ..)
+            | Command::BitFieldRo(key, _)
+            | Command::GeoPos(key, _)
+            | Command::GeoDist(key, ..)
+            | Command::GeoHash(key, _)
+            | Command::GeoSearch(key, _) => vec![key],
+            Command::PfCount(keys) => keys.iter().collect(),
+            _ => Vec::new(),
+        }
+    }
+
+    /// Whether the command may be propagated to replicas, and to the AOF if `is_logged`.
+    pub fn is_write(&self) -> bool {
+        matches!(
+            self,
+            Command::Set(..)
+                | Command::SetBit(..)
+                | Command::BitOp(..)
+                | Command::BitField(..)
+                | Command::PfAdd(..)
+                | Command::PfMerge(..)
+                | Command::GeoAdd(..)
+                | Command::GeoSearchStore(..)
+                | Command::Del(_)
+                | Command::Restore(..)
+                | Command::Migrate(_)
+                | Command::FlushDb
+                | Command::FlushAll
+                | Command::Publish(..)
+                | Command::SPublish(..)
+        )
+    }
+
+    /// Whether a propagated command is also appended to the AOF. Messages published
+    /// reach replicas, whose subscribers may want them, but replaying them on restart
+    /// would deliver them again.
+    pub fn is_logged(&self) -> bool {
+        !matches!(self, Command::Publish(..) | Command::SPublish(..))
+    }
+
+    /// Whether the command may snapshot the dataset, so it has to run with no other
+    /// command in progress.
+    pub fn snapshots_dataset(&self) -> bool {
+        matches!(
+            self,
+            Command::Save | Command::BgSave | Command::Shutdown(_) | Command::FlushAll
+        )
+    }
+
+    /// Whether a RESP2 connection in subscriber mode may run this command.
+    pub fn is_allowed_while_subscribed(&self) -> bool {
+        matches!(
+            self,
+            Command::Subscribe(_)
+                | Command::Unsubscribe(_)
+                | Command::PSubscribe(_)
+                | Command::PUnsubscribe(_)
+                | Command::SSubscribe(_)
+                | Command::SUnsubscribe(_)
+                | Command::Ping
+        )
+    }
+}
+
+impl RestoreOptions {
+    /// The RESTORE command recreating the key, with its TTL as an absolute time so
+    /// replaying it later doesn't extend it.
+    fn to_args(&self, key: &[u8]) -> Vec<Vec<u8>> {
+        let ttl = self.expiry.map_or(0, unix_millis);
+        let mut args = vec![
+            b"RESTORE".to_vec(),
+            key.to_vec(),
+            ttl.to_string().into_bytes(),
+            self.payload.clone(),
+        ];
+        if self.replace {
+            args.push(b"REPLACE".to_vec());
+        }
+        if self.expiry.is_some() {
+            args.push(b"ABSTTL".to_vec());
+        }
+        match self.access {
+            Some(AccessHint::LastAccess(last_access)) => {
+                let idle = SystemTime::now()
+                    .duration_since(last_access)
+                    .unwrap_or_default();
+                args.push(b"IDLETIME".to_vec());
+                args.push(idle.as_secs().to_string().into_bytes());
+            }
+            Some(AccessHint::Frequency(freq)) => {
+                args.push(b"FREQ".to_vec());
+                args.push(freq.to_string().into_bytes());
+            }
+            None => {}
+        }
+        args
+    }
+}
+
+fn unix_millis(time: SystemTime) -> u128 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis()
+}
+
+static CRLF: &str = "\r\n";
+/// Redis version this server reports to clients and records in RDB files.
+pub const SERVER_VERSION: &str = "7.2.0";
+static CONFIG: Lazy<Arc<RwLock<Config>>> = Lazy::new(|| Arc::new(RwLock::new(Config::new())));
+
+#[derive(Error, Debug)]
+pub enum ResponseErrors {
+    #[error("Received too many bytes before reaching end of message")]
+    MessageTooBig,
+
+    #[error("Unhandled data type: {0}")]
+    UnhandledRespDataType(char),
+
+    #[error("Array number of elements specifier is not a valid integer: '{0}'")]
+    ArrayNumElementsInvalidLength(String),
+
+    #[error("BulkString length specifier is not a valid integer: '{0}'")]
+    BulkStringInvalidLength(String),
+}
+
+async fn load_db() -> Result<(), anyhow::Error> {
+    let (path, verify_checksum, policy) = {
+        let config = CONFIG.read().await;
+        (
+            config.rdb_path(),
+            config.rdb_checksum,
+            config.rdb_corrupt_policy,
+        )
+    };
+    store::db_load(path, verify_checksum, policy).await?;
+    Ok(())
+}
+
+/// Loads the dataset from the AOF when appendonly is on, falling back to the RDB file
+/// if there is no AOF yet, then starts logging writes.
+async fn load_data() -> Result<(), anyhow::Error> {
+    if !CONFIG.read().await.appendonly {
+        return load_db().await;
+    }
+    if !aof::load().await? {
+        load_db().await?;
+    }
+    aof::open().await
+}
+
+async fn handle_arguments() -> Result<(), anyhow::Error> {
+    let args: Vec<String> = args().collect();
+    let mut iter = args.iter();
+    let mut config = CONFIG.write().await;
+    while let Some(arg) = iter.next() {
+        match arg.to_lowercase().as_str() {
+            "--dir" => {
+                config.dir = iter.next().map(|s| s.to_owned());
+            }
+            "--dbfilename" => {
+                config.dbfilename = iter.next().map(|s| s.to_owned());
+            }
+            "--port" => {
+                config.port = iter
+                    .next()
+                    .map(|s| s.parse::<u16>().unwrap_or(6379))
+                    .unwrap_or(6379);
+            }
+            "--notify-keyspace-events" => {
+                if let Some(flags) = iter.next() {
+                    match notify::parse_flags(flags) {
+                        Ok(flags) => config.notify_keyspace_events = flags,
+                        Err(e) => println!("Ignoring notify-keyspace-events {:?}: {}", flags, e),
+                    }
+                }
+            }
+            "--save" => {
+                if let Some(params) = iter.next() {
+                    match persistence::parse_save_params(params) {
+                        Ok(params) => config.save_params = params,
+                        Err(e) => println!("Ignoring save {:?}: {}", params, e),
+                    }
+                }
+            }
+            "--rdbcompression" | "--rdbchecksum" => {
+                match iter.next().and_then(|value| parse_yes_no(value)) {
+                    Some(enabled) if arg == "--rdbcompression" => config.rdb_compression = enabled,
+                    Some(enabled) => config.rdb_checksum = enabled,
+                    None => println!("Ignoring {}: expected yes or no", arg),
+                }
+            }
+            "--appendonly" | "--aof-load-truncated" | "--aof-use-rdb-preamble" => {
+                match iter.next().and_then(|value| parse_yes_no(value)) {
+                    Some(enabled) if arg == "--appendonly" => config.appendonly = enabled,
+                    Some(enabled) if arg == "--aof-load-truncated" => {
+                        config.aof_load_truncated = enabled
+                    }
+                    Some(enabled) => config.aof_use_rdb_preamble = enabled,
+                    None => println!("Ignoring {}: expected yes or no", arg),
+                }
+            }
+            "--appendfilename" | "--appenddirname" => match iter.next() {
+                Some(name) if name.contains('/') => {
+                    println!("Ignoring {}: {} must be a plain name", arg, name)
+                }
+                Some(name) if arg == "--appendfilename" => config.appendfilename = name.to_owned(),
+                Some(name) => config.appenddirname = name.to_owned(),
+                None => {}
+            },
+            "--auto-aof-rewrite-percentage" => {
+                match iter.next().and_then(|value| value.parse::<u64>().ok()) {
+                    Some(percentage) => config.auto_aof_rewrite_percentage = percentage,
+                    None => println!("Ignoring {}: expected a percentage", arg),
+                }
+            }
+            "--auto-aof-rewrite-min-size" => {
+                match iter.next().and_then(|value| parse_memory(value)) {
+                    Some(size) => config.auto_aof_rewrite_min_size = size,
+                    None => println!("Ignoring {}: expected a size such as 64mb", arg),
+                }
+            }
+            "--appendfsync" => match iter.next().and_then(|value| AppendFsync::parse(value)) {
+                Some(fsync) => config.appendfsync = fsync,
+                None => println!("Ignoring {}: expected always, everysec or no", arg),
+            },
+            "--rdb-corrupt-policy" => {
+                match iter.next().and_then(|value| CorruptRdbPolicy::parse(value)) {
+                    Some(policy) => config.rdb_corrupt_policy = policy,
+                    None => println!("Ignoring {}: expected refuse, empty or partial", arg),
+                }
+            }
+            "--replicaof" => {
+                let masterhost = iter.next().map(|s| s.to_owned());
+                let masterport = iter.next().map(|s| s.parse::<u16>());
+                if let (Some(masterhost), Some(Ok(masterport))) = (masterhost, masterport) {
+                    config.masterhost = Some(masterhost);
+                    config.masterport = Some(masterport);
+                    config.mode = store::ServerMode::Replica;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    Ok(())
+}
+
+/// Sends one command to the master during the handshake.
+async fn send_to_master(stream: &mut TcpStream, args: &[&str]) -> anyhow::Result<()> {
+    let mut buf = Vec::with_capacity(256).writer();
+    get_array(
+        &mut buf,
+        args.iter().map(|arg| arg.as_bytes().to_vec()).collect(),
+    )?;
+    stream.write_all(buf.get_ref()).await?;
+    stream.flush().await?;
+    Ok(())
+}
+
+/// Takes exactly `len` bytes of what the master sent, reading more as needed; bytes
+/// past them stay in `pending`.
+async fn read_from_master(
+    stream: &mut TcpStream,
+    pending: &mut Vec<u8>,
+    len: usize,
+) -> anyhow::Result<Vec<u8>> {
+    let mut buff = [0; 4096];
+    while pending.len() < len {
+        let bytes_read = stream.read(&mut buff).await?;
+        if bytes_read == 0 {
+            return Err(anyhow::anyhow!("Master closed the connection"));
+        }
+        pending.extend_from_slice(&buff[..bytes_read]);
+    }
+    Ok(pending.drain(..len).collect())
+}
+
+/// Takes one line sent by the master, without its CRLF.
+async fn read_master_line(stream: &mut TcpStream, pending: &mut Vec<u8>) -> anyhow::Result<String> {
+    let mut buff = [0; 4096];
+    loop {
+        if let Some(end) = pending.windows(2).position(|window| window == b"\r\n") {
+            let line = String::from_utf8_lossy(&pending[..end]).into_owned();
+            pending.drain(..end + 2);
+            return Ok(line);
+        }
+        let bytes_read = stream.read(&mut buff).await?;
+        if bytes_read == 0 {
+            return Err(anyhow::anyhow!("Master closed the connection"));
+        }
+        pending.extend_from_slice(&buff[..bytes_read]);
+    }
+}
+
+/// Reads the master's reply to a handshake command, as a simple or bulk string.
+async fn read_master_reply(
+    stream: &mut TcpStream,
+    pending: &mut Vec<u8>,
+) -> anyhow::Result<String> {
+    let line = read_master_line(stream, pending).await?;
+    if let Some(message) = line.strip_prefix('-') {
+        return Err(anyhow::anyhow!("Master replied with error: {}", message));
+    }
+    match line.strip_prefix('$').map(str::parse::<usize>) {
+        Some(Ok(len)) => {
+            let reply = read_from_master(stream, pending, len + 2).await?;
+            Ok(String::from_utf8_lossy(&reply[..len]).into_owned())
+        }
+        Some(Err(_)) => Err(anyhow::anyhow!("Invalid reply from master: {}", line)),
+        None => Ok(line.get(1..).unwrap_or_default().to_owned()),
+    }
+}
+
+/// Reads the `$<len>` RDB payload of a full resynchronization and replaces the dataset
+/// with it.
+async fn load_master_rdb(stream: &mut TcpStream, pending: &mut Vec<u8>) -> anyhow::Result<()> {
+    let header = read_master_line(stream, pending).await?;
+    let len = header
+        .strip_prefix('$')
+        .and_then(|len| len.parse::<usize>().ok())
+        .ok_or_else(|| anyhow::anyhow!("Invalid RDB header from master: {}", header))?;
+    let payload = read_from_master(stream, pending, len).await?;
+    let verify_checksum = CONFIG.read().await.rdb_checksum;
+    let data = rdb::RdbReader::read_from(payload.as_slice(), verify_checksum).await?;
+    store::db_replace(data).await;
+    println!("Loaded {} bytes of RDB from master", len);
+    Ok(())
+}
+
+pub async fn handshake(addr: String, port: u16) -> anyhow::Result<()> {
+    let mut stream = TcpStream::connect(addr).await?;
+    let mut pending = Vec::new();
+    println!("Connected to Master");
+    send_to_master(&mut stream, &["PING"]).await?;
+    read_master_reply(&mut stream, &mut pending).await?;
+    let port = port.to_string();
+    send_to_master(&mut stream, &["REPLCONF", "listening-port", &port]).await?;
+    read_master_reply(&mut stream, &mut pending).await?;
+    send_to_master(&mut stream, &["REPLCONF", "capa", "psync2"]).await?;
+    read_master_reply(&mut stream, &mut pending).await?;
+    send_to_master(&mut stream, &["PSYNC", "?", "-1"]).await?;
+
+    let reply = read_master_reply(&mut stream, &mut pending).await?;
+    let mut parts = reply.split(' ');
+    let (Some("FULLRESYNC"), Some(replid), Some(Ok(offset))) = (
+        parts.next(),
+        parts.next(),
+        parts.next().map(str::parse::<u64>),
+    ) else {
+        return Err(anyhow::anyhow!("Unexpected reply to PSYNC: {}", reply));
+    };
+    load_master_rdb(&mut stream, &mut pending).await?;
+    {
+        let mut config = CONFIG.write().await;
+        config.master_replid = replid.to_owned();
+        config.master_repl_offset = offset;
+    }
+    // The AOF still describes the dataset that was just flushed. Its new base is written
+    // before the replication stream is applied, so a restart never replays the master's
+    // writes on top of the old dataset.
+    if aof::enabled() {
+        if let Err(e) = aof::rewrite_after_running().await {
+            println!("Cannot rewrite the AOF after syncing with master: {}", e);
+        }
+    }
+
+    // Whatever followed the payload in the same reads is the start of the command stream.
+    tokio::spawn(async move {
+        let _ = handle_client(stream, false, pending).await;
+    });
+    Ok(())
+}
+
+async fn replica_connect_to_master() -> anyhow::Result<()> {
+    let (masterhost, masterport, port) = {
+        let config = CONFIG.read().await;
+        (config.masterhost.clone(), config.masterport, config.port)
+    };
+    if let (Some(masterhost), Some(masterport)) = (masterhost, masterport) {
+        let replica = Replica::new(0, masterhost, masterport);
+        let addr = format!("{}:{}", replica.address, replica.port);
+        if let Err(e) = handshake(addr, port).await {
+            println!("Replication handshake with master failed: {}", e);
+        }
+    }
+    Ok(())
+}
+
+/// Writes out `first` and the rest of the pushes queued for `client`, or drops them if
+/// the connection gets no replies. Fails once the client left too many pushes unread.
+async fn write_pushes(
+    client: &mut Client,
+    stream: &ClientStream,
+    respond: bool,
+    first: Option<Vec<u8>>,
+) -> anyhow::Result<()> {
+    let frames = client.take_pushes(first);
+    if respond && !frames.is_empty() {
+        let mut write_lock = stream.lock().await;
+        for frame in frames {
+            write_lock.write_all(&frame).await?;
+        }
+        write_lock.flush().await?;
+    }
+    if client.handle.overflowed() {
+        return Err(anyhow::anyhow!(
+            "Client {} closed for overcoming of output buffer limits",
+            client.id
+        ));
+    }
+    Ok(())
+}
+
+/// Serves one connection; `pending` holds bytes already read from it.
+async fn handle_client(
+    stream: TcpStream,
+    respond: bool,
+    mut pending: Vec<u8>,
+) -> anyhow::Result<()> {
+    let (mut read, write) = stream.into_split();
+    let write_guarded: ClientStream = Arc::new(Mutex::new(Box::new(write)));
+    let mut client = Client::new(Arc::clone(&write_guarded));
+    loop {
+        let commands_vectors = process_buff(&mut pending)?;
+        for cmd_vec in commands_vectors {
+            // Pushes queued before a command go out ahead of its reply, and those queued
+            // while it runs after it, as Redis orders them.
+            write_pushes(&mut client, &write_guarded, respond, None).await?;
+            let command = match parse_command(cmd_vec) {
+                Ok(command) => command,
+                Err(e) => {
+                    if let Some(transaction) = client.transaction.as_mut() {
+                        transaction.aborted = true;
+                    }
+                    if respond {
+                        let mut write_lock = write_guarded.lock().await;
+                        write_lock
+                            .write_all(&build_resp_error(&e.to_string()))
+                            .await?;
+                        write_lock.flush().await?;
+                    }
+                    continue;
+                }
+            };
+            if client.is_subscribed() && !client.resp3() && !command.is_allowed_while_subscribed() {
+                if respond {
+                    let name = command.name().to_lowercase();
+                    let mut write_lock = write_guarded.lock().await;
+                    write_lock
+                        .write_all(&build_resp_error(&format!(
+                            "Can't execute '{}': only (P|S)SUBSCRIBE / (P|S)UNSUBSCRIBE / PING / QUIT / RESET are allowed in this context",
+                            name
+                        )))
+                        .await?;
+                    write_lock.flush().await?;
+                }
+                continue;
+            }
+            let responses = {
+                let write_clone = Arc::clone(&write_guarded);
+                dispatch_command(write_clone, &mut client, command).await
+            };
+
+            if !respond {
+                continue;
+            }
+
+            for response in responses {
+                let write_clone = Arc::clone(&write_guarded);
+                let mut write_lock = write_clone.lock().await;
+                write_lock.write_all(&response).await?;
+                write_lock.flush().await?;
+            }
+        }
+
+        let mut buff = [0; 512];
+        // Waits for the next request, writing out pushes as they are queued meanwhile.
+        let push = tokio::select! {
+            bytes_read = read.read(&mut buff) => match bytes_read? {
+                0 => {
+                    println!("Connection closed");
+                    return Ok(());
+                }
+                bytes_read => {
+                    pending.extend_from_slice(&buff[..bytes_read]);
+                    None
+                }
+            },
+            Some(frame) = client.outbox.recv() => Some(frame),
+        };
+        if push.is_some() {
+            write_pushes(&mut client, &write_guarded, respond, push).await?;
+        }
+    }
+}
+
+#[cfg(unix)]
+async fn wait_for_shutdown_signal() {
+    use tokio::signal::unix::{signal, SignalKind};
+    let mut sigterm = signal(SignalKind::terminate()).expect("failed to install SIGTERM handler");
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => {}
+        _ = sigterm.recv() => {}
+    }
+}
+
+#[cfg(not(unix))]
+async fn wait_for_shutdown_signal() {
+    let _ = tokio::signal::ctrl_c().await;
+}
+
+/// Runs the server: parses the command line, loads the dataset and serves clients.
+pub async fn run() {
+    println!("Logs from your program will appear here!");
+    let _ = handle_arguments().await;
+    if let Err(e) = load_data().await {
+        println!("Fatal error loading the DB: {}. Exiting.", e);
+        std::process::exit(1);
+    }
+    let (port, mode) = {
+        let config = CONFIG.read().await;
+        (config.port, config.mode)
+    };
+    let addr = format!("127.0.0.1:{}", port);
+    let listener = TcpListener::bind(addr).await.unwrap();
+    tokio::spawn(async {
+        let mut interval = tokio::time::interval(Duration::from_millis(100));
+        loop {
+            interval.tick().await;
+            expire_keys().await;
+            persistence::save_if_needed().await;
+            aof::fsync_if_needed().await;
+            aof::rewrite_if_needed().await;
+        }
+    });
+    tokio::spawn(async {
+        loop {
+            wait_for_shutdown_signal().await;
+            println!("Received shutdown signal, scheduling shutdown...");
+            let _exclusive = EXEC_LOCK.write().await;
+            if let Err(e) = persistence::shutdown(None).await {
+                println!("{}", e);
+            }
+        }
+    });
+    if mode == store::ServerMode::Replica {
+        tokio::spawn(async move {
+            let _ = replica_connect_to_master().await;
+        });
+    }
+    loop {
+        let (stream, socket_addr) = listener.accept().await.unwrap();
+        println!("Accepted new connection from {}", socket_addr);
+        tokio::spawn(async move {
+            let _ = handle_client(stream, true, Vec::new()).await;
+        });
+    }
+}
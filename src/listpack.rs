@@ -0,0 +1,287 @@
+// Listpacks, the compact encoding of small lists, sets, hashes, sorted sets and stream
+// nodes in RDB files, following Redis' `listpack.c`.
+
+const LP_HEADER_SIZE: usize = 6;
+const LP_EOF: u8 = 0xFF;
+
+/// An element of a listpack or ziplist, which stores integers apart from strings.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ListpackEntry {
+    String(Vec<u8>),
+    Integer(i64),
+}
+
+impl ListpackEntry {
+    pub fn into_bytes(self) -> Vec<u8> {
+        match self {
+            ListpackEntry::String(bytes) => bytes,
+            ListpackEntry::Integer(n) => n.to_string().into_bytes(),
+        }
+    }
+
+    /// The entry as an integer, parsing strings the way `lpGetInteger` does.
+    pub fn as_integer(&self) -> Option<i64> {
+        match self {
+            ListpackEntry::String(bytes) => std::str::from_utf8(bytes).ok()?.parse().ok(),
+            ListpackEntry::Integer(n) => Some(*n),
+        }
+    }
+
+    /// The entry as a sorted set score.
+    pub fn as_float(&self) -> Option<f64> {
+        match self {
+            ListpackEntry::String(bytes) => std::str::from_utf8(bytes).ok()?.parse().ok(),
+            ListpackEntry::Integer(n) => Some(*n as f64),
+        }
+    }
+}
+
+/// Number of bytes the back-length of an entry of `len` bytes takes.
+fn backlen_size(len: usize) -> usize {
+    match len {
+        0..=127 => 1,
+        128..=16382 => 2,
+        16383..=2097150 => 3,
+        2097151..=268435454 => 4,
+        _ => 5,
+    }
+}
+
+/// Sign-extends the low `bits` bits of `value`.
+fn sign_extend(value: u64, bits: u32) -> i64 {
+    let shift = 64 - bits;
+    ((value << shift) as i64) >> shift
+}
+
+fn read_uint_le(bytes: &[u8]) -> u64 {
+    bytes
+        .iter()
+        .rev()
+        .fold(0, |value, byte| (value << 8) | *byte as u64)
+}
+
+/// Decodes every entry of a serialized listpack, or `None` if it is malformed.
+pub fn decode(bytes: &[u8]) -> Option<Vec<ListpackEntry>> {
+    if bytes.len() < LP_HEADER_SIZE + 1 {
+        return None;
+    }
+    let total = read_uint_le(&bytes[0..4]) as usize;
+    if total != bytes.len() || bytes[total - 1] != LP_EOF {
+        return None;
+    }
+
+    let mut entries = Vec::new();
+    let mut pos = LP_HEADER_SIZE;
+    while bytes[pos] != LP_EOF {
+        let encoding = bytes[pos];
+        let (entry, len) = match encoding {
+            0x00..=0x7F => (ListpackEntry::Integer(encoding as i64), 1),
+            0x80..=0xBF => {
+                let str_len = (encoding & 0x3F) as usize;
+                let s = bytes.get(pos + 1..pos + 1 + str_len)?;
+                (ListpackEntry::String(s.to_vec()), 1 + str_len)
+            }
+            0xC0..=0xDF => {
+                let value = (((encoding & 0x1F) as u64) << 8) | *bytes.get(pos + 1)? as u64;
+                (ListpackEntry::Integer(sign_extend(value, 13)), 2)
+            }
+            0xE0..=0xEF => {
+                let str_len = (((encoding & 0x0F) as usize) << 8) | *bytes.get(pos + 1)? as usize;
+                let s = bytes.get(pos + 2..pos + 2 + str_len)?;
+                (ListpackEntry::String(s.to_vec()), 2 + str_len)
+            }
+            0xF0 => {
+                let str_len = read_uint_le(bytes.get(pos + 1..pos + 5)?) as usize;
+                let s = bytes.get(pos + 5..pos + 5 + str_len)?;
+                (ListpackEntry::String(s.to_vec()), 5 + str_len)
+            }
+            0xF1..=0xF4 => {
+                let width = match encoding {
+                    0xF1 => 2,
+                    0xF2 => 3,
+                    0xF3 => 4,
+                    _ => 8,
+                };
+                let value = read_uint_le(bytes.get(pos + 1..pos + 1 + width)?);
+                let value = sign_extend(value, width as u32 * 8);
+                (ListpackEntry::Integer(value), 1 + width)
+            }
+            _ => return None,
+        };
+        entries.push(entry);
+        pos += len + backlen_size(len);
+        if pos >= bytes.len() {
+            return None;
+        }
+    }
+    Some(entries)
+}
+
+/// Builds listpacks, picking the smallest encoding for every entry.
+pub struct ListpackWriter {
+    buff: Vec<u8>,
+    count: usize,
+}
+
+impl ListpackWriter {
+    pub fn new() -> Self {
+        Self {
+            buff: vec![0; LP_HEADER_SIZE],
+            count: 0,
+        }
+    }
+
+    /// Appends `bytes`, stored as an integer if it is the canonical form of one.
+    pub fn push(&mut self, bytes: &[u8]) {
+        let integer = std::str::from_utf8(bytes)
+            .ok()
+            .and_then(|s| s.parse::<i64>().ok())
+            .filter(|n| n.to_string().as_bytes() == bytes);
+        match integer {
+            Some(n) => self.push_integer(n),
+            None => self.push_string(bytes),
+        }
+    }
+
+    pub fn push_integer(&mut self, n: i64) {
+        let mut entry = Vec::with_capacity(9);
+        if (0..=127).contains(&n) {
+            entry.push(n as u8);
+        } else if (-4096..=4095).contains(&n) {
+            let n = n as u64 & 0x1FFF;
+            entry.push(0xC0 | (n >> 8) as u8);
+            entry.push(n as u8);
+        } else {
+            let (encoding, width) = if (-32768..=32767).contains(&n) {
+                (0xF1, 2)
+            } else if (-8388608..=8388607).contains(&n) {
+                (0xF2, 3)
+            } else if (i32::MIN as i64..=i32::MAX as i64).contains(&n) {
+                (0xF3, 4)
+            } else {
+                (0xF4, 8)
+            };
+            entry.push(encoding);
+            entry.extend_from_slice(&n.to_le_bytes()[..width]);
+        }
+        self.push_entry(entry);
+    }
+
+    pub fn push_string(&mut self, bytes: &[u8]) {
+        let mut entry = Vec::with_capacity(bytes.len() + 5);
+        if bytes.len() < 64 {
+            entry.push(0x80 | bytes.len() as u8);
+        } else if bytes.len() < 4096 {
+            entry.push(0xE0 | (bytes.len() >> 8) as u8);
+            entry.push(bytes.len() as u8);
+        } else {
+            entry.push(0xF0);
+            entry.extend_from_slice(&(bytes.len() as u32).to_le_bytes());
+        }
+        entry.extend_from_slice(bytes);
+        self.push_entry(entry);
+    }
+
+    fn push_entry(&mut self, entry: Vec<u8>) {
+        let len = entry.len();
+        self.buff.extend_from_slice(&entry);
+        // The back-length stores `len` in 7 bit groups, most significant first, with the
+        // high bit set on all but the first byte so it can be read backwards.
+        let size = backlen_size(len);
+        for i in (0..size).rev() {
+            let group = ((len >> (7 * i)) & 0x7F) as u8;
+            self.buff
+                .push(if i == size - 1 { group } else { group | 0x80 });
+        }
+        self.count += 1;
+    }
+
+    pub fn finish(mut self) -> Vec<u8> {
+        self.buff.push(LP_EOF);
+        let total = self.buff.len() as u32;
+        self.buff[0..4].copy_from_slice(&total.to_le_bytes());
+        // Counts that don't fit are stored as 65535, meaning "unknown".
+        let count = self.count.min(u16::MAX as usize) as u16;
+        self.buff[4..6].copy_from_slice(&count.to_le_bytes());
+        self.buff
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::corrupt::assert_rejects_damage;
+
+    /// A listpack laid out byte by byte as Redis' `lpAppend` builds it: one entry of each
+    /// encoding, with one and two byte back-lengths.
+    fn fixture() -> (Vec<u8>, Vec<ListpackEntry>) {
+        let mut bytes = vec![0x44, 0x01, 0x00, 0x00, 0x0A, 0x00];
+        bytes.extend_from_slice(b"\x85hello\x06");
+        bytes.extend_from_slice(&[0x01, 0x01]);
+        // 13 bit integers.
+        bytes.extend_from_slice(&[0xDF, 0xFF, 0x02, 0xC4, 0x00, 0x02]);
+        // 16, 24, 32 and 64 bit integers.
+        bytes.extend_from_slice(&[0xF1, 0x78, 0xEC, 0x03]);
+        bytes.extend_from_slice(&[0xF2, 0x40, 0x9C, 0x00, 0x04]);
+        bytes.extend_from_slice(&[0xF3, 0x00, 0xE1, 0xF5, 0x05, 0x05]);
+        bytes.extend_from_slice(&[0xF4, 0x00, 0xF2, 0x05, 0x2A, 0x01, 0x00, 0x00, 0x00, 0x09]);
+        // 12 bit string lengths; the second entry needs a two byte back-length.
+        bytes.extend_from_slice(&[0xE0, 0x46]);
+        bytes.extend_from_slice(&[b'm'; 70]);
+        bytes.push(0x48);
+        bytes.extend_from_slice(&[0xE0, 0xC8]);
+        bytes.extend_from_slice(&[b'l'; 200]);
+        bytes.extend_from_slice(&[0x01, 0xCA]);
+        bytes.push(LP_EOF);
+
+        let entries = vec![
+            ListpackEntry::String(b"hello".to_vec()),
+            ListpackEntry::Integer(1),
+            ListpackEntry::Integer(-1),
+            ListpackEntry::Integer(1024),
+            ListpackEntry::Integer(-5000),
+            ListpackEntry::Integer(40000),
+            ListpackEntry::Integer(100_000_000),
+            ListpackEntry::Integer(5_000_000_000),
+            ListpackEntry::String(vec![b'm'; 70]),
+            ListpackEntry::String(vec![b'l'; 200]),
+        ];
+        (bytes, entries)
+    }
+
+    #[test]
+    fn decodes_redis_listpacks() {
+        let (bytes, entries) = fixture();
+        assert_eq!(decode(&bytes).unwrap(), entries);
+        // An empty listpack is just the header and the terminator.
+        let empty = [0x07, 0x00, 0x00, 0x00, 0x00, 0x00, LP_EOF];
+        assert_eq!(decode(&empty).unwrap(), vec![]);
+    }
+
+    #[test]
+    fn writes_like_redis() {
+        let (bytes, entries) = fixture();
+        let mut writer = ListpackWriter::new();
+        for entry in entries {
+            writer.push(&entry.into_bytes());
+        }
+        assert_eq!(writer.finish(), bytes);
+        // "007" isn't the canonical form of 7, so it stays a string.
+        let mut writer = ListpackWriter::new();
+        writer.push(b"007");
+        assert_eq!(
+            writer.finish(),
+            [0x0C, 0x00, 0x00, 0x00, 0x01, 0x00, 0x83, b'0', b'0', b'7', 0x04, LP_EOF]
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_input() {
+        let (bytes, _) = fixture();
+        assert_rejects_damage(&bytes, |bytes| decode(bytes).is_some());
+        // The header length no longer matches the contents.
+        let mut extended = bytes.clone();
+        extended.insert(LP_HEADER_SIZE, 0x01);
+        assert!(decode(&extended).is_none());
+    }
+}
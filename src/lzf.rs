@@ -0,0 +1,246 @@
+use thiserror::Error;
+
+// LZF as used for strings in RDB files, following liblzf's `lzf_c.c` and `lzf_d.c`.
+
+const HLOG: u32 = 16;
+const HSIZE: usize = 1 << HLOG;
+const MAX_LIT: usize = 1 << 5;
+const MAX_OFF: usize = 1 << 13;
+const MAX_REF: usize = (1 << 8) + (1 << 3);
+
+#[derive(Error, Debug)]
+pub enum LzfError {
+    #[error("Invalid LZF compressed data")]
+    Corrupt,
+}
+
+fn hash_index(bytes: &[u8]) -> usize {
+    let v = ((bytes[0] as u32) << 16) | ((bytes[1] as u32) << 8) | bytes[2] as u32;
+    ((v >> (24 - HLOG)).wrapping_sub(v.wrapping_mul(5)) as usize) & (HSIZE - 1)
+}
+
+/// Sets the control byte of the literal run ending the output, or drops it if the run
+/// is empty.
+fn close_literal_run(out: &mut Vec<u8>, lit: usize) {
+    if lit == 0 {
+        out.pop();
+    } else {
+        let control = out.len() - lit - 1;
+        out[control] = (lit - 1) as u8;
+    }
+}
+
+/// Compresses `input`, giving up with `None` if the result would exceed `max_len` bytes.
+/// The output is byte for byte what liblzf produces, including where it gives up.
+pub fn compress(input: &[u8], max_len: usize) -> Option<Vec<u8>> {
+    if input.is_empty() || max_len == 0 {
+        return None;
+    }
+    // liblzf's empty slots point at the start of the input, which is why a reference to
+    // the first byte is never taken.
+    let mut table = vec![0; HSIZE];
+    let mut out = Vec::with_capacity(max_len + 1);
+    let mut lit = 0;
+    let mut ip = 0;
+    // Placeholder for the control byte of the first literal run.
+    out.push(0);
+
+    while ip + 2 < input.len() {
+        let slot = hash_index(&input[ip..]);
+        let reference = table[slot];
+        table[slot] = ip;
+
+        if reference > 0
+            && ip - reference - 1 < MAX_OFF
+            && input[reference..reference + 3] == input[ip..ip + 3]
+        {
+            let off = ip - reference - 1;
+            // The reference and the control byte of the next run must fit.
+            if out.len() - usize::from(lit == 0) + 4 >= max_len {
+                return None;
+            }
+            let max_match = (input.len() - ip - 2).min(MAX_REF);
+            let mut len = 3;
+            while len < max_match && input[reference + len] == input[ip + len] {
+                len += 1;
+            }
+
+            close_literal_run(&mut out, lit);
+            let encoded = len - 2;
+            if encoded < 7 {
+                out.push(((off >> 8) + (encoded << 5)) as u8);
+            } else {
+                out.push(((off >> 8) + (7 << 5)) as u8);
+                out.push((encoded - 7) as u8);
+            }
+            out.push(off as u8);
+            lit = 0;
+            out.push(0);
+            ip += len;
+            if ip + 2 >= input.len() {
+                break;
+            }
+            // Like liblzf's VERY_FAST mode, hash the last two positions of the match.
+            for position in [ip - 2, ip - 1] {
+                table[hash_index(&input[position..])] = position;
+            }
+        } else {
+            if out.len() >= max_len {
+                return None;
+            }
+            lit += 1;
+            out.push(input[ip]);
+            ip += 1;
+            if lit == MAX_LIT {
+                close_literal_run(&mut out, lit);
+                lit = 0;
+                out.push(0);
+            }
+        }
+    }
+
+    if out.len() + 3 > max_len {
+        return None;
+    }
+    while ip < input.len() {
+        lit += 1;
+        out.push(input[ip]);
+        ip += 1;
+        if lit == MAX_LIT {
+            close_literal_run(&mut out, lit);
+            lit = 0;
+            out.push(0);
+        }
+    }
+    close_literal_run(&mut out, lit);
+    Some(out)
+}
+
+/// Decompresses `input`, which must expand to exactly `len` bytes.
+pub fn decompress(input: &[u8], len: usize) -> Result<Vec<u8>, LzfError> {
+    // `len` comes from the input, so don't trust it for more than LZF could expand to.
+    let mut out = Vec::with_capacity(len.min(input.len().saturating_mul(MAX_REF)));
+    let mut ip = 0;
+
+    while ip < input.len() {
+        let control = input[ip] as usize;
+        ip += 1;
+
+        if control < 1 << 5 {
+            let run = control + 1;
+            let literal = input.get(ip..ip + run).ok_or(LzfError::Corrupt)?;
+            out.extend_from_slice(literal);
+            ip += run;
+        } else {
+            let mut run = control >> 5;
+            if run == 7 {
+                run += *input.get(ip).ok_or(LzfError::Corrupt)? as usize;
+                ip += 1;
+            }
+            let low = *input.get(ip).ok_or(LzfError::Corrupt)? as usize;
+            ip += 1;
+            let back = ((control & 0x1f) << 8) + low + 1;
+            if back > out.len() {
+                return Err(LzfError::Corrupt);
+            }
+            // Byte by byte, since the reference may overlap what is being written.
+            let start = out.len() - back;
+            for i in 0..run + 2 {
+                out.push(out[start + i]);
+            }
+        }
+        if out.len() > len {
+            return Err(LzfError::Corrupt);
+        }
+    }
+
+    if out.len() != len {
+        return Err(LzfError::Corrupt);
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::corrupt::assert_rejects_damage;
+
+    /// Bytes of the C library's `rand()` sequence, which doesn't repeat for a while.
+    fn noise(len: usize) -> Vec<u8> {
+        let mut state = 1u32;
+        (0..len)
+            .map(|_| {
+                state = state.wrapping_mul(1103515245).wrapping_add(12345);
+                (state >> 16) as u8
+            })
+            .collect()
+    }
+
+    /// Inputs with what liblzf's `lzf_compress`, as built into Redis, makes of them when
+    /// given `len - 4` bytes of output, the way RDB strings are compressed.
+    fn fixtures() -> Vec<(Vec<u8>, Vec<u8>)> {
+        vec![
+            (
+                b"hello world, hello world, hello world".to_vec(),
+                b"\x0dhello world, h\xe0\x0c\x0c\x01ld".to_vec(),
+            ),
+            (
+                b"abcabcabcabcabcabcabcabc".to_vec(),
+                b"\x03abca\xe0\x09\x02\x01bc".to_vec(),
+            ),
+            // A run of one byte, copied from a reference overlapping the output.
+            (vec![b'a'; 100], b"\x01aa\xe0\x57\x00\x01aa".to_vec()),
+            // Matches longer than the 264 bytes a reference can copy.
+            (
+                vec![b'x'; 1000],
+                b"\x01xx\xe0\xff\x00\xe0\xff\x00\xe0\xff\x00\xe0\xc3\x00\x01xx".to_vec(),
+            ),
+            // Literal runs of the 32 byte maximum, and a reference 300 bytes back.
+            {
+                let input = [noise(300), noise(40)].concat();
+                let mut compressed = Vec::new();
+                for run in input[..301].chunks(32) {
+                    compressed.push(run.len() as u8 - 1);
+                    compressed.extend_from_slice(run);
+                }
+                compressed.extend_from_slice(&[0xe1, 0x1c, 0x2b, 0x01]);
+                compressed.extend_from_slice(&input[338..]);
+                (input, compressed)
+            },
+        ]
+    }
+
+    #[test]
+    fn decompresses_liblzf_output() {
+        for (input, compressed) in fixtures() {
+            assert_eq!(decompress(&compressed, input.len()).unwrap(), input);
+        }
+    }
+
+    #[test]
+    fn compresses_like_liblzf() {
+        for (input, compressed) in fixtures() {
+            assert_eq!(compress(&input, input.len() - 4).unwrap(), compressed);
+        }
+    }
+
+    #[test]
+    fn gives_up_when_output_exceeds_max_len() {
+        assert!(compress(b"abcdefgh", 4).is_none());
+    }
+
+    #[test]
+    fn rejects_malformed_input() {
+        // A back reference before the start of the output.
+        assert!(decompress(&[0x20, 0x00], 3).is_err());
+        // A literal run longer than the remaining input.
+        assert!(decompress(&[0x05, b'a'], 6).is_err());
+        // A long back reference missing its length byte.
+        assert!(decompress(&[0x00, b'a', 0xE0], 10).is_err());
+
+        let (input, compressed) = fixtures().remove(0);
+        assert!(decompress(&compressed, input.len() - 1).is_err());
+        assert!(decompress(&compressed, input.len() + 1).is_err());
+        assert_rejects_damage(&compressed, |bytes| decompress(bytes, input.len()).is_ok());
+    }
+}
@@ -0,0 +1,192 @@
+use crate::rdb::RdbWriter;
+use crate::store;
+use crate::utils::{build_resp_array_raw, build_resp_bulk};
+use crate::{MigrateOptions, CONFIG};
+use std::time::SystemTime;
+use thiserror::Error;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+use tokio::time::timeout;
+
+#[derive(Error, Debug)]
+pub enum MigrateError {
+    #[error("IOERR error or timeout {0} target instance")]
+    Io(&'static str),
+
+    #[error("Target instance replied with error: {0}")]
+    Target(String),
+}
+
+/// What MIGRATE did with the keys it was given.
+pub enum Migrated {
+    /// None of the keys exist, so nothing was sent.
+    NoKeys,
+    /// Keys were sent; `deleted` lists those removed here after the target stored them.
+    Done {
+        deleted: Vec<Vec<u8>>,
+        error: Option<MigrateError>,
+    },
+}
+
+/// A key read for transfer, with the version it had so a concurrent write isn't lost
+/// by deleting the key afterwards.
+struct Outgoing {
+    key: Vec<u8>,
+    payload: Vec<u8>,
+    ttl_ms: u64,
+    version: u64,
+}
+
+/// Copies `options.keys` from `db_id` to another instance with RESTORE, then deletes
+/// those the target stored unless COPY was given.
+pub async fn migrate(db_id: usize, options: &MigrateOptions) -> Migrated {
+    let compression = CONFIG.read().await.rdb_compression;
+    let mut outgoing = Vec::new();
+    for key in options.keys.iter() {
+        let version = store::watch_key(db_id, key);
+        let dumped = store::db_view(db_id, key, |entry| {
+            Ok(entry.map(|entry| {
+                let payload = RdbWriter::dump_value(&entry.value, compression);
+                (payload, entry.expiry)
+            }))
+        })
+        .await;
+        let Ok(Some((payload, expiry))) = dumped else {
+            store::unwatch_key(db_id, key);
+            continue;
+        };
+        // RESTORE takes 0 as "no expiry", so a key about to expire keeps 1ms.
+        let ttl_ms = expiry.map_or(0, |expiry| {
+            let left = expiry.duration_since(SystemTime::now()).unwrap_or_default();
+            (left.as_millis() as u64).max(1)
+        });
+        outgoing.push(Outgoing {
+            key: key.clone(),
+            payload,
+            ttl_ms,
+            version,
+        });
+    }
+    if outgoing.is_empty() {
+        return Migrated::NoKeys;
+    }
+
+    let result = transfer(options, &outgoing).await;
+    let mut deleted = Vec::new();
+    let mut error = None;
+    match result {
+        Ok(replies) => {
+            for (item, reply) in outgoing.iter().zip(replies) {
+                match reply {
+                    Ok(()) if !options.copy => {
+                        if delete_unchanged(db_id, item).await {
+                            deleted.push(item.key.clone());
+                        }
+                    }
+                    Ok(()) => {}
+                    Err(message) => {
+                        error.get_or_insert(MigrateError::Target(message));
+                    }
+                }
+            }
+        }
+        Err(e) => error = Some(e),
+    }
+    for item in outgoing.iter() {
+        store::unwatch_key(db_id, &item.key);
+    }
+    Migrated::Done { deleted, error }
+}
+
+/// Deletes a migrated key, unless it was written to since it was read.
+async fn delete_unchanged(db_id: usize, item: &Outgoing) -> bool {
+    store::db_update_if_changed(db_id, &item.key, None, |entry| {
+        if entry.is_none() || store::watched_key_version(db_id, &item.key) != item.version {
+            return Ok((false, false));
+        }
+        *entry = None;
+        Ok((true, true))
+    })
+    .await
+    .unwrap_or(false)
+}
+
+/// Sends SELECT and one RESTORE per key as a single pipeline, returning the target's
+/// reply to each RESTORE.
+async fn transfer(
+    options: &MigrateOptions,
+    outgoing: &[Outgoing],
+) -> Result<Vec<Result<(), String>>, MigrateError> {
+    let address = (options.host.as_str(), options.port);
+    let stream = timeout(options.timeout, TcpStream::connect(address))
+        .await
+        .ok()
+        .and_then(Result::ok)
+        .ok_or(MigrateError::Io("connecting to"))?;
+    let mut stream = BufReader::new(stream);
+
+    let mut commands = vec![vec![
+        b"SELECT".to_vec(),
+        options.db.to_string().into_bytes(),
+    ]];
+    let setup = commands.len();
+    for item in outgoing {
+        let mut restore = vec![
+            b"RESTORE".to_vec(),
+            item.key.clone(),
+            item.ttl_ms.to_string().into_bytes(),
+            item.payload.clone(),
+        ];
+        if options.replace {
+            restore.push(b"REPLACE".to_vec());
+        }
+        commands.push(restore);
+    }
+    let request = commands
+        .iter()
+        .flat_map(|args| {
+            build_resp_array_raw(args.iter().map(|arg| build_resp_bulk(arg)).collect())
+        })
+        .collect::<Vec<_>>();
+    let written = async {
+        stream.get_mut().write_all(&request).await?;
+        stream.get_mut().flush().await
+    };
+    timeout(options.timeout, written)
+        .await
+        .ok()
+        .and_then(Result::ok)
+        .ok_or(MigrateError::Io("writing to"))?;
+
+    let mut replies = Vec::with_capacity(outgoing.len());
+    for i in 0..commands.len() {
+        let reply = timeout(options.timeout, read_status_reply(&mut stream))
+            .await
+            .ok()
+            .and_then(Result::ok)
+            .ok_or(MigrateError::Io("reading from"))?;
+        if i < setup {
+            reply.map_err(MigrateError::Target)?;
+        } else {
+            replies.push(reply);
+        }
+    }
+    Ok(replies)
+}
+
+/// Reads a one-line reply: `Ok` for a status or integer, the message for an error.
+async fn read_status_reply(
+    stream: &mut BufReader<TcpStream>,
+) -> std::io::Result<Result<(), String>> {
+    let mut line = Vec::new();
+    if stream.read_until(b'\n', &mut line).await? == 0 {
+        return Err(std::io::ErrorKind::UnexpectedEof.into());
+    }
+    let line = String::from_utf8_lossy(&line);
+    let line = line.trim_end();
+    match line.strip_prefix('-') {
+        Some(message) => Ok(Err(message.to_owned())),
+        None if line.starts_with('+') || line.starts_with(':') => Ok(Ok(())),
+        None => Err(std::io::ErrorKind::InvalidData.into()),
+    }
+}
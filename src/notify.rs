@@ -0,0 +1,172 @@
+use crate::pubsub;
+use crate::CONFIG;
+use thiserror::Error;
+
+// Event classes, as the flag letters of `notify-keyspace-events` in redis.conf. Every
+// Redis class is accepted so configurations carry over, even though there are no list,
+// set, hash or stream commands, no eviction and no modules to raise some of them.
+pub const NOTIFY_KEYSPACE: u32 = 1 << 0; // K
+pub const NOTIFY_KEYEVENT: u32 = 1 << 1; // E
+pub const NOTIFY_GENERIC: u32 = 1 << 2; // g
+pub const NOTIFY_STRING: u32 = 1 << 3; // $
+pub const NOTIFY_LIST: u32 = 1 << 4; // l
+pub const NOTIFY_SET: u32 = 1 << 5; // s
+pub const NOTIFY_HASH: u32 = 1 << 6; // h
+pub const NOTIFY_ZSET: u32 = 1 << 7; // z
+pub const NOTIFY_EXPIRED: u32 = 1 << 8; // x
+pub const NOTIFY_EVICTED: u32 = 1 << 9; // e
+pub const NOTIFY_STREAM: u32 = 1 << 10; // t
+pub const NOTIFY_KEY_MISS: u32 = 1 << 11; // m
+pub const NOTIFY_MODULE: u32 = 1 << 12; // d
+pub const NOTIFY_NEW: u32 = 1 << 13; // n
+/// Everything `A` stands for; key misses and new keys must be asked for explicitly.
+pub const NOTIFY_ALL: u32 = NOTIFY_GENERIC
+    | NOTIFY_STRING
+    | NOTIFY_LIST
+    | NOTIFY_SET
+    | NOTIFY_HASH
+    | NOTIFY_ZSET
+    | NOTIFY_EXPIRED
+    | NOTIFY_EVICTED
+    | NOTIFY_STREAM
+    | NOTIFY_MODULE;
+
+const CLASS_FLAGS: [(char, u32); 10] = [
+    ('g', NOTIFY_GENERIC),
+    ('$', NOTIFY_STRING),
+    ('l', NOTIFY_LIST),
+    ('s', NOTIFY_SET),
+    ('h', NOTIFY_HASH),
+    ('z', NOTIFY_ZSET),
+    ('x', NOTIFY_EXPIRED),
+    ('e', NOTIFY_EVICTED),
+    ('t', NOTIFY_STREAM),
+    ('d', NOTIFY_MODULE),
+];
+
+/// An event the store raises on a key: the class that must be enabled for it, and the
+/// name published on the channels.
+#[derive(Debug, Clone, Copy)]
+pub struct Event {
+    pub class: u32,
+    pub name: &'static str,
+}
+
+impl Event {
+    const fn new(class: u32, name: &'static str) -> Self {
+        Event { class, name }
+    }
+}
+
+pub const SET: Event = Event::new(NOTIFY_STRING, "set");
+pub const SETBIT: Event = Event::new(NOTIFY_STRING, "setbit");
+pub const PFADD: Event = Event::new(NOTIFY_STRING, "pfadd");
+pub const ZADD: Event = Event::new(NOTIFY_ZSET, "zadd");
+pub const GEOSEARCHSTORE: Event = Event::new(NOTIFY_ZSET, "geosearchstore");
+pub const RESTORE: Event = Event::new(NOTIFY_GENERIC, "restore");
+pub const EXPIRE: Event = Event::new(NOTIFY_GENERIC, "expire");
+pub const DEL: Event = Event::new(NOTIFY_GENERIC, "del");
+pub const EXPIRED: Event = Event::new(NOTIFY_EXPIRED, "expired");
+pub const NEW: Event = Event::new(NOTIFY_NEW, "new");
+pub const KEYMISS: Event = Event::new(NOTIFY_KEY_MISS, "keymiss");
+
+#[derive(Error, Debug)]
+pub enum NotifyError {
+    #[error("Invalid event class character. Use 'Ag$lshzxeKEtmdn'.")]
+    InvalidClass,
+}
+
+/// Parses a `notify-keyspace-events` string such as `"Ex"` or `"KEA"`.
+pub fn parse_flags(flags: &str) -> Result<u32, NotifyError> {
+    flags.chars().try_fold(0, |flags, c| {
+        let flag = match c {
+            'A' => NOTIFY_ALL,
+            'K' => NOTIFY_KEYSPACE,
+            'E' => NOTIFY_KEYEVENT,
+            'm' => NOTIFY_KEY_MISS,
+            'n' => NOTIFY_NEW,
+            c => CLASS_FLAGS
+                .iter()
+                .find(|(letter, _)| *letter == c)
+                .map(|(_, flag)| *flag)
+                .ok_or(NotifyError::InvalidClass)?,
+        };
+        Ok(flags | flag)
+    })
+}
+
+/// Renders flags back to the canonical string reported by CONFIG GET.
+pub fn flags_to_string(flags: u32) -> String {
+    let mut s = String::new();
+    if flags & NOTIFY_ALL == NOTIFY_ALL {
+        s.push('A');
+    } else {
+        for (letter, flag) in CLASS_FLAGS {
+            if flags & flag != 0 {
+                s.push(letter);
+            }
+        }
+    }
+    for (letter, flag) in [
+        ('K', NOTIFY_KEYSPACE),
+        ('E', NOTIFY_KEYEVENT),
+        ('m', NOTIFY_KEY_MISS),
+        ('n', NOTIFY_NEW),
+    ] {
+        if flags & flag != 0 {
+            s.push(letter);
+        }
+    }
+    s
+}
+
+/// Publishes `event` on `key` to `__keyspace@<db>__:<key>` and `__keyevent@<db>__:<event>`,
+/// as enabled by `notify-keyspace-events`.
+pub async fn keyspace_event(event: Event, key: &[u8], db_id: usize) {
+    let flags = CONFIG.read().await.notify_keyspace_events;
+    if flags & event.class == 0 {
+        return;
+    }
+    if flags & NOTIFY_KEYSPACE != 0 {
+        let mut channel = format!("__keyspace@{}__:", db_id).into_bytes();
+        channel.extend_from_slice(key);
+        pubsub::publish(&channel, event.name.as_bytes());
+    }
+    if flags & NOTIFY_KEYEVENT != 0 {
+        let channel = format!("__keyevent@{}__:{}", db_id, event.name);
+        pubsub::publish(channel.as_bytes(), key);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_and_renders_flags() {
+        assert_eq!(parse_flags("").unwrap(), 0);
+        assert_eq!(parse_flags("Ex").unwrap(), NOTIFY_KEYEVENT | NOTIFY_EXPIRED);
+        assert_eq!(parse_flags("KEA").unwrap() & NOTIFY_KEY_MISS, 0);
+        assert!(parse_flags("KEQ").is_err());
+        // Classes of events that are never raised here are still accepted and kept.
+        for (never, flag) in [
+            ("l", NOTIFY_LIST),
+            ("s", NOTIFY_SET),
+            ("h", NOTIFY_HASH),
+            ("t", NOTIFY_STREAM),
+            ("e", NOTIFY_EVICTED),
+            ("d", NOTIFY_MODULE),
+        ] {
+            assert_eq!(parse_flags(never).unwrap(), flag);
+            assert_eq!(parse_flags("A").unwrap() & flag, flag);
+        }
+
+        // A, or every class spelled out, renders as A; K and E always come last.
+        assert_eq!(flags_to_string(parse_flags("EKA").unwrap()), "AKE");
+        assert_eq!(flags_to_string(parse_flags("g$lshzxetdK").unwrap()), "AK");
+        assert_eq!(flags_to_string(parse_flags("g$zxK").unwrap()), "g$zxK");
+        assert_eq!(flags_to_string(parse_flags("xKg").unwrap()), "gxK");
+        assert_eq!(flags_to_string(parse_flags("Eth").unwrap()), "htE");
+        assert_eq!(flags_to_string(parse_flags("nmE").unwrap()), "Emn");
+    }
+}
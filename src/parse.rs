@@ -1,26 +1,239 @@
+use anyhow::{Error, Result};
 use std::{
     str,
-    time::{Duration, SystemTime},
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
-static CRLF: &str = "\r\n";
-use anyhow::{Error, Result};
 
-use crate::Command;
+use crate::{
+    bitmap::{
+        parse_bit_offset, parse_bitfield_offset, parse_bitfield_type, BitFieldOp, BitOperation,
+        BitUnit, BitmapError, Overflow,
+    },
+    geo::{self, GeoOrigin, GeoSearch, GeoShape, GeoSort, GeoUnit},
+    pubsub::SubscriptionKind,
+    store::{self, AccessHint},
+    tracking::TrackingOptions,
+    Command, GeoAddOptions, MigrateOptions, ResponseErrors, RestoreOptions,
+};
 
-pub fn parse_command(cmd_vec: Vec<String>) -> Result<Command> {
-    match cmd_vec[0].to_uppercase().as_str() {
-        "PING" => Ok(Command::Ping),
-        "ECHO" => {
-            let arg = cmd_vec.get(1);
-            let mut args = String::new();
-            if let Some(arg) = arg {
-                args.push_str(arg);
+fn wrong_arguments(name: &str) -> Error {
+    Error::msg(format!(
+        "wrong number of arguments for '{}' command",
+        name.to_lowercase()
+    ))
+}
+
+fn parse_integer(arg: &str) -> Result<i64> {
+    arg.parse::<i64>()
+        .map_err(|_| Error::msg("value is not an integer or out of range"))
+}
+
+fn parse_float(arg: &str) -> Result<f64> {
+    arg.parse::<f64>()
+        .ok()
+        .filter(|value| !value.is_nan())
+        .ok_or_else(|| Error::msg("value is not a valid float"))
+}
+
+/// Parses the GEOSEARCH options in `args`; `raw` holds the same arguments as sent, for
+/// the FROMMEMBER member.
+fn parse_geo_search(
+    name: &str,
+    args: &[String],
+    raw: &[Vec<u8>],
+    store: bool,
+) -> Result<GeoSearch> {
+    let mut origin = None;
+    let mut shape = None;
+    let mut unit = GeoUnit::Meters;
+    let mut sort = None;
+    let mut count = None;
+    let (mut with_coord, mut with_dist, mut with_hash, mut store_dist) =
+        (false, false, false, false);
+    let syntax_error = || Error::msg("syntax error");
+
+    let mut iter = args.iter().zip(raw);
+    while let Some((arg, _)) = iter.next() {
+        match arg.to_uppercase().as_str() {
+            "FROMMEMBER" if origin.is_none() => {
+                let (_, member) = iter.next().ok_or_else(syntax_error)?;
+                origin = Some(GeoOrigin::Member(member.clone()));
+            }
+            "FROMLONLAT" if origin.is_none() => {
+                let (Some((lon, _)), Some((lat, _))) = (iter.next(), iter.next()) else {
+                    return Err(syntax_error());
+                };
+                let (lon, lat) = (parse_float(lon)?, parse_float(lat)?);
+                geo::validate(lon, lat)?;
+                origin = Some(GeoOrigin::LonLat(lon, lat));
+            }
+            "FROMMEMBER" | "FROMLONLAT" => {
+                return Err(Error::msg(format!(
+                    "exactly one of FROMMEMBER or FROMLONLAT can be specified for {}",
+                    name
+                )))
+            }
+            "BYRADIUS" if shape.is_none() => {
+                let (Some((radius, _)), Some((radius_unit, _))) = (iter.next(), iter.next()) else {
+                    return Err(syntax_error());
+                };
+                let radius = parse_float(radius)?;
+                if radius < 0.0 {
+                    return Err(Error::msg("radius cannot be negative"));
+                }
+                unit = GeoUnit::parse(radius_unit)?;
+                shape = Some(GeoShape::Radius(radius));
+            }
+            "BYBOX" if shape.is_none() => {
+                let (Some((width, _)), Some((height, _)), Some((box_unit, _))) =
+                    (iter.next(), iter.next(), iter.next())
+                else {
+                    return Err(syntax_error());
+                };
+                let (width, height) = (parse_float(width)?, parse_float(height)?);
+                if width < 0.0 || height < 0.0 {
+                    return Err(Error::msg("height or width cannot be negative"));
+                }
+                unit = GeoUnit::parse(box_unit)?;
+                shape = Some(GeoShape::Box(width, height));
+            }
+            "BYRADIUS" | "BYBOX" => {
+                return Err(Error::msg(format!(
+                    "exactly one of BYRADIUS and BYBOX can be specified for {}",
+                    name
+                )))
+            }
+            "ASC" => sort = Some(GeoSort::Asc),
+            "DESC" => sort = Some(GeoSort::Desc),
+            "COUNT" => {
+                let (value, _) = iter.next().ok_or_else(syntax_error)?;
+                let value = parse_integer(value)?;
+                if value <= 0 {
+                    return Err(Error::msg("COUNT must be > 0"));
+                }
+                let any = iter
+                    .clone()
+                    .next()
+                    .is_some_and(|(next, _)| next.eq_ignore_ascii_case("ANY"));
+                if any {
+                    iter.next();
+                }
+                count = Some((value as usize, any));
             }
-            let len = args.len();
-            args.insert_str(0, format!("${}{}", len, CRLF).as_str());
-            args.push_str(CRLF);
-            Ok(Command::Echo(args.clone()))
+            "WITHCOORD" if !store => with_coord = true,
+            "WITHDIST" if !store => with_dist = true,
+            "WITHHASH" if !store => with_hash = true,
+            "STOREDIST" if store => store_dist = true,
+            _ => return Err(syntax_error()),
         }
+    }
+
+    let Some(origin) = origin else {
+        return Err(Error::msg(format!(
+            "exactly one of FROMMEMBER or FROMLONLAT can be specified for {}",
+            name
+        )));
+    };
+    let Some(shape) = shape else {
+        return Err(Error::msg(format!(
+            "exactly one of BYRADIUS and BYBOX can be specified for {}",
+            name
+        )));
+    };
+    Ok(GeoSearch {
+        origin,
+        shape,
+        unit,
+        sort,
+        count,
+        with_coord,
+        with_dist,
+        with_hash,
+        store_dist,
+    })
+}
+
+fn parse_bit_unit(arg: Option<&String>) -> Result<BitUnit> {
+    match arg.map(|s| s.to_uppercase()).as_deref() {
+        None | Some("BYTE") => Ok(BitUnit::Byte),
+        Some("BIT") => Ok(BitUnit::Bit),
+        Some(_) => Err(BitmapError::Syntax.into()),
+    }
+}
+
+fn parse_bit_field_ops(args: &[String], read_only: bool) -> Result<Vec<BitFieldOp>> {
+    let mut ops = Vec::new();
+    let mut overflow = Overflow::Wrap;
+    let mut iter = args.iter();
+    while let Some(subcommand) = iter.next() {
+        let subcommand = subcommand.to_uppercase();
+        if read_only && subcommand != "GET" {
+            return Err(BitmapError::ReadOnlySubcommand.into());
+        }
+        if subcommand == "OVERFLOW" {
+            overflow = match iter.next().map(|s| s.to_uppercase()).as_deref() {
+                Some("WRAP") => Overflow::Wrap,
+                Some("SAT") => Overflow::Sat,
+                Some("FAIL") => Overflow::Fail,
+                _ => return Err(BitmapError::InvalidOverflowType.into()),
+            };
+            continue;
+        }
+
+        let (Some(ty), Some(offset)) = (iter.next(), iter.next()) else {
+            return Err(BitmapError::Syntax.into());
+        };
+        let ty = parse_bitfield_type(ty)?;
+        let offset = parse_bitfield_offset(offset, ty)?;
+        let op = match subcommand.as_str() {
+            "GET" => BitFieldOp::Get { ty, offset },
+            "SET" | "INCRBY" => {
+                let Some(value) = iter.next() else {
+                    return Err(BitmapError::Syntax.into());
+                };
+                let value = value
+                    .parse::<i64>()
+                    .map_err(|_| BitmapError::NotAnInteger)?;
+                if subcommand == "SET" {
+                    BitFieldOp::Set {
+                        ty,
+                        offset,
+                        value,
+                        overflow,
+                    }
+                } else {
+                    BitFieldOp::IncrBy {
+                        ty,
+                        offset,
+                        increment: value,
+                        overflow,
+                    }
+                }
+            }
+            _ => return Err(BitmapError::Syntax.into()),
+        };
+        ops.push(op);
+    }
+    Ok(ops)
+}
+
+/// The arguments of one request, as sent by the client.
+pub type Args = Vec<Vec<u8>>;
+
+/// Parses the arguments of a request into a command. Keywords and numbers are read from
+/// a lossy UTF-8 copy of the arguments; keys, values and members are kept as the bytes
+/// the client sent.
+pub fn parse_command(args: Args) -> Result<Command> {
+    if args[0].eq_ignore_ascii_case(b"RESTORE") {
+        return parse_restore(args);
+    }
+    let cmd_vec = args
+        .iter()
+        .map(|arg| String::from_utf8_lossy(arg).into_owned())
+        .collect::<Vec<_>>();
+    match cmd_vec[0].to_uppercase().as_str() {
+        "PING" => Ok(Command::Ping),
+        "ECHO" => Ok(Command::Echo(args.get(1).cloned().unwrap_or_default())),
         "REPLCONF" => {
             if let Some(arg) = cmd_vec.get(1) {
                 match arg.as_str() {
@@ -38,51 +251,32 @@ pub fn parse_command(cmd_vec: Vec<String>) -> Result<Command> {
             }
             Ok(Command::Psync(args))
         }
-        "GET" => {
-            let key = cmd_vec.get(1);
-            println!("Key is: {:?}", key);
-            let mut key_str = String::new();
-            if let Some(key) = key {
-                key_str.push_str(key);
-            }
-            Ok(Command::Get(key_str.clone()))
-        }
+        "GET" => Ok(Command::Get(args.get(1).cloned().unwrap_or_default())),
         "SET" => {
-            let key = cmd_vec.get(1);
-            let value = cmd_vec.get(2);
-            let mut key_str = String::new();
-            let mut value_str = String::new();
-            if let Some(key) = key {
-                key_str.push_str(key);
-            }
-            if let Some(value) = value {
-                value_str.push_str(value);
-            }
+            let key = args.get(1).cloned().unwrap_or_default();
+            let value = args.get(2).cloned().unwrap_or_default();
             if let Some(arg) = cmd_vec.get(3) {
-                match arg.as_str() {
-                    "px" | "ex" => {
-                        if let Some(expiry) = cmd_vec.get(4) {
-                            match expiry.parse::<u64>() {
-                                Ok(duration) => Ok(Command::Set(
-                                    key_str.clone(),
-                                    value_str.clone(),
-                                    Some(SystemTime::now() + Duration::from_millis(duration)),
-                                )),
-                                Err(_) => Err(Error::msg("Invalid expiry duration")),
-                            }
-                        } else {
-                            Err(Error::msg("Invalid expiry duration"))
-                        }
-                    }
-                    _ => Err(Error::msg("Invalid argument")),
+                let option = arg.to_uppercase();
+                if !matches!(option.as_str(), "EX" | "PX" | "EXAT" | "PXAT") {
+                    return Err(Error::msg("Invalid argument"));
                 }
+                let Some(Ok(amount)) = cmd_vec.get(4).map(|amount| amount.parse::<u64>()) else {
+                    return Err(Error::msg("Invalid expiry duration"));
+                };
+                let expiry = match option.as_str() {
+                    "EX" => SystemTime::now() + Duration::from_secs(amount),
+                    "PX" => SystemTime::now() + Duration::from_millis(amount),
+                    "EXAT" => UNIX_EPOCH + Duration::from_secs(amount),
+                    _ => UNIX_EPOCH + Duration::from_millis(amount),
+                };
+                Ok(Command::Set(key, value, Some(expiry)))
             } else {
-                Ok(Command::Set(key_str.clone(), value_str.clone(), None))
+                Ok(Command::Set(key, value, None))
             }
         }
         "CONFIG" => {
             if let Some(sub_command) = cmd_vec.get(1) {
-                match sub_command.as_str() {
+                match sub_command.to_lowercase().as_str() {
                     "get" => {
                         let key = cmd_vec.get(2);
                         let mut key_str = String::new();
@@ -91,23 +285,30 @@ pub fn parse_command(cmd_vec: Vec<String>) -> Result<Command> {
                         }
                         Ok(Command::GetConfig(key_str.clone()))
                     }
-                    _ => Err(Error::msg("Only supports CONFIG GET command")),
+                    "set" => {
+                        let pairs = &cmd_vec[2..];
+                        if pairs.is_empty() || !pairs.len().is_multiple_of(2) {
+                            return Err(wrong_arguments("config|set"));
+                        }
+                        Ok(Command::SetConfig(
+                            pairs
+                                .chunks(2)
+                                .map(|pair| (pair[0].to_lowercase(), pair[1].clone()))
+                                .collect(),
+                        ))
+                    }
+                    _ => Err(Error::msg(
+                        "Only supports CONFIG GET and CONFIG SET commands",
+                    )),
                 }
             } else {
                 Err(Error::msg("Invalid command"))
             }
         }
-        "KEYS" => {
-            let pattern = cmd_vec.get(1);
-            let mut pattern_str = String::new();
-            if let Some(pattern) = pattern {
-                if pattern == "" {
-                    return Ok(Command::Keys("*".to_string()));
-                }
-                pattern_str.push_str(pattern);
-            }
-            Ok(Command::Keys(pattern_str.clone()))
-        }
+        "KEYS" => match args.get(1) {
+            Some(pattern) if pattern.is_empty() => Ok(Command::Keys(b"*".to_vec())),
+            pattern => Ok(Command::Keys(pattern.cloned().unwrap_or_default())),
+        },
         "INFO" => {
             let arg = cmd_vec.get(1);
             let mut arg_str = String::new();
@@ -116,28 +317,572 @@ pub fn parse_command(cmd_vec: Vec<String>) -> Result<Command> {
             }
             Ok(Command::Info(arg_str.clone()))
         }
+        "SETBIT" => {
+            let (Some(key), Some(offset), Some(bit)) =
+                (args.get(1), cmd_vec.get(2), cmd_vec.get(3))
+            else {
+                return Err(wrong_arguments("SETBIT"));
+            };
+            let offset = parse_bit_offset(offset)?;
+            let bit = match bit.as_str() {
+                "0" => 0,
+                "1" => 1,
+                _ => return Err(BitmapError::InvalidBit.into()),
+            };
+            Ok(Command::SetBit(key.clone(), offset, bit))
+        }
+        "GETBIT" => {
+            let (Some(key), Some(offset)) = (args.get(1), cmd_vec.get(2)) else {
+                return Err(wrong_arguments("GETBIT"));
+            };
+            Ok(Command::GetBit(key.clone(), parse_bit_offset(offset)?))
+        }
+        "BITCOUNT" => {
+            let Some(key) = args.get(1) else {
+                return Err(wrong_arguments("BITCOUNT"));
+            };
+            match cmd_vec.len() {
+                2 => Ok(Command::BitCount(key.clone(), None)),
+                4 | 5 => {
+                    let start = parse_integer(&cmd_vec[2])?;
+                    let end = parse_integer(&cmd_vec[3])?;
+                    let unit = parse_bit_unit(cmd_vec.get(4))?;
+                    Ok(Command::BitCount(key.clone(), Some((start, end, unit))))
+                }
+                _ => Err(BitmapError::Syntax.into()),
+            }
+        }
+        "BITPOS" => {
+            let (Some(key), Some(bit)) = (args.get(1), cmd_vec.get(2)) else {
+                return Err(wrong_arguments("BITPOS"));
+            };
+            let bit = match bit.as_str() {
+                "0" => 0,
+                "1" => 1,
+                _ => return Err(Error::msg("The bit argument must be 1 or 0.")),
+            };
+            if cmd_vec.len() > 6 {
+                return Err(BitmapError::Syntax.into());
+            }
+            let start = cmd_vec.get(3).map(|s| parse_integer(s)).transpose()?;
+            let end = cmd_vec.get(4).map(|s| parse_integer(s)).transpose()?;
+            let unit = parse_bit_unit(cmd_vec.get(5))?;
+            Ok(Command::BitPos(key.clone(), bit, start, end, unit))
+        }
+        "BITOP" => {
+            if cmd_vec.len() < 4 {
+                return Err(wrong_arguments("BITOP"));
+            }
+            let operation = match cmd_vec[1].to_uppercase().as_str() {
+                "AND" => BitOperation::And,
+                "OR" => BitOperation::Or,
+                "XOR" => BitOperation::Xor,
+                "NOT" => BitOperation::Not,
+                _ => return Err(BitmapError::Syntax.into()),
+            };
+            let sources = args[3..].to_vec();
+            if operation == BitOperation::Not && sources.len() != 1 {
+                return Err(BitmapError::BitOpNotSingleSource.into());
+            }
+            Ok(Command::BitOp(operation, args[2].clone(), sources))
+        }
+        "BITFIELD" | "BITFIELD_RO" => {
+            let name = cmd_vec[0].to_uppercase();
+            let Some(key) = args.get(1) else {
+                return Err(wrong_arguments(&name));
+            };
+            if name == "BITFIELD" {
+                let ops = parse_bit_field_ops(&cmd_vec[2..], false)?;
+                Ok(Command::BitField(key.clone(), ops))
+            } else {
+                let ops = parse_bit_field_ops(&cmd_vec[2..], true)?;
+                Ok(Command::BitFieldRo(key.clone(), ops))
+            }
+        }
+        "PFADD" => {
+            let Some(key) = args.get(1) else {
+                return Err(wrong_arguments("PFADD"));
+            };
+            Ok(Command::PfAdd(key.clone(), args[2..].to_vec()))
+        }
+        "PFCOUNT" => {
+            if cmd_vec.len() < 2 {
+                return Err(wrong_arguments("PFCOUNT"));
+            }
+            Ok(Command::PfCount(args[1..].to_vec()))
+        }
+        "PFMERGE" => {
+            let Some(dest) = args.get(1) else {
+                return Err(wrong_arguments("PFMERGE"));
+            };
+            Ok(Command::PfMerge(dest.clone(), args[2..].to_vec()))
+        }
+        "MULTI" => Ok(Command::Multi),
+        "EXEC" => Ok(Command::Exec),
+        "DISCARD" => Ok(Command::Discard),
+        "WATCH" => {
+            if cmd_vec.len() < 2 {
+                return Err(wrong_arguments("WATCH"));
+            }
+            Ok(Command::Watch(args[1..].to_vec()))
+        }
+        "UNWATCH" => Ok(Command::Unwatch),
+        "SAVE" if cmd_vec.len() == 1 => Ok(Command::Save),
+        "BGSAVE" => match cmd_vec.get(1).map(|arg| arg.to_uppercase()).as_deref() {
+            None | Some("SCHEDULE") if cmd_vec.len() <= 2 => Ok(Command::BgSave),
+            _ => Err(Error::msg("syntax error")),
+        },
+        "BGREWRITEAOF" if cmd_vec.len() == 1 => Ok(Command::BgRewriteAof),
+        "LASTSAVE" if cmd_vec.len() == 1 => Ok(Command::LastSave),
+        "MIGRATE" => parse_migrate(&cmd_vec, &args),
+        "DUMP" => match &args[1..] {
+            [key] => Ok(Command::Dump(key.clone())),
+            _ => Err(wrong_arguments("DUMP")),
+        },
+        "SHUTDOWN" => match cmd_vec.get(1).map(|arg| arg.to_uppercase()).as_deref() {
+            None => Ok(Command::Shutdown(None)),
+            Some("SAVE") if cmd_vec.len() == 2 => Ok(Command::Shutdown(Some(true))),
+            Some("NOSAVE") if cmd_vec.len() == 2 => Ok(Command::Shutdown(Some(false))),
+            _ => Err(Error::msg("syntax error")),
+        },
+        "SAVE" | "LASTSAVE" => Err(wrong_arguments(&cmd_vec[0].to_uppercase())),
+        "CLIENT" => {
+            let Some(sub_command) = cmd_vec.get(1) else {
+                return Err(wrong_arguments("CLIENT"));
+            };
+            match (sub_command.to_uppercase().as_str(), cmd_vec.len()) {
+                ("ID", 2) => Ok(Command::ClientId),
+                ("GETREDIR", 2) => Ok(Command::ClientGetRedir),
+                ("CACHING", 3) => match cmd_vec[2].to_uppercase().as_str() {
+                    "YES" => Ok(Command::ClientCaching(true)),
+                    "NO" => Ok(Command::ClientCaching(false)),
+                    _ => Err(Error::msg("syntax error")),
+                },
+                ("TRACKING", 3..) => {
+                    let on = match cmd_vec[2].to_uppercase().as_str() {
+                        "ON" => true,
+                        "OFF" => false,
+                        _ => return Err(Error::msg("syntax error")),
+                    };
+                    let mut options = TrackingOptions::default();
+                    let mut iter = cmd_vec[3..].iter().zip(&args[3..]);
+                    while let Some((arg, _)) = iter.next() {
+                        match arg.to_uppercase().as_str() {
+                            "REDIRECT" => {
+                                let (id, _) =
+                                    iter.next().ok_or_else(|| Error::msg("syntax error"))?;
+                                let id = id.parse::<u64>().map_err(|_| {
+                                    Error::msg("value is not an integer or out of range")
+                                })?;
+                                options.redirect = Some(id);
+                            }
+                            "PREFIX" => {
+                                let (_, prefix) =
+                                    iter.next().ok_or_else(|| Error::msg("syntax error"))?;
+                                options.prefixes.push(prefix.clone());
+                            }
+                            "BCAST" => options.bcast = true,
+                            "OPTIN" => options.optin = true,
+                            "OPTOUT" => options.optout = true,
+                            "NOLOOP" => options.noloop = true,
+                            _ => return Err(Error::msg("syntax error")),
+                        }
+                    }
+                    Ok(Command::ClientTracking(on, options))
+                }
+                ("ID" | "GETREDIR" | "CACHING" | "TRACKING", _) => Err(Error::msg(format!(
+                    "wrong number of arguments for 'client|{}' command",
+                    sub_command.to_lowercase()
+                ))),
+                _ => Err(Error::msg(format!(
+                    "unknown subcommand '{}'. Try CLIENT HELP.",
+                    sub_command
+                ))),
+            }
+        }
+        "DEL" => {
+            if cmd_vec.len() < 2 {
+                return Err(wrong_arguments("DEL"));
+            }
+            Ok(Command::Del(args[1..].to_vec()))
+        }
+        "SELECT" => {
+            let Some(index) = cmd_vec.get(1) else {
+                return Err(wrong_arguments("SELECT"));
+            };
+            let index = parse_integer(index)?;
+            if !(0..store::DATABASES as i64).contains(&index) {
+                return Err(Error::msg("DB index is out of range"));
+            }
+            Ok(Command::Select(index as usize))
+        }
+        "SUBSCRIBE" | "PSUBSCRIBE" => {
+            let name = cmd_vec[0].to_uppercase();
+            if cmd_vec.len() < 2 {
+                return Err(wrong_arguments(&name));
+            }
+            let targets = args[1..].to_vec();
+            if name == "SUBSCRIBE" {
+                Ok(Command::Subscribe(targets))
+            } else {
+                Ok(Command::PSubscribe(targets))
+            }
+        }
+        "SSUBSCRIBE" => {
+            if cmd_vec.len() < 2 {
+                return Err(wrong_arguments("SSUBSCRIBE"));
+            }
+            Ok(Command::SSubscribe(args[1..].to_vec()))
+        }
+        "UNSUBSCRIBE" => Ok(Command::Unsubscribe(args[1..].to_vec())),
+        "PUNSUBSCRIBE" => Ok(Command::PUnsubscribe(args[1..].to_vec())),
+        "SUNSUBSCRIBE" => Ok(Command::SUnsubscribe(args[1..].to_vec())),
+        "PUBLISH" | "SPUBLISH" => {
+            let name = cmd_vec[0].to_uppercase();
+            let (Some(channel), Some(message), 3) = (args.get(1), args.get(2), args.len())
+            else {
+                return Err(wrong_arguments(&name));
+            };
+            if name == "PUBLISH" {
+                Ok(Command::Publish(channel.clone(), message.clone()))
+            } else {
+                Ok(Command::SPublish(channel.clone(), message.clone()))
+            }
+        }
+        "HELLO" => {
+            let Some(protocol) = cmd_vec.get(1) else {
+                return Ok(Command::Hello(None));
+            };
+            let protocol = protocol
+                .parse::<i64>()
+                .map_err(|_| Error::msg("Protocol version is not an integer or out of range"))?;
+            // Authentication is not configurable, so AUTH is accepted for any credentials.
+            match &cmd_vec[2..] {
+                [] => {}
+                [auth, _username, _password] if auth.eq_ignore_ascii_case("AUTH") => {}
+                _ => return Err(Error::msg("syntax error")),
+            }
+            Ok(Command::Hello(Some(protocol)))
+        }
+        "PUBSUB" => {
+            let Some(sub_command) = cmd_vec.get(1) else {
+                return Err(wrong_arguments("PUBSUB"));
+            };
+            match sub_command.to_uppercase().as_str() {
+                "CHANNELS" if cmd_vec.len() <= 3 => Ok(Command::PubSubChannels(
+                    SubscriptionKind::Channel,
+                    args.get(2).cloned(),
+                )),
+                "SHARDCHANNELS" if cmd_vec.len() <= 3 => Ok(Command::PubSubChannels(
+                    SubscriptionKind::ShardChannel,
+                    args.get(2).cloned(),
+                )),
+                "NUMSUB" => Ok(Command::PubSubNumSub(
+                    SubscriptionKind::Channel,
+                    args[2..].to_vec(),
+                )),
+                "SHARDNUMSUB" => Ok(Command::PubSubNumSub(
+                    SubscriptionKind::ShardChannel,
+                    args[2..].to_vec(),
+                )),
+                "NUMPAT" if cmd_vec.len() == 2 => Ok(Command::PubSubNumPat),
+                "CHANNELS" | "SHARDCHANNELS" | "NUMPAT" => Err(Error::msg(format!(
+                    "wrong number of arguments for 'pubsub|{}' command",
+                    sub_command.to_lowercase()
+                ))),
+                _ => Err(Error::msg(format!(
+                    "unknown subcommand '{}'. Try PUBSUB HELP.",
+                    sub_command
+                ))),
+            }
+        }
+        "FLUSHDB" | "FLUSHALL" => {
+            let name = cmd_vec[0].to_uppercase();
+            match cmd_vec.get(1).map(|arg| arg.to_uppercase()).as_deref() {
+                None | Some("SYNC") | Some("ASYNC") if cmd_vec.len() <= 2 => {}
+                _ => return Err(Error::msg("syntax error")),
+            }
+            if name == "FLUSHDB" {
+                Ok(Command::FlushDb)
+            } else {
+                Ok(Command::FlushAll)
+            }
+        }
+        "GEOADD" => {
+            let Some(key) = args.get(1) else {
+                return Err(wrong_arguments("GEOADD"));
+            };
+            let mut options = GeoAddOptions::default();
+            let mut position = 2;
+            while let Some(arg) = cmd_vec.get(position) {
+                match arg.to_uppercase().as_str() {
+                    "NX" => options.nx = true,
+                    "XX" => options.xx = true,
+                    "CH" => options.ch = true,
+                    _ => break,
+                }
+                position += 1;
+            }
+            if options.nx && options.xx {
+                return Err(Error::msg(
+                    "XX and NX options at the same time are not compatible",
+                ));
+            }
+            let items = &cmd_vec[position..];
+            if items.is_empty() || !items.len().is_multiple_of(3) {
+                return Err(Error::msg(
+                    "syntax error. Try GEOADD key [x1] [y1] [name1] [x2] [y2] [name2] ... ",
+                ));
+            }
+            let mut positions = Vec::with_capacity(items.len() / 3);
+            for (item, raw) in items.chunks(3).zip(args[position..].chunks(3)) {
+                let longitude = parse_float(&item[0])?;
+                let latitude = parse_float(&item[1])?;
+                geo::validate(longitude, latitude)?;
+                positions.push((longitude, latitude, raw[2].clone()));
+            }
+            Ok(Command::GeoAdd(key.clone(), options, positions))
+        }
+        "GEOPOS" | "GEOHASH" => {
+            let name = cmd_vec[0].to_uppercase();
+            let Some(key) = args.get(1) else {
+                return Err(wrong_arguments(&name));
+            };
+            let members = args[2..].to_vec();
+            if name == "GEOPOS" {
+                Ok(Command::GeoPos(key.clone(), members))
+            } else {
+                Ok(Command::GeoHash(key.clone(), members))
+            }
+        }
+        "GEODIST" => {
+            let (Some(key), Some(member1), Some(member2)) = (args.get(1), args.get(2), args.get(3))
+            else {
+                return Err(wrong_arguments("GEODIST"));
+            };
+            if cmd_vec.len() > 5 {
+                return Err(Error::msg("syntax error"));
+            }
+            let unit = match cmd_vec.get(4) {
+                Some(unit) => GeoUnit::parse(unit)?,
+                None => GeoUnit::Meters,
+            };
+            Ok(Command::GeoDist(
+                key.clone(),
+                member1.clone(),
+                member2.clone(),
+                unit,
+            ))
+        }
+        "GEOSEARCH" => {
+            let Some(key) = args.get(1) else {
+                return Err(wrong_arguments("GEOSEARCH"));
+            };
+            let search = parse_geo_search("GEOSEARCH", &cmd_vec[2..], &args[2..], false)?;
+            Ok(Command::GeoSearch(key.clone(), search))
+        }
+        "GEOSEARCHSTORE" => {
+            let (Some(dest), Some(src)) = (args.get(1), args.get(2)) else {
+                return Err(wrong_arguments("GEOSEARCHSTORE"));
+            };
+            let search = parse_geo_search("GEOSEARCHSTORE", &cmd_vec[3..], &args[3..], true)?;
+            Ok(Command::GeoSearchStore(dest.clone(), src.clone(), search))
+        }
         _ => Err(Error::msg("Command not supported")),
     }
 }
 
-pub async fn process_buff(buff: &[u8]) -> Result<Vec<Vec<String>>> {
-    let resp_message = std::str::from_utf8(buff)?;
-    let resp_arrays = resp_message.split('*').collect::<Vec<_>>();
-    let mut commands: Vec<Vec<String>> = Vec::new();
-    for resp_array in resp_arrays.iter() {
-        if resp_array.is_empty() {
-            continue;
+/// Parses `RESTORE key ttl payload [REPLACE] [ABSTTL] [IDLETIME seconds] [FREQ frequency]`.
+fn parse_restore(args: Args) -> Result<Command> {
+    if args.len() < 4 {
+        return Err(wrong_arguments("RESTORE"));
+    }
+    let mut args = args.into_iter().skip(1);
+    let key = args.next().unwrap_or_default();
+    let ttl = args
+        .next()
+        .map(|arg| String::from_utf8_lossy(&arg).into_owned())
+        .unwrap_or_default();
+    let ttl = parse_integer(&ttl)?;
+    if ttl < 0 {
+        return Err(Error::msg("Invalid TTL value, must be >= 0"));
+    }
+    let payload = args.next().unwrap_or_default();
+    let mut options = RestoreOptions {
+        payload,
+        expiry: None,
+        replace: false,
+        access: None,
+    };
+    let mut absttl = false;
+    let syntax_error = || Error::msg("syntax error");
+    let mut words = args.map(|arg| String::from_utf8_lossy(&arg).into_owned());
+    while let Some(option) = words.next() {
+        match option.to_uppercase().as_str() {
+            "REPLACE" => options.replace = true,
+            "ABSTTL" => absttl = true,
+            "IDLETIME" if options.access.is_none() => {
+                let idle = parse_integer(&words.next().ok_or_else(syntax_error)?)?;
+                let idle = u64::try_from(idle)
+                    .map_err(|_| Error::msg("Invalid IDLETIME value, must be >= 0"))?;
+                let last_access = SystemTime::now()
+                    .checked_sub(Duration::from_secs(idle))
+                    .unwrap_or(UNIX_EPOCH);
+                options.access = Some(AccessHint::LastAccess(last_access));
+            }
+            "FREQ" if options.access.is_none() => {
+                let freq = parse_integer(&words.next().ok_or_else(syntax_error)?)?;
+                let freq = u8::try_from(freq)
+                    .map_err(|_| Error::msg("Invalid FREQ value, must be >= 0 and <= 255"))?;
+                options.access = Some(AccessHint::Frequency(freq));
+            }
+            _ => return Err(syntax_error()),
         }
-        let resp_words = resp_array.split('$').collect::<Vec<_>>();
-        let mut command: Vec<String> = Vec::new();
-        for resp_word in resp_words.iter().skip(1) {
-            let words = resp_word
-                .split("\r\n")
-                .map(|s| s.to_owned())
-                .collect::<Vec<_>>();
-            command.push(words.get(1).unwrap().clone());
+    }
+    options.expiry = match ttl as u64 {
+        0 => None,
+        ms if absttl => Some(UNIX_EPOCH + Duration::from_millis(ms)),
+        ms => Some(SystemTime::now() + Duration::from_millis(ms)),
+    };
+    Ok(Command::Restore(key, options))
+}
+
+/// Parses `MIGRATE host port key|"" destination-db timeout [COPY] [REPLACE]
+/// [KEYS key ...]`. AUTH and AUTH2 are refused, since altredis has no AUTH command for
+/// them to send.
+fn parse_migrate(cmd_vec: &[String], args: &[Vec<u8>]) -> Result<Command> {
+    if cmd_vec.len() < 6 {
+        return Err(wrong_arguments("MIGRATE"));
+    }
+    let port = cmd_vec[2]
+        .parse::<u16>()
+        .map_err(|_| Error::msg("Invalid port"))?;
+    let db = parse_integer(&cmd_vec[4])?;
+    if !(0..store::DATABASES as i64).contains(&db) {
+        return Err(Error::msg("DB index is out of range"));
+    }
+    // Like Redis, a timeout of zero or less means one second.
+    let timeout = match parse_integer(&cmd_vec[5])? {
+        ms if ms <= 0 => Duration::from_secs(1),
+        ms => Duration::from_millis(ms as u64),
+    };
+    let mut options = MigrateOptions {
+        host: cmd_vec[1].clone(),
+        port,
+        keys: vec![args[3].clone()],
+        db: db as usize,
+        timeout,
+        copy: false,
+        replace: false,
+    };
+    let mut i = 6;
+    while i < cmd_vec.len() {
+        match cmd_vec[i].to_uppercase().as_str() {
+            "COPY" => options.copy = true,
+            "REPLACE" => options.replace = true,
+            "AUTH" | "AUTH2" => {
+                return Err(Error::msg(
+                    "MIGRATE AUTH and AUTH2 are not supported, altredis servers have no passwords",
+                ))
+            }
+            "KEYS" => {
+                if !cmd_vec[3].is_empty() {
+                    return Err(Error::msg(
+                        "When using MIGRATE KEYS option, the key argument must be set to the empty string",
+                    ));
+                }
+                options.keys = args[i + 1..].to_vec();
+                break;
+            }
+            _ => return Err(Error::msg("syntax error")),
+        }
+        i += 1;
+    }
+    Ok(Command::Migrate(options))
+}
+
+/// Longest inline command accepted before the connection is rejected.
+const MAX_INLINE_LEN: usize = 64 * 1024;
+/// Largest bulk string accepted in a request, as Redis' default `proto-max-bulk-len`.
+const MAX_BULK_LEN: usize = 512 * 1024 * 1024;
+
+/// Returns the line starting at `start` and the position just after its CRLF, or `None`
+/// if the line is not complete yet.
+fn read_line(buff: &[u8], start: usize) -> Option<(&[u8], usize)> {
+    let end = buff.get(start..)?.windows(2).position(|w| w == b"\r\n")? + start;
+    Some((&buff[start..end], end + 2))
+}
+
+fn parse_length(line: &[u8]) -> Option<i64> {
+    str::from_utf8(line).ok()?.parse::<i64>().ok()
+}
+
+/// Parses one request from the front of `buff`, returning its arguments and length in
+/// bytes, or `None` if more data is needed.
+pub fn parse_request(buff: &[u8]) -> Result<Option<(Args, usize)>> {
+    let Some(first) = buff.first() else {
+        return Ok(None);
+    };
+    if *first != b'*' {
+        // Inline command, as typed into telnet.
+        let Some((line, next)) = read_line(buff, 0) else {
+            if buff.len() > MAX_INLINE_LEN {
+                return Err(ResponseErrors::MessageTooBig.into());
+            }
+            return Ok(None);
+        };
+        let words = line
+            .split(|c| c.is_ascii_whitespace())
+            .filter(|word| !word.is_empty())
+            .map(|word| word.to_vec())
+            .collect();
+        return Ok(Some((words, next)));
+    }
+
+    let Some((line, mut position)) = read_line(buff, 1) else {
+        return Ok(None);
+    };
+    let count = parse_length(line).ok_or_else(|| {
+        ResponseErrors::ArrayNumElementsInvalidLength(String::from_utf8_lossy(line).into())
+    })?;
+    let mut words = Vec::with_capacity(count.clamp(0, 1024) as usize);
+    for _ in 0..count {
+        match buff.get(position) {
+            None => return Ok(None),
+            Some(b'$') => {}
+            Some(other) => return Err(ResponseErrors::UnhandledRespDataType(*other as char).into()),
+        }
+        let Some((line, next)) = read_line(buff, position + 1) else {
+            return Ok(None);
+        };
+        let len = parse_length(line)
+            .and_then(|len| usize::try_from(len).ok())
+            .ok_or_else(|| {
+                ResponseErrors::BulkStringInvalidLength(String::from_utf8_lossy(line).into())
+            })?;
+        if len > MAX_BULK_LEN {
+            return Err(ResponseErrors::MessageTooBig.into());
+        }
+        if buff.len() < next + len + 2 {
+            return Ok(None);
+        }
+        words.push(buff[next..next + len].to_vec());
+        position = next + len + 2;
+    }
+    Ok(Some((words, position)))
+}
+
+/// Takes every complete request off the front of `buff`. A trailing partial request is
+/// left in place until the rest of it has been read.
+pub fn process_buff(buff: &mut Vec<u8>) -> Result<Vec<Args>> {
+    let mut commands = Vec::new();
+    let mut consumed = 0;
+    while let Some((command, len)) = parse_request(&buff[consumed..])? {
+        consumed += len;
+        if !command.is_empty() {
+            commands.push(command);
         }
-        commands.push(command);
     }
+    buff.drain(..consumed);
     Ok(commands)
 }
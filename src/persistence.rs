@@ -0,0 +1,325 @@
+use crate::aof;
+use crate::connection::EXEC_LOCK;
+use crate::rdb::RdbWriter;
+use crate::store::{self, Database};
+use crate::CONFIG;
+use once_cell::sync::Lazy;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use thiserror::Error;
+
+/// How long to wait before retrying a failed automatic background save.
+const BGSAVE_RETRY_DELAY: Duration = Duration::from_secs(5);
+
+#[derive(Error, Debug)]
+pub enum PersistenceError {
+    #[error("Background save already in progress")]
+    BgsaveInProgress,
+
+    #[error("Save already in progress")]
+    SaveInProgress,
+
+    #[error("Invalid save parameters")]
+    InvalidSaveParams,
+
+    #[error("Errors trying to SHUTDOWN. Check logs.")]
+    ShutdownFailed,
+
+    #[error("Superseded by a later save")]
+    Superseded,
+}
+
+/// Number of changes to the dataset since the last successful save.
+static DIRTY: AtomicU64 = AtomicU64::new(0);
+
+struct SaveState {
+    last_save: SystemTime,
+    /// Set by SAVE and SHUTDOWN while they write in the foreground; together with
+    /// `bgsave_in_progress` it keeps a single snapshot writer at a time.
+    save_in_progress: bool,
+    bgsave_in_progress: bool,
+    last_bgsave_ok: bool,
+    last_bgsave_try: Option<SystemTime>,
+}
+
+/// Bumped to discard a running background save, as Redis kills its child. A save only
+/// renames its file over the RDB file while holding this with the value it started with.
+static GENERATION: Lazy<tokio::sync::Mutex<u64>> = Lazy::new(|| tokio::sync::Mutex::new(0));
+
+static STATE: Lazy<Mutex<SaveState>> = Lazy::new(|| {
+    Mutex::new(SaveState {
+        last_save: SystemTime::now(),
+        save_in_progress: false,
+        bgsave_in_progress: false,
+        last_bgsave_ok: true,
+        last_bgsave_try: None,
+    })
+});
+
+/// Parses the value of the `save` option, pairs of `<seconds> <changes>`. An empty
+/// string disables automatic snapshots.
+pub fn parse_save_params(value: &str) -> Result<Vec<(u64, u64)>, PersistenceError> {
+    let numbers = value
+        .split_whitespace()
+        .map(|n| n.parse::<u64>())
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|_| PersistenceError::InvalidSaveParams)?;
+    if numbers.len() % 2 != 0 {
+        return Err(PersistenceError::InvalidSaveParams);
+    }
+    Ok(numbers.chunks(2).map(|pair| (pair[0], pair[1])).collect())
+}
+
+pub fn save_params_to_string(params: &[(u64, u64)]) -> String {
+    params
+        .iter()
+        .map(|(seconds, changes)| format!("{} {}", seconds, changes))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Records `changes` modifications of the dataset towards the save rules.
+pub fn add_dirty(changes: u64) {
+    DIRTY.fetch_add(changes, Ordering::Relaxed);
+}
+
+pub fn dirty() -> u64 {
+    DIRTY.load(Ordering::Relaxed)
+}
+
+/// Forgets the changes made while loading the dataset at startup.
+pub fn clear_dirty() {
+    DIRTY.store(0, Ordering::Relaxed);
+}
+
+/// Serializes `databases` and writes them over the configured RDB file, unless the save
+/// of `generation` was superseded meanwhile. `dirty` is the change count when the
+/// snapshot was taken; later changes still count after the save.
+async fn write_snapshot(
+    databases: HashMap<usize, Database>,
+    dirty: u64,
+    generation: u64,
+) -> anyhow::Result<()> {
+    let (path, compression, checksum) = {
+        let config = CONFIG.read().await;
+        (
+            config.rdb_path(),
+            config.rdb_compression,
+            config.rdb_checksum,
+        )
+    };
+    let contents = tokio::task::spawn_blocking(move || {
+        RdbWriter::write_snapshot(&databases, compression, checksum)
+    })
+    .await?;
+    let temp_path = RdbWriter::write_temp_file(&path, &contents).await?;
+    {
+        let current = GENERATION.lock().await;
+        let result = if *current == generation {
+            tokio::fs::rename(&temp_path, &path).await.map_err(Into::into)
+        } else {
+            Err(PersistenceError::Superseded.into())
+        };
+        if let Err(e) = result {
+            let _ = tokio::fs::remove_file(&temp_path).await;
+            return Err(e);
+        }
+    }
+    let _ = DIRTY.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |current| {
+        Some(current.saturating_sub(dirty))
+    });
+    STATE.lock().unwrap().last_save = SystemTime::now();
+    println!("DB saved on disk at {:?}", path);
+    Ok(())
+}
+
+impl SaveState {
+    fn check_idle(&self) -> Result<(), PersistenceError> {
+        if self.bgsave_in_progress {
+            return Err(PersistenceError::BgsaveInProgress);
+        }
+        if self.save_in_progress {
+            return Err(PersistenceError::SaveInProgress);
+        }
+        Ok(())
+    }
+}
+
+/// Snapshots and writes the dataset in the foreground, unless another save is running.
+/// Like every snapshot, taken while holding the write side of `EXEC_LOCK`; commands
+/// that save get it from `dispatch`, and taking it here would deadlock them.
+async fn foreground_save() -> anyhow::Result<()> {
+    {
+        let mut state = STATE.lock().unwrap();
+        state.check_idle()?;
+        state.save_in_progress = true;
+    }
+    let dirty = dirty();
+    let generation = *GENERATION.lock().await;
+    let result = write_snapshot(store::db_snapshot().await, dirty, generation).await;
+    STATE.lock().unwrap().save_in_progress = false;
+    result
+}
+
+/// Saves the dataset in the foreground, as SAVE does.
+pub async fn save() -> anyhow::Result<()> {
+    foreground_save().await
+}
+
+/// Saves the dataset in the foreground right away, as FLUSHALL and SHUTDOWN do. A
+/// running background save is superseded rather than waited for: it is left to finish
+/// writing, but its result is discarded and its file never replaces this one.
+pub async fn save_superseding() -> anyhow::Result<()> {
+    {
+        let mut generation = GENERATION.lock().await;
+        *generation += 1;
+        let mut state = STATE.lock().unwrap();
+        if state.bgsave_in_progress {
+            println!("Background saving superseded by a foreground save");
+            state.bgsave_in_progress = false;
+        }
+    }
+    foreground_save().await
+}
+
+/// Takes a snapshot of the dataset and writes it from a background task, so clients are
+/// only held up while the keys are copied: values stay shared with the store until they
+/// are next written. Callers hold the write side of `EXEC_LOCK`, as for `foreground_save`.
+pub async fn bgsave() -> Result<(), PersistenceError> {
+    {
+        let mut state = STATE.lock().unwrap();
+        state.check_idle()?;
+        state.bgsave_in_progress = true;
+        state.last_bgsave_try = Some(SystemTime::now());
+    }
+    let dirty = dirty();
+    let generation = *GENERATION.lock().await;
+    let databases = store::db_snapshot().await;
+    tokio::spawn(async move {
+        let result = write_snapshot(databases, dirty, generation).await;
+        if let Err(e) = result.as_ref() {
+            if let Some(PersistenceError::Superseded) = e.downcast_ref() {
+                println!("Background saving discarded: superseded by a later save");
+                // The state already belongs to the save that superseded this one.
+                return;
+            }
+            println!("Background saving error: {:?}", e);
+        }
+        let mut state = STATE.lock().unwrap();
+        state.bgsave_in_progress = false;
+        state.last_bgsave_ok = result.is_ok();
+    });
+    Ok(())
+}
+
+/// Starts a background save if any `save <seconds> <changes>` rule is met. Called
+/// periodically from the server's background task.
+pub async fn save_if_needed() {
+    let params = CONFIG.read().await.save_params.clone();
+    let dirty = dirty();
+    let now = SystemTime::now();
+    let since_last_save = {
+        let state = STATE.lock().unwrap();
+        let retry_delay_passed = state
+            .last_bgsave_try
+            .is_none_or(|tried| now.duration_since(tried).unwrap_or_default() > BGSAVE_RETRY_DELAY);
+        if state.check_idle().is_err() || !(state.last_bgsave_ok || retry_delay_passed) {
+            return;
+        }
+        now.duration_since(state.last_save).unwrap_or_default()
+    };
+    let rule = params.iter().find(|(seconds, changes)| {
+        dirty >= *changes && since_last_save > Duration::from_secs(*seconds)
+    });
+    if let Some((seconds, changes)) = rule {
+        println!("{} changes in {} seconds. Saving...", changes, seconds);
+        let _exclusive = EXEC_LOCK.write().await;
+        if let Err(e) = bgsave().await {
+            println!("Failed to start background save: {}", e);
+        }
+    }
+}
+
+/// Saves the dataset if `save` asks for it, or by default when save rules are configured,
+/// then exits the process. Returns only if the final save failed.
+pub async fn shutdown(save: Option<bool>) -> Result<(), PersistenceError> {
+    let save = match save {
+        Some(save) => save,
+        None => !CONFIG.read().await.save_params.is_empty(),
+    };
+    if save {
+        println!("Saving the final RDB snapshot before exiting.");
+        if let Err(e) = save_superseding().await {
+            println!("Error trying to save the DB, can't exit: {:?}", e);
+            return Err(PersistenceError::ShutdownFailed);
+        }
+    }
+    aof::fsync().await;
+    println!("Redis is now ready to exit, bye bye...");
+    std::process::exit(0);
+}
+
+/// Unix time of the last successful save, or of startup if there was none.
+pub fn last_save() -> u64 {
+    unix_seconds(STATE.lock().unwrap().last_save)
+}
+
+fn unix_seconds(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// The `# Persistence` section of INFO.
+pub fn info() -> String {
+    let state = STATE.lock().unwrap();
+    [
+        "# Persistence".to_owned(),
+        format!("loading:{}", aof::loading() as u8),
+        format!("rdb_changes_since_last_save:{}", dirty()),
+        format!("rdb_bgsave_in_progress:{}", state.bgsave_in_progress as u8),
+        format!("rdb_last_save_time:{}", unix_seconds(state.last_save)),
+        format!(
+            "rdb_last_bgsave_status:{}",
+            if state.last_bgsave_ok { "ok" } else { "err" }
+        ),
+        format!("aof_enabled:{}", aof::enabled() as u8),
+        format!(
+            "aof_last_write_status:{}",
+            if aof::last_write_ok() { "ok" } else { "err" }
+        ),
+        format!(
+            "aof_rewrite_in_progress:{}",
+            aof::rewrite_in_progress() as u8
+        ),
+        format!(
+            "aof_last_bgrewrite_status:{}",
+            if aof::last_rewrite_ok() { "ok" } else { "err" }
+        ),
+    ]
+    .join("\r\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_save_rules() {
+        assert_eq!(
+            parse_save_params("3600 1 300 100").unwrap(),
+            [(3600, 1), (300, 100)]
+        );
+        assert!(parse_save_params("").unwrap().is_empty());
+        assert!(parse_save_params("  ").unwrap().is_empty());
+        assert!(parse_save_params("3600").is_err());
+        assert!(parse_save_params("3600 -1").is_err());
+        assert!(parse_save_params("an hour").is_err());
+        assert_eq!(
+            save_params_to_string(&parse_save_params(" 60  10000 ").unwrap()),
+            "60 10000"
+        );
+    }
+}
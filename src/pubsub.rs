@@ -0,0 +1,155 @@
+use crate::client::ClientHandle;
+use crate::utils::{build_resp_array_raw, build_resp_bulk, build_resp_push, glob_match};
+use once_cell::sync::Lazy;
+use std::collections::HashMap;
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex};
+
+/// The three subscription namespaces. Sharded channels are kept apart from regular ones:
+/// SPUBLISH only reaches SSUBSCRIBE clients and PUBLISH never does. Without cluster mode
+/// every slot is served by this node, so sharded channels behave like single-node ones.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SubscriptionKind {
+    Channel,
+    Pattern,
+    ShardChannel,
+}
+
+impl SubscriptionKind {
+    pub fn subscribe_reply(self) -> &'static str {
+        match self {
+            SubscriptionKind::Channel => "subscribe",
+            SubscriptionKind::Pattern => "psubscribe",
+            SubscriptionKind::ShardChannel => "ssubscribe",
+        }
+    }
+
+    pub fn unsubscribe_reply(self) -> &'static str {
+        match self {
+            SubscriptionKind::Channel => "unsubscribe",
+            SubscriptionKind::Pattern => "punsubscribe",
+            SubscriptionKind::ShardChannel => "sunsubscribe",
+        }
+    }
+}
+
+/// Subscribers of each channel and pattern, keyed by client id. Names are kept as the
+/// bytes clients sent, so binary channel names round-trip.
+#[derive(Default)]
+struct Registry {
+    channels: HashMap<Vec<u8>, HashMap<u64, Arc<ClientHandle>>>,
+    patterns: HashMap<Vec<u8>, HashMap<u64, Arc<ClientHandle>>>,
+    shard_channels: HashMap<Vec<u8>, HashMap<u64, Arc<ClientHandle>>>,
+}
+
+impl Registry {
+    fn namespace(
+        &mut self,
+        kind: SubscriptionKind,
+    ) -> &mut HashMap<Vec<u8>, HashMap<u64, Arc<ClientHandle>>> {
+        match kind {
+            SubscriptionKind::Channel => &mut self.channels,
+            SubscriptionKind::Pattern => &mut self.patterns,
+            SubscriptionKind::ShardChannel => &mut self.shard_channels,
+        }
+    }
+}
+
+static REGISTRY: Lazy<Mutex<Registry>> = Lazy::new(|| Mutex::new(Registry::default()));
+
+/// Builds a message, or a (un)subscribe confirmation, in the client's protocol.
+pub fn build_frame(resp3: bool, elements: Vec<Vec<u8>>) -> Vec<u8> {
+    if resp3 {
+        build_resp_push(elements)
+    } else {
+        build_resp_array_raw(elements)
+    }
+}
+
+pub fn subscribe(kind: SubscriptionKind, client_id: u64, name: &[u8], handle: Arc<ClientHandle>) {
+    let mut registry = REGISTRY.lock().unwrap();
+    registry
+        .namespace(kind)
+        .entry(name.to_vec())
+        .or_default()
+        .insert(client_id, handle);
+}
+
+pub fn unsubscribe(kind: SubscriptionKind, client_id: u64, name: &[u8]) {
+    let mut registry = REGISTRY.lock().unwrap();
+    let subscriptions = registry.namespace(kind);
+    if let Some(subscribers) = subscriptions.get_mut(name) {
+        subscribers.remove(&client_id);
+        if subscribers.is_empty() {
+            subscriptions.remove(name);
+        }
+    }
+}
+
+/// Queues a message for `subscriber`, built from `elements` after its kind.
+fn deliver(subscriber: &ClientHandle, elements: Vec<&[u8]>) {
+    let resp3 = subscriber.resp3.load(Ordering::Relaxed);
+    let frame = build_frame(resp3, elements.into_iter().map(build_resp_bulk).collect());
+    subscriber.push(frame);
+}
+
+/// Delivers `message` to the subscribers of `channel` and of every matching pattern,
+/// returning how many deliveries were made. Messages are only queued: each subscriber's
+/// connection writes them out, so the publisher never waits on a subscriber's socket.
+pub fn publish(channel: &[u8], message: &[u8]) -> usize {
+    let registry = REGISTRY.lock().unwrap();
+    let mut receivers = 0;
+    for subscriber in registry
+        .channels
+        .get(channel)
+        .into_iter()
+        .flat_map(|s| s.values())
+    {
+        deliver(subscriber, vec![b"message".as_slice(), channel, message]);
+        receivers += 1;
+    }
+    for (pattern, subscribers) in registry.patterns.iter() {
+        if !glob_match(pattern, channel, false) {
+            continue;
+        }
+        for subscriber in subscribers.values() {
+            deliver(subscriber, vec![b"pmessage".as_slice(), pattern, channel, message]);
+            receivers += 1;
+        }
+    }
+    receivers
+}
+
+/// Delivers `message` to the SSUBSCRIBE clients of the sharded `channel`.
+pub fn spublish(channel: &[u8], message: &[u8]) -> usize {
+    let registry = REGISTRY.lock().unwrap();
+    let subscribers = registry.shard_channels.get(channel);
+    for subscriber in subscribers.into_iter().flat_map(|s| s.values()) {
+        deliver(subscriber, vec![b"smessage".as_slice(), channel, message]);
+    }
+    subscribers.map_or(0, |subscribers| subscribers.len())
+}
+
+/// Channels of `kind` with at least one subscriber, optionally filtered by a glob pattern.
+pub fn active_channels(kind: SubscriptionKind, pattern: Option<&[u8]>) -> Vec<Vec<u8>> {
+    let mut registry = REGISTRY.lock().unwrap();
+    registry
+        .namespace(kind)
+        .keys()
+        .filter(|channel| pattern.is_none_or(|pattern| glob_match(pattern, channel, false)))
+        .cloned()
+        .collect()
+}
+
+pub fn subscriber_count(kind: SubscriptionKind, channel: &[u8]) -> usize {
+    let mut registry = REGISTRY.lock().unwrap();
+    registry
+        .namespace(kind)
+        .get(channel)
+        .map_or(0, |subscribers| subscribers.len())
+}
+
+/// Number of distinct patterns subscribed to by any client.
+pub fn pattern_count() -> usize {
+    REGISTRY.lock().unwrap().patterns.len()
+}
@@ -1,20 +1,100 @@
 use std::cmp::Ordering;
-use std::collections::HashMap;
-use std::io::SeekFrom;
-use std::path::Path;
-use std::time::{Duration, SystemTime};
+use std::collections::{BTreeMap, BTreeSet, HashMap, VecDeque};
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
+use crate::crc64::crc64;
+use crate::listpack::{self, ListpackEntry, ListpackWriter};
+use crate::lzf::{self, LzfError};
+use crate::sorted_set::SortedSet;
+use crate::store::{AccessHint, Data, Database, Value, DATABASES};
+use crate::stream::{Consumer, ConsumerGroup, PendingEntry, Stream, StreamFields, StreamId};
+use crate::ziplist;
+use crate::SERVER_VERSION;
 use async_trait::async_trait;
 use std::str::FromStr;
 use thiserror::Error;
 use tokio::fs::File;
-use tokio::io::{AsyncReadExt, AsyncSeekExt, BufReader};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt, ReadBuf};
 
+/// Version written in the header of the files we produce.
+pub const RDB_VERSION: u16 = 11;
+/// Newest version we can read, the one of Redis 7.4 with hash field expiration.
+const RDB_MAX_VERSION: u16 = 12;
+
+const RDB_OPCODE_SLOT_INFO: u8 = 0xF4;
+const RDB_OPCODE_FUNCTION2: u8 = 0xF5;
+const RDB_OPCODE_FUNCTION_PRE_GA: u8 = 0xF6;
+const RDB_OPCODE_MODULE_AUX: u8 = 0xF7;
+const RDB_OPCODE_IDLE: u8 = 0xF8;
+const RDB_OPCODE_FREQ: u8 = 0xF9;
+const RDB_OPCODE_AUX: u8 = 0xFA;
+const RDB_OPCODE_RESIZEDB: u8 = 0xFB;
+const RDB_OPCODE_EXPIRETIME_MS: u8 = 0xFC;
+const RDB_OPCODE_EXPIRETIME: u8 = 0xFD;
+const RDB_OPCODE_SELECTDB: u8 = 0xFE;
+const RDB_OPCODE_EOF: u8 = 0xFF;
+
+/// Opcodes of the values modules serialize, in module values and MODULE_AUX.
+const RDB_MODULE_OPCODE_EOF: usize = 0;
+const RDB_MODULE_OPCODE_SINT: usize = 1;
+const RDB_MODULE_OPCODE_UINT: usize = 2;
+const RDB_MODULE_OPCODE_FLOAT: usize = 3;
+const RDB_MODULE_OPCODE_DOUBLE: usize = 4;
+const RDB_MODULE_OPCODE_STRING: usize = 5;
+
+const RDB_ENC_LZF: u8 = 3;
+
+const RDB_TYPE_STRING: u8 = 0;
+const RDB_TYPE_LIST: u8 = 1;
+const RDB_TYPE_SET: u8 = 2;
+const RDB_TYPE_ZSET: u8 = 3;
+const RDB_TYPE_HASH: u8 = 4;
+const RDB_TYPE_ZSET_2: u8 = 5;
+const RDB_TYPE_MODULE_PRE_GA: u8 = 6;
+const RDB_TYPE_MODULE_2: u8 = 7;
+const RDB_TYPE_HASH_ZIPMAP: u8 = 9;
+const RDB_TYPE_LIST_ZIPLIST: u8 = 10;
+const RDB_TYPE_SET_INTSET: u8 = 11;
+const RDB_TYPE_ZSET_ZIPLIST: u8 = 12;
+const RDB_TYPE_HASH_ZIPLIST: u8 = 13;
+const RDB_TYPE_LIST_QUICKLIST: u8 = 14;
+const RDB_TYPE_STREAM_LISTPACKS: u8 = 15;
+const RDB_TYPE_HASH_LISTPACK: u8 = 16;
+const RDB_TYPE_ZSET_LISTPACK: u8 = 17;
+const RDB_TYPE_LIST_QUICKLIST_2: u8 = 18;
+const RDB_TYPE_STREAM_LISTPACKS_2: u8 = 19;
+const RDB_TYPE_SET_LISTPACK: u8 = 20;
+const RDB_TYPE_STREAM_LISTPACKS_3: u8 = 21;
+const RDB_TYPE_HASH_METADATA_PRE_GA: u8 = 22;
+const RDB_TYPE_HASH_LISTPACK_EX_PRE_GA: u8 = 23;
+const RDB_TYPE_HASH_METADATA: u8 = 24;
+const RDB_TYPE_HASH_LISTPACK_EX: u8 = 25;
+
+/// Quicklist node containers: a single large element, or a listpack of elements.
+const QUICKLIST_NODE_CONTAINER_PLAIN: usize = 1;
+const QUICKLIST_NODE_CONTAINER_PACKED: usize = 2;
+
+/// Stream entry flags within a listpack node.
+const STREAM_ITEM_FLAG_DELETED: i64 = 1 << 0;
+const STREAM_ITEM_FLAG_SAMEFIELDS: i64 = 1 << 1;
+
+/// Elements per listpack node when writing lists and streams, the defaults of
+/// `list-max-listpack-size` (approximately) and `stream-node-max-entries`.
+const LIST_NODE_MAX_ENTRIES: usize = 128;
+const STREAM_NODE_MAX_ENTRIES: usize = 100;
+
+#[derive(Default)]
 pub struct RdbData {
     pub rdb_version: u16,
     pub metadata: HashMap<String, String>,
-    pub databases: HashMap<usize, HashMap<String, String>>,
-    pub expirations: HashMap<usize, HashMap<String, SystemTime>>,
+    pub databases: HashMap<usize, HashMap<Vec<u8>, Data>>,
+    pub expirations: HashMap<usize, HashMap<Vec<u8>, SystemTime>>,
+    pub access_hints: HashMap<usize, HashMap<Vec<u8>, AccessHint>>,
+    /// Code of the function libraries in the file.
+    pub functions: Vec<String>,
 }
 
 #[derive(Error, Debug)]
@@ -22,7 +102,16 @@ pub enum RdbReadError {
     #[error("File is not a redis database")]
     NotRedisDatabase,
 
-    #[error("IO Error")]
+    #[error("Can't handle RDB format version {0}")]
+    UnsupportedVersion(u16),
+
+    #[error("Pre-release function format not supported")]
+    PreGaFunctionsNotSupported,
+
+    #[error("DUMP payload version or checksum are wrong")]
+    DumpPayloadMismatch,
+
+    #[error("IO Error: {0}")]
     IoError(#[from] tokio::io::Error),
 
     #[error("Error reading utf8 string")]
@@ -40,18 +129,111 @@ pub enum RdbReadError {
     #[error("Invalid flag when reading Expiry Timestamp {0:02X}")]
     InvalidExpiryTimestampFlag(u8),
 
+    #[error("Database {0} is out of range")]
+    DatabaseOutOfRange(usize),
+
     #[error("Attempted to read key without a database selected")]
     AttemptReadKeyWithoutDatabaseSelected,
+
+    #[error("Invalid compressed string: {0}")]
+    LzfError(#[from] LzfError),
+
+    #[error("Corrupt {0} encoded value")]
+    CorruptEncoding(&'static str),
+
+    #[error("Unknown RDB value type {0}")]
+    UnknownValueType(u8),
+
+    #[error("Value of module type '{0}' can't be loaded: modules are not supported")]
+    ModuleValueNotSupported(String),
+
+    #[error("Wrong RDB checksum expected: ({expected:x}) got: ({actual:x})")]
+    ChecksumMismatch { expected: u64, actual: u64 },
+
+    #[error("Invalid string encoding {0}")]
+    InvalidStringEncoding(usize),
+
+    #[error("Invalid UTF-8 in {0}")]
+    InvalidUtf8(&'static str),
+
+    #[error("{source} at offset {offset}")]
+    AtOffset {
+        offset: u64,
+        source: Box<RdbReadError>,
+    },
 }
+
+/// Keeps the CRC64 and count of every byte read through it, so the trailing checksum
+/// can be verified without reading the file twice and errors can tell where they hit.
+struct ChecksumReader<R> {
+    inner: R,
+    crc: u64,
+    offset: u64,
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for ChecksumReader<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let already_filled = buf.filled().len();
+        let poll = Pin::new(&mut self.inner).poll_read(cx, buf);
+        if let Poll::Ready(Ok(())) = poll {
+            let read = &buf.filled()[already_filled..];
+            self.crc = crc64(self.crc, read);
+            self.offset += read.len() as u64;
+        }
+        poll
+    }
+}
+
 pub struct RdbReader;
 
 impl RdbReader {
-    pub async fn read(path: impl AsRef<Path>) -> Result<RdbData, RdbReadError> {
-        let mut reader = {
-            let file = File::open(path).await?;
-            BufReader::new(file)
+    /// Reads an RDB payload from any byte source, such as a file, the socket of a full
+    /// resynchronization or a buffer in memory. Unless `verify_checksum` is false, the
+    /// trailing CRC64 must match the contents; a zero checksum means it was not computed.
+    /// Nothing past the checksum is consumed, so the source can keep being used
+    /// afterwards; reads are not buffered, which is up to the caller.
+    pub async fn read_from<R: AsyncRead + Unpin + Send>(
+        reader: R,
+        verify_checksum: bool,
+    ) -> Result<RdbData, RdbReadError> {
+        match Self::read_partial_from(reader, verify_checksum).await {
+            (data, None) => Ok(data),
+            (_, Some(e)) => Err(e),
+        }
+    }
+
+    /// Like `read_from`, but keeps what was decoded before an error, which is returned
+    /// alongside.
+    pub async fn read_partial_from<R: AsyncRead + Unpin + Send>(
+        reader: R,
+        verify_checksum: bool,
+    ) -> (RdbData, Option<RdbReadError>) {
+        let mut reader = ChecksumReader {
+            inner: reader,
+            crc: 0,
+            offset: 0,
         };
-        if !Self::is_rdb_file(&mut reader).await? {
+        let mut data = RdbData::default();
+        let error = Self::decode(&mut reader, verify_checksum, &mut data)
+            .await
+            .err()
+            .map(|e| RdbReadError::AtOffset {
+                offset: reader.offset,
+                source: Box::new(e),
+            });
+        (data, error)
+    }
+
+    async fn decode<R: AsyncRead + Unpin + Send>(
+        reader: &mut ChecksumReader<R>,
+        verify_checksum: bool,
+        data: &mut RdbData,
+    ) -> Result<(), RdbReadError> {
+        if !Self::is_rdb_file(reader).await? {
             return Err(RdbReadError::NotRedisDatabase);
         }
         let rdb_version = {
@@ -60,27 +242,33 @@ impl RdbReader {
             let ver_str = std::str::from_utf8(&buff)?;
             u16::from_str(ver_str)?
         };
-        let mut databases: HashMap<usize, HashMap<String, String>> = HashMap::new();
-        let mut metadata = HashMap::new();
-        let mut expirations: HashMap<usize, HashMap<String, SystemTime>> = HashMap::new();
+        if !(1..=RDB_MAX_VERSION).contains(&rdb_version) {
+            return Err(RdbReadError::UnsupportedVersion(rdb_version));
+        }
+        data.rdb_version = rdb_version;
         let mut current_database: Option<usize> = None;
         let mut next_expiration: Option<SystemTime> = None;
+        let mut next_access: Option<AccessHint> = None;
         loop {
             let opcode = reader.read_u8().await?;
             match opcode {
-                0xFA => {
-                    // Auxiliary data
-                    let key = reader.read_string_encoded().await?;
-                    let value = reader.read_string_encoded().await?;
-                    metadata.insert(key, value);
+                RDB_OPCODE_AUX => {
+                    let key = reader.read_string_encoded("aux field").await?;
+                    let value = reader.read_string_encoded("aux field").await?;
+                    data.metadata.insert(key, value);
                 }
-                0xFB => {
+                RDB_OPCODE_RESIZEDB => {
                     let _db_table_size = reader.read_length_encoded_int().await?;
                     let _expiry_table_size = reader.read_length_encoded_int().await?;
                 }
-                0xFC | 0xFD => {
+                RDB_OPCODE_SLOT_INFO => {
+                    // Cluster slot sizing hints, not needed to load the keys.
+                    let _slot_id = reader.read_length_encoded_int().await?;
+                    let _slot_size = reader.read_length_encoded_int().await?;
+                    let _expires_slot_size = reader.read_length_encoded_int().await?;
+                }
+                RDB_OPCODE_EXPIRETIME_MS | RDB_OPCODE_EXPIRETIME => {
                     if current_database.is_none() {
-                        println!("Database: {:?}", current_database);
                         return Err(RdbReadError::AttemptReadKeyWithoutDatabaseSelected);
                     }
 
@@ -93,13 +281,50 @@ impl RdbReader {
                     let expiration_time = SystemTime::UNIX_EPOCH + expiration_time;
                     next_expiration = Some(expiration_time);
                 }
-                0xFE => {
-                    let database = reader.read_u8().await?;
-                    current_database = Some(database.into());
+                RDB_OPCODE_IDLE => {
+                    let idle = Duration::from_secs(reader.read_length_encoded_int().await? as u64);
+                    let last_access = SystemTime::now().checked_sub(idle).unwrap_or(UNIX_EPOCH);
+                    next_access = Some(AccessHint::LastAccess(last_access));
+                }
+                RDB_OPCODE_FREQ => {
+                    next_access = Some(AccessHint::Frequency(reader.read_u8().await?));
+                }
+                RDB_OPCODE_FUNCTION2 => {
+                    data.functions
+                        .push(reader.read_string_encoded("function").await?);
+                }
+                RDB_OPCODE_FUNCTION_PRE_GA => {
+                    return Err(RdbReadError::PreGaFunctionsNotSupported);
+                }
+                RDB_OPCODE_MODULE_AUX => {
+                    let module_id = reader.read_length_encoded_int().await? as u64;
+                    let when_opcode = reader.read_length_encoded_int().await?;
+                    let _when = reader.read_length_encoded_int().await?;
+                    if when_opcode != RDB_MODULE_OPCODE_UINT {
+                        return Err(RdbReadError::CorruptEncoding("module aux"));
+                    }
+                    // Without module support the data can't be used, but it describes
+                    // its own layout so it can be stepped over.
+                    RdbBufReader::skip_module_data(reader).await?;
+                    println!(
+                        "Skipping AUX data of module '{}': modules are not supported",
+                        module_type_name(module_id)
+                    );
+                }
+                RDB_OPCODE_SELECTDB => {
+                    let database = reader.read_length_encoded_int().await?;
+                    if database >= DATABASES {
+                        return Err(RdbReadError::DatabaseOutOfRange(database));
+                    }
+                    current_database = Some(database);
                 }
-                0xFF => {
+                RDB_OPCODE_EOF => {
                     if rdb_version >= 5 {
-                        let _crc64 = reader.read_u64().await?;
+                        let actual = reader.crc;
+                        let expected = reader.read_u64_le().await?;
+                        if verify_checksum && expected != 0 && expected != actual {
+                            return Err(RdbReadError::ChecksumMismatch { expected, actual });
+                        }
                     }
                     break;
                 }
@@ -108,35 +333,59 @@ impl RdbReader {
                         return Err(RdbReadError::AttemptReadKeyWithoutDatabaseSelected);
                     };
 
-                    let (key, value) = match reader.read_key_value(Some(opcode)).await? {
-                        (key, value) => (key, value),
-                    };
+                    let (key, value) = reader.read_key_value(Some(opcode)).await?;
+                    let expiration = next_expiration.take();
+                    let access = next_access.take();
+                    // Like Redis, drop collections that ended up with no elements, such as
+                    // a hash whose fields have all expired.
+                    if is_empty_collection(&value) {
+                        continue;
+                    }
 
-                    if let Some(expiration) = next_expiration {
-                        expirations
+                    if let Some(expiration) = expiration {
+                        data.expirations
                             .entry(current_database)
                             .or_default()
                             .insert(key.clone(), expiration);
-
-                        next_expiration = None;
+                    }
+                    if let Some(access) = access {
+                        data.access_hints
+                            .entry(current_database)
+                            .or_default()
+                            .insert(key.clone(), access);
                     }
 
-                    let database = databases.entry(current_database).or_default();
+                    let database = data.databases.entry(current_database).or_default();
                     database.insert(key, value);
                 }
             }
         }
 
-        Ok(RdbData {
-            rdb_version,
-            metadata,
-            databases,
-            expirations,
-        })
+        Ok(())
     }
 
-    async fn is_rdb_file(reader: &mut BufReader<File>) -> Result<bool, RdbReadError> {
-        reader.seek(SeekFrom::Start(0)).await?;
+    /// Decodes a DUMP payload after checking its RDB version and CRC64 footer.
+    pub async fn read_dump(payload: &[u8]) -> Result<Data, RdbReadError> {
+        if payload.len() < 10 {
+            return Err(RdbReadError::DumpPayloadMismatch);
+        }
+        let (signed, checksum) = payload.split_at(payload.len() - 8);
+        let version = u16::from_le_bytes([signed[signed.len() - 2], signed[signed.len() - 1]]);
+        let checksum = u64::from_le_bytes(checksum.try_into().unwrap_or_default());
+        if version > RDB_MAX_VERSION || crc64(0, signed) != checksum {
+            return Err(RdbReadError::DumpPayloadMismatch);
+        }
+
+        let mut body = &signed[..signed.len() - 2];
+        let value_type = body.read_u8().await?;
+        let value = RdbBufReader::read_value_type(&mut body, value_type).await?;
+        if !body.is_empty() || is_empty_collection(&value) {
+            return Err(RdbReadError::CorruptEncoding("DUMP"));
+        }
+        Ok(value)
+    }
+
+    async fn is_rdb_file<R: AsyncRead + Unpin>(reader: &mut R) -> Result<bool, RdbReadError> {
         let mut buff = [0u8; 5];
         reader.read_exact(&mut buff).await?;
         Ok(buff.cmp(b"REDIS") == Ordering::Equal)
@@ -144,17 +393,18 @@ impl RdbReader {
 }
 
 #[async_trait]
-trait RdbBufReader {
+trait RdbBufReader: AsyncRead + Unpin + Send + Sized {
     async fn read_length_encoded_int(&mut self) -> Result<usize, RdbReadError>;
-    async fn read_string_encoded(&mut self) -> Result<String, RdbReadError>;
+    async fn read_string_encoded(&mut self, what: &'static str) -> Result<String, RdbReadError>;
+    async fn read_bytes_encoded(&mut self) -> Result<Vec<u8>, RdbReadError>;
     async fn read_expiry_timestamp(&mut self, opcode: u8) -> Result<ExpiryTimestamp, RdbReadError>;
     async fn read_key_value(
         &mut self,
         known_type: Option<u8>,
-    ) -> Result<(String, String), RdbReadError>;
+    ) -> Result<(Vec<u8>, Data), RdbReadError>;
 
     async fn read_length_encoding(
-        reader: &mut BufReader<File>,
+        reader: &mut Self,
     ) -> Result<(LengthEncoding, usize), RdbReadError> {
         let length = reader.read_u8().await?;
         let (encoding, length) = {
@@ -163,7 +413,11 @@ trait RdbBufReader {
             match (length & mask) >> 6 {
                 0b00 => (LengthEncoding::Remaining6Bits, remaining_bits),
                 0b01 => (LengthEncoding::RemainingAndNextByte, remaining_bits),
-                0b10 => (LengthEncoding::DiscardRemainingGetNext4Bytes, 0),
+                0b10 => match remaining_bits {
+                    0 => (LengthEncoding::DiscardRemainingGetNext4Bytes, 0),
+                    1 => (LengthEncoding::DiscardRemainingGetNext8Bytes, 0),
+                    _ => return Err(RdbReadError::InvalidLengthEncoding(length)),
+                },
                 0b11 => (LengthEncoding::SpecialFormat, remaining_bits),
                 x => return Err(RdbReadError::InvalidLengthEncoding(x)),
             }
@@ -172,13 +426,14 @@ trait RdbBufReader {
     }
 
     async fn interpret_length_encoding(
-        reader: &mut BufReader<File>,
+        reader: &mut Self,
         length_encoding: LengthEncoding,
         length: usize,
     ) -> Result<usize, RdbReadError> {
         let value = match length_encoding {
             LengthEncoding::Remaining6Bits => length,
-            LengthEncoding::DiscardRemainingGetNext4Bytes => reader.read_u32_le().await? as usize,
+            LengthEncoding::DiscardRemainingGetNext4Bytes => reader.read_u32().await? as usize,
+            LengthEncoding::DiscardRemainingGetNext8Bytes => reader.read_u64().await? as usize,
             LengthEncoding::RemainingAndNextByte => {
                 (length << 8) | (reader.read_u8().await? as usize)
             }
@@ -190,21 +445,472 @@ trait RdbBufReader {
         Ok(value)
     }
 
-    async fn read_value_type(
-        reader: &mut BufReader<File>,
-        value_type: u8,
-    ) -> Result<String, RdbReadError> {
+    async fn read_value_type(reader: &mut Self, value_type: u8) -> Result<Data, RdbReadError> {
         let value = match value_type {
-            0 => reader.read_string_encoded().await?,
-            _ => todo!("DataType isn't handled yet!"),
+            RDB_TYPE_STRING => Data::String(reader.read_bytes_encoded().await?),
+            RDB_TYPE_LIST | RDB_TYPE_SET => {
+                let len = reader.read_length_encoded_int().await?;
+                let mut elements = Vec::new();
+                for _ in 0..len {
+                    elements.push(reader.read_bytes_encoded().await?);
+                }
+                if value_type == RDB_TYPE_LIST {
+                    Data::List(elements.into())
+                } else {
+                    Data::Set(elements.into_iter().collect())
+                }
+            }
+            RDB_TYPE_ZSET | RDB_TYPE_ZSET_2 => {
+                let len = reader.read_length_encoded_int().await?;
+                let mut zset = SortedSet::new();
+                for _ in 0..len {
+                    let member = reader.read_bytes_encoded().await?;
+                    let score = if value_type == RDB_TYPE_ZSET_2 {
+                        reader.read_f64_le().await?
+                    } else {
+                        Self::read_double_string(reader).await?
+                    };
+                    zset.insert(member, score);
+                }
+                Data::SortedSet(zset)
+            }
+            RDB_TYPE_HASH => {
+                let len = reader.read_length_encoded_int().await?;
+                let mut hash = HashMap::new();
+                for _ in 0..len {
+                    let field = reader.read_bytes_encoded().await?;
+                    let value = reader.read_bytes_encoded().await?;
+                    hash.insert(field, value);
+                }
+                Data::Hash(hash)
+            }
+            RDB_TYPE_MODULE_PRE_GA | RDB_TYPE_MODULE_2 => {
+                let module_id = reader.read_length_encoded_int().await? as u64;
+                return Err(RdbReadError::ModuleValueNotSupported(module_type_name(
+                    module_id,
+                )));
+            }
+            RDB_TYPE_HASH_ZIPMAP => {
+                let blob = reader.read_bytes_encoded().await?;
+                let pairs =
+                    ziplist::decode_zipmap(&blob).ok_or(RdbReadError::CorruptEncoding("zipmap"))?;
+                Data::Hash(pairs.into_iter().collect())
+            }
+            RDB_TYPE_LIST_ZIPLIST => {
+                let entries = decode_ziplist(&reader.read_bytes_encoded().await?)?;
+                Data::List(entries.into_iter().map(ListpackEntry::into_bytes).collect())
+            }
+            RDB_TYPE_SET_INTSET => {
+                let blob = reader.read_bytes_encoded().await?;
+                let members =
+                    ziplist::decode_intset(&blob).ok_or(RdbReadError::CorruptEncoding("intset"))?;
+                Data::Set(
+                    members
+                        .into_iter()
+                        .map(|n| n.to_string().into_bytes())
+                        .collect(),
+                )
+            }
+            RDB_TYPE_SET_LISTPACK => {
+                let entries = decode_listpack(&reader.read_bytes_encoded().await?)?;
+                Data::Set(entries.into_iter().map(ListpackEntry::into_bytes).collect())
+            }
+            RDB_TYPE_ZSET_ZIPLIST | RDB_TYPE_ZSET_LISTPACK => {
+                let blob = reader.read_bytes_encoded().await?;
+                let entries = if value_type == RDB_TYPE_ZSET_ZIPLIST {
+                    decode_ziplist(&blob)?
+                } else {
+                    decode_listpack(&blob)?
+                };
+                let mut zset = SortedSet::new();
+                for (member, score) in into_pairs(entries)? {
+                    let score = score
+                        .as_float()
+                        .ok_or(RdbReadError::CorruptEncoding("sorted set score"))?;
+                    zset.insert(member.into_bytes(), score);
+                }
+                Data::SortedSet(zset)
+            }
+            RDB_TYPE_HASH_ZIPLIST | RDB_TYPE_HASH_LISTPACK => {
+                let blob = reader.read_bytes_encoded().await?;
+                let entries = if value_type == RDB_TYPE_HASH_ZIPLIST {
+                    decode_ziplist(&blob)?
+                } else {
+                    decode_listpack(&blob)?
+                };
+                Data::Hash(
+                    into_pairs(entries)?
+                        .into_iter()
+                        .map(|(field, value)| (field.into_bytes(), value.into_bytes()))
+                        .collect(),
+                )
+            }
+            RDB_TYPE_LIST_QUICKLIST | RDB_TYPE_LIST_QUICKLIST_2 => {
+                let nodes = reader.read_length_encoded_int().await?;
+                let mut list = VecDeque::new();
+                for _ in 0..nodes {
+                    let container = if value_type == RDB_TYPE_LIST_QUICKLIST_2 {
+                        reader.read_length_encoded_int().await?
+                    } else {
+                        QUICKLIST_NODE_CONTAINER_PACKED
+                    };
+                    let blob = reader.read_bytes_encoded().await?;
+                    let entries = match container {
+                        QUICKLIST_NODE_CONTAINER_PLAIN => {
+                            list.push_back(blob);
+                            continue;
+                        }
+                        QUICKLIST_NODE_CONTAINER_PACKED
+                            if value_type == RDB_TYPE_LIST_QUICKLIST =>
+                        {
+                            decode_ziplist(&blob)?
+                        }
+                        QUICKLIST_NODE_CONTAINER_PACKED => decode_listpack(&blob)?,
+                        _ => return Err(RdbReadError::CorruptEncoding("quicklist")),
+                    };
+                    list.extend(entries.into_iter().map(ListpackEntry::into_bytes));
+                }
+                Data::List(list)
+            }
+            RDB_TYPE_STREAM_LISTPACKS
+            | RDB_TYPE_STREAM_LISTPACKS_2
+            | RDB_TYPE_STREAM_LISTPACKS_3 => {
+                Data::Stream(Self::read_stream(reader, value_type).await?)
+            }
+            RDB_TYPE_HASH_METADATA_PRE_GA
+            | RDB_TYPE_HASH_LISTPACK_EX_PRE_GA
+            | RDB_TYPE_HASH_METADATA
+            | RDB_TYPE_HASH_LISTPACK_EX => {
+                Data::Hash(Self::read_hash_with_expiry(reader, value_type).await?)
+            }
+            _ => return Err(RdbReadError::UnknownValueType(value_type)),
+        };
+
+        Ok(value)
+    }
+
+    /// Reads exactly `len` bytes. The buffer grows as data arrives, so a corrupt length
+    /// ends in an early EOF rather than a huge allocation.
+    async fn read_exact_len(reader: &mut Self, len: usize) -> Result<Vec<u8>, RdbReadError> {
+        let mut buff = Vec::new();
+        (&mut *reader)
+            .take(len as u64)
+            .read_to_end(&mut buff)
+            .await?;
+        if buff.len() != len {
+            return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into());
+        }
+        Ok(buff)
+    }
+
+    /// Steps over values serialized by a module, up to their EOF opcode.
+    async fn skip_module_data(reader: &mut Self) -> Result<(), RdbReadError> {
+        loop {
+            match reader.read_length_encoded_int().await? {
+                RDB_MODULE_OPCODE_EOF => return Ok(()),
+                RDB_MODULE_OPCODE_SINT | RDB_MODULE_OPCODE_UINT => {
+                    reader.read_length_encoded_int().await?;
+                }
+                RDB_MODULE_OPCODE_FLOAT => {
+                    reader.read_f32_le().await?;
+                }
+                RDB_MODULE_OPCODE_DOUBLE => {
+                    reader.read_f64_le().await?;
+                }
+                RDB_MODULE_OPCODE_STRING => {
+                    reader.read_bytes_encoded().await?;
+                }
+                _ => return Err(RdbReadError::CorruptEncoding("module")),
+            }
+        }
+    }
+
+    /// Reads a hash whose fields may carry expiration times. Fields aren't expired
+    /// individually here, so those already expired are dropped and the rest are kept
+    /// without one.
+    async fn read_hash_with_expiry(
+        reader: &mut Self,
+        value_type: u8,
+    ) -> Result<HashMap<Vec<u8>, Vec<u8>>, RdbReadError> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as u64;
+        let is_live = |expire_at: u64| expire_at == 0 || expire_at > now;
+        // The GA types store the earliest expiration first, which TTLs are relative to.
+        let min_expire = match value_type {
+            RDB_TYPE_HASH_METADATA | RDB_TYPE_HASH_LISTPACK_EX => reader.read_u64_le().await?,
+            _ => 0,
         };
 
+        let mut hash = HashMap::new();
+        if matches!(
+            value_type,
+            RDB_TYPE_HASH_METADATA | RDB_TYPE_HASH_METADATA_PRE_GA
+        ) {
+            let len = reader.read_length_encoded_int().await?;
+            for _ in 0..len {
+                let mut expire_at = reader.read_length_encoded_int().await? as u64;
+                if value_type == RDB_TYPE_HASH_METADATA && expire_at != 0 {
+                    expire_at = expire_at.wrapping_add(min_expire).wrapping_sub(1);
+                }
+                let field = reader.read_bytes_encoded().await?;
+                let value = reader.read_bytes_encoded().await?;
+                if is_live(expire_at) {
+                    hash.insert(field, value);
+                }
+            }
+        } else {
+            // A listpack of field, value, expiration triplets, with 0 for no expiration.
+            let entries = decode_listpack(&reader.read_bytes_encoded().await?)?;
+            if !entries.len().is_multiple_of(3) {
+                return Err(RdbReadError::CorruptEncoding("listpack"));
+            }
+            let mut entries = entries.into_iter();
+            while let (Some(field), Some(value), Some(expire_at)) =
+                (entries.next(), entries.next(), entries.next())
+            {
+                let expire_at = expire_at
+                    .as_integer()
+                    .ok_or(RdbReadError::CorruptEncoding("listpack"))?;
+                if is_live(expire_at as u64) {
+                    hash.insert(field.into_bytes(), value.into_bytes());
+                }
+            }
+        }
+        Ok(hash)
+    }
+
+    /// Reads a score of the old ZSET type, stored as a length-prefixed decimal string.
+    async fn read_double_string(reader: &mut Self) -> Result<f64, RdbReadError> {
+        let value = match reader.read_u8().await? {
+            253 => f64::NAN,
+            254 => f64::INFINITY,
+            255 => f64::NEG_INFINITY,
+            len => {
+                let mut buff = vec![0; len as usize];
+                reader.read_exact(&mut buff).await?;
+                std::str::from_utf8(&buff)?
+                    .parse()
+                    .map_err(|_| RdbReadError::CorruptEncoding("sorted set score"))?
+            }
+        };
         Ok(value)
     }
+
+    async fn read_stream_id(reader: &mut Self) -> Result<StreamId, RdbReadError> {
+        let ms = reader.read_length_encoded_int().await? as u64;
+        let seq = reader.read_length_encoded_int().await? as u64;
+        Ok(StreamId { ms, seq })
+    }
+
+    async fn read_raw_stream_id(reader: &mut Self) -> Result<StreamId, RdbReadError> {
+        let mut raw = [0u8; 16];
+        reader.read_exact(&mut raw).await?;
+        StreamId::from_be_bytes(&raw).ok_or(RdbReadError::CorruptEncoding("stream"))
+    }
+
+    async fn read_stream(reader: &mut Self, value_type: u8) -> Result<Stream, RdbReadError> {
+        let mut stream = Stream::default();
+        let nodes = reader.read_length_encoded_int().await?;
+        for _ in 0..nodes {
+            let master_id = StreamId::from_be_bytes(&reader.read_bytes_encoded().await?)
+                .ok_or(RdbReadError::CorruptEncoding("stream"))?;
+            let entries = decode_listpack(&reader.read_bytes_encoded().await?)?;
+            decode_stream_node(master_id, entries, &mut stream.entries)
+                .ok_or(RdbReadError::CorruptEncoding("stream"))?;
+        }
+
+        let _length = reader.read_length_encoded_int().await?;
+        stream.last_id = Self::read_stream_id(reader).await?;
+        if value_type >= RDB_TYPE_STREAM_LISTPACKS_2 {
+            stream.first_id = Self::read_stream_id(reader).await?;
+            stream.max_deleted_id = Self::read_stream_id(reader).await?;
+            stream.entries_added = reader.read_length_encoded_int().await? as u64;
+        } else {
+            // Older versions didn't keep these; derive them as Redis does on load.
+            stream.first_id = stream.entries.keys().next().copied().unwrap_or_default();
+            stream.entries_added = stream.entries.len() as u64;
+        }
+
+        let groups = reader.read_length_encoded_int().await?;
+        for _ in 0..groups {
+            let name = reader.read_string_encoded("stream group name").await?;
+            let last_id = Self::read_stream_id(reader).await?;
+            let entries_read = if value_type >= RDB_TYPE_STREAM_LISTPACKS_2 {
+                reader.read_length_encoded_int().await? as i64
+            } else if last_id >= stream.last_id {
+                stream.entries_added as i64
+            } else {
+                -1
+            };
+
+            let mut pending = BTreeMap::new();
+            let pending_len = reader.read_length_encoded_int().await?;
+            for _ in 0..pending_len {
+                let id = Self::read_raw_stream_id(reader).await?;
+                let delivery_time = reader.read_u64_le().await?;
+                let delivery_count = reader.read_length_encoded_int().await? as u64;
+                pending.insert(
+                    id,
+                    PendingEntry {
+                        consumer: String::new(),
+                        delivery_time,
+                        delivery_count,
+                    },
+                );
+            }
+
+            let mut consumers = BTreeMap::new();
+            let consumers_len = reader.read_length_encoded_int().await?;
+            for _ in 0..consumers_len {
+                let consumer_name = reader.read_string_encoded("stream consumer name").await?;
+                let seen_time = reader.read_u64_le().await?;
+                let active_time = if value_type == RDB_TYPE_STREAM_LISTPACKS_3 {
+                    reader.read_u64_le().await?
+                } else {
+                    seen_time
+                };
+                let mut consumer_pending = BTreeSet::new();
+                let consumer_pending_len = reader.read_length_encoded_int().await?;
+                for _ in 0..consumer_pending_len {
+                    let id = Self::read_raw_stream_id(reader).await?;
+                    // Every entry a consumer holds must be in the group's PEL.
+                    let entry = pending
+                        .get_mut(&id)
+                        .ok_or(RdbReadError::CorruptEncoding("stream"))?;
+                    entry.consumer = consumer_name.clone();
+                    consumer_pending.insert(id);
+                }
+                consumers.insert(
+                    consumer_name,
+                    Consumer {
+                        seen_time,
+                        active_time,
+                        pending: consumer_pending,
+                    },
+                );
+            }
+            if pending.values().any(|entry| entry.consumer.is_empty()) {
+                return Err(RdbReadError::CorruptEncoding("stream"));
+            }
+
+            stream.groups.insert(
+                name,
+                ConsumerGroup {
+                    last_id,
+                    entries_read,
+                    pending,
+                    consumers,
+                },
+            );
+        }
+        Ok(stream)
+    }
+}
+
+/// Whether `value` is a list, set, hash or sorted set without elements. Streams can
+/// legitimately be empty.
+fn is_empty_collection(value: &Data) -> bool {
+    match value {
+        Data::List(list) => list.is_empty(),
+        Data::Set(set) => set.is_empty(),
+        Data::Hash(hash) => hash.is_empty(),
+        Data::SortedSet(zset) => zset.is_empty(),
+        Data::String(_) | Data::Stream(_) => false,
+    }
+}
+
+fn decode_listpack(blob: &[u8]) -> Result<Vec<ListpackEntry>, RdbReadError> {
+    listpack::decode(blob).ok_or(RdbReadError::CorruptEncoding("listpack"))
+}
+
+fn decode_ziplist(blob: &[u8]) -> Result<Vec<ListpackEntry>, RdbReadError> {
+    ziplist::decode_ziplist(blob).ok_or(RdbReadError::CorruptEncoding("ziplist"))
+}
+
+/// Groups the flat field/value (or member/score) entries of a hash or sorted set.
+fn into_pairs(
+    entries: Vec<ListpackEntry>,
+) -> Result<Vec<(ListpackEntry, ListpackEntry)>, RdbReadError> {
+    if !entries.len().is_multiple_of(2) {
+        return Err(RdbReadError::CorruptEncoding("listpack"));
+    }
+    let mut entries = entries.into_iter();
+    let mut pairs = Vec::new();
+    while let (Some(first), Some(second)) = (entries.next(), entries.next()) {
+        pairs.push((first, second));
+    }
+    Ok(pairs)
+}
+
+/// Decodes the entries of a stream listpack node: a master entry holding the fields
+/// shared by most entries, then every entry with its id as a delta from `master_id`.
+fn decode_stream_node(
+    master_id: StreamId,
+    entries: Vec<ListpackEntry>,
+    into: &mut BTreeMap<StreamId, StreamFields>,
+) -> Option<()> {
+    fn next_integer(entries: &mut std::vec::IntoIter<ListpackEntry>) -> Option<i64> {
+        entries.next().and_then(|entry| entry.as_integer())
+    }
+
+    let mut entries = entries.into_iter();
+
+    let _count = next_integer(&mut entries)?;
+    let _deleted = next_integer(&mut entries)?;
+    let master_fields_len = next_integer(&mut entries)? as usize;
+    let master_fields = (0..master_fields_len)
+        .map(|_| entries.next().map(ListpackEntry::into_bytes))
+        .collect::<Option<Vec<_>>>()?;
+    // The master entry is terminated by a zero.
+    next_integer(&mut entries)?;
+
+    while entries.len() > 0 {
+        let flags = next_integer(&mut entries)?;
+        let ms_diff = next_integer(&mut entries)?;
+        let seq_diff = next_integer(&mut entries)?;
+        let id = StreamId {
+            ms: master_id.ms.wrapping_add(ms_diff as u64),
+            seq: master_id.seq.wrapping_add(seq_diff as u64),
+        };
+        let fields = if flags & STREAM_ITEM_FLAG_SAMEFIELDS != 0 {
+            master_fields
+                .iter()
+                .map(|field| Some((field.clone(), entries.next()?.into_bytes())))
+                .collect::<Option<Vec<_>>>()?
+        } else {
+            let fields_len = next_integer(&mut entries)? as usize;
+            (0..fields_len)
+                .map(|_| {
+                    let field = entries.next()?.into_bytes();
+                    Some((field, entries.next()?.into_bytes()))
+                })
+                .collect::<Option<Vec<_>>>()?
+        };
+        // Number of listpack elements the entry used, for backwards iteration.
+        next_integer(&mut entries)?;
+        if flags & STREAM_ITEM_FLAG_DELETED == 0 {
+            into.insert(id, fields);
+        }
+    }
+    Some(())
+}
+
+/// Recovers the 9 character name a module type id was derived from.
+fn module_type_name(module_id: u64) -> String {
+    const CHARSET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789-_";
+    let mut id = module_id >> 10;
+    let mut name = [0u8; 9];
+    for c in name.iter_mut().rev() {
+        *c = CHARSET[(id & 63) as usize];
+        id >>= 6;
+    }
+    String::from_utf8_lossy(&name).to_string()
 }
 
 #[async_trait]
-impl RdbBufReader for BufReader<File> {
+impl<R: AsyncRead + Unpin + Send> RdbBufReader for R {
     async fn read_length_encoded_int(&mut self) -> Result<usize, RdbReadError> {
         let (encoding, length) = Self::read_length_encoding(self).await?;
         let value = Self::interpret_length_encoding(self, encoding, length).await?;
@@ -212,31 +918,40 @@ impl RdbBufReader for BufReader<File> {
         Ok(value)
     }
 
-    async fn read_string_encoded(&mut self) -> Result<String, RdbReadError> {
+    /// Reads a string that must be text, such as metadata or a stream group name. Keys
+    /// and values are binary and read with `read_bytes_encoded` instead.
+    async fn read_string_encoded(&mut self, what: &'static str) -> Result<String, RdbReadError> {
+        let bytes = self.read_bytes_encoded().await?;
+        String::from_utf8(bytes).map_err(|_| RdbReadError::InvalidUtf8(what))
+    }
+
+    async fn read_bytes_encoded(&mut self) -> Result<Vec<u8>, RdbReadError> {
         let (encoding, length) = Self::read_length_encoding(self).await?;
         if encoding == LengthEncoding::SpecialFormat {
             let value = match length {
-                0 => self.read_u8().await? as usize,
-                1 => self.read_u16_le().await? as usize,
-                2 => self.read_u32_le().await? as usize,
-                3 => todo!("Compressed string not implemented"),
-                _ => panic!("Invalid SpecialFormat for string encoding! {}", length),
+                0 => self.read_i8().await? as i64,
+                1 => self.read_i16_le().await? as i64,
+                2 => self.read_i32_le().await? as i64,
+                3 => {
+                    let compressed_len = self.read_length_encoded_int().await?;
+                    let len = self.read_length_encoded_int().await?;
+                    let compressed = Self::read_exact_len(self, compressed_len).await?;
+                    return Ok(lzf::decompress(&compressed, len)?);
+                }
+                _ => return Err(RdbReadError::InvalidStringEncoding(length)),
             };
 
-            Ok(value.to_string())
+            Ok(value.to_string().into_bytes())
         } else {
             let length = Self::interpret_length_encoding(self, encoding, length).await?;
-            let mut buff = vec![0; length];
-            self.read_exact(&mut buff).await?;
-
-            Ok(String::from_utf8_lossy(&buff).to_string())
+            Self::read_exact_len(self, length).await
         }
     }
 
     async fn read_expiry_timestamp(&mut self, opcode: u8) -> Result<ExpiryTimestamp, RdbReadError> {
         let value = match opcode {
-            0xFD => ExpiryTimestamp::Seconds(self.read_u32_le().await?),
-            0xFC => ExpiryTimestamp::Milliseconds(self.read_u64_le().await?),
+            RDB_OPCODE_EXPIRETIME => ExpiryTimestamp::Seconds(self.read_u32_le().await?),
+            RDB_OPCODE_EXPIRETIME_MS => ExpiryTimestamp::Milliseconds(self.read_u64_le().await?),
             _ => return Err(RdbReadError::InvalidExpiryTimestampFlag(opcode)),
         };
         Ok(value)
@@ -245,14 +960,14 @@ impl RdbBufReader for BufReader<File> {
     async fn read_key_value(
         &mut self,
         known_type: Option<u8>,
-    ) -> Result<(String, String), RdbReadError> {
+    ) -> Result<(Vec<u8>, Data), RdbReadError> {
         let value_type = if let Some(known_type) = known_type {
             known_type
         } else {
             self.read_u8().await?
         };
 
-        let key = self.read_string_encoded().await?;
+        let key = self.read_bytes_encoded().await?;
         let value = Self::read_value_type(self, value_type).await?;
         Ok((key, value))
     }
@@ -269,5 +984,1005 @@ enum LengthEncoding {
     Remaining6Bits,
     RemainingAndNextByte,
     DiscardRemainingGetNext4Bytes,
+    DiscardRemainingGetNext8Bytes,
     SpecialFormat,
 }
+
+/// Serializes databases in the RDB format. The output is built in memory, so the
+/// checksum can be computed over it before it is written anywhere.
+pub struct RdbWriter {
+    buff: Vec<u8>,
+    compression: bool,
+}
+
+impl RdbWriter {
+    /// Starts a new RDB stream with the `REDIS<version>` header. With `compression`, long
+    /// strings are LZF compressed when that makes them smaller.
+    pub fn new(compression: bool) -> Self {
+        let mut buff = Vec::with_capacity(1024);
+        buff.extend_from_slice(format!("REDIS{:04}", RDB_VERSION).as_bytes());
+        Self { buff, compression }
+    }
+
+    /// Serializes every database with the usual aux fields, ready to be written to disk.
+    pub fn write_snapshot(
+        databases: &HashMap<usize, Database>,
+        compression: bool,
+        checksum: bool,
+    ) -> Vec<u8> {
+        let mut body = Self {
+            buff: Vec::new(),
+            compression,
+        };
+        let mut db_ids = databases.keys().copied().collect::<Vec<_>>();
+        db_ids.sort();
+        for db_id in db_ids {
+            body.write_database(db_id, &databases[&db_id]);
+        }
+
+        let ctime = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let mut writer = Self::new(compression);
+        writer.write_aux("redis-ver", SERVER_VERSION);
+        writer.write_aux("redis-bits", "64");
+        writer.write_aux("ctime", &ctime.to_string());
+        // There is no allocator to ask, so report the size of the serialized dataset.
+        writer.write_aux("used-mem", &body.buff.len().to_string());
+        writer.write_aux("aof-base", "0");
+        writer.buff.extend_from_slice(&body.buff);
+        writer.finish(checksum)
+    }
+
+    pub fn write_aux(&mut self, key: &str, value: &str) {
+        self.buff.push(RDB_OPCODE_AUX);
+        self.write_bytes(key.as_bytes());
+        self.write_bytes(value.as_bytes());
+    }
+
+    /// Writes the live keys of a database; nothing is written if it has none.
+    pub fn write_database(&mut self, db_id: usize, database: &Database) {
+        let now = SystemTime::now();
+        let live = database
+            .iter()
+            .filter(|(_, entry)| entry.expiry.is_none_or(|expiry| expiry >= now))
+            .collect::<Vec<_>>();
+        if live.is_empty() {
+            return;
+        }
+        let expires = live
+            .iter()
+            .filter(|(_, entry)| entry.expiry.is_some())
+            .count();
+
+        self.buff.push(RDB_OPCODE_SELECTDB);
+        self.write_length(db_id as u64);
+        self.buff.push(RDB_OPCODE_RESIZEDB);
+        self.write_length(live.len() as u64);
+        self.write_length(expires as u64);
+        for (key, entry) in live {
+            self.write_key_value(key, entry);
+        }
+    }
+
+    pub fn write_key_value(&mut self, key: &[u8], entry: &Value) {
+        if let Some(expiry) = entry.expiry {
+            let millis = expiry
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_millis() as u64;
+            self.buff.push(RDB_OPCODE_EXPIRETIME_MS);
+            self.buff.extend_from_slice(&millis.to_le_bytes());
+        }
+        match entry.access {
+            Some(AccessHint::LastAccess(last_access)) => {
+                let idle = SystemTime::now()
+                    .duration_since(last_access)
+                    .unwrap_or_default();
+                self.buff.push(RDB_OPCODE_IDLE);
+                self.write_length(idle.as_secs());
+            }
+            Some(AccessHint::Frequency(counter)) => {
+                self.buff.push(RDB_OPCODE_FREQ);
+                self.buff.push(counter);
+            }
+            None => {}
+        }
+        self.buff.push(Self::value_type(&entry.value));
+        self.write_bytes(key);
+        self.write_value(&entry.value);
+    }
+
+    /// Serializes a value as DUMP does: its RDB type and encoding, followed by the RDB
+    /// version and a CRC64 of everything before it.
+    pub fn dump_value(value: &Data, compression: bool) -> Vec<u8> {
+        let mut writer = Self {
+            buff: Vec::new(),
+            compression,
+        };
+        writer.buff.push(Self::value_type(value));
+        writer.write_value(value);
+        writer.buff.extend_from_slice(&RDB_VERSION.to_le_bytes());
+        let checksum = crc64(0, &writer.buff);
+        writer.buff.extend_from_slice(&checksum.to_le_bytes());
+        writer.buff
+    }
+
+    fn value_type(value: &Data) -> u8 {
+        match value {
+            Data::String(_) => RDB_TYPE_STRING,
+            Data::List(_) => RDB_TYPE_LIST_QUICKLIST_2,
+            Data::Set(_) => RDB_TYPE_SET,
+            Data::Hash(_) => RDB_TYPE_HASH,
+            Data::SortedSet(_) => RDB_TYPE_ZSET_2,
+            Data::Stream(_) => RDB_TYPE_STREAM_LISTPACKS_3,
+        }
+    }
+
+    fn write_value(&mut self, value: &Data) {
+        match value {
+            Data::String(bytes) => self.write_bytes(bytes),
+            Data::List(list) => {
+                let elements = list.iter().collect::<Vec<_>>();
+                let nodes = elements.chunks(LIST_NODE_MAX_ENTRIES);
+                self.write_length(nodes.len() as u64);
+                for node in nodes {
+                    let mut listpack = ListpackWriter::new();
+                    for element in node {
+                        listpack.push(element);
+                    }
+                    self.write_length(QUICKLIST_NODE_CONTAINER_PACKED as u64);
+                    self.write_bytes(&listpack.finish());
+                }
+            }
+            Data::Set(set) => {
+                self.write_length(set.len() as u64);
+                for member in set {
+                    self.write_bytes(member);
+                }
+            }
+            Data::Hash(hash) => {
+                self.write_length(hash.len() as u64);
+                for (field, value) in hash {
+                    self.write_bytes(field);
+                    self.write_bytes(value);
+                }
+            }
+            Data::SortedSet(zset) => {
+                let members = zset.iter().collect::<Vec<_>>();
+                self.write_length(members.len() as u64);
+                for (member, score) in members {
+                    self.write_bytes(member);
+                    self.buff.extend_from_slice(&score.to_le_bytes());
+                }
+            }
+            Data::Stream(stream) => self.write_stream(stream),
+        }
+    }
+
+    fn write_stream_id(&mut self, id: StreamId) {
+        self.write_length(id.ms);
+        self.write_length(id.seq);
+    }
+
+    fn write_stream(&mut self, stream: &Stream) {
+        let entries = stream.entries.iter().collect::<Vec<_>>();
+        let nodes = entries.chunks(STREAM_NODE_MAX_ENTRIES);
+        self.write_length(nodes.len() as u64);
+        for node in nodes {
+            let (master_id, master_fields) = node[0];
+            let master_fields = master_fields
+                .iter()
+                .map(|(field, _)| field)
+                .collect::<Vec<_>>();
+            let mut listpack = ListpackWriter::new();
+            listpack.push_integer(node.len() as i64);
+            listpack.push_integer(0);
+            listpack.push_integer(master_fields.len() as i64);
+            for field in master_fields.iter() {
+                listpack.push(field);
+            }
+            listpack.push_integer(0);
+
+            for (id, fields) in node {
+                let same_fields = fields.len() == master_fields.len()
+                    && fields
+                        .iter()
+                        .zip(master_fields.iter())
+                        .all(|((field, _), master_field)| field == *master_field);
+                let flags = if same_fields {
+                    STREAM_ITEM_FLAG_SAMEFIELDS
+                } else {
+                    0
+                };
+                listpack.push_integer(flags);
+                listpack.push_integer(id.ms.wrapping_sub(master_id.ms) as i64);
+                listpack.push_integer(id.seq.wrapping_sub(master_id.seq) as i64);
+                if same_fields {
+                    for (_, value) in fields.iter() {
+                        listpack.push(value);
+                    }
+                } else {
+                    listpack.push_integer(fields.len() as i64);
+                    for (field, value) in fields.iter() {
+                        listpack.push(field);
+                        listpack.push(value);
+                    }
+                }
+                let elements = if same_fields {
+                    fields.len() + 3
+                } else {
+                    fields.len() * 2 + 4
+                };
+                listpack.push_integer(elements as i64);
+            }
+
+            self.write_bytes(&master_id.to_be_bytes());
+            self.write_bytes(&listpack.finish());
+        }
+
+        self.write_length(stream.entries.len() as u64);
+        self.write_stream_id(stream.last_id);
+        self.write_stream_id(stream.first_id);
+        self.write_stream_id(stream.max_deleted_id);
+        self.write_length(stream.entries_added);
+
+        self.write_length(stream.groups.len() as u64);
+        for (name, group) in stream.groups.iter() {
+            self.write_bytes(name.as_bytes());
+            self.write_stream_id(group.last_id);
+            self.write_length(group.entries_read as u64);
+            self.write_length(group.pending.len() as u64);
+            for (id, entry) in group.pending.iter() {
+                self.buff.extend_from_slice(&id.to_be_bytes());
+                self.buff
+                    .extend_from_slice(&entry.delivery_time.to_le_bytes());
+                self.write_length(entry.delivery_count);
+            }
+            self.write_length(group.consumers.len() as u64);
+            for (name, consumer) in group.consumers.iter() {
+                self.write_bytes(name.as_bytes());
+                self.buff
+                    .extend_from_slice(&consumer.seen_time.to_le_bytes());
+                self.buff
+                    .extend_from_slice(&consumer.active_time.to_le_bytes());
+                self.write_length(consumer.pending.len() as u64);
+                for id in consumer.pending.iter() {
+                    self.buff.extend_from_slice(&id.to_be_bytes());
+                }
+            }
+        }
+    }
+
+    fn write_length(&mut self, length: u64) {
+        if length < 1 << 6 {
+            self.buff.push(length as u8);
+        } else if length < 1 << 14 {
+            self.buff.push(0x40 | (length >> 8) as u8);
+            self.buff.push(length as u8);
+        } else if length <= u32::MAX as u64 {
+            self.buff.push(0x80);
+            self.buff.extend_from_slice(&(length as u32).to_be_bytes());
+        } else {
+            self.buff.push(0x81);
+            self.buff.extend_from_slice(&length.to_be_bytes());
+        }
+    }
+
+    fn write_bytes(&mut self, bytes: &[u8]) {
+        // Like Redis, only bother for strings over 20 bytes, and keep the result only if it
+        // saves at least 4 bytes.
+        if self.compression && bytes.len() > 20 {
+            if let Some(compressed) = lzf::compress(bytes, bytes.len() - 4) {
+                self.buff.push(0xC0 | RDB_ENC_LZF);
+                self.write_length(compressed.len() as u64);
+                self.write_length(bytes.len() as u64);
+                self.buff.extend_from_slice(&compressed);
+                return;
+            }
+        }
+        self.write_length(bytes.len() as u64);
+        self.buff.extend_from_slice(bytes);
+    }
+
+    /// Terminates the stream with the EOF opcode and the CRC64 of everything before it,
+    /// or zero if `checksum` is false.
+    pub fn finish(mut self, checksum: bool) -> Vec<u8> {
+        self.buff.push(RDB_OPCODE_EOF);
+        let checksum = if checksum { crc64(0, &self.buff) } else { 0 };
+        self.buff.extend_from_slice(&checksum.to_le_bytes());
+        self.buff
+    }
+
+    /// Writes `contents` to a temporary file next to `path`, then renames it over `path`
+    /// so readers never observe a partially written database.
+    pub async fn write_file(path: impl AsRef<Path>, contents: &[u8]) -> tokio::io::Result<()> {
+        let path = path.as_ref();
+        let temp_path = Self::write_temp_file(path, contents).await?;
+        let result = tokio::fs::rename(&temp_path, path).await;
+        if result.is_err() {
+            let _ = tokio::fs::remove_file(&temp_path).await;
+        }
+        result
+    }
+
+    /// Writes `contents` to a new temporary file next to `path` and returns its path, for
+    /// callers that decide only afterwards whether it replaces `path`.
+    pub async fn write_temp_file(
+        path: impl AsRef<Path>,
+        contents: &[u8],
+    ) -> tokio::io::Result<PathBuf> {
+        // Each write gets its own temporary file, so concurrent writers never share one.
+        static TEMP_FILES: std::sync::atomic::AtomicU64 = std::sync::atomic::AtomicU64::new(0);
+        let temp_path = path.as_ref().with_file_name(format!(
+            "temp-{}-{}.rdb",
+            std::process::id(),
+            TEMP_FILES.fetch_add(1, std::sync::atomic::Ordering::Relaxed)
+        ));
+        let result = async {
+            let mut file = File::create(&temp_path).await?;
+            file.write_all(contents).await?;
+            file.sync_all().await
+        }
+        .await;
+        match result {
+            Ok(()) => Ok(temp_path),
+            Err(e) => {
+                let _ = tokio::fs::remove_file(&temp_path).await;
+                Err(e)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::corrupt::assert_rejects_damage;
+    use std::sync::Arc;
+
+    fn sample_values() -> Vec<Data> {
+        vec![
+            Data::String(b"hello".repeat(40)),
+            Data::List((0..50).map(|i| format!("item{}", i).into_bytes()).collect()),
+            Data::Hash(
+                (0..20)
+                    .map(|i| (format!("f{}", i).into_bytes(), b"v".repeat(i)))
+                    .collect(),
+            ),
+        ]
+    }
+
+    /// Replaces the CRC64 footer so a tampered payload still passes the checksum.
+    fn resign(payload: &mut Vec<u8>) {
+        payload.truncate(payload.len() - 8);
+        let crc = crc64(0, payload);
+        payload.extend_from_slice(&crc.to_le_bytes());
+    }
+
+    /// A DUMP payload of `body`, a value type followed by the value, signed with the RDB
+    /// `version` of the Redis that produced it.
+    fn signed(body: &[u8], version: u16) -> Vec<u8> {
+        let mut payload = body.to_vec();
+        payload.extend_from_slice(&version.to_le_bytes());
+        payload.extend_from_slice(&crc64(0, &payload).to_le_bytes());
+        payload
+    }
+
+    /// `bytes` as an RDB string with a one byte length.
+    fn short_string(bytes: &[u8]) -> Vec<u8> {
+        [&[bytes.len() as u8][..], bytes].concat()
+    }
+
+    async fn restore(body: &[u8], version: u16) -> Data {
+        RdbReader::read_dump(&signed(body, version)).await.unwrap()
+    }
+
+    fn list(value: Data) -> Vec<Vec<u8>> {
+        match value {
+            Data::List(list) => list.into(),
+            other => panic!("expected a list, got {:?}", other),
+        }
+    }
+
+    fn hash(value: Data) -> HashMap<Vec<u8>, Vec<u8>> {
+        match value {
+            Data::Hash(hash) => hash,
+            other => panic!("expected a hash, got {:?}", other),
+        }
+    }
+
+    fn pairs(pairs: &[(&str, &str)]) -> HashMap<Vec<u8>, Vec<u8>> {
+        pairs
+            .iter()
+            .map(|(field, value)| (field.as_bytes().to_vec(), value.as_bytes().to_vec()))
+            .collect()
+    }
+
+    /// The listpack Redis builds for "a", "b".
+    const LISTPACK_A_B: &[u8] = b"\x0d\x00\x00\x00\x02\x00\x81a\x02\x81b\x02\xff";
+
+    #[tokio::test]
+    async fn dumps_like_redis() {
+        let cases = [
+            (Data::String(b"hello".to_vec()), b"\x00\x05hello".to_vec()),
+            (
+                Data::String(b"a".repeat(100)),
+                b"\x00\xc3\x09\x40\x64\x01aa\xe0\x57\x00\x01aa".to_vec(),
+            ),
+            // Redis 7 saves even a small list as a quicklist with one listpack node.
+            (
+                Data::List([b"a".to_vec(), b"b".to_vec()].into()),
+                [b"\x12\x01\x02", &short_string(LISTPACK_A_B)[..]].concat(),
+            ),
+        ];
+        for (value, body) in cases {
+            let payload = signed(&body, RDB_VERSION);
+            assert_eq!(RdbWriter::dump_value(&value, true), payload);
+            let restored = RdbReader::read_dump(&payload).await.unwrap();
+            assert_eq!(format!("{:?}", restored), format!("{:?}", value));
+        }
+    }
+
+    #[tokio::test]
+    async fn reads_redis_string_encodings() {
+        for (body, expected) in [
+            (&b"\x00\xc0\x7b"[..], &b"123"[..]),
+            (b"\x00\xc1\x2c\x01", b"300"),
+            (b"\x00\xc2\x60\x79\xfe\xff", b"-100000"),
+            (b"\x00\x40\x03abc", b"abc"),
+        ] {
+            assert!(matches!(restore(body, 11).await, Data::String(s) if s == expected));
+        }
+    }
+
+    #[tokio::test]
+    async fn reads_redis_lists() {
+        let expected = [b"a".to_vec(), b"b".to_vec()];
+        assert_eq!(list(restore(b"\x01\x02\x01a\x01b", 6).await), expected);
+
+        // Redis 2.6 to 3.0: one ziplist.
+        let ziplist = b"\x13\x00\x00\x00\x10\x00\x00\x00\x03\x00\x00\x01a\x03\x01b\x03\xf8\xff";
+        let mut with_seven = expected.to_vec();
+        with_seven.push(b"7".to_vec());
+        let body = [&b"\x0a"[..], &short_string(ziplist)].concat();
+        assert_eq!(list(restore(&body, 6).await), with_seven);
+
+        // Redis 3.2 to 6.2: a quicklist of ziplists.
+        let body = [
+            &b"\x0e\x02"[..],
+            &short_string(ziplist),
+            &short_string(ziplist),
+        ]
+        .concat();
+        assert_eq!(
+            list(restore(&body, 9).await),
+            [with_seven.clone(), with_seven].concat()
+        );
+
+        // Redis 7: a quicklist of listpacks, with large elements in plain nodes.
+        let body = [
+            &b"\x12\x02\x02"[..],
+            &short_string(LISTPACK_A_B),
+            b"\x01",
+            &short_string(b"plain"),
+        ]
+        .concat();
+        assert_eq!(list(restore(&body, 11).await), [b"a", b"b", &b"plain"[..]]);
+    }
+
+    #[tokio::test]
+    async fn reads_redis_sets() {
+        let members = |value: Data| match value {
+            Data::Set(set) => {
+                let mut members = set.into_iter().collect::<Vec<_>>();
+                members.sort();
+                members
+            }
+            other => panic!("expected a set, got {:?}", other),
+        };
+        assert_eq!(
+            members(restore(b"\x02\x02\x01a\x01b", 6).await),
+            [b"a", b"b"]
+        );
+        let intset = b"\x02\x00\x00\x00\x02\x00\x00\x00\x01\x00\x02\x00";
+        let body = [&b"\x0b"[..], &short_string(intset)].concat();
+        assert_eq!(members(restore(&body, 6).await), [b"1", b"2"]);
+        let body = [&b"\x14"[..], &short_string(LISTPACK_A_B)].concat();
+        assert_eq!(members(restore(&body, 11).await), [b"a", b"b"]);
+    }
+
+    #[tokio::test]
+    async fn reads_redis_sorted_sets() {
+        let zset = |value: Data| match value {
+            Data::SortedSet(zset) => zset,
+            other => panic!("expected a sorted set, got {:?}", other),
+        };
+        // Redis 2.x stored scores as text, with special lengths for the infinities.
+        let old = zset(restore(b"\x03\x02\x01m\x031.5\x01n\xfe", 6).await);
+        assert_eq!(old.score(b"m"), Some(1.5));
+        assert_eq!(old.score(b"n"), Some(f64::INFINITY));
+
+        let body = [&b"\x05\x01\x01m"[..], &1.5f64.to_le_bytes()].concat();
+        assert_eq!(zset(restore(&body, 8).await).score(b"m"), Some(1.5));
+
+        let ziplist = b"\x13\x00\x00\x00\x0d\x00\x00\x00\x02\x00\x00\x01m\x03\x031.5\xff";
+        let body = [&b"\x0c"[..], &short_string(ziplist)].concat();
+        assert_eq!(zset(restore(&body, 6).await).score(b"m"), Some(1.5));
+
+        let listpack = b"\x0c\x00\x00\x00\x02\x00\x81m\x02\x02\x01\xff";
+        let body = [&b"\x11"[..], &short_string(listpack)].concat();
+        assert_eq!(zset(restore(&body, 10).await).score(b"m"), Some(2.0));
+    }
+
+    #[tokio::test]
+    async fn reads_redis_hashes() {
+        let expected = pairs(&[("f", "v")]);
+        assert_eq!(hash(restore(b"\x04\x01\x01f\x01v", 6).await), expected);
+
+        // Redis 2.4 and older: a zipmap.
+        let body = [&b"\x09"[..], &short_string(b"\x01\x01f\x01\x00v\xff")].concat();
+        assert_eq!(hash(restore(&body, 4).await), expected);
+
+        let ziplist = b"\x11\x00\x00\x00\x0d\x00\x00\x00\x02\x00\x00\x01f\x03\x01v\xff";
+        let body = [&b"\x0d"[..], &short_string(ziplist)].concat();
+        assert_eq!(hash(restore(&body, 6).await), expected);
+
+        let listpack = b"\x0d\x00\x00\x00\x02\x00\x81f\x02\x81v\x02\xff";
+        let body = [&b"\x10"[..], &short_string(listpack)].concat();
+        assert_eq!(hash(restore(&body, 10).await), expected);
+    }
+
+    #[tokio::test]
+    async fn reads_redis_hashes_with_field_expiration() {
+        // f1 never expires, f2 expires in 2100 and f3 expired long ago, so it is dropped.
+        let expected = pairs(&[("f1", "v1"), ("f2", "v2")]);
+        let in_2100 = b"\x00\xd8\xc3\x2c\xbb\x03\x00\x00";
+        let listpack = [
+            &b"\x2e\x00\x00\x00\x09\x00"[..],
+            b"\x82f1\x03\x82v1\x03\x00\x01",
+            b"\x82f2\x03\x82v2\x03\xf4",
+            in_2100,
+            b"\x09\x82f3\x03\x82v3\x03\xc3\xe8\x02\xff",
+        ]
+        .concat();
+
+        // Redis 7.4: the listpack follows the earliest expiration time.
+        let min_expire = 1000u64.to_le_bytes();
+        let body = [&b"\x19"[..], &min_expire, &short_string(&listpack)].concat();
+        assert_eq!(hash(restore(&body, 12).await), expected);
+        // Release candidates didn't store it.
+        let body = [&b"\x17"[..], &short_string(&listpack)].concat();
+        assert_eq!(hash(restore(&body, 12).await), expected);
+
+        // Larger hashes: each field's expiration relative to the earliest one, plus one,
+        // or zero for none.
+        let body = [
+            &b"\x18"[..],
+            &min_expire,
+            b"\x03\x00\x02f1\x02v1\x81",
+            &(4102444800000u64 - 1000 + 1).to_be_bytes(),
+            b"\x02f2\x02v2\x01\x02f3\x02v3",
+        ]
+        .concat();
+        assert_eq!(hash(restore(&body, 12).await), expected);
+        // Release candidates stored absolute times.
+        let body = [
+            &b"\x16\x03\x00\x02f1\x02v1\x81"[..],
+            &4102444800000u64.to_be_bytes(),
+            b"\x02f2\x02v2\x43\xe8\x02f3\x02v3",
+        ]
+        .concat();
+        assert_eq!(hash(restore(&body, 12).await), expected);
+    }
+
+    /// The body of a stream as `value_type` stores it: entries 1700000000000-0 with
+    /// temp=20 and 1700000000001-1 with hum=50, and group "g" where alice has the first
+    /// entry pending.
+    fn stream_body(value_type: u8) -> Vec<u8> {
+        let first = b"\x00\x00\x01\x8b\xcf\xe5\x68\x00\x00\x00\x00\x00\x00\x00\x00\x00";
+        let listpack = [
+            &b"\x30\x00\x00\x00\x11\x00"[..],
+            // Entry count, deleted count, the master fields and their terminator.
+            b"\x02\x01\x00\x01\x01\x01\x84temp\x05\x00\x01",
+            // Same fields as the master entry: flags, id deltas, values, element count.
+            b"\x02\x01\x00\x01\x00\x01\x14\x01\x04\x01",
+            // Its own fields.
+            b"\x00\x01\x01\x01\x01\x01\x01\x01\x83hum\x04\x32\x01\x06\x01",
+            b"\xff",
+        ]
+        .concat();
+        let first_ms = b"\x81\x00\x00\x01\x8b\xcf\xe5\x68\x00";
+        let v2 = value_type >= RDB_TYPE_STREAM_LISTPACKS_2;
+        let mut body = vec![value_type, 0x01];
+        body.extend_from_slice(&short_string(first));
+        body.extend_from_slice(&short_string(&listpack));
+        // Length and last id.
+        body.extend_from_slice(b"\x02\x81\x00\x00\x01\x8b\xcf\xe5\x68\x01\x01");
+        if v2 {
+            // First id, max deleted id and entries added.
+            body.extend_from_slice(first_ms);
+            body.extend_from_slice(b"\x00\x00\x00\x02");
+        }
+        body.extend_from_slice(b"\x01\x01g");
+        body.extend_from_slice(first_ms);
+        body.push(0x00);
+        if v2 {
+            // Entries read.
+            body.push(0x01);
+        }
+        // The group's pending entries: id, delivery time and count.
+        body.push(0x01);
+        body.extend_from_slice(first);
+        body.extend_from_slice(&1700000000500u64.to_le_bytes());
+        body.push(0x01);
+        // Consumers: name, seen time, active time since Redis 7.2, and pending ids.
+        body.extend_from_slice(b"\x01\x05alice");
+        body.extend_from_slice(&1700000001000u64.to_le_bytes());
+        if value_type == RDB_TYPE_STREAM_LISTPACKS_3 {
+            body.extend_from_slice(&1700000002000u64.to_le_bytes());
+        }
+        body.push(0x01);
+        body.extend_from_slice(first);
+        body
+    }
+
+    #[tokio::test]
+    async fn reads_redis_streams() {
+        let first = StreamId {
+            ms: 1700000000000,
+            seq: 0,
+        };
+        let second = StreamId {
+            ms: 1700000000001,
+            seq: 1,
+        };
+        for (value_type, version, active_time) in [
+            (RDB_TYPE_STREAM_LISTPACKS, 9, 1700000001000),
+            (RDB_TYPE_STREAM_LISTPACKS_2, 10, 1700000001000),
+            (RDB_TYPE_STREAM_LISTPACKS_3, 11, 1700000002000),
+        ] {
+            let Data::Stream(stream) = restore(&stream_body(value_type), version).await else {
+                panic!("expected a stream");
+            };
+            let entries = stream.entries.into_iter().collect::<Vec<_>>();
+            assert_eq!(
+                entries,
+                [
+                    (first, vec![(b"temp".to_vec(), b"20".to_vec())]),
+                    (second, vec![(b"hum".to_vec(), b"50".to_vec())]),
+                ]
+            );
+            assert_eq!(stream.last_id, second);
+            assert_eq!(stream.first_id, first);
+            assert_eq!(stream.entries_added, 2);
+            let group = &stream.groups["g"];
+            assert_eq!(group.last_id, first);
+            // Older streams don't know, and the group isn't at the last id.
+            let entries_read = if value_type == RDB_TYPE_STREAM_LISTPACKS {
+                -1
+            } else {
+                1
+            };
+            assert_eq!(group.entries_read, entries_read);
+            assert_eq!(group.pending[&first].consumer, "alice");
+            assert_eq!(group.pending[&first].delivery_time, 1700000000500);
+            let alice = &group.consumers["alice"];
+            assert_eq!(alice.seen_time, 1700000001000);
+            assert_eq!(alice.active_time, active_time);
+            assert!(alice.pending.contains(&first));
+        }
+    }
+
+    #[test]
+    fn dump_rejects_bad_checksum() {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let payload = RdbWriter::dump_value(&sample_values()[0], false);
+        assert_rejects_damage(&payload, |bytes| {
+            runtime.block_on(RdbReader::read_dump(bytes)).is_ok()
+        });
+        let mut corrupted = payload.clone();
+        let last = corrupted.len() - 1;
+        corrupted[last] ^= 0xFF;
+        assert!(matches!(
+            runtime.block_on(RdbReader::read_dump(&corrupted)),
+            Err(RdbReadError::DumpPayloadMismatch)
+        ));
+    }
+
+    #[test]
+    fn dump_rejects_malformed_body() {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        for value in sample_values() {
+            let payload = RdbWriter::dump_value(&value, true);
+            // Damage only the body, re-signed so the checksum doesn't catch it first.
+            let (body, footer) = payload.split_at(payload.len() - 10);
+            assert_rejects_damage(body, |body| {
+                let mut damaged = [body, footer].concat();
+                resign(&mut damaged);
+                runtime.block_on(RdbReader::read_dump(&damaged)).is_ok()
+            });
+        }
+    }
+
+    #[test]
+    fn snapshot_rejects_damage() {
+        let mut database = Database::new();
+        for (i, value) in sample_values().into_iter().enumerate() {
+            database.insert(
+                format!("key{}", i).into_bytes(),
+                Arc::new(Value {
+                    value,
+                    expiry: None,
+                    access: None,
+                }),
+            );
+        }
+        let databases = HashMap::from([(0, database)]);
+        let contents = RdbWriter::write_snapshot(&databases, true, true);
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let data = runtime
+            .block_on(RdbReader::read_from(contents.as_slice(), true))
+            .unwrap();
+        assert_eq!(data.databases[&0].len(), 3);
+        assert_rejects_damage(&contents, |bytes| {
+            runtime.block_on(RdbReader::read_from(bytes, true)).is_ok()
+        });
+    }
+
+    #[tokio::test]
+    async fn snapshot_keeps_binary_keys_and_members() {
+        // Both members would read back as the same U+FFFD string if decoded lossily.
+        let mut zset = SortedSet::new();
+        zset.insert(b"\xff".to_vec(), 1.0);
+        zset.insert(b"\xfe".to_vec(), 2.0);
+        let database = Database::from([
+            (
+                b"\xffkey".to_vec(),
+                Arc::new(Value::string(b"\x00\xff".to_vec())),
+            ),
+            (
+                b"zset\x80".to_vec(),
+                Arc::new(Value {
+                    value: Data::SortedSet(zset),
+                    expiry: None,
+                    access: None,
+                }),
+            ),
+        ]);
+        let contents = RdbWriter::write_snapshot(&HashMap::from([(0, database)]), false, true);
+        let data = RdbReader::read_from(contents.as_slice(), true)
+            .await
+            .unwrap();
+        let database = &data.databases[&0];
+        assert!(matches!(&database[&b"\xffkey".to_vec()], Data::String(v) if v == b"\x00\xff"));
+        let Data::SortedSet(zset) = &database[&b"zset\x80".to_vec()] else {
+            panic!("expected a sorted set");
+        };
+        assert_eq!(zset.score(b"\xff"), Some(1.0));
+        assert_eq!(zset.score(b"\xfe"), Some(2.0));
+    }
+
+    /// An RDB file of `version` with `body` between the header and the EOF opcode, and the
+    /// checksum Redis appends from version 5 on.
+    fn rdb_file(version: u16, body: &[u8]) -> Vec<u8> {
+        let mut file = format!("REDIS{:04}", version).into_bytes();
+        file.extend_from_slice(body);
+        file.push(RDB_OPCODE_EOF);
+        if version >= 5 {
+            file.extend_from_slice(&crc64(0, &file).to_le_bytes());
+        }
+        file
+    }
+
+    async fn load(version: u16, body: &[u8]) -> RdbData {
+        RdbReader::read_from(rdb_file(version, body).as_slice(), true)
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn reads_every_rdb_version() {
+        let zset = |score| {
+            let mut zset = SortedSet::new();
+            zset.insert(b"m".to_vec(), score);
+            Data::SortedSet(zset)
+        };
+        let hash = Data::Hash(pairs(&[("f", "v")]));
+        let list = Data::List([b"a".to_vec(), b"b".to_vec()].into());
+        // Each file selects database 0 and holds key "k" in an encoding the Redis versions
+        // writing that RDB version introduced. Sets and hashes have one element, so their
+        // debug output doesn't depend on iteration order.
+        let files = [
+            (1, b"\x01\x01k\x02\x01a\x01b".to_vec(), list.clone()),
+            (
+                2,
+                b"\x02\x01k\x01\x01a".to_vec(),
+                Data::Set([b"a".to_vec()].into()),
+            ),
+            (3, b"\x03\x01k\x01\x01m\x031.5".to_vec(), zset(1.5)),
+            (
+                4,
+                [&b"\x09\x01k"[..], &short_string(b"\x01\x01f\x01\x00v\xff")].concat(),
+                hash.clone(),
+            ),
+            (
+                5,
+                [
+                    &b"\x0a\x01k"[..],
+                    &short_string(
+                        b"\x11\x00\x00\x00\x0d\x00\x00\x00\x02\x00\x00\x01a\x03\x01b\xff",
+                    ),
+                ]
+                .concat(),
+                list.clone(),
+            ),
+            (
+                6,
+                [
+                    &b"\x0d\x01k"[..],
+                    &short_string(
+                        b"\x11\x00\x00\x00\x0d\x00\x00\x00\x02\x00\x00\x01f\x03\x01v\xff",
+                    ),
+                ]
+                .concat(),
+                hash.clone(),
+            ),
+            (
+                7,
+                [
+                    &b"\x0e\x01k\x01"[..],
+                    &short_string(
+                        b"\x11\x00\x00\x00\x0d\x00\x00\x00\x02\x00\x00\x01a\x03\x01b\xff",
+                    ),
+                ]
+                .concat(),
+                list.clone(),
+            ),
+            (
+                8,
+                [&b"\x05\x01k\x01\x01m"[..], &2.5f64.to_le_bytes()].concat(),
+                zset(2.5),
+            ),
+            (
+                10,
+                [&b"\x12\x01k\x01\x02"[..], &short_string(LISTPACK_A_B)].concat(),
+                list.clone(),
+            ),
+            (
+                11,
+                [
+                    &b"\x14\x01k"[..],
+                    &short_string(b"\x0a\x00\x00\x00\x01\x00\x81a\x02\xff"),
+                ]
+                .concat(),
+                Data::Set([b"a".to_vec()].into()),
+            ),
+            (
+                12,
+                [
+                    &b"\x19\x01k"[..],
+                    &0u64.to_le_bytes(),
+                    &short_string(b"\x0f\x00\x00\x00\x03\x00\x81f\x02\x81v\x02\x00\x01\xff"),
+                ]
+                .concat(),
+                hash.clone(),
+            ),
+        ];
+        for (version, value, expected) in files {
+            let data = load(version, &[&b"\xfe\x00"[..], &value].concat()).await;
+            assert_eq!(data.rdb_version, version);
+            assert_eq!(
+                format!("{:?}", data.databases[&0][&b"k"[..]]),
+                format!("{:?}", expected),
+                "RDB version {}",
+                version
+            );
+        }
+
+        // Version 9 brought streams.
+        let data = load(
+            9,
+            &[
+                &b"\xfe\x00\x0f\x01k"[..],
+                &stream_body(RDB_TYPE_STREAM_LISTPACKS)[1..],
+            ]
+            .concat(),
+        )
+        .await;
+        assert!(
+            matches!(&data.databases[&0][&b"k"[..]], Data::Stream(stream) if stream.entries.len() == 2)
+        );
+    }
+
+    #[tokio::test]
+    async fn reads_expiration_times_in_seconds_and_milliseconds() {
+        let in_2100 = UNIX_EPOCH + Duration::from_secs(4102444800);
+        // Redis 2.4 and older stored seconds, as a 32-bit number.
+        let data = load(4, b"\xfe\x00\xfd\x00\x57\x86\xf4\x00\x01k\x01v").await;
+        assert_eq!(data.expirations[&0][&b"k"[..]], in_2100);
+        let data = load(
+            5,
+            b"\xfe\x00\xfc\x00\xd8\xc3\x2c\xbb\x03\x00\x00\x00\x01k\x01v",
+        )
+        .await;
+        assert_eq!(data.expirations[&0][&b"k"[..]], in_2100);
+    }
+
+    #[tokio::test]
+    async fn reads_metadata_and_skips_what_it_doesnt_need() {
+        let module_id = 0x1234u64 << 10;
+        let body = [
+            &b"\xfa\x09redis-ver\x057.4.0"[..],
+            // A module's AUX data: its id, when it was saved, then typed fields up to EOF.
+            b"\xf7\x81",
+            &module_id.to_be_bytes(),
+            b"\x02\x02\x02\x02\x05\x03abc\x04",
+            &1.5f64.to_le_bytes(),
+            b"\x00",
+            b"\xf5\x28#!lua name=lib\nredis.register_function()",
+            b"\xfe\x00\xfb\x02\x00",
+            // Slot 100 holds two keys, neither with an expiration time.
+            b"\xf4\x40\x64\x02\x00",
+            // Seconds since the last access under LRU, the access counter under LFU.
+            b"\xf8\x0a\x00\x01a\x01v",
+            b"\xf9\x05\x00\x01b\x01v",
+        ]
+        .concat();
+        let data = load(12, &body).await;
+        assert_eq!(data.metadata["redis-ver"], "7.4.0");
+        assert_eq!(
+            data.functions,
+            ["#!lua name=lib\nredis.register_function()"]
+        );
+        assert_eq!(data.databases[&0].len(), 2);
+        let AccessHint::LastAccess(last_access) = data.access_hints[&0][&b"a"[..]] else {
+            panic!("expected the last access time of a");
+        };
+        let idle = SystemTime::now().duration_since(last_access).unwrap();
+        assert!((10..12).contains(&idle.as_secs()));
+        assert_eq!(data.access_hints[&0][&b"b"[..]], AccessHint::Frequency(5));
+    }
+
+    #[tokio::test]
+    async fn rejects_unknown_versions_and_databases() {
+        for version in [0, RDB_MAX_VERSION + 1] {
+            assert!(matches!(
+                RdbReader::read_from(rdb_file(version, b"").as_slice(), true).await,
+                Err(RdbReadError::AtOffset { source, .. })
+                    if matches!(*source, RdbReadError::UnsupportedVersion(v) if v == version)
+            ));
+        }
+        let body = [&b"\xfe"[..], &[DATABASES as u8], b"\x00\x01k\x01v"].concat();
+        assert!(matches!(
+            RdbReader::read_from(rdb_file(12, &body).as_slice(), true).await,
+            Err(RdbReadError::AtOffset { source, .. })
+                if matches!(*source, RdbReadError::DatabaseOutOfRange(DATABASES))
+        ));
+    }
+
+    #[tokio::test]
+    async fn snapshot_checksum_is_verified_unless_disabled() {
+        let database = Database::from([(b"key".to_vec(), Arc::new(Value::string(b"value".to_vec())))]);
+        let databases = HashMap::from([(0, database)]);
+        let mut contents = RdbWriter::write_snapshot(&databases, false, true);
+        let footer = contents.len() - 8;
+        contents[footer] ^= 0x01;
+        assert!(matches!(
+            RdbReader::read_from(contents.as_slice(), true).await,
+            Err(RdbReadError::AtOffset { source, .. })
+                if matches!(*source, RdbReadError::ChecksumMismatch { .. })
+        ));
+        assert!(RdbReader::read_from(contents.as_slice(), false)
+            .await
+            .is_ok());
+
+        // Written with rdbchecksum off, the footer is zero and is never checked.
+        let unchecked = RdbWriter::write_snapshot(&databases, false, false);
+        assert_eq!(unchecked[unchecked.len() - 8..], [0; 8]);
+        assert!(RdbReader::read_from(unchecked.as_slice(), true)
+            .await
+            .is_ok());
+    }
+}
@@ -0,0 +1,75 @@
+use std::cmp::Ordering;
+use std::collections::{BTreeSet, HashMap};
+
+/// Score wrapper giving `f64` the total order needed by the ordered index.
+#[derive(Debug, Clone, Copy)]
+struct Score(f64);
+
+impl PartialEq for Score {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Score {}
+
+impl PartialOrd for Score {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Score {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.0.total_cmp(&other.0)
+    }
+}
+
+/// Members ordered by score, ties broken by the member bytes, as in Redis.
+#[derive(Debug, Clone, Default)]
+pub struct SortedSet {
+    scores: HashMap<Vec<u8>, f64>,
+    ordered: BTreeSet<(Score, Vec<u8>)>,
+}
+
+impl SortedSet {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.scores.is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.scores.len()
+    }
+
+    pub fn score(&self, member: &[u8]) -> Option<f64> {
+        self.scores.get(member).copied()
+    }
+
+    /// Inserts or updates `member`, returning its previous score.
+    pub fn insert(&mut self, member: Vec<u8>, score: f64) -> Option<f64> {
+        let previous = self.scores.insert(member.clone(), score);
+        if let Some(previous) = previous {
+            self.ordered.remove(&(Score(previous), member.clone()));
+        }
+        self.ordered.insert((Score(score), member));
+        previous
+    }
+
+    /// Iterates members in ascending score order.
+    pub fn iter(&self) -> impl Iterator<Item = (&[u8], f64)> {
+        self.ordered
+            .iter()
+            .map(|(score, member)| (member.as_slice(), score.0))
+    }
+
+    /// Iterates members with `min <= score < max` in ascending score order.
+    pub fn range(&self, min: f64, max: f64) -> impl Iterator<Item = (&[u8], f64)> {
+        self.ordered
+            .range((Score(min), Vec::new())..(Score(max), Vec::new()))
+            .map(|(score, member)| (member.as_slice(), score.0))
+    }
+}
@@ -1,29 +1,276 @@
-use crate::rdb::RdbReader;
+use crate::aof::AppendFsync;
+use crate::client::ClientStream;
+use crate::notify::{self, Event};
+use crate::persistence;
+use crate::rdb::{RdbData, RdbReader};
+use crate::sorted_set::SortedSet;
+use crate::stream::Stream;
+use crate::tracking;
 use anyhow::Result;
 use once_cell::sync::Lazy;
-use std::collections::HashMap;
-use std::path::Path;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
-use std::time::SystemTime;
+use std::time::{Duration, Instant, SystemTime};
+use thiserror::Error;
 use tokio::{
-    net::tcp::OwnedWriteHalf,
+    fs::File,
+    io::BufReader,
     sync::{Mutex, RwLock},
 };
 
+#[derive(Debug, Clone)]
+pub enum Data {
+    String(Vec<u8>),
+    List(VecDeque<Vec<u8>>),
+    Set(HashSet<Vec<u8>>),
+    Hash(HashMap<Vec<u8>, Vec<u8>>),
+    SortedSet(SortedSet),
+    Stream(Stream),
+}
+
+/// Eviction metadata Redis keeps per key, carried over from RDB files.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AccessHint {
+    /// When the key was last accessed, for LRU eviction.
+    LastAccess(SystemTime),
+    /// The logarithmic access counter, for LFU eviction.
+    Frequency(u8),
+}
+
 #[derive(Debug, Clone)]
 pub struct Value {
-    pub value: String,
+    pub value: Data,
     pub expiry: Option<SystemTime>,
+    pub access: Option<AccessHint>,
+}
+
+#[derive(Error, Debug)]
+pub enum StoreError {
+    #[error("WRONGTYPE Operation against a key holding the wrong kind of value")]
+    WrongType,
+
+    #[error("BUSYKEY Target key name already exists.")]
+    BusyKey,
+}
+
+impl Data {
+    pub fn as_string(&self) -> Result<&Vec<u8>, StoreError> {
+        match self {
+            Data::String(bytes) => Ok(bytes),
+            _ => Err(StoreError::WrongType),
+        }
+    }
+
+    pub fn as_string_mut(&mut self) -> Result<&mut Vec<u8>, StoreError> {
+        match self {
+            Data::String(bytes) => Ok(bytes),
+            _ => Err(StoreError::WrongType),
+        }
+    }
+
+    pub fn as_sorted_set(&self) -> Result<&SortedSet, StoreError> {
+        match self {
+            Data::SortedSet(zset) => Ok(zset),
+            _ => Err(StoreError::WrongType),
+        }
+    }
+
+    pub fn as_sorted_set_mut(&mut self) -> Result<&mut SortedSet, StoreError> {
+        match self {
+            Data::SortedSet(zset) => Ok(zset),
+            _ => Err(StoreError::WrongType),
+        }
+    }
+}
+
+impl Value {
+    pub fn string(bytes: Vec<u8>) -> Self {
+        Self {
+            value: Data::String(bytes),
+            expiry: None,
+            access: None,
+        }
+    }
+}
+
+pub const DATABASES: usize = 16;
+
+static CACHE: Lazy<Arc<RwLock<HashMap<usize, Database>>>> =
+    Lazy::new(|| Arc::new(RwLock::new(empty_databases())));
+/// Values are shared with snapshots being saved, and copied by the first write that
+/// follows, so taking a snapshot copies only the keys and pointers.
+pub type Database = HashMap<Vec<u8>, Arc<Value>>;
+
+/// A key together with the database it lives in.
+type DbKey = (usize, Vec<u8>);
+
+/// Modification versions for keys that at least one client is WATCHing.
+static WATCHED_KEYS: Lazy<std::sync::Mutex<HashMap<DbKey, WatchedVersion>>> =
+    Lazy::new(|| std::sync::Mutex::new(HashMap::new()));
+
+struct WatchedVersion {
+    version: u64,
+    watchers: usize,
+}
+
+/// Keys to check for expiry on each active expire round, per database.
+const ACTIVE_EXPIRE_KEYS_PER_LOOP: usize = 20;
+/// Another round runs while more than this percentage of the checked keys had expired.
+const ACTIVE_EXPIRE_STALE_PERCENT: usize = 25;
+/// Upper bound on the time a single active expire cycle may take.
+const ACTIVE_EXPIRE_TIME_LIMIT: Duration = Duration::from_millis(25);
+
+/// Keys of one database that may have an expiry, walked by the active expire cycle with
+/// a cursor instead of scanning every key. Writes add keys that end up with an expiry;
+/// keys that were deleted or made persistent are pruned when the cursor reaches them.
+#[derive(Default)]
+struct VolatileKeys {
+    keys: Vec<Vec<u8>>,
+    positions: HashMap<Vec<u8>, usize>,
+    cursor: usize,
+}
+
+impl VolatileKeys {
+    fn insert(&mut self, key: &[u8]) {
+        if !self.positions.contains_key(key) {
+            self.positions.insert(key.to_vec(), self.keys.len());
+            self.keys.push(key.to_vec());
+        }
+    }
+
+    fn remove(&mut self, key: &[u8]) {
+        let Some(position) = self.positions.remove(key) else {
+            return;
+        };
+        self.keys.swap_remove(position);
+        if let Some(moved) = self.keys.get(position) {
+            self.positions.insert(moved.clone(), position);
+        }
+    }
+
+    /// The key under the cursor, moving the cursor past it.
+    fn next(&mut self) -> Option<Vec<u8>> {
+        if self.keys.is_empty() {
+            return None;
+        }
+        self.cursor = (self.cursor + 1) % self.keys.len();
+        Some(self.keys[self.cursor].clone())
+    }
+}
+
+/// Always locked while holding the `CACHE` write lock, so both stay consistent.
+static VOLATILE_KEYS: Lazy<std::sync::Mutex<HashMap<usize, VolatileKeys>>> =
+    Lazy::new(|| std::sync::Mutex::new(HashMap::new()));
+
+/// Keys found expired and removed when accessed, waiting for the DEL that tells the AOF
+/// and replicas about it. Only pushed to while holding the `CACHE` write lock, so the
+/// DELs come out in the order the keys were removed.
+static LAZILY_EXPIRED: Lazy<std::sync::Mutex<Vec<DbKey>>> =
+    Lazy::new(|| std::sync::Mutex::new(Vec::new()));
+
+/// Takes the keys removed on access since the last call, as `(db, key)` pairs.
+pub fn take_lazily_expired() -> Vec<(usize, Vec<u8>)> {
+    std::mem::take(&mut *LAZILY_EXPIRED.lock().unwrap())
+}
+
+/// Whether any key was removed on access since the last `take_lazily_expired`.
+pub fn has_lazily_expired() -> bool {
+    !LAZILY_EXPIRED.lock().unwrap().is_empty()
+}
+
+fn expire_lazily(db_id: usize, key: &[u8]) {
+    touch_key(db_id, key);
+    LAZILY_EXPIRED.lock().unwrap().push((db_id, key.to_vec()));
+}
+
+fn track_volatile_key(db_id: usize, key: &[u8], entry: &Value) {
+    if entry.expiry.is_some() {
+        let mut volatile = VOLATILE_KEYS.lock().unwrap();
+        volatile.entry(db_id).or_default().insert(key);
+    }
+}
+
+/// Rebuilds the volatile key sets after the whole dataset was replaced.
+fn reset_volatile_keys(databases: &HashMap<usize, Database>) {
+    let mut volatile = VOLATILE_KEYS.lock().unwrap();
+    volatile.clear();
+    for (db_id, database) in databases {
+        for (key, entry) in database {
+            if entry.expiry.is_some() {
+                volatile.entry(*db_id).or_default().insert(key);
+            }
+        }
+    }
+}
+
+fn empty_databases() -> HashMap<usize, Database> {
+    (0..DATABASES).map(|i| (i, Database::new())).collect()
+}
+
+fn is_expired(entry: &Arc<Value>) -> bool {
+    matches!(entry.expiry, Some(expiry) if expiry < SystemTime::now())
 }
 
-static CACHE: Lazy<Arc<RwLock<HashMap<usize, Database>>>> = Lazy::new(|| {
-    let mut databases = HashMap::new();
-    for i in 0..16 {
-        databases.insert(i, Database::new());
+/// Starts tracking modifications of `key`, returning its current version.
+pub fn watch_key(db_id: usize, key: &[u8]) -> u64 {
+    let mut watched = WATCHED_KEYS.lock().unwrap();
+    let entry = watched
+        .entry((db_id, key.to_vec()))
+        .or_insert(WatchedVersion {
+            version: 0,
+            watchers: 0,
+        });
+    entry.watchers += 1;
+    entry.version
+}
+
+pub fn unwatch_key(db_id: usize, key: &[u8]) {
+    let mut watched = WATCHED_KEYS.lock().unwrap();
+    let id = (db_id, key.to_vec());
+    if let Some(entry) = watched.get_mut(&id) {
+        entry.watchers -= 1;
+        if entry.watchers == 0 {
+            watched.remove(&id);
+        }
+    }
+}
+
+pub fn watched_key_version(db_id: usize, key: &[u8]) -> u64 {
+    let watched = WATCHED_KEYS.lock().unwrap();
+    watched
+        .get(&(db_id, key.to_vec()))
+        .map_or(0, |entry| entry.version)
+}
+
+/// Records a modification of `key`, invalidating transactions that WATCH it and the
+/// copies client-side caches hold of it.
+fn touch_key(db_id: usize, key: &[u8]) {
+    persistence::add_dirty(1);
+    {
+        let mut watched = WATCHED_KEYS.lock().unwrap();
+        if let Some(entry) = watched.get_mut(&(db_id, key.to_vec())) {
+            entry.version += 1;
+        }
+    }
+    tracking::invalidate_key(key);
+}
+
+/// Touches every watched key that currently exists in `database`, ahead of a flush.
+fn touch_database(db_id: usize, database: &Database) {
+    persistence::add_dirty(database.len() as u64);
+    touch_watched_keys(db_id, |key| database.contains_key(key));
+}
+
+/// Bumps the version of the keys of `db_id` that clients WATCH and `changed` selects.
+fn touch_watched_keys(db_id: usize, changed: impl Fn(&[u8]) -> bool) {
+    let mut watched = WATCHED_KEYS.lock().unwrap();
+    for ((watched_db, key), entry) in watched.iter_mut() {
+        if *watched_db == db_id && changed(key) {
+            entry.version += 1;
+        }
     }
-    Arc::new(RwLock::new(databases))
-});
-type Database = HashMap<String, Value>;
+}
 
 #[derive(PartialEq, Debug, Clone, Copy)]
 pub enum ServerMode {
@@ -31,6 +278,37 @@ pub enum ServerMode {
     Replica,
 }
 
+/// What to do at startup with an RDB file that can't be loaded completely, set by
+/// `rdb-corrupt-policy`.
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum CorruptRdbPolicy {
+    /// Refuse to start.
+    Refuse,
+    /// Start with an empty dataset.
+    Empty,
+    /// Keep the keys decoded before the error.
+    Partial,
+}
+
+impl CorruptRdbPolicy {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.to_lowercase().as_str() {
+            "refuse" => Some(Self::Refuse),
+            "empty" => Some(Self::Empty),
+            "partial" => Some(Self::Partial),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Refuse => "refuse",
+            Self::Empty => "empty",
+            Self::Partial => "partial",
+        }
+    }
+}
+
 pub struct Config {
     pub dir: Option<String>,
     pub dbfilename: Option<String>,
@@ -39,8 +317,37 @@ pub struct Config {
     pub masterport: Option<u16>,
     pub master_replid: String,
     pub master_repl_offset: u64,
-    pub replicas: Mutex<Vec<Arc<Mutex<OwnedWriteHalf>>>>,
+    pub replicas: Mutex<Vec<ClientStream>>,
     pub mode: ServerMode,
+    /// Event classes enabled by `notify-keyspace-events`, see `notify`.
+    pub notify_keyspace_events: u32,
+    /// `save <seconds> <changes>` rules triggering a background save.
+    pub save_params: Vec<(u64, u64)>,
+    /// `rdbcompression`: whether long strings are LZF compressed in RDB files.
+    pub rdb_compression: bool,
+    /// `rdbchecksum`: whether RDB files are written with, and checked against, a CRC64.
+    pub rdb_checksum: bool,
+    pub rdb_corrupt_policy: CorruptRdbPolicy,
+    /// `appendonly`: whether write commands are logged to the AOF.
+    pub appendonly: bool,
+    pub appendfilename: String,
+    pub appendfsync: AppendFsync,
+    /// `aof-load-truncated`: whether an AOF with an incomplete tail is loaded anyway.
+    pub aof_load_truncated: bool,
+    /// `appenddirname`: directory under `dir` holding the AOF files and their manifest.
+    pub appenddirname: String,
+    /// `aof-use-rdb-preamble`: whether rewritten base files are RDB rather than commands.
+    pub aof_use_rdb_preamble: bool,
+    /// `auto-aof-rewrite-percentage`: growth over the last rewrite triggering another, 0 to disable.
+    pub auto_aof_rewrite_percentage: u64,
+    /// `auto-aof-rewrite-min-size`: smallest AOF, in bytes, rewritten automatically.
+    pub auto_aof_rewrite_min_size: u64,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Config {
@@ -51,28 +358,111 @@ impl Config {
             port: 6379,
             masterhost: None,
             masterport: None,
-            master_replid: format!("8371b4fb1155b71f4a04d3e1bc3e18c4a990aeeb"),
+            master_replid: "8371b4fb1155b71f4a04d3e1bc3e18c4a990aeeb".to_string(),
             master_repl_offset: 0,
             replicas: Mutex::new(Vec::new()),
             mode: ServerMode::Master,
+            notify_keyspace_events: 0,
+            save_params: vec![(3600, 1), (300, 100), (60, 10000)],
+            rdb_compression: true,
+            rdb_checksum: true,
+            rdb_corrupt_policy: CorruptRdbPolicy::Refuse,
+            appendonly: false,
+            appendfilename: "appendonly.aof".to_string(),
+            appendfsync: AppendFsync::EverySec,
+            aof_load_truncated: true,
+            appenddirname: "appendonlydir".to_string(),
+            aof_use_rdb_preamble: true,
+            auto_aof_rewrite_percentage: 100,
+            auto_aof_rewrite_min_size: 64 * 1024 * 1024,
         }
     }
-}
 
-pub async fn db_load(db_file: impl AsRef<Path>) -> Result<(), anyhow::Error> {
-    let mut cache = CACHE.write().await;
-    cache.clear();
+    /// Where the RDB file lives, defaulting like Redis to `./dump.rdb`.
+    pub fn rdb_path(&self) -> PathBuf {
+        Path::new(self.dir.as_deref().unwrap_or("."))
+            .join(self.dbfilename.as_deref().unwrap_or("dump.rdb"))
+    }
+
+    /// Where a single-file AOF lived before AOFs were split into a base and incremental
+    /// files; it is moved into `aof_dir` on startup.
+    pub fn aof_path(&self) -> PathBuf {
+        Path::new(self.dir.as_deref().unwrap_or(".")).join(&self.appendfilename)
+    }
+
+    /// Directory holding the AOF manifest and the files it lists.
+    pub fn aof_dir(&self) -> PathBuf {
+        Path::new(self.dir.as_deref().unwrap_or(".")).join(&self.appenddirname)
+    }
+}
 
-    let data = match RdbReader::read(db_file).await {
-        Ok(r) => r,
-        Err(e) => {
+/// Replaces the dataset with the contents of an RDB file. A missing file leaves the
+/// databases empty. A file that can't be read completely is handled as `policy` says,
+/// returning the error if it refuses; the current dataset is only replaced once the
+/// file has been read.
+pub async fn db_load(
+    db_file: impl AsRef<Path>,
+    verify_checksum: bool,
+    policy: CorruptRdbPolicy,
+) -> Result<(), anyhow::Error> {
+    let file = match File::open(db_file).await {
+        Ok(file) => file,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
             println!("Failed to open database - {:?}", e);
             return Ok(());
         }
+        Err(e) => return Err(e.into()),
     };
+    let reader = BufReader::new(file);
+
+    let data = if policy == CorruptRdbPolicy::Partial {
+        let (data, error) = RdbReader::read_partial_from(reader, verify_checksum).await;
+        if let Some(e) = error {
+            let keys: usize = data.databases.values().map(|db| db.len()).sum();
+            println!(
+                "Error loading the DB: {}. Keeping the {} keys read before it.",
+                e, keys
+            );
+        }
+        data
+    } else {
+        match RdbReader::read_from(reader, verify_checksum).await {
+            Ok(data) => data,
+            Err(e) if policy == CorruptRdbPolicy::Empty => {
+                println!(
+                    "Error loading the DB: {}. Starting with an empty dataset.",
+                    e
+                );
+                let mut cache = CACHE.write().await;
+                *cache = empty_databases();
+                reset_volatile_keys(&cache);
+                return Ok(());
+            }
+            Err(e) => return Err(e.into()),
+        }
+    };
+    println!(
+        "Loaded RDB version {} with metadata {:?}",
+        data.rdb_version, data.metadata
+    );
+    db_replace(data).await;
+
+    Ok(())
+}
+
+/// Replaces the whole dataset with the contents of a decoded RDB payload.
+pub async fn db_replace(data: RdbData) {
+    if !data.functions.is_empty() {
+        println!(
+            "Ignoring {} function libraries: functions are not supported",
+            data.functions.len()
+        );
+    }
 
+    let mut databases = empty_databases();
     for (id, map) in data.databases {
         let expirations = data.expirations.get(&id);
+        let access_hints = data.access_hints.get(&id);
         let remapped = map
             .into_iter()
             .map(|(k, v)| {
@@ -81,23 +471,38 @@ pub async fn db_load(db_file: impl AsRef<Path>) -> Result<(), anyhow::Error> {
                 } else {
                     None
                 };
+                let access = access_hints.and_then(|hints| hints.get(&k).copied());
 
-                (k, Value { expiry, value: v })
+                (
+                    k,
+                    Arc::new(Value {
+                        expiry,
+                        value: v,
+                        access,
+                    }),
+                )
             })
             .collect();
-        cache.insert(id, remapped);
+        databases.insert(id, remapped);
     }
-
-    Ok(())
+    let mut cache = CACHE.write().await;
+    // Like a flush, replacing the dataset changes every key that existed before or after.
+    for db_id in 0..DATABASES {
+        let (old, new) = (cache.get(&db_id), databases.get(&db_id));
+        touch_watched_keys(db_id, |key| {
+            old.is_some_and(|db| db.contains_key(key)) || new.is_some_and(|db| db.contains_key(key))
+        });
+    }
+    tracking::invalidate_all();
+    reset_volatile_keys(&databases);
+    *cache = databases;
 }
 
-pub async fn db_get(db_id: usize, key: &String) -> Result<Option<String>, anyhow::Error> {
-    println!("Getting key: {}", key);
+pub async fn db_get(db_id: usize, key: &[u8]) -> Result<Option<Vec<u8>>, anyhow::Error> {
     let (result, should_remove) = {
         let cache = CACHE.read().await;
         if let Some(database) = cache.get(&db_id) {
             let mut is_valid = true;
-            println!("Database: {:?}", database);
             if let Some(entry) = database.get(key) {
                 if let Some(expiration) = entry.expiry.as_ref() {
                     if *expiration < SystemTime::now() {
@@ -106,7 +511,7 @@ pub async fn db_get(db_id: usize, key: &String) -> Result<Option<String>, anyhow
                 }
 
                 if is_valid {
-                    (Some(entry.value.clone()), false)
+                    (Some(entry.value.as_string()?.clone()), false)
                 } else {
                     (None, true)
                 }
@@ -121,30 +526,269 @@ pub async fn db_get(db_id: usize, key: &String) -> Result<Option<String>, anyhow
     if should_remove {
         let mut cache = CACHE.write().await;
         let database = cache.get_mut(&db_id).unwrap();
-        database.remove(key);
+        // Another client may have written the key since the read lock was released.
+        let still_expired = database.get(key).is_some_and(is_expired);
+        if still_expired {
+            database.remove(key);
+            expire_lazily(db_id, key);
+        }
+        drop(cache);
+        if still_expired {
+            notify::keyspace_event(notify::EXPIRED, key, db_id).await;
+        }
+    }
+    if result.is_none() {
+        notify::keyspace_event(notify::KEYMISS, key, db_id).await;
     }
 
     Ok(result)
 }
 
-pub async fn db_set(db_id: usize, key: String, value: Value) -> Result<(), anyhow::Error> {
-    println!("Setting key: {}", key);
+/// Stores `value` under `key`, replacing whatever was there, and raises `event` on it,
+/// followed by an `expire` event when the value has an expiry time.
+pub async fn db_set(
+    db_id: usize,
+    key: Vec<u8>,
+    value: Value,
+    event: Event,
+) -> Result<(), anyhow::Error> {
     let mut cache = CACHE.write().await;
+    let mut created = false;
+    let has_expiry = value.expiry.is_some();
     if let Some(database) = cache.get_mut(&db_id) {
         let entry = Value {
             value: value.value,
             expiry: value.expiry,
+            access: value.access,
         };
-        database.insert(key, entry);
+        touch_key(db_id, &key);
+        created = database.get(&key).is_none_or(is_expired);
+        track_volatile_key(db_id, &key, &entry);
+        database.insert(key.clone(), Arc::new(entry));
+    }
+    drop(cache);
+    if created {
+        notify::keyspace_event(notify::NEW, &key, db_id).await;
+    }
+    notify::keyspace_event(event, &key, db_id).await;
+    if has_expiry {
+        notify::keyspace_event(notify::EXPIRE, &key, db_id).await;
+    }
+
+    Ok(())
+}
+
+/// Runs `f` against the entry stored under `key` while holding the write lock, so a
+/// read-modify-write is atomic. Expired entries are presented as missing, and the
+/// entry is removed if `f` leaves it as `None`. The key counts as modified unless `f`
+/// fails, and then `event` is raised on it, or `del` if `f` removed it.
+pub async fn db_update<F, R>(
+    db_id: usize,
+    key: &[u8],
+    event: Option<Event>,
+    f: F,
+) -> Result<R, anyhow::Error>
+where
+    F: FnOnce(&mut Option<Value>) -> Result<R, anyhow::Error>,
+{
+    db_update_if_changed(db_id, key, event, |entry| {
+        f(entry).map(|result| (result, true))
+    })
+    .await
+}
+
+/// Like `db_update`, but `f` also reports whether it changed the entry, and the key
+/// only counts as modified when it did.
+pub async fn db_update_if_changed<F, R>(
+    db_id: usize,
+    key: &[u8],
+    event: Option<Event>,
+    f: F,
+) -> Result<R, anyhow::Error>
+where
+    F: FnOnce(&mut Option<Value>) -> Result<(R, bool), anyhow::Error>,
+{
+    let mut cache = CACHE.write().await;
+    let Some(database) = cache.get_mut(&db_id) else {
+        return Err(anyhow::Error::msg("Database doesn't exist"));
+    };
+
+    let mut entry = database.remove(key);
+    let expired = entry.as_ref().is_some_and(is_expired);
+    if expired {
+        entry = None;
+        expire_lazily(db_id, key);
+    }
+    // A value still shared with a snapshot being saved is copied here, so the snapshot
+    // keeps what it had.
+    let mut entry = entry.map(Arc::unwrap_or_clone);
+    let existed = entry.is_some();
+    let result = f(&mut entry);
+    let exists = entry.is_some();
+    if let Some(entry) = entry {
+        track_volatile_key(db_id, key, &entry);
+        database.insert(key.to_vec(), Arc::new(entry));
+    }
+    let changed = matches!(result, Ok((_, true)));
+    if changed {
+        touch_key(db_id, key);
+    }
+    drop(cache);
+
+    if expired {
+        notify::keyspace_event(notify::EXPIRED, key, db_id).await;
+    }
+    if !existed && exists {
+        notify::keyspace_event(notify::NEW, key, db_id).await;
+    }
+    match event {
+        Some(event) if changed && exists => notify::keyspace_event(event, key, db_id).await,
+        _ if existed && !exists => notify::keyspace_event(notify::DEL, key, db_id).await,
+        _ => {}
+    }
+    result.map(|(result, _)| result)
+}
+
+/// Runs `f` against the entry stored under `key` while holding the read lock.
+/// Expired entries are presented as missing, and raise a `keymiss` event.
+pub async fn db_view<F, R>(db_id: usize, key: &[u8], f: F) -> Result<R, anyhow::Error>
+where
+    F: FnOnce(Option<&Value>) -> Result<R, anyhow::Error>,
+{
+    let cache = CACHE.read().await;
+    let Some(database) = cache.get(&db_id) else {
+        return Err(anyhow::Error::msg("Database doesn't exist"));
+    };
+
+    let entry = database.get(key).filter(|entry| !is_expired(entry));
+    let missed = entry.is_none();
+    let result = f(entry.map(Arc::as_ref));
+    drop(cache);
+    if missed {
+        notify::keyspace_event(notify::KEYMISS, key, db_id).await;
+    }
+    result
+}
+
+/// Whether `key` is still stored but past its expiry time.
+pub async fn db_is_expired(db_id: usize, key: &[u8]) -> bool {
+    let cache = CACHE.read().await;
+    cache
+        .get(&db_id)
+        .and_then(|database| database.get(key))
+        .is_some_and(is_expired)
+}
+
+/// Removes `key`, returning whether a live key was deleted, which raises `del`.
+pub async fn db_delete(db_id: usize, key: &[u8]) -> Result<bool, anyhow::Error> {
+    let mut cache = CACHE.write().await;
+    let Some(database) = cache.get_mut(&db_id) else {
+        return Err(anyhow::Error::msg("Database doesn't exist"));
+    };
+    let Some(entry) = database.remove(key) else {
+        return Ok(false);
+    };
+    let expired = is_expired(&entry);
+    if expired {
+        expire_lazily(db_id, key);
+    } else {
+        touch_key(db_id, key);
+    }
+    drop(cache);
+    if expired {
+        notify::keyspace_event(notify::EXPIRED, key, db_id).await;
+        return Ok(false);
+    }
+    notify::keyspace_event(notify::DEL, key, db_id).await;
+    Ok(true)
+}
+
+/// Actively removes expired keys, returning the `(db, key)` pairs removed. Like Redis'
+/// active expire cycle, each database is checked a few keys at a time, moving on once
+/// few of them turn out to be expired or the time limit is reached.
+pub async fn db_remove_expired() -> Vec<(usize, Vec<u8>)> {
+    let started = Instant::now();
+    let mut removed = Vec::new();
+    'databases: for db_id in 0..DATABASES {
+        loop {
+            let (checked, expired) = {
+                let mut cache = CACHE.write().await;
+                let Some(database) = cache.get_mut(&db_id) else {
+                    continue 'databases;
+                };
+                let mut volatile = VOLATILE_KEYS.lock().unwrap();
+                let keys = volatile.entry(db_id).or_default();
+                let checked = ACTIVE_EXPIRE_KEYS_PER_LOOP.min(keys.keys.len());
+                let mut expired = 0;
+                for _ in 0..checked {
+                    let Some(key) = keys.next() else {
+                        break;
+                    };
+                    match database.get(&key) {
+                        Some(entry) if is_expired(entry) => {
+                            database.remove(&key);
+                            keys.remove(&key);
+                            removed.push((db_id, key));
+                            expired += 1;
+                        }
+                        Some(entry) if entry.expiry.is_some() => {}
+                        _ => keys.remove(&key),
+                    }
+                }
+                (checked, expired)
+            };
+            if started.elapsed() > ACTIVE_EXPIRE_TIME_LIMIT {
+                break 'databases;
+            }
+            if checked == 0 || expired * 100 <= checked * ACTIVE_EXPIRE_STALE_PERCENT {
+                break;
+            }
+        }
     }
+    for (db_id, key) in removed.iter() {
+        touch_key(*db_id, key);
+        notify::keyspace_event(notify::EXPIRED, key, *db_id).await;
+    }
+    removed
+}
 
+pub async fn db_flush(db_id: usize) -> Result<(), anyhow::Error> {
+    let mut cache = CACHE.write().await;
+    let Some(database) = cache.get_mut(&db_id) else {
+        return Err(anyhow::Error::msg("Database doesn't exist"));
+    };
+    touch_database(db_id, database);
+    database.clear();
+    VOLATILE_KEYS.lock().unwrap().remove(&db_id);
+    tracking::invalidate_all();
     Ok(())
 }
 
-pub async fn db_list_keys(db_id: usize) -> Result<Vec<String>, anyhow::Error> {
+pub async fn db_flush_all() -> Result<(), anyhow::Error> {
+    let mut cache = CACHE.write().await;
+    for (db_id, database) in cache.iter_mut() {
+        touch_database(*db_id, database);
+        database.clear();
+    }
+    VOLATILE_KEYS.lock().unwrap().clear();
+    tracking::invalidate_all();
+    Ok(())
+}
+
+/// Copies every database, so it can be serialized without holding the lock. Values are
+/// shared rather than copied, so this costs a pointer per key.
+pub async fn db_snapshot() -> HashMap<usize, Database> {
+    CACHE.read().await.clone()
+}
+
+pub async fn db_list_keys(db_id: usize) -> Result<Vec<Vec<u8>>, anyhow::Error> {
     let cache = CACHE.read().await;
     if let Some(database) = cache.get(&db_id) {
-        Ok(database.keys().cloned().collect::<Vec<_>>())
+        Ok(database
+            .iter()
+            .filter(|(_, entry)| !is_expired(entry))
+            .map(|(key, _)| key.clone())
+            .collect::<Vec<_>>())
     } else {
         Err(anyhow::Error::msg("Database doesn't exist"))
     }
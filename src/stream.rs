@@ -0,0 +1,72 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+
+/// A stream entry id, `<milliseconds>-<sequence>`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct StreamId {
+    pub ms: u64,
+    pub seq: u64,
+}
+
+impl StreamId {
+    /// Parses the 128 bit big-endian form used as radix tree keys and in RDB files.
+    pub fn from_be_bytes(bytes: &[u8]) -> Option<Self> {
+        let ms = u64::from_be_bytes(bytes.get(0..8)?.try_into().ok()?);
+        let seq = u64::from_be_bytes(bytes.get(8..16)?.try_into().ok()?);
+        (bytes.len() == 16).then_some(Self { ms, seq })
+    }
+
+    pub fn to_be_bytes(self) -> [u8; 16] {
+        let mut bytes = [0; 16];
+        bytes[0..8].copy_from_slice(&self.ms.to_be_bytes());
+        bytes[8..16].copy_from_slice(&self.seq.to_be_bytes());
+        bytes
+    }
+}
+
+impl fmt::Display for StreamId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}-{}", self.ms, self.seq)
+    }
+}
+
+/// An entry delivered to a consumer but not yet acknowledged.
+#[derive(Debug, Clone)]
+pub struct PendingEntry {
+    pub consumer: String,
+    /// Unix time in milliseconds of the last delivery.
+    pub delivery_time: u64,
+    pub delivery_count: u64,
+}
+
+#[derive(Debug, Clone)]
+pub struct Consumer {
+    /// Unix time in milliseconds of the last interaction.
+    pub seen_time: u64,
+    /// Unix time in milliseconds of the last successful interaction.
+    pub active_time: u64,
+    pub pending: BTreeSet<StreamId>,
+}
+
+#[derive(Debug, Clone)]
+pub struct ConsumerGroup {
+    pub last_id: StreamId,
+    /// Logical read counter of the group, or -1 when it is unknown.
+    pub entries_read: i64,
+    pub pending: BTreeMap<StreamId, PendingEntry>,
+    pub consumers: BTreeMap<String, Consumer>,
+}
+
+/// Field/value pairs of a stream entry.
+pub type StreamFields = Vec<(Vec<u8>, Vec<u8>)>;
+
+#[derive(Debug, Clone, Default)]
+pub struct Stream {
+    pub entries: BTreeMap<StreamId, StreamFields>,
+    pub last_id: StreamId,
+    pub first_id: StreamId,
+    pub max_deleted_id: StreamId,
+    /// Number of entries ever added, including deleted ones.
+    pub entries_added: u64,
+    pub groups: BTreeMap<String, ConsumerGroup>,
+}
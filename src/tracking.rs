@@ -0,0 +1,277 @@
+use crate::client::{self, ClientHandle};
+use crate::utils::{
+    build_resp_array_raw, build_resp_bulk, build_resp_integer, build_resp_null_array,
+    build_resp_push, word,
+};
+use once_cell::sync::Lazy;
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex};
+use thiserror::Error;
+
+/// Channel RESP2 clients subscribe to in order to receive redirected invalidations.
+pub const INVALIDATE_CHANNEL: &str = "__redis__:invalidate";
+
+tokio::task_local! {
+    /// Id of the client whose command is running, so NOLOOP can skip its own writes.
+    pub static CURRENT_CLIENT: u64;
+}
+
+#[derive(Error, Debug)]
+pub enum TrackingError {
+    #[error("The client ID you want redirect to does not exist")]
+    UnknownRedirect,
+
+    #[error("PREFIX option requires BCAST mode to be enabled")]
+    PrefixWithoutBcast,
+
+    #[error("OPTIN and OPTOUT are not compatible with BCAST")]
+    OptInOutWithBcast,
+
+    #[error("You can't use OPTIN and OPTOUT at the same time")]
+    OptInAndOptOut,
+
+    #[error("You can't switch BCAST mode on/off before disabling tracking for this client, and then re-enabling it with a different mode.")]
+    BcastSwitch,
+}
+
+/// Options given to CLIENT TRACKING ON.
+#[derive(Debug, Clone, Default)]
+pub struct TrackingOptions {
+    pub redirect: Option<u64>,
+    pub bcast: bool,
+    pub prefixes: Vec<Vec<u8>>,
+    pub optin: bool,
+    pub optout: bool,
+    pub noloop: bool,
+}
+
+impl TrackingOptions {
+    pub fn validate(&self) -> Result<(), TrackingError> {
+        if !self.prefixes.is_empty() && !self.bcast {
+            return Err(TrackingError::PrefixWithoutBcast);
+        }
+        if self.bcast && (self.optin || self.optout) {
+            return Err(TrackingError::OptInOutWithBcast);
+        }
+        if self.optin && self.optout {
+            return Err(TrackingError::OptInAndOptOut);
+        }
+        Ok(())
+    }
+}
+
+impl TrackingOptions {
+    /// The options as CLIENT TRACKING arguments, following ON or OFF.
+    pub fn to_args(&self) -> Vec<Vec<u8>> {
+        let mut args = Vec::new();
+        if let Some(redirect) = self.redirect {
+            args.extend([word("REDIRECT"), word(redirect)]);
+        }
+        for prefix in self.prefixes.iter() {
+            args.extend([word("PREFIX"), prefix.clone()]);
+        }
+        for (enabled, flag) in [
+            (self.bcast, "BCAST"),
+            (self.optin, "OPTIN"),
+            (self.optout, "OPTOUT"),
+            (self.noloop, "NOLOOP"),
+        ] {
+            if enabled {
+                args.push(word(flag));
+            }
+        }
+        args
+    }
+}
+
+struct TrackingClient {
+    handle: Arc<ClientHandle>,
+    options: TrackingOptions,
+}
+
+/// Keys read by clients in the default mode, and prefixes registered in BCAST mode.
+/// `client_keys` indexes `keys` by client, so that disabling tracking can drop the
+/// client's entries right away.
+#[derive(Default)]
+struct TrackingTable {
+    keys: HashMap<Vec<u8>, HashSet<u64>>,
+    client_keys: HashMap<u64, HashSet<Vec<u8>>>,
+    prefixes: HashMap<Vec<u8>, HashSet<u64>>,
+    clients: HashMap<u64, TrackingClient>,
+}
+
+static TABLE: Lazy<Mutex<TrackingTable>> = Lazy::new(|| Mutex::new(TrackingTable::default()));
+
+/// Turns tracking on for `client_id`, or updates its options if already on.
+pub fn enable(
+    client_id: u64,
+    handle: Arc<ClientHandle>,
+    options: TrackingOptions,
+) -> Result<(), TrackingError> {
+    options.validate()?;
+    if let Some(redirect) = options.redirect {
+        if client::lookup_client(redirect).is_none() {
+            return Err(TrackingError::UnknownRedirect);
+        }
+    }
+
+    let mut table = TABLE.lock().unwrap();
+    if let Some(current) = table.clients.get(&client_id) {
+        if current.options.bcast != options.bcast {
+            return Err(TrackingError::BcastSwitch);
+        }
+        // The prefixes given now replace those of the earlier CLIENT TRACKING ON.
+        remove_prefixes(&mut table, client_id);
+    }
+    if options.bcast {
+        // BCAST without PREFIX tracks every key, i.e. the empty prefix.
+        let prefixes = if options.prefixes.is_empty() {
+            vec![Vec::new()]
+        } else {
+            options.prefixes.clone()
+        };
+        for prefix in prefixes {
+            table.prefixes.entry(prefix).or_default().insert(client_id);
+        }
+    }
+    table
+        .clients
+        .insert(client_id, TrackingClient { handle, options });
+    Ok(())
+}
+
+pub fn disable(client_id: u64) {
+    let mut table = TABLE.lock().unwrap();
+    table.clients.remove(&client_id);
+    remove_prefixes(&mut table, client_id);
+    for key in table.client_keys.remove(&client_id).unwrap_or_default() {
+        if let Some(clients) = table.keys.get_mut(&key) {
+            clients.remove(&client_id);
+            if clients.is_empty() {
+                table.keys.remove(&key);
+            }
+        }
+    }
+}
+
+fn remove_prefixes(table: &mut TrackingTable, client_id: u64) {
+    table.prefixes.retain(|_, clients| {
+        clients.remove(&client_id);
+        !clients.is_empty()
+    });
+}
+
+/// Remembers that `client_id` read `keys`, in the default (non-BCAST) mode.
+pub fn remember_keys(client_id: u64, keys: &[&Vec<u8>]) {
+    let mut table = TABLE.lock().unwrap();
+    for key in keys {
+        table
+            .keys
+            .entry((*key).clone())
+            .or_default()
+            .insert(client_id);
+        table
+            .client_keys
+            .entry(client_id)
+            .or_default()
+            .insert((*key).clone());
+    }
+}
+
+/// Builds the invalidation message for `key` (`None` meaning every key) as seen by the
+/// receiving connection, or `None` if it cannot receive it.
+fn invalidation_frame(
+    target: &ClientHandle,
+    redirected: bool,
+    key: Option<&[u8]>,
+) -> Option<Vec<u8>> {
+    let resp3 = target.resp3.load(Ordering::Relaxed);
+    let value = match key {
+        Some(key) => build_resp_array_raw(vec![build_resp_bulk(key)]),
+        None => build_resp_null_array(resp3),
+    };
+    if resp3 {
+        Some(build_resp_push(vec![build_resp_bulk(b"invalidate"), value]))
+    } else if redirected && target.subscribed.load(Ordering::Relaxed) {
+        Some(build_resp_array_raw(vec![
+            build_resp_bulk(b"message"),
+            build_resp_bulk(INVALIDATE_CHANNEL.as_bytes()),
+            value,
+        ]))
+    } else {
+        // A RESP2 connection cannot receive pushes on the connection it sends commands on.
+        None
+    }
+}
+
+/// Resolves where invalidations for a tracking client go, honouring REDIRECT.
+fn delivery(client: &TrackingClient, key: Option<&[u8]>) -> Option<(Arc<ClientHandle>, Vec<u8>)> {
+    let Some(redirect) = client.options.redirect else {
+        let frame = invalidation_frame(&client.handle, false, key)?;
+        return Some((Arc::clone(&client.handle), frame));
+    };
+    match client::lookup_client(redirect) {
+        Some(target) => {
+            let frame = invalidation_frame(&target, true, key)?;
+            Some((target, frame))
+        }
+        None if client.handle.resp3.load(Ordering::Relaxed) => {
+            let frame = build_resp_push(vec![
+                build_resp_bulk(b"tracking-redir-broken"),
+                build_resp_integer(redirect as i64),
+            ]);
+            Some((Arc::clone(&client.handle), frame))
+        }
+        None => None,
+    }
+}
+
+/// Queues invalidations on their targets' connections, in the order the keys changed.
+fn deliver(deliveries: Vec<(Arc<ClientHandle>, Vec<u8>)>) {
+    for (target, frame) in deliveries {
+        target.push(frame);
+    }
+}
+
+/// Sends invalidations for a modified key to the clients that read it or registered a
+/// matching BCAST prefix. Default mode entries are one-shot and removed here.
+pub fn invalidate_key(key: &[u8]) {
+    let origin = CURRENT_CLIENT.try_with(|id| *id).ok();
+    let mut table = TABLE.lock().unwrap();
+    let mut targets: HashSet<u64> = table.keys.remove(key).unwrap_or_default();
+    for id in targets.iter() {
+        if let Some(keys) = table.client_keys.get_mut(id) {
+            keys.remove(key);
+            if keys.is_empty() {
+                table.client_keys.remove(id);
+            }
+        }
+    }
+    for (prefix, clients) in table.prefixes.iter() {
+        if key.starts_with(prefix) {
+            targets.extend(clients.iter().copied());
+        }
+    }
+
+    let deliveries = targets
+        .iter()
+        .filter_map(|id| table.clients.get(id).map(|client| (*id, client)))
+        .filter(|(id, client)| !(client.options.noloop && origin == Some(*id)))
+        .filter_map(|(_, client)| delivery(client, Some(key)))
+        .collect();
+    deliver(deliveries);
+}
+
+/// Invalidates everything for every tracking client, as after FLUSHDB or FLUSHALL.
+pub fn invalidate_all() {
+    let mut table = TABLE.lock().unwrap();
+    table.keys.clear();
+    table.client_keys.clear();
+    let deliveries = table
+        .clients
+        .values()
+        .filter_map(|client| delivery(client, None))
+        .collect();
+    deliver(deliveries);
+}
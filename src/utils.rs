@@ -1,13 +1,11 @@
 use bytes::buf::Writer;
 use std::{io::Write, str};
 
-pub const EMPTY_RDB_HEX: &str = "524544495330303131fa0972656469732d76657205372e322e30fa0a72656469732d62697473c040fa056374696d65c26d08bc65fa08757365642d6d656dc2b0c41000fa08616f662d62617365c000fff06e3bfec0ff5aa2";
-
 pub fn get_bulk_string(buffer: &mut Writer<Vec<u8>>, string: &[u8]) -> tokio::io::Result<()> {
     let length_str = string.len().to_string();
-    buffer.write_all(
-        format!("${}\r\n{}\r\n", length_str, String::from_utf8_lossy(string)).as_bytes(),
-    )?;
+    buffer.write_all(format!("${}\r\n", length_str).as_bytes())?;
+    buffer.write_all(string)?;
+    buffer.write_all(b"\r\n")?;
     Ok(())
 }
 
@@ -37,3 +35,170 @@ pub fn build_resp_array(command: &str) -> Vec<u8> {
     string.extend_from_slice(&res);
     string
 }
+/// One command argument, from its textual form.
+pub fn word(arg: impl ToString) -> Vec<u8> {
+    arg.to_string().into_bytes()
+}
+
+/// Parses a boolean configuration value, `yes` or `no`.
+pub fn parse_yes_no(value: &str) -> Option<bool> {
+    match value.to_lowercase().as_str() {
+        "yes" => Some(true),
+        "no" => Some(false),
+        _ => None,
+    }
+}
+
+/// Parses a memory size as in Redis configs: bytes, or with a `k`, `kb`, `m`, `mb`, `g` or
+/// `gb` suffix (`k` is 1000, `kb` is 1024).
+pub fn parse_memory(value: &str) -> Option<u64> {
+    let value = value.to_lowercase();
+    let digits = value.trim_end_matches(|c: char| c.is_ascii_alphabetic());
+    let unit = match &value[digits.len()..] {
+        "" | "b" => 1,
+        "k" => 1000,
+        "kb" => 1024,
+        "m" => 1000 * 1000,
+        "mb" => 1024 * 1024,
+        "g" => 1000 * 1000 * 1000,
+        "gb" => 1024 * 1024 * 1024,
+        _ => return None,
+    };
+    digits.parse::<u64>().ok()?.checked_mul(unit)
+}
+
+pub fn yes_no(enabled: bool) -> &'static str {
+    if enabled {
+        "yes"
+    } else {
+        "no"
+    }
+}
+
+pub fn build_resp_simple_string(text: &str) -> Vec<u8> {
+    format!("+{}\r\n", text).as_bytes().to_vec()
+}
+pub fn build_resp_integer(value: i64) -> Vec<u8> {
+    format!(":{}\r\n", value).as_bytes().to_vec()
+}
+pub fn build_resp_error(message: &str) -> Vec<u8> {
+    format!("-ERR {}\r\n", message).as_bytes().to_vec()
+}
+/// Builds an error reply whose message already carries its error code (e.g. `WRONGTYPE ...`).
+pub fn build_resp_error_raw(message: &str) -> Vec<u8> {
+    format!("-{}\r\n", message).as_bytes().to_vec()
+}
+pub fn build_resp_array_raw(elements: Vec<Vec<u8>>) -> Vec<u8> {
+    let mut array = format!("*{}\r\n", elements.len()).as_bytes().to_vec();
+    array.extend_from_slice(&elements.concat());
+    array
+}
+/// Builds a RESP3 push frame, used for out-of-band messages such as Pub/Sub deliveries.
+pub fn build_resp_push(elements: Vec<Vec<u8>>) -> Vec<u8> {
+    let mut push = format!(">{}\r\n", elements.len()).as_bytes().to_vec();
+    push.extend_from_slice(&elements.concat());
+    push
+}
+/// Builds a RESP3 map, or the equivalent flat array for RESP2 clients.
+pub fn build_resp_map(pairs: Vec<(Vec<u8>, Vec<u8>)>, resp3: bool) -> Vec<u8> {
+    let mut map = if resp3 {
+        format!("%{}\r\n", pairs.len())
+    } else {
+        format!("*{}\r\n", pairs.len() * 2)
+    }
+    .as_bytes()
+    .to_vec();
+    for (key, value) in pairs {
+        map.extend_from_slice(&key);
+        map.extend_from_slice(&value);
+    }
+    map
+}
+/// Builds a null array: `_` for RESP3 clients, `*-1` for RESP2 ones.
+pub fn build_resp_null_array(resp3: bool) -> Vec<u8> {
+    if resp3 {
+        b"_\r\n".to_vec()
+    } else {
+        b"*-1\r\n".to_vec()
+    }
+}
+/// Builds a binary-safe bulk string; unlike `build_resp_string`, empty input is `$0`.
+pub fn build_resp_bulk(bytes: &[u8]) -> Vec<u8> {
+    let mut bulk = format!("${}\r\n", bytes.len()).as_bytes().to_vec();
+    bulk.extend_from_slice(bytes);
+    bulk.extend_from_slice(b"\r\n");
+    bulk
+}
+
+/// Glob-style matching as used by KEYS and PSUBSCRIBE: `*`, `?`, `[abc]`, `[^a-z]` and
+/// backslash escapes, following Redis' `stringmatchlen`.
+pub fn glob_match(pattern: &[u8], string: &[u8], nocase: bool) -> bool {
+    let fold = |c: u8| if nocase { c.to_ascii_lowercase() } else { c };
+    let (mut p, mut s) = (0, 0);
+    while p < pattern.len() {
+        match pattern[p] {
+            b'*' => {
+                while pattern.get(p + 1) == Some(&b'*') {
+                    p += 1;
+                }
+                if p + 1 == pattern.len() {
+                    return true;
+                }
+                return (s..=string.len())
+                    .any(|start| glob_match(&pattern[p + 1..], &string[start..], nocase));
+            }
+            b'?' => {
+                if s >= string.len() {
+                    return false;
+                }
+                s += 1;
+            }
+            b'[' => {
+                let Some(&c) = string.get(s) else {
+                    return false;
+                };
+                p += 1;
+                let negate = pattern.get(p) == Some(&b'^');
+                if negate {
+                    p += 1;
+                }
+                let mut matched = false;
+                while p < pattern.len() && pattern[p] != b']' {
+                    if pattern[p] == b'\\' && p + 1 < pattern.len() {
+                        p += 1;
+                        matched |= fold(pattern[p]) == fold(c);
+                    } else if pattern.get(p + 1) == Some(&b'-') && p + 2 < pattern.len() {
+                        let (mut start, mut end) = (fold(pattern[p]), fold(pattern[p + 2]));
+                        if start > end {
+                            std::mem::swap(&mut start, &mut end);
+                        }
+                        p += 2;
+                        matched |= (start..=end).contains(&fold(c));
+                    } else {
+                        matched |= fold(pattern[p]) == fold(c);
+                    }
+                    p += 1;
+                }
+                if matched == negate {
+                    return false;
+                }
+                s += 1;
+            }
+            b'\\' if p + 1 < pattern.len() => {
+                p += 1;
+                if string.get(s).map(|c| fold(*c)) != Some(fold(pattern[p])) {
+                    return false;
+                }
+                s += 1;
+            }
+            c => {
+                if string.get(s).map(|c| fold(*c)) != Some(fold(c)) {
+                    return false;
+                }
+                s += 1;
+            }
+        }
+        p += 1;
+    }
+    s == string.len()
+}
@@ -0,0 +1,218 @@
+use crate::listpack::ListpackEntry;
+
+// Encodings older Redis versions used for small collections: ziplists (`ziplist.c`),
+// intsets (`intset.c`) and zipmaps (`zipmap.c`). They only need to be read.
+
+const ZIPLIST_HEADER_SIZE: usize = 10;
+const ZIP_END: u8 = 0xFF;
+const ZIP_BIG_PREVLEN: u8 = 0xFE;
+
+fn read_uint_le(bytes: &[u8]) -> u64 {
+    bytes
+        .iter()
+        .rev()
+        .fold(0, |value, byte| (value << 8) | *byte as u64)
+}
+
+/// Decodes every entry of a serialized ziplist, or `None` if it is malformed.
+pub fn decode_ziplist(bytes: &[u8]) -> Option<Vec<ListpackEntry>> {
+    if bytes.len() < ZIPLIST_HEADER_SIZE + 1 {
+        return None;
+    }
+    let total = read_uint_le(&bytes[0..4]) as usize;
+    if total != bytes.len() || bytes[total - 1] != ZIP_END {
+        return None;
+    }
+
+    let mut entries = Vec::new();
+    let mut pos = ZIPLIST_HEADER_SIZE;
+    while *bytes.get(pos)? != ZIP_END {
+        // Skip the length of the previous entry.
+        pos += if bytes[pos] == ZIP_BIG_PREVLEN { 5 } else { 1 };
+        let encoding = *bytes.get(pos)?;
+        let (entry, len) = match encoding >> 6 {
+            0b00 => {
+                let str_len = (encoding & 0x3F) as usize;
+                let s = bytes.get(pos + 1..pos + 1 + str_len)?;
+                (ListpackEntry::String(s.to_vec()), 1 + str_len)
+            }
+            0b01 => {
+                let str_len = (((encoding & 0x3F) as usize) << 8) | *bytes.get(pos + 1)? as usize;
+                let s = bytes.get(pos + 2..pos + 2 + str_len)?;
+                (ListpackEntry::String(s.to_vec()), 2 + str_len)
+            }
+            0b10 => {
+                let len_bytes = bytes.get(pos + 1..pos + 5)?;
+                let str_len = u32::from_be_bytes(len_bytes.try_into().ok()?) as usize;
+                let s = bytes.get(pos + 5..pos + 5 + str_len)?;
+                (ListpackEntry::String(s.to_vec()), 5 + str_len)
+            }
+            _ => {
+                let width = match encoding {
+                    0xC0 => 2,
+                    0xD0 => 4,
+                    0xE0 => 8,
+                    0xF0 => 3,
+                    0xFE => 1,
+                    // 1111xxxx: an immediate 0 to 12, stored as xxxx - 1.
+                    0xF1..=0xFD => {
+                        let value = (encoding & 0x0F) as i64 - 1;
+                        entries.push(ListpackEntry::Integer(value));
+                        pos += 1;
+                        continue;
+                    }
+                    _ => return None,
+                };
+                let value = read_uint_le(bytes.get(pos + 1..pos + 1 + width)?);
+                let shift = 64 - width as u32 * 8;
+                let value = ((value << shift) as i64) >> shift;
+                (ListpackEntry::Integer(value), 1 + width)
+            }
+        };
+        entries.push(entry);
+        pos += len;
+    }
+    Some(entries)
+}
+
+/// Decodes the members of a serialized intset.
+pub fn decode_intset(bytes: &[u8]) -> Option<Vec<i64>> {
+    let width = read_uint_le(bytes.get(0..4)?) as usize;
+    let count = read_uint_le(bytes.get(4..8)?) as usize;
+    if !matches!(width, 2 | 4 | 8) || bytes.len() != 8 + width * count {
+        return None;
+    }
+    let shift = 64 - width as u32 * 8;
+    Some(
+        bytes[8..]
+            .chunks(width)
+            .map(|chunk| ((read_uint_le(chunk) << shift) as i64) >> shift)
+            .collect(),
+    )
+}
+
+/// Decodes the field/value pairs of a serialized zipmap.
+pub fn decode_zipmap(bytes: &[u8]) -> Option<Vec<(Vec<u8>, Vec<u8>)>> {
+    fn read_len(bytes: &[u8], pos: &mut usize) -> Option<usize> {
+        let len = *bytes.get(*pos)?;
+        if len < 254 {
+            *pos += 1;
+            Some(len as usize)
+        } else if len == 254 {
+            let len = read_uint_le(bytes.get(*pos + 1..*pos + 5)?) as usize;
+            *pos += 5;
+            Some(len)
+        } else {
+            None
+        }
+    }
+
+    // The first byte is the number of pairs, or 254 if there are more; read to the end.
+    let mut pos = 1;
+    let mut pairs = Vec::new();
+    while *bytes.get(pos)? != ZIP_END {
+        let field_len = read_len(bytes, &mut pos)?;
+        let field = bytes.get(pos..pos + field_len)?.to_vec();
+        pos += field_len;
+        let value_len = read_len(bytes, &mut pos)?;
+        let free = *bytes.get(pos)? as usize;
+        pos += 1;
+        let value = bytes.get(pos..pos + value_len)?.to_vec();
+        pos += value_len + free;
+        pairs.push((field, value));
+    }
+    Some(pairs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::corrupt::assert_rejects_damage;
+
+    /// A ziplist laid out byte by byte as Redis' `ziplistPush` builds it: one entry of each
+    /// encoding, and an entry after a long one, whose previous length takes five bytes.
+    fn fixture_ziplist() -> (Vec<u8>, Vec<ListpackEntry>) {
+        let mut bytes = vec![0x8C, 0x01, 0x00, 0x00, 0x82, 0x01, 0x00, 0x00, 0x0B, 0x00];
+        bytes.extend_from_slice(b"\x00\x05hello");
+        // 4 bit immediate, then 8, 16, 24, 32 and 64 bit integers.
+        bytes.extend_from_slice(&[0x07, 0xFD]);
+        bytes.extend_from_slice(&[0x02, 0xFE, 0x0D, 0x03, 0xFE, 0xFE]);
+        bytes.extend_from_slice(&[0x03, 0xC0, 0x2C, 0x01]);
+        bytes.extend_from_slice(&[0x04, 0xF0, 0x70, 0x11, 0x01]);
+        bytes.extend_from_slice(&[0x05, 0xD0, 0x00, 0xE1, 0xF5, 0x05]);
+        bytes.extend_from_slice(&[0x06, 0xE0, 0x00, 0xF2, 0x05, 0x2A, 0x01, 0x00, 0x00, 0x00]);
+        // 14 bit string lengths.
+        bytes.extend_from_slice(&[0x0A, 0x40, 0x46]);
+        bytes.extend_from_slice(&[b'a'; 70]);
+        bytes.extend_from_slice(&[0x49, 0x41, 0x04]);
+        bytes.extend_from_slice(&[b'b'; 260]);
+        bytes.extend_from_slice(b"\xFE\x07\x01\x00\x00\x03end");
+        bytes.push(ZIP_END);
+
+        let entries = vec![
+            ListpackEntry::String(b"hello".to_vec()),
+            ListpackEntry::Integer(12),
+            ListpackEntry::Integer(13),
+            ListpackEntry::Integer(-2),
+            ListpackEntry::Integer(300),
+            ListpackEntry::Integer(70000),
+            ListpackEntry::Integer(100_000_000),
+            ListpackEntry::Integer(5_000_000_000),
+            ListpackEntry::String(vec![b'a'; 70]),
+            ListpackEntry::String(vec![b'b'; 260]),
+            ListpackEntry::String(b"end".to_vec()),
+        ];
+        (bytes, entries)
+    }
+
+    #[test]
+    fn decodes_redis_ziplists() {
+        let (bytes, entries) = fixture_ziplist();
+        assert_eq!(decode_ziplist(&bytes).unwrap(), entries);
+        let empty = [0x0B, 0x00, 0x00, 0x00, 0x0A, 0x00, 0x00, 0x00, 0x00, 0x00, ZIP_END];
+        assert_eq!(decode_ziplist(&empty).unwrap(), vec![]);
+    }
+
+    #[test]
+    fn rejects_malformed_ziplist() {
+        let (bytes, _) = fixture_ziplist();
+        assert_rejects_damage(&bytes, |bytes| decode_ziplist(bytes).is_some());
+    }
+
+    #[test]
+    fn decodes_redis_intsets() {
+        // Members are sorted, in the smallest width that fits all of them.
+        let int16 = b"\x02\x00\x00\x00\x03\x00\x00\x00\xFE\xFF\x07\x00\x2C\x01";
+        assert_eq!(decode_intset(int16).unwrap(), vec![-2, 7, 300]);
+        let int32 = b"\x04\x00\x00\x00\x02\x00\x00\x00\x60\x79\xFE\xFF\x05\x00\x00\x00";
+        assert_eq!(decode_intset(int32).unwrap(), vec![-100_000, 5]);
+        let int64 = b"\x08\x00\x00\x00\x01\x00\x00\x00\x00\xF2\x05\x2A\x01\x00\x00\x00";
+        assert_eq!(decode_intset(int64).unwrap(), vec![5_000_000_000]);
+
+        assert_rejects_damage(int16, |bytes| decode_intset(bytes).is_some());
+        let mut bad_width = int16.to_vec();
+        bad_width[0] = 3;
+        assert!(decode_intset(&bad_width).is_none());
+        let mut bad_count = int16.to_vec();
+        bad_count[4] = 200;
+        assert!(decode_intset(&bad_count).is_none());
+    }
+
+    #[test]
+    fn decodes_redis_zipmaps() {
+        // The value of "field" is followed by one free byte, left over from a shorter
+        // value overwriting a longer one, and "big" has a five byte length.
+        let mut bytes = b"\x03\x01a\x01\x001\x05field\x05\x01valuex\x03big\xFE\x2C\x01\x00\x00\x00".to_vec();
+        bytes.extend_from_slice(&[b'v'; 300]);
+        bytes.push(ZIP_END);
+        assert_eq!(
+            decode_zipmap(&bytes).unwrap(),
+            vec![
+                (b"a".to_vec(), b"1".to_vec()),
+                (b"field".to_vec(), b"value".to_vec()),
+                (b"big".to_vec(), vec![b'v'; 300]),
+            ]
+        );
+        assert_rejects_damage(&bytes, |bytes| decode_zipmap(bytes).is_some());
+    }
+}
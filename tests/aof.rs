@@ -0,0 +1,306 @@
+mod common;
+
+use common::{encode, run_to_exit, Reply, Server};
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::Duration;
+
+const APPENDONLY: &[&str] = &["--appendonly", "yes", "--save", ""];
+
+/// The incremental AOF file commands are appended to.
+fn incr_file(dir: &Path) -> PathBuf {
+    let aof_dir = dir.join("appendonlydir");
+    let mut incrs = std::fs::read_dir(&aof_dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.to_string_lossy().ends_with(".incr.aof"))
+        .collect::<Vec<_>>();
+    incrs.sort();
+    incrs.pop().expect("an incremental AOF file")
+}
+
+#[test]
+fn writes_are_logged_whole_and_replayed_on_restart() {
+    let server = Server::start("aof-replay", APPENDONLY);
+    let mut client = server.client();
+    client.cmd(&["SET", "a key with spaces", "a value"]);
+    client.call(&[b"SET", b"bin\xff\r\n", b"\x00\xff"]);
+    client.cmd(&["SET", "ttl", "v", "EX", "3600"]);
+    client.cmd(&["SETBIT", "bits", "9", "1"]);
+    client.cmd(&["PFADD", "hll", "x", "y"]);
+    client.cmd(&["SELECT", "5"]);
+    client.cmd(&["SET", "deleted", "v"]);
+    client.cmd(&["DEL", "deleted"]);
+    client.cmd(&["SET", "db5", "v"]);
+    // Reads are not logged.
+    client.cmd(&["GET", "db5"]);
+
+    let log = std::fs::read(incr_file(&server.dir)).unwrap();
+    let expected = [
+        encode(&[b"SET", b"a key with spaces", b"a value"]),
+        encode(&[b"SET", b"bin\xff\r\n", b"\x00\xff"]),
+        encode(&[b"SELECT", b"5"]),
+        encode(&[b"DEL", b"deleted"]),
+    ];
+    for command in expected {
+        assert!(
+            log.windows(command.len()).any(|window| window == command),
+            "{:?} is not in the AOF",
+            String::from_utf8_lossy(&command)
+        );
+    }
+    // Relative expiries are logged as absolute times, so replay doesn't extend them.
+    assert!(log.windows(4).any(|window| window == b"PXAT"));
+    assert!(!log.windows(3).any(|window| window == b"GET"));
+
+    let server = Server::start_in(server.stop(), APPENDONLY);
+    let mut client = server.client();
+    assert_eq!(
+        client.cmd(&["GET", "a key with spaces"]),
+        Reply::bulk("a value")
+    );
+    assert_eq!(
+        client.call(&[b"GET", b"bin\xff\r\n"]),
+        Reply::bulk(b"\x00\xff")
+    );
+    assert_eq!(client.cmd(&["GET", "ttl"]), Reply::bulk("v"));
+    assert_eq!(client.cmd(&["GETBIT", "bits", "9"]), Reply::Integer(1));
+    assert_eq!(client.cmd(&["PFCOUNT", "hll"]), Reply::Integer(2));
+    client.cmd(&["SELECT", "5"]);
+    assert_eq!(client.cmd(&["GET", "db5"]), Reply::bulk("v"));
+    assert_eq!(client.cmd(&["GET", "deleted"]), Reply::Nil);
+}
+
+#[test]
+fn concurrent_writes_replay_to_the_same_result() {
+    let server = Server::start("aof-ordering", APPENDONLY);
+    let port = server.port;
+    let writers = (0..8)
+        .map(|writer| {
+            thread::spawn(move || {
+                let mut client = common::Client::connect(port);
+                for i in 0..200 {
+                    let value = format!("{}-{}", writer, i);
+                    client.cmd(&["SET", "contended", &value]);
+                    client.cmd(&["SETBIT", "bits", &(writer * 200 + i).to_string(), "1"]);
+                }
+            })
+        })
+        .collect::<Vec<_>>();
+    for writer in writers {
+        writer.join().unwrap();
+    }
+    let mut client = server.client();
+    let before = client.cmd(&["GET", "contended"]);
+    assert_eq!(client.cmd(&["BITCOUNT", "bits"]), Reply::Integer(1600));
+
+    let server = Server::start_in(server.stop(), APPENDONLY);
+    let mut client = server.client();
+    assert_eq!(client.cmd(&["GET", "contended"]), before);
+    assert_eq!(client.cmd(&["BITCOUNT", "bits"]), Reply::Integer(1600));
+}
+
+#[test]
+fn transactions_are_logged_as_a_block() {
+    let server = Server::start("aof-multi", APPENDONLY);
+    let mut client = server.client();
+    client.cmd(&["MULTI"]);
+    client.cmd(&["SET", "a", "1"]);
+    client.cmd(&["SET", "b", "2"]);
+    client.cmd(&["EXEC"]);
+    let log = std::fs::read(incr_file(&server.dir)).unwrap();
+    let block = [
+        encode(&[b"MULTI"]),
+        encode(&[b"SET", b"a", b"1"]),
+        encode(&[b"SET", b"b", b"2"]),
+        encode(&[b"EXEC"]),
+    ]
+    .concat();
+    assert!(log.windows(block.len()).any(|window| window == block));
+}
+
+#[test]
+fn keys_expired_on_access_are_logged_as_deleted() {
+    let server = Server::start("aof-lazy-expire", APPENDONLY);
+    let mut client = server.client();
+    client.cmd(&["SET", "short", "v", "PX", "30"]);
+    thread::sleep(Duration::from_millis(60));
+    assert_eq!(client.cmd(&["GET", "short"]), Reply::Nil);
+    client.cmd(&["SET", "after", "1"]);
+
+    let log = std::fs::read(incr_file(&server.dir)).unwrap();
+    let deleted = [encode(&[b"DEL", b"short"]), encode(&[b"SET", b"after", b"1"])].concat();
+    assert!(log.windows(deleted.len()).any(|window| window == deleted));
+}
+
+#[test]
+fn published_messages_are_not_logged() {
+    let server = Server::start("aof-publish", APPENDONLY);
+    let mut client = server.client();
+    client.cmd(&["PUBLISH", "news", "hello"]);
+    client.cmd(&["SPUBLISH", "news", "hello"]);
+    client.cmd(&["MULTI"]);
+    client.cmd(&["PUBLISH", "news", "hello"]);
+    client.cmd(&["EXEC"]);
+    client.cmd(&["SET", "after", "1"]);
+
+    let log = std::fs::read(incr_file(&server.dir)).unwrap();
+    assert!(!log.windows(7).any(|window| window == b"PUBLISH"));
+    assert!(!log.windows(5).any(|window| window == b"MULTI"));
+    assert!(log.windows(5).any(|window| window == b"after"));
+}
+
+#[test]
+fn truncated_tail_is_dropped_or_refused() {
+    let server = Server::start("aof-truncated", APPENDONLY);
+    let mut client = server.client();
+    client.cmd(&["SET", "kept", "1"]);
+    let dir = server.stop();
+
+    // A write torn by a crash, and a transaction that never reached EXEC.
+    let path = incr_file(&dir);
+    let mut log = std::fs::read(&path).unwrap();
+    let intact_len = log.len();
+    log.extend_from_slice(&encode(&[b"MULTI"]));
+    log.extend_from_slice(&encode(&[b"SET", b"lost", b"1"]));
+    log.extend_from_slice(b"*3\r\n$3\r\nSET\r\n$4\r\nto");
+    std::fs::write(&path, &log).unwrap();
+
+    let refusing = [APPENDONLY, &["--aof-load-truncated", "no"]].concat();
+    assert_eq!(run_to_exit(&dir, &refusing), Some(1));
+    assert_eq!(std::fs::read(&path).unwrap(), log);
+
+    let server = Server::start_in(dir, APPENDONLY);
+    let mut client = server.client();
+    assert_eq!(client.cmd(&["GET", "kept"]), Reply::bulk("1"));
+    assert_eq!(client.cmd(&["GET", "lost"]), Reply::Nil);
+    assert_eq!(std::fs::metadata(&path).unwrap().len() as usize, intact_len);
+}
+
+fn manifest(dir: &Path) -> String {
+    std::fs::read_to_string(dir.join("appendonlydir/appendonly.aof.manifest")).unwrap()
+}
+
+fn aof_files(dir: &Path) -> Vec<String> {
+    let mut names = std::fs::read_dir(dir.join("appendonlydir"))
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+        .collect::<Vec<_>>();
+    names.sort();
+    names
+}
+
+#[test]
+fn bgrewriteaof_replaces_the_base_and_incremental_files() {
+    let server = Server::start("aof-rewrite", APPENDONLY);
+    let mut client = server.client();
+    for i in 0..50 {
+        client.cmd(&["SET", "counter", &i.to_string()]);
+    }
+    client.cmd(&["GEOADD", "places", "13.361389", "38.115556", "Palermo"]);
+    assert_eq!(
+        client.cmd(&["BGREWRITEAOF"]),
+        Reply::status("Background append only file rewriting started")
+    );
+    common::wait_until(|| manifest(&server.dir).contains("appendonly.aof.2.base.rdb"));
+    assert_eq!(
+        manifest(&server.dir),
+        "file appendonly.aof.2.base.rdb seq 2 type b\n\
+         file appendonly.aof.2.incr.aof seq 2 type i\n"
+    );
+    // The replaced files are deleted right after the new manifest is written.
+    common::wait_until(|| {
+        aof_files(&server.dir)
+            == [
+                "appendonly.aof.2.base.rdb",
+                "appendonly.aof.2.incr.aof",
+                "appendonly.aof.manifest",
+            ]
+    });
+
+    client.cmd(&["SET", "after", "rewrite"]);
+    let server = Server::start_in(server.stop(), APPENDONLY);
+    let mut client = server.client();
+    assert_eq!(client.cmd(&["GET", "counter"]), Reply::bulk("49"));
+    assert_eq!(client.cmd(&["GET", "after"]), Reply::bulk("rewrite"));
+    assert_eq!(
+        client.cmd(&["GEOHASH", "places", "Palermo"]),
+        Reply::Array(vec![Reply::bulk("sqc8b49rny0")])
+    );
+}
+
+#[test]
+fn rewrite_without_preamble_writes_commands() {
+    let args = [APPENDONLY, &["--aof-use-rdb-preamble", "no"]].concat();
+    let server = Server::start("aof-rewrite-commands", &args);
+    let mut client = server.client();
+    client.cmd(&["SET", "a key", "v"]);
+    client.cmd(&["BGREWRITEAOF"]);
+    let base = server.dir.join("appendonlydir/appendonly.aof.2.base.aof");
+    common::wait_until(|| manifest(&server.dir).contains("2.base.aof"));
+    let contents = std::fs::read(base).unwrap();
+    assert!(contents.starts_with(b"*2\r\n$6\r\nSELECT\r\n$1\r\n0\r\n"));
+    assert!(contents.ends_with(&encode(&[b"SET", b"a key", b"v"])));
+
+    let server = Server::start_in(server.stop(), &args);
+    assert_eq!(server.client().cmd(&["GET", "a key"]), Reply::bulk("v"));
+}
+
+#[test]
+fn rewrite_without_preamble_restores_non_string_values() {
+    let args = [APPENDONLY, &["--aof-use-rdb-preamble", "no"]].concat();
+    let server = Server::start("aof-rewrite-restore", &args);
+    let mut client = server.client();
+    client.cmd(&["GEOADD", "sicily", "13.361389", "38.115556", "Palermo"]);
+    let position = client.cmd(&["GEOPOS", "sicily", "Palermo"]);
+    client.cmd(&["BGREWRITEAOF"]);
+    let base = server.dir.join("appendonlydir/appendonly.aof.2.base.aof");
+    common::wait_until(|| manifest(&server.dir).contains("2.base.aof"));
+    let contents = std::fs::read(base).unwrap();
+    assert!(contents.starts_with(b"*2\r\n$6\r\nSELECT\r\n$1\r\n0\r\n"));
+    let restore = b"$7\r\nRESTORE\r\n$6\r\nsicily\r\n$1\r\n0\r\n";
+    assert!(contents
+        .windows(restore.len())
+        .any(|window| window == restore));
+
+    let server = Server::start_in(server.stop(), &args);
+    assert_eq!(
+        server.client().cmd(&["GEOPOS", "sicily", "Palermo"]),
+        position
+    );
+}
+
+#[test]
+fn automatic_rewrite_after_the_aof_doubles() {
+    let args = [APPENDONLY, &["--auto-aof-rewrite-min-size", "4kb"]].concat();
+    let server = Server::start("aof-auto-rewrite", &args);
+    let mut client = server.client();
+    let value = "x".repeat(100);
+    for _ in 0..60 {
+        client.cmd(&["SET", "k", &value]);
+    }
+    common::wait_until(|| manifest(&server.dir).contains("seq 2 type b"));
+}
+
+#[test]
+fn legacy_single_file_aof_is_moved_into_the_directory() {
+    let server = Server::start("aof-legacy", &["--save", ""]);
+    let dir = server.stop();
+    let commands = [
+        encode(&[b"SELECT", b"0"]),
+        encode(&[b"SET", b"legacy", b"1"]),
+    ]
+    .concat();
+    std::fs::write(dir.join("appendonly.aof"), commands).unwrap();
+
+    let server = Server::start_in(dir, APPENDONLY);
+    assert_eq!(server.client().cmd(&["GET", "legacy"]), Reply::bulk("1"));
+    assert!(!server.dir.join("appendonly.aof").exists());
+    assert!(manifest(&server.dir).starts_with("file appendonly.aof seq 1 type b\n"));
+}
+
+#[test]
+fn bgrewriteaof_needs_appendonly() {
+    let server = Server::start("aof-disabled", &["--save", ""]);
+    assert!(server.client().cmd(&["BGREWRITEAOF"]).is_error());
+}
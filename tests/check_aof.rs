@@ -0,0 +1,152 @@
+mod common;
+
+use common::{encode, Server};
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// Runs the checker and returns its exit code and output.
+fn check_aof(args: &[&str]) -> (Option<i32>, String) {
+    let output = Command::new(env!("CARGO_BIN_EXE_altredis-check-aof"))
+        .args(args)
+        .output()
+        .expect("run altredis-check-aof");
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    (output.status.code(), stdout)
+}
+
+/// Writes `contents` as a single-file AOF in a fresh directory.
+fn legacy_aof(name: &str, contents: &[u8]) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!(
+        "altredis-check-aof-{}-{}",
+        name,
+        std::process::id()
+    ));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    let file = dir.join("appendonly.aof");
+    std::fs::write(&file, contents).unwrap();
+    file
+}
+
+fn path(file: &Path) -> &str {
+    file.to_str().unwrap()
+}
+
+fn commands(commands: &[&[&[u8]]]) -> Vec<u8> {
+    commands.iter().flat_map(|args| encode(args)).collect()
+}
+
+#[test]
+fn valid_file_reports_its_commands() {
+    let contents = commands(&[&[b"SELECT", b"0"], &[b"SET", b"key", b"\r\n\xff"]]);
+    let file = legacy_aof("valid", &contents);
+
+    let (code, output) = check_aof(&[path(&file)]);
+    assert_eq!(code, Some(0), "{}", output);
+    assert!(output.starts_with("Start checking Old-Style AOF\n"));
+    assert!(output.contains(&format!(
+        "size={}, ok_up_to={}, commands=2, diff=0",
+        contents.len(),
+        contents.len()
+    )));
+    assert!(output.contains("is valid"));
+}
+
+#[test]
+fn fix_truncates_to_the_last_whole_command() {
+    let whole = commands(&[&[b"SET", b"a", b"1"], &[b"SET", b"b", b"2"]]);
+    let mut contents = whole.clone();
+    contents.extend_from_slice(b"*3\r\n$3\r\nSET\r\n$1\r\nc");
+    let file = legacy_aof("truncated", &contents);
+
+    let (code, output) = check_aof(&[path(&file)]);
+    assert_eq!(code, Some(1), "{}", output);
+    assert!(output.contains(&format!(
+        "Unexpected end of file reading the append only file at offset {}",
+        whole.len()
+    )));
+    assert!(output.contains("Use the --fix option"));
+    assert_eq!(std::fs::read(&file).unwrap(), contents);
+
+    let (code, output) = check_aof(&["--fix", path(&file)]);
+    assert_eq!(code, Some(0), "{}", output);
+    assert!(output.contains(&format!("from {} to {} bytes", contents.len(), whole.len())));
+    assert_eq!(std::fs::read(&file).unwrap(), whole);
+    assert_eq!(check_aof(&[path(&file)]).0, Some(0));
+}
+
+#[test]
+fn fix_drops_an_unfinished_transaction() {
+    let kept = commands(&[&[b"SET", b"a", b"1"]]);
+    let mut contents = kept.clone();
+    contents.extend(commands(&[&[b"MULTI"], &[b"SET", b"b", b"2"]]));
+    let file = legacy_aof("multi", &contents);
+
+    let (code, output) = check_aof(&[path(&file)]);
+    assert_eq!(code, Some(1), "{}", output);
+    assert!(output.contains(&format!(
+        "Reached EOF before reading EXEC for the MULTI at offset {}",
+        kept.len()
+    )));
+    assert_eq!(check_aof(&["--fix", path(&file)]).0, Some(0));
+    assert_eq!(std::fs::read(&file).unwrap(), kept);
+}
+
+#[test]
+fn garbage_and_unbalanced_exec_are_reported_at_their_offset() {
+    let set = commands(&[&[b"SET", b"a", b"1"]]);
+    let mut garbage = set.clone();
+    garbage.extend_from_slice(b"SET b 2\r\n");
+    let file = legacy_aof("garbage", &garbage);
+    let (code, output) = check_aof(&[path(&file)]);
+    assert_eq!(code, Some(1));
+    assert!(output.contains(&format!(
+        "Bad file format reading the append only file at offset {}",
+        set.len()
+    )));
+
+    let mut exec = set.clone();
+    exec.extend(commands(&[&[b"EXEC"]]));
+    let file = legacy_aof("exec", &exec);
+    let (code, output) = check_aof(&[path(&file)]);
+    assert_eq!(code, Some(1));
+    assert!(output.contains(&format!(
+        "Unexpected EXEC without MULTI at offset {}",
+        set.len()
+    )));
+}
+
+#[test]
+fn manifest_checks_each_file_and_fixes_only_the_last() {
+    let server = Server::start("check-aof-manifest", &["--save", "", "--appendonly", "yes"]);
+    let mut client = server.client();
+    client.cmd(&["SET", "a", "1"]);
+    client.cmd(&["SET", "b", "2"]);
+    let dir = server.stop().join("appendonlydir");
+    let manifest = dir.join("appendonly.aof.manifest");
+
+    let (code, output) = check_aof(&[path(&manifest)]);
+    assert_eq!(code, Some(0), "{}", output);
+    assert!(output.starts_with("Start checking Multi Part AOF\n"));
+    assert!(output.contains("rdb_preamble=yes"));
+    assert!(output.contains("commands=3"));
+
+    let incr = dir.join("appendonly.aof.1.incr.aof");
+    let whole = std::fs::read(&incr).unwrap();
+    let mut file = OpenOptions::new().append(true).open(&incr).unwrap();
+    file.write_all(b"*2\r\n$3\r\nDEL").unwrap();
+    assert_eq!(check_aof(&[path(&manifest)]).0, Some(1));
+    assert_eq!(check_aof(&["--fix", path(&manifest)]).0, Some(0));
+    assert_eq!(std::fs::read(&incr).unwrap(), whole);
+
+    // A damaged base file is followed by the increments, so it can't be cut short.
+    let base = dir.join("appendonly.aof.1.base.rdb");
+    let rdb = std::fs::read(&base).unwrap();
+    std::fs::write(&base, &rdb[..rdb.len() - 4]).unwrap();
+    let (code, output) = check_aof(&["--fix", path(&manifest)]);
+    assert_eq!(code, Some(1), "{}", output);
+    assert!(output.contains("Only the last file of the manifest can be fixed"));
+    assert_eq!(std::fs::read(&base).unwrap().len(), rdb.len() - 4);
+}
@@ -0,0 +1,140 @@
+mod common;
+
+use common::{Reply, Server};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// Runs the checker and returns its exit code and output.
+fn check_rdb(args: &[&str]) -> (Option<i32>, String) {
+    let output = Command::new(env!("CARGO_BIN_EXE_altredis-check-rdb"))
+        .args(args)
+        .output()
+        .expect("run altredis-check-rdb");
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    (output.status.code(), stdout)
+}
+
+/// Saves a small dataset spread over two databases and returns the dump file.
+fn saved_dump(name: &str) -> PathBuf {
+    let server = Server::start(name, &["--save", ""]);
+    let mut client = server.client();
+    client.cmd(&["SET", "small", "v"]);
+    client.cmd(&["SET", "big", &"x".repeat(100)]);
+    client.cmd(&["SET", "expiring", "v", "PX", "3600000"]);
+    client.cmd(&[
+        "GEOADD",
+        "Sicily",
+        "13.361389",
+        "38.115556",
+        "Palermo",
+        "15.087269",
+        "37.502669",
+        "Catania",
+    ]);
+    client.cmd(&["SELECT", "2"]);
+    client.cmd(&["SET", "quote\"\n", "v"]);
+    assert_eq!(client.cmd(&["SAVE"]), Reply::status("OK"));
+    server.stop().join("dump.rdb")
+}
+
+fn path(file: &Path) -> &str {
+    file.to_str().unwrap()
+}
+
+#[test]
+fn reports_databases_biggest_keys_and_expirations() {
+    let dump = saved_dump("check-rdb-report");
+    let (code, output) = check_rdb(&[path(&dump), "--top", "1"]);
+    assert_eq!(code, Some(0), "{}", output);
+
+    let lines = output.lines().collect::<Vec<_>>();
+    for expected in [
+        "RDB version: 11",
+        "DB 0: 4 keys, 1 with an expiry (string: 3, zset: 1)",
+        "DB 2: 1 keys, 0 with an expiry (string: 1)",
+        "  db 0 string 'big': 100 bytes",
+        "  db 0 zset 'Sicily': 2 members",
+        "\\o/ RDB looks OK! \\o/",
+    ] {
+        assert!(
+            lines.contains(&expected),
+            "{:?} not in:\n{}",
+            expected,
+            output
+        );
+    }
+    assert!(!output.contains("'small'"));
+    let expiry = lines
+        .iter()
+        .find(|line| line.starts_with("  db 0 'expiring' at "))
+        .expect("expiration listed");
+    let seconds = expiry
+        .rsplit_once("(in ")
+        .and_then(|(_, rest)| rest.strip_suffix("s)"))
+        .and_then(|seconds| seconds.parse::<u64>().ok())
+        .expect("time left");
+    assert!((3500..=3600).contains(&seconds), "{}", expiry);
+}
+
+#[test]
+fn writes_every_key_to_the_json_report() {
+    let dump = saved_dump("check-rdb-json");
+    let json = dump.with_file_name("report.json");
+    let (code, output) = check_rdb(&[path(&dump), "--json", path(&json)]);
+    assert_eq!(code, Some(0), "{}", output);
+
+    let report = std::fs::read_to_string(&json).unwrap();
+    assert!(report.starts_with("{\"valid\":true,\"error\":null,\"rdb_version\":11,"));
+    assert!(
+        report.contains("{\"db\":0,\"keys\":4,\"expires\":1,\"types\":{\"string\":3,\"zset\":1}}")
+    );
+    assert!(report.contains(
+        "{\"db\":2,\"key\":\"quote\\\"\\n\",\"type\":\"string\",\"size\":1,\"expiry_ms\":null}"
+    ));
+    assert!(report.contains("{\"db\":0,\"key\":\"Sicily\",\"type\":\"zset\",\"size\":2,"));
+}
+
+#[test]
+fn reports_the_keys_read_before_a_truncation() {
+    let dump = saved_dump("check-rdb-truncated");
+    let json = dump.with_file_name("report.json");
+    let bytes = std::fs::read(&dump).unwrap();
+    std::fs::write(&dump, &bytes[..bytes.len() - 20]).unwrap();
+
+    let (code, output) = check_rdb(&[path(&dump), "--json", path(&json)]);
+    assert_eq!(code, Some(1), "{}", output);
+    assert!(output.contains("--- RDB ERROR DETECTED ---"));
+    assert!(output.contains("Keys read before the error: "));
+    let report = std::fs::read_to_string(&json).unwrap();
+    assert!(report.starts_with("{\"valid\":false,\"error\":\""));
+}
+
+#[test]
+fn checksum_mismatch_is_an_error_unless_skipped() {
+    let dump = saved_dump("check-rdb-checksum");
+    let mut bytes = std::fs::read(&dump).unwrap();
+    let last = bytes.len() - 1;
+    bytes[last] ^= 0xff;
+    std::fs::write(&dump, &bytes).unwrap();
+
+    let (code, output) = check_rdb(&[path(&dump)]);
+    assert_eq!(code, Some(1), "{}", output);
+    assert!(output.contains("--- RDB ERROR DETECTED ---"));
+    // All keys come before the checksum, so they are still reported.
+    assert!(output.contains("Keys read before the error: 5"));
+
+    let (code, output) = check_rdb(&[path(&dump), "--no-checksum"]);
+    assert_eq!(code, Some(0), "{}", output);
+}
+
+#[test]
+fn usage_and_missing_files_exit_with_an_error() {
+    let (code, output) = check_rdb(&[]);
+    assert_eq!(code, Some(1));
+    assert!(output.starts_with("Usage: altredis-check-rdb"));
+
+    let missing = std::env::temp_dir().join("altredis-check-rdb-missing.rdb");
+    let (code, output) = check_rdb(&[path(&missing)]);
+    assert_eq!(code, Some(1));
+    assert!(output.contains("Cannot open "));
+}
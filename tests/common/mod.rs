@@ -0,0 +1,253 @@
+//! Helpers for tests that run the server binary and talk to it over TCP.
+#![allow(dead_code)]
+
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// A decoded RESP2 or RESP3 reply.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Reply {
+    Status(String),
+    Error(String),
+    Integer(i64),
+    Bulk(Vec<u8>),
+    Nil,
+    Array(Vec<Reply>),
+    /// A RESP3 out-of-band push.
+    Push(Vec<Reply>),
+}
+
+impl Reply {
+    pub fn bulk(bytes: impl AsRef<[u8]>) -> Self {
+        Reply::Bulk(bytes.as_ref().to_vec())
+    }
+
+    pub fn status(text: &str) -> Self {
+        Reply::Status(text.to_owned())
+    }
+
+    pub fn into_array(self) -> Vec<Reply> {
+        match self {
+            Reply::Array(items) | Reply::Push(items) => items,
+            other => panic!("expected an array, got {:?}", other),
+        }
+    }
+
+    pub fn is_error(&self) -> bool {
+        matches!(self, Reply::Error(_))
+    }
+}
+
+pub struct Client {
+    reader: BufReader<TcpStream>,
+    writer: TcpStream,
+}
+
+impl Client {
+    pub fn connect(port: u16) -> Self {
+        let stream = TcpStream::connect(("127.0.0.1", port)).expect("connect to server");
+        stream
+            .set_read_timeout(Some(Duration::from_secs(10)))
+            .unwrap();
+        Self {
+            reader: BufReader::new(stream.try_clone().unwrap()),
+            writer: stream,
+        }
+    }
+
+    pub fn send(&mut self, args: &[&[u8]]) {
+        self.writer.write_all(&encode(args)).unwrap();
+    }
+
+    /// Sends a command and reads its reply.
+    pub fn call(&mut self, args: &[&[u8]]) -> Reply {
+        self.send(args);
+        self.read()
+    }
+
+    /// Like `call`, for commands whose arguments are all text.
+    pub fn cmd(&mut self, args: &[&str]) -> Reply {
+        let args = args.iter().map(|arg| arg.as_bytes()).collect::<Vec<_>>();
+        self.call(&args)
+    }
+
+    pub fn read(&mut self) -> Reply {
+        read_reply(&mut self.reader)
+    }
+
+    /// Reads a reply, or `None` if nothing arrives within `timeout`.
+    pub fn try_read(&mut self, timeout: Duration) -> Option<Reply> {
+        self.writer.set_read_timeout(Some(timeout)).unwrap();
+        let pending = !self.reader.buffer().is_empty() || self.reader.fill_buf().is_ok();
+        self.writer
+            .set_read_timeout(Some(Duration::from_secs(10)))
+            .unwrap();
+        pending.then(|| self.read())
+    }
+
+    /// Reads exactly `len` raw bytes, e.g. an RDB payload after FULLRESYNC.
+    pub fn read_raw(&mut self, len: usize) -> Vec<u8> {
+        let mut bytes = vec![0; len];
+        self.reader.read_exact(&mut bytes).unwrap();
+        bytes
+    }
+
+    pub fn read_line(&mut self) -> String {
+        let mut line = String::new();
+        self.reader.read_line(&mut line).unwrap();
+        line.trim_end().to_owned()
+    }
+}
+
+pub fn encode(args: &[&[u8]]) -> Vec<u8> {
+    let mut out = format!("*{}\r\n", args.len()).into_bytes();
+    for arg in args {
+        out.extend_from_slice(format!("${}\r\n", arg.len()).as_bytes());
+        out.extend_from_slice(arg);
+        out.extend_from_slice(b"\r\n");
+    }
+    out
+}
+
+fn read_line(reader: &mut impl BufRead) -> String {
+    let mut line = String::new();
+    reader.read_line(&mut line).expect("read reply");
+    assert!(line.ends_with("\r\n"), "connection closed mid-reply");
+    line.truncate(line.len() - 2);
+    line
+}
+
+pub fn read_reply(reader: &mut impl BufRead) -> Reply {
+    let line = read_line(reader);
+    let (kind, rest) = line.split_at(1);
+    let items = |reader: &mut _, count: usize| (0..count).map(|_| read_reply(reader)).collect();
+    match kind {
+        "+" => Reply::Status(rest.to_owned()),
+        "-" => Reply::Error(rest.to_owned()),
+        ":" => Reply::Integer(rest.parse().unwrap()),
+        "_" => Reply::Nil,
+        "#" => Reply::Integer((rest == "t") as i64),
+        "," => Reply::Status(rest.to_owned()),
+        "$" if rest == "-1" => Reply::Nil,
+        "$" => {
+            let mut bytes = vec![0; rest.parse::<usize>().unwrap() + 2];
+            reader.read_exact(&mut bytes).unwrap();
+            bytes.truncate(bytes.len() - 2);
+            Reply::Bulk(bytes)
+        }
+        "*" if rest == "-1" => Reply::Nil,
+        "*" | "~" => Reply::Array(items(reader, rest.parse().unwrap())),
+        "%" => Reply::Array(items(reader, rest.parse::<usize>().unwrap() * 2)),
+        ">" => Reply::Push(items(reader, rest.parse().unwrap())),
+        _ => panic!("unexpected reply line {:?}", line),
+    }
+}
+
+/// A server process with its own port and data directory, killed when dropped.
+pub struct Server {
+    pub port: u16,
+    pub dir: PathBuf,
+    child: Child,
+}
+
+impl Server {
+    pub fn start(name: &str, args: &[&str]) -> Self {
+        let dir = std::env::temp_dir().join(format!("altredis-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        Self::start_in(dir, args)
+    }
+
+    /// Starts a server on an existing data directory, e.g. to check what it loads.
+    pub fn start_in(dir: PathBuf, args: &[&str]) -> Self {
+        let port = free_port();
+        let child = Command::new(env!("CARGO_BIN_EXE_altredis"))
+            .args(["--port", &port.to_string(), "--dir", dir.to_str().unwrap()])
+            .args(args)
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .expect("spawn server");
+        let server = Self { port, dir, child };
+        wait_until(|| TcpStream::connect(("127.0.0.1", port)).is_ok());
+        server
+    }
+
+    pub fn client(&self) -> Client {
+        Client::connect(self.port)
+    }
+
+    /// Stops the server and hands back its data directory.
+    pub fn stop(mut self) -> PathBuf {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+        std::mem::take(&mut self.dir)
+    }
+
+    /// Waits for the process to exit on its own, e.g. after SHUTDOWN.
+    pub fn wait_exit(&mut self) -> bool {
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while Instant::now() < deadline {
+            if let Ok(Some(_)) = self.child.try_wait() {
+                return true;
+            }
+            thread::sleep(Duration::from_millis(20));
+        }
+        false
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+/// Runs the server on `dir` expecting it to exit by itself, e.g. because it refuses to
+/// load its data, and returns the exit code.
+pub fn run_to_exit(dir: &Path, args: &[&str]) -> Option<i32> {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_altredis"))
+        .args([
+            "--port",
+            &free_port().to_string(),
+            "--dir",
+            dir.to_str().unwrap(),
+        ])
+        .args(args)
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .expect("spawn server");
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while Instant::now() < deadline {
+        if let Some(status) = child.try_wait().unwrap() {
+            return status.code();
+        }
+        thread::sleep(Duration::from_millis(20));
+    }
+    let _ = child.kill();
+    let _ = child.wait();
+    panic!("server kept running");
+}
+
+fn free_port() -> u16 {
+    TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port()
+}
+
+/// Polls `condition` until it holds, failing the test after a few seconds.
+pub fn wait_until(mut condition: impl FnMut() -> bool) {
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while !condition() {
+        assert!(Instant::now() < deadline, "timed out waiting for condition");
+        thread::sleep(Duration::from_millis(20));
+    }
+}
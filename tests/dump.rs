@@ -0,0 +1,191 @@
+mod common;
+
+use common::{wait_until, Client, Reply, Server};
+
+fn dump(client: &mut Client, key: &[u8]) -> Vec<u8> {
+    match client.call(&[b"DUMP", key]) {
+        Reply::Bulk(payload) => payload,
+        other => panic!("DUMP replied {:?}", other),
+    }
+}
+
+#[test]
+fn restore_recreates_each_type_under_a_new_key() {
+    let server = Server::start("dump-types", &["--save", ""]);
+    let mut client = server.client();
+    client.call(&[b"SET", b"bytes", b"\x00\xffvalue\r\n"]);
+    client.cmd(&["PFADD", "visitors", "a", "b", "c", "d"]);
+    client.cmd(&[
+        "GEOADD",
+        "Sicily",
+        "13.361389",
+        "38.115556",
+        "Palermo",
+        "15.087269",
+        "37.502669",
+        "Catania",
+    ]);
+
+    for (from, to) in [
+        (&b"bytes"[..], &b"bytes-copy"[..]),
+        (b"visitors", b"visitors-copy"),
+        (b"Sicily", b"Sicily-copy"),
+    ] {
+        let payload = dump(&mut client, from);
+        assert_eq!(
+            client.call(&[b"RESTORE", to, b"0", &payload]),
+            Reply::status("OK")
+        );
+    }
+    assert_eq!(
+        client.cmd(&["GET", "bytes-copy"]),
+        Reply::bulk(b"\x00\xffvalue\r\n")
+    );
+    assert_eq!(client.cmd(&["PFCOUNT", "visitors-copy"]), Reply::Integer(4));
+    client.cmd(&["PFADD", "visitors-copy", "e"]);
+    assert_eq!(client.cmd(&["PFCOUNT", "visitors-copy"]), Reply::Integer(5));
+    assert_eq!(
+        client.cmd(&["GEODIST", "Sicily-copy", "Palermo", "Catania"]),
+        Reply::bulk("166274.1516")
+    );
+}
+
+#[test]
+fn dump_of_a_missing_key_is_nil() {
+    let server = Server::start("dump-missing", &["--save", ""]);
+    let mut client = server.client();
+    assert_eq!(client.cmd(&["DUMP", "nothing"]), Reply::Nil);
+}
+
+#[test]
+fn restore_accepts_a_payload_dumped_by_redis() {
+    // `SET mykey 10` then `DUMP mykey` on Redis 7.0, as shown in the DUMP docs.
+    let payload = b"\x00\xc0\n\n\x00n\x9fWE\x0e\xaec\xbb";
+    let server = Server::start("dump-redis", &["--save", ""]);
+    let mut client = server.client();
+    assert_eq!(
+        client.call(&[b"RESTORE", b"mykey", b"0", payload]),
+        Reply::status("OK")
+    );
+    assert_eq!(client.cmd(&["GET", "mykey"]), Reply::bulk("10"));
+}
+
+#[test]
+fn restore_needs_replace_to_overwrite() {
+    let server = Server::start("dump-busy", &["--save", ""]);
+    let mut client = server.client();
+    client.cmd(&["SET", "key", "old"]);
+    client.cmd(&["SET", "source", "new"]);
+    let payload = dump(&mut client, b"source");
+
+    assert_eq!(
+        client.call(&[b"RESTORE", b"key", b"0", &payload]),
+        Reply::Error("BUSYKEY Target key name already exists.".into())
+    );
+    assert_eq!(client.cmd(&["GET", "key"]), Reply::bulk("old"));
+    assert_eq!(
+        client.call(&[b"RESTORE", b"key", b"0", &payload, b"REPLACE"]),
+        Reply::status("OK")
+    );
+    assert_eq!(client.cmd(&["GET", "key"]), Reply::bulk("new"));
+}
+
+#[test]
+fn restore_rejects_a_wrong_version_or_checksum() {
+    let server = Server::start("dump-corrupt", &["--save", ""]);
+    let mut client = server.client();
+    client.cmd(&["SET", "key", "value"]);
+    let payload = dump(&mut client, b"key");
+    let mismatch = Reply::Error("ERR DUMP payload version or checksum are wrong".into());
+
+    let mut flipped = payload.clone();
+    flipped[2] ^= 0x20;
+    assert_eq!(
+        client.call(&[b"RESTORE", b"flipped", b"0", &flipped]),
+        mismatch
+    );
+    let mut newer = payload.clone();
+    let version = newer.len() - 10;
+    newer[version] = 0xff;
+    assert_eq!(client.call(&[b"RESTORE", b"newer", b"0", &newer]), mismatch);
+    assert_eq!(
+        client.call(&[b"RESTORE", b"short", b"0", &payload[..6]]),
+        mismatch
+    );
+    assert_eq!(client.cmd(&["KEYS", "*"]).into_array().len(), 1);
+}
+
+#[test]
+fn restore_applies_relative_and_absolute_ttls() {
+    let server = Server::start("dump-ttl", &["--save", ""]);
+    let mut client = server.client();
+    client.cmd(&["SET", "source", "v"]);
+    let payload = dump(&mut client, b"source");
+
+    client.call(&[b"RESTORE", b"short", b"200", &payload]);
+    assert_eq!(client.cmd(&["GET", "short"]), Reply::bulk("v"));
+    wait_until(|| client.cmd(&["GET", "short"]) == Reply::Nil);
+
+    // An absolute TTL in the past restores nothing, and with REPLACE removes the key.
+    assert_eq!(
+        client.call(&[b"RESTORE", b"gone", b"1000", &payload, b"ABSTTL"]),
+        Reply::status("OK")
+    );
+    assert_eq!(client.cmd(&["GET", "gone"]), Reply::Nil);
+    client.cmd(&["SET", "replaced", "old"]);
+    client.call(&[
+        b"RESTORE",
+        b"replaced",
+        b"1000",
+        &payload,
+        b"ABSTTL",
+        b"REPLACE",
+    ]);
+    assert_eq!(client.cmd(&["GET", "replaced"]), Reply::Nil);
+
+    client.call(&[
+        b"RESTORE",
+        b"future",
+        b"32503680000000",
+        &payload,
+        b"ABSTTL",
+    ]);
+    assert_eq!(client.cmd(&["GET", "future"]), Reply::bulk("v"));
+}
+
+#[test]
+fn restore_validates_its_options() {
+    let server = Server::start("dump-options", &["--save", ""]);
+    let mut client = server.client();
+    client.cmd(&["SET", "source", "v"]);
+    let payload = dump(&mut client, b"source");
+
+    let cases: [(&[&[u8]], &str); 4] = [
+        (&[b"-1"], "ERR Invalid TTL value, must be >= 0"),
+        (
+            &[b"0", b"IDLETIME", b"-5"],
+            "ERR Invalid IDLETIME value, must be >= 0",
+        ),
+        (
+            &[b"0", b"FREQ", b"256"],
+            "ERR Invalid FREQ value, must be >= 0 and <= 255",
+        ),
+        (
+            &[b"0", b"IDLETIME", b"5", b"FREQ", b"1"],
+            "ERR syntax error",
+        ),
+    ];
+    for (options, error) in cases {
+        let mut args: Vec<&[u8]> = vec![b"RESTORE", b"target", options[0], &payload];
+        args.extend_from_slice(&options[1..]);
+        assert_eq!(client.call(&args), Reply::Error(error.into()));
+    }
+    assert_eq!(
+        client.call(&[b"RESTORE", b"target", b"0", &payload, b"IDLETIME", b"30"]),
+        Reply::status("OK")
+    );
+    assert_eq!(
+        client.call(&[b"RESTORE", b"other", b"0", &payload, b"FREQ", b"7"]),
+        Reply::status("OK")
+    );
+}
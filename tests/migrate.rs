@@ -0,0 +1,173 @@
+mod common;
+
+use common::{Client, Reply, Server};
+use std::net::TcpListener;
+
+fn migrate(client: &mut Client, target: &Server, key: &[u8], options: &[&[u8]]) -> Reply {
+    let port = target.port.to_string();
+    let mut args: Vec<&[u8]> = vec![
+        b"MIGRATE",
+        b"127.0.0.1",
+        port.as_bytes(),
+        key,
+        b"0",
+        b"1000",
+    ];
+    args.extend_from_slice(options);
+    client.call(&args)
+}
+
+#[test]
+fn migrate_moves_the_key_to_the_target() {
+    let source = Server::start("migrate-move-source", &["--save", ""]);
+    let target = Server::start("migrate-move-target", &["--save", ""]);
+    let (mut from, mut to) = (source.client(), target.client());
+    from.call(&[b"SET", b"key with spaces", b"\x00\xff"]);
+    from.cmd(&["PFADD", "visitors", "a", "b", "c"]);
+
+    for key in [&b"key with spaces"[..], b"visitors"] {
+        assert_eq!(migrate(&mut from, &target, key, &[]), Reply::status("OK"));
+        assert_eq!(from.call(&[b"DUMP", key]), Reply::Nil);
+    }
+    assert_eq!(
+        to.call(&[b"GET", b"key with spaces"]),
+        Reply::bulk(b"\x00\xff")
+    );
+    assert_eq!(to.cmd(&["PFCOUNT", "visitors"]), Reply::Integer(3));
+}
+
+#[test]
+fn migrate_copy_keeps_the_key_and_replace_overwrites() {
+    let source = Server::start("migrate-copy-source", &["--save", ""]);
+    let target = Server::start("migrate-copy-target", &["--save", ""]);
+    let (mut from, mut to) = (source.client(), target.client());
+    from.cmd(&["SET", "key", "new"]);
+    to.cmd(&["SET", "key", "old"]);
+
+    assert_eq!(
+        migrate(&mut from, &target, b"key", &[b"COPY"]),
+        Reply::Error(
+            "ERR Target instance replied with error: BUSYKEY Target key name already exists."
+                .into()
+        )
+    );
+    assert_eq!(to.cmd(&["GET", "key"]), Reply::bulk("old"));
+
+    assert_eq!(
+        migrate(&mut from, &target, b"key", &[b"COPY", b"REPLACE"]),
+        Reply::status("OK")
+    );
+    assert_eq!(to.cmd(&["GET", "key"]), Reply::bulk("new"));
+    assert_eq!(from.cmd(&["GET", "key"]), Reply::bulk("new"));
+
+    // Without COPY, a key the target refused stays here.
+    from.cmd(&["SET", "key", "newer"]);
+    assert!(migrate(&mut from, &target, b"key", &[]).is_error());
+    assert_eq!(from.cmd(&["GET", "key"]), Reply::bulk("newer"));
+}
+
+#[test]
+fn migrate_keys_skips_missing_ones_and_selects_the_target_db() {
+    let source = Server::start("migrate-keys-source", &["--save", ""]);
+    let target = Server::start("migrate-keys-target", &["--save", ""]);
+    let (mut from, mut to) = (source.client(), target.client());
+    from.cmd(&["SET", "a", "1"]);
+    from.cmd(&["SET", "b", "2", "PX", "3600000"]);
+
+    let port = target.port.to_string();
+    assert_eq!(
+        from.cmd(&[
+            "MIGRATE",
+            "127.0.0.1",
+            &port,
+            "",
+            "3",
+            "1000",
+            "KEYS",
+            "a",
+            "missing",
+            "b"
+        ]),
+        Reply::status("OK")
+    );
+    assert_eq!(from.cmd(&["KEYS", "*"]), Reply::Array(vec![]));
+    assert_eq!(to.cmd(&["KEYS", "*"]), Reply::Array(vec![]));
+    to.cmd(&["SELECT", "3"]);
+    assert_eq!(to.cmd(&["GET", "a"]), Reply::bulk("1"));
+    assert_eq!(to.cmd(&["GET", "b"]), Reply::bulk("2"));
+
+    assert_eq!(
+        migrate(&mut from, &target, b"", &[b"KEYS", b"missing"]),
+        Reply::status("NOKEY")
+    );
+    assert_eq!(
+        migrate(&mut from, &target, b"a", &[b"KEYS", b"b"]),
+        Reply::Error(
+            "ERR When using MIGRATE KEYS option, the key argument must be set to the empty string"
+                .into()
+        )
+    );
+}
+
+#[test]
+fn migrate_keeps_the_key_when_the_target_is_unreachable() {
+    let source = Server::start("migrate-unreachable", &["--save", ""]);
+    let mut client = source.client();
+    client.cmd(&["SET", "key", "v"]);
+    let port = {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.local_addr().unwrap().port().to_string()
+    };
+
+    assert_eq!(
+        client.cmd(&["MIGRATE", "127.0.0.1", &port, "key", "0", "200"]),
+        Reply::Error("IOERR error or timeout connecting to target instance".into())
+    );
+    assert_eq!(client.cmd(&["GET", "key"]), Reply::bulk("v"));
+}
+
+#[test]
+fn migrate_reports_the_deleted_keys() {
+    let source = Server::start(
+        "migrate-notify",
+        &["--save", "", "--notify-keyspace-events", "Eg"],
+    );
+    let target = Server::start("migrate-notify-target", &["--save", ""]);
+    let mut client = source.client();
+    let mut subscriber = source.client();
+    subscriber.cmd(&["SUBSCRIBE", "__keyevent@0__:del"]);
+    client.cmd(&["SET", "moved", "v"]);
+    client.cmd(&["SET", "copied", "v"]);
+
+    migrate(&mut client, &target, b"copied", &[b"COPY"]);
+    migrate(&mut client, &target, b"moved", &[]);
+    assert_eq!(
+        subscriber.read().into_array(),
+        [
+            Reply::bulk("message"),
+            Reply::bulk("__keyevent@0__:del"),
+            Reply::bulk("moved")
+        ]
+    );
+}
+
+#[test]
+fn migrate_refuses_auth_options() {
+    let source = Server::start("migrate-auth-source", &["--save", ""]);
+    let target = Server::start("migrate-auth-target", &["--save", ""]);
+    let (mut from, mut to) = (source.client(), target.client());
+    from.cmd(&["SET", "key", "v"]);
+
+    for auth in [&[&b"AUTH"[..], b"secret"][..], &[b"AUTH2", b"user", b"secret"]] {
+        let reply = migrate(&mut from, &target, b"key", auth);
+        assert_eq!(
+            reply,
+            Reply::Error(
+                "ERR MIGRATE AUTH and AUTH2 are not supported, altredis servers have no passwords"
+                    .into()
+            )
+        );
+    }
+    assert_eq!(from.cmd(&["GET", "key"]), Reply::bulk("v"));
+    assert_eq!(to.cmd(&["GET", "key"]), Reply::Nil);
+}
@@ -0,0 +1,165 @@
+mod common;
+
+use common::{Client, Reply, Server};
+use std::time::Duration;
+
+/// Subscribes to every keyspace and keyevent channel.
+fn listen(server: &Server) -> Client {
+    let mut listener = server.client();
+    listener.cmd(&["PSUBSCRIBE", "__key*__:*"]);
+    listener
+}
+
+/// The next notification as `(channel, message)`.
+fn next_event(listener: &mut Client) -> (Vec<u8>, Vec<u8>) {
+    match listener.read().into_array().as_slice() {
+        [_, _, Reply::Bulk(channel), Reply::Bulk(message)] => (channel.clone(), message.clone()),
+        other => panic!("unexpected notification {:?}", other),
+    }
+}
+
+fn event(channel: &str, message: impl AsRef<[u8]>) -> (Vec<u8>, Vec<u8>) {
+    (channel.as_bytes().to_vec(), message.as_ref().to_vec())
+}
+
+#[test]
+fn writes_publish_keyspace_and_keyevent_messages() {
+    let server = Server::start("notify-writes", &["--notify-keyspace-events", "KEA"]);
+    let mut listener = listen(&server);
+    let mut client = server.client();
+
+    client.cmd(&["SET", "k", "v"]);
+    assert_eq!(next_event(&mut listener), event("__keyspace@0__:k", "set"));
+    assert_eq!(next_event(&mut listener), event("__keyevent@0__:set", "k"));
+
+    client.cmd(&["SELECT", "3"]);
+    client.cmd(&["DEL", "missing"]);
+    client.cmd(&["SETBIT", "b", "1", "1"]);
+    assert_eq!(
+        next_event(&mut listener),
+        event("__keyspace@3__:b", "setbit")
+    );
+    assert_eq!(
+        next_event(&mut listener),
+        event("__keyevent@3__:setbit", "b")
+    );
+
+    // The keyevent message carries a binary key exactly as it was written.
+    client.call(&[b"SET", b"\xffkey", b"v"]);
+    next_event(&mut listener);
+    assert_eq!(
+        next_event(&mut listener),
+        event("__keyevent@3__:set", b"\xffkey")
+    );
+}
+
+#[test]
+fn flags_select_the_classes_and_channels() {
+    let server = Server::start("notify-flags", &["--notify-keyspace-events", "Eg"]);
+    let mut listener = listen(&server);
+    let mut client = server.client();
+
+    // String events are off and keyspace channels are off.
+    client.cmd(&["SET", "k", "v"]);
+    client.cmd(&["DEL", "k"]);
+    assert_eq!(next_event(&mut listener), event("__keyevent@0__:del", "k"));
+
+    assert_eq!(
+        client.cmd(&["CONFIG", "SET", "notify-keyspace-events", ""]),
+        Reply::status("OK")
+    );
+    client.cmd(&["SET", "k", "v"]);
+    client.cmd(&["DEL", "k"]);
+    assert_eq!(listener.try_read(Duration::from_millis(200)), None);
+}
+
+#[test]
+fn expired_keys_are_announced() {
+    let server = Server::start("notify-expired", &["--notify-keyspace-events", "Ex"]);
+    let mut listener = listen(&server);
+    let mut client = server.client();
+    client.cmd(&["SET", "session", "v", "PX", "50"]);
+    // Found either by the active expire cycle or by the lookup below.
+    std::thread::sleep(Duration::from_millis(150));
+    assert_eq!(client.cmd(&["GET", "session"]), Reply::Nil);
+    assert_eq!(
+        next_event(&mut listener),
+        event("__keyevent@0__:expired", "session")
+    );
+    assert_eq!(listener.try_read(Duration::from_millis(200)), None);
+}
+
+#[test]
+fn read_misses_are_announced_when_asked_for() {
+    let server = Server::start("notify-keymiss", &["--notify-keyspace-events", "Em"]);
+    let mut listener = listen(&server);
+    let mut client = server.client();
+    client.cmd(&["SET", "k", "v"]);
+    client.cmd(&["GET", "k"]);
+    client.cmd(&["GET", "missing"]);
+    assert_eq!(
+        next_event(&mut listener),
+        event("__keyevent@0__:keymiss", "missing")
+    );
+    client.cmd(&["PFCOUNT", "nohll"]);
+    assert_eq!(
+        next_event(&mut listener),
+        event("__keyevent@0__:keymiss", "nohll")
+    );
+    // Writes don't look keys up for reading.
+    client.cmd(&["SETBIT", "new", "1", "1"]);
+    assert_eq!(listener.try_read(Duration::from_millis(200)), None);
+}
+
+#[test]
+fn writes_that_empty_a_key_announce_its_deletion() {
+    let server = Server::start("notify-emptied", &["--notify-keyspace-events", "E$g"]);
+    let mut listener = listen(&server);
+    let mut client = server.client();
+    client.cmd(&["SET", "dest", "v"]);
+    assert_eq!(
+        next_event(&mut listener),
+        event("__keyevent@0__:set", "dest")
+    );
+    client.cmd(&["BITOP", "AND", "dest", "missing"]);
+    assert_eq!(
+        next_event(&mut listener),
+        event("__keyevent@0__:del", "dest")
+    );
+    // Nothing was there to delete the second time.
+    client.cmd(&["BITOP", "AND", "dest", "missing"]);
+    assert_eq!(listener.try_read(Duration::from_millis(200)), None);
+}
+
+#[test]
+fn classes_that_never_fire_are_accepted() {
+    let server = Server::start("notify-accepted", &[]);
+    let mut client = server.client();
+    for flags in ["El", "Es", "Eh", "Et", "Ee", "Ed"] {
+        assert_eq!(
+            client.cmd(&["CONFIG", "SET", "notify-keyspace-events", flags]),
+            Reply::status("OK")
+        );
+        assert_eq!(
+            client.cmd(&["CONFIG", "GET", "notify-keyspace-events"]),
+            Reply::Array(vec![
+                Reply::bulk("notify-keyspace-events"),
+                Reply::bulk(format!("{}E", &flags[1..]))
+            ])
+        );
+    }
+    assert!(client
+        .cmd(&["CONFIG", "SET", "notify-keyspace-events", "EQ"])
+        .is_error());
+    assert_eq!(
+        client.cmd(&["CONFIG", "SET", "notify-keyspace-events", "KEAmn"]),
+        Reply::status("OK")
+    );
+    assert_eq!(
+        client.cmd(&["CONFIG", "GET", "notify-keyspace-events"]),
+        Reply::Array(vec![
+            Reply::bulk("notify-keyspace-events"),
+            Reply::bulk("AKEmn")
+        ])
+    );
+}
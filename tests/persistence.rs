@@ -0,0 +1,270 @@
+mod common;
+
+use altredis::rdb::RdbReader;
+use common::{wait_until, Reply, Server};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+fn last_save(client: &mut common::Client) -> i64 {
+    match client.cmd(&["LASTSAVE"]) {
+        Reply::Integer(time) => time,
+        other => panic!("LASTSAVE replied {:?}", other),
+    }
+}
+
+#[test]
+fn save_writes_a_snapshot_the_next_start_loads() {
+    let server = Server::start("save-restart", &["--save", ""]);
+    let mut client = server.client();
+    client.call(&[b"SET", b"bin\xff", b"\x00\xff"]);
+    client.cmd(&["SET", "later", "v", "PX", "3600000"]);
+    client.cmd(&["PFADD", "visitors", "a", "b", "c"]);
+    client.cmd(&["GEOADD", "places", "13.361389", "38.115556", "Palermo"]);
+    client.cmd(&["SELECT", "2"]);
+    client.cmd(&["SET", "other-db", "2"]);
+    assert_eq!(client.cmd(&["SAVE"]), Reply::status("OK"));
+    assert!(server.dir.join("dump.rdb").exists());
+
+    let server = Server::start_in(server.stop(), &["--save", ""]);
+    let mut client = server.client();
+    assert_eq!(client.call(&[b"GET", b"bin\xff"]), Reply::bulk(b"\x00\xff"));
+    assert_eq!(client.cmd(&["GET", "later"]), Reply::bulk("v"));
+    assert_eq!(client.cmd(&["PFCOUNT", "visitors"]), Reply::Integer(3));
+    assert_eq!(
+        client.cmd(&["GEOHASH", "places", "Palermo"]),
+        Reply::Array(vec![Reply::bulk("sqc8b49rny0")])
+    );
+    assert_eq!(client.cmd(&["GET", "other-db"]), Reply::Nil);
+    client.cmd(&["SELECT", "2"]);
+    assert_eq!(client.cmd(&["GET", "other-db"]), Reply::bulk("2"));
+}
+
+#[test]
+fn bgsave_writes_in_the_background_and_updates_lastsave() {
+    let server = Server::start("bgsave", &["--save", "", "--dbfilename", "snapshot.rdb"]);
+    let mut client = server.client();
+    client.cmd(&["SET", "k", "v"]);
+    let before = last_save(&mut client);
+    // LASTSAVE has a one second resolution.
+    thread::sleep(Duration::from_millis(1100));
+    assert_eq!(
+        client.cmd(&["BGSAVE"]),
+        Reply::status("Background saving started")
+    );
+    wait_until(|| last_save(&mut client) > before);
+    assert!(server.dir.join("snapshot.rdb").exists());
+    assert!(!server.dir.join("dump.rdb").exists());
+
+    let server = Server::start_in(server.stop(), &["--dbfilename", "snapshot.rdb"]);
+    assert_eq!(server.client().cmd(&["GET", "k"]), Reply::bulk("v"));
+}
+
+fn info_field(client: &mut common::Client, name: &str) -> String {
+    let Reply::Bulk(info) = client.cmd(&["INFO", "persistence"]) else {
+        panic!("INFO should reply with a bulk string");
+    };
+    String::from_utf8(info)
+        .unwrap()
+        .lines()
+        .find_map(|line| line.strip_prefix(&format!("{}:", name)).map(str::to_owned))
+        .unwrap_or_else(|| panic!("INFO has no {}", name))
+}
+
+#[test]
+fn snapshots_never_catch_a_command_halfway() {
+    const KEYS: usize = 1000;
+    let server = Server::start("save-atomic", &["--save", ""]);
+    let port = server.port;
+    let keys = (0..KEYS).map(|i| format!("key:{}", i)).collect::<Vec<_>>();
+    let stop = Arc::new(AtomicBool::new(false));
+    let writer = {
+        let stop = Arc::clone(&stop);
+        let keys = keys.clone();
+        thread::spawn(move || {
+            let mut client = common::Client::connect(port);
+            let mut del = vec!["DEL"];
+            del.extend(keys.iter().map(String::as_str));
+            while !stop.load(Ordering::Relaxed) {
+                // Pipelined, so the server spends its time on the commands.
+                client.send(&[b"MULTI"]);
+                for key in keys.iter() {
+                    client.send(&[b"SET", key.as_bytes(), b"v"]);
+                }
+                client.send(&[b"EXEC"]);
+                for _ in 0..keys.len() + 2 {
+                    client.read();
+                }
+                client.cmd(&del);
+            }
+        })
+    };
+
+    let mut client = server.client();
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    for _ in 0..100 {
+        assert_eq!(client.cmd(&["SAVE"]), Reply::status("OK"));
+        let contents = std::fs::read(server.dir.join("dump.rdb")).unwrap();
+        let data = runtime
+            .block_on(RdbReader::read_from(contents.as_slice(), true))
+            .unwrap();
+        let saved = data.databases.get(&0).map_or(0, |db| db.len());
+        assert!(saved == 0 || saved == KEYS, "{} of {} keys saved", saved, KEYS);
+    }
+    stop.store(true, Ordering::Relaxed);
+    writer.join().unwrap();
+}
+
+#[test]
+fn flushall_during_a_background_save_stays_flushed_after_restart() {
+    const KEYS: usize = 100_000;
+    let args = ["--save", "3600 1000000000"];
+    let server = Server::start("flushall-bgsave", &args);
+    let mut client = server.client();
+    for i in 0..KEYS {
+        client.send(&[b"SET", format!("key:{}", i).as_bytes(), b"v"]);
+    }
+    for _ in 0..KEYS {
+        client.read();
+    }
+    client.send(&[b"BGSAVE"]);
+    client.send(&[b"FLUSHALL"]);
+    assert_eq!(client.read(), Reply::status("Background saving started"));
+    assert_eq!(client.read(), Reply::status("OK"));
+    // FLUSHALL doesn't wait for the old save, which is discarded once it has written
+    // its temporary file.
+    assert_eq!(info_field(&mut client, "rdb_bgsave_in_progress"), "0");
+    wait_until(|| {
+        std::fs::read_dir(&server.dir).unwrap().all(|entry| {
+            !entry
+                .unwrap()
+                .file_name()
+                .to_string_lossy()
+                .starts_with("temp-")
+        })
+    });
+
+    let server = Server::start_in(server.stop(), &args);
+    assert_eq!(server.client().cmd(&["GET", "key:0"]), Reply::Nil);
+}
+
+#[test]
+fn clients_are_served_while_a_large_background_save_runs() {
+    const KEYS: usize = 32;
+    let server = Server::start("bgsave-served", &["--save", ""]);
+    let mut client = server.client();
+    // A megabyte of noise per key, which doesn't compress, so writing it takes a while.
+    let mut state = 1u32;
+    let mut noise = || {
+        (0..1 << 20)
+            .map(|_| {
+                state = state.wrapping_mul(1103515245).wrapping_add(12345);
+                (state >> 16) as u8
+            })
+            .collect::<Vec<_>>()
+    };
+    let values = (0..KEYS).map(|_| noise()).collect::<Vec<_>>();
+    for (i, value) in values.iter().enumerate() {
+        client.call(&[b"SET", format!("big:{}", i).as_bytes(), value]);
+    }
+
+    assert_eq!(
+        client.cmd(&["BGSAVE"]),
+        Reply::status("Background saving started")
+    );
+    let mut other = server.client();
+    let first_bit = values[0][0] >> 7;
+    let flipped = (1 - first_bit).to_string();
+    assert_eq!(
+        other.cmd(&["SETBIT", "big:0", "0", &flipped]),
+        Reply::Integer(first_bit as i64)
+    );
+    other.cmd(&["SET", "small", "v"]);
+    assert_eq!(other.cmd(&["GET", "small"]), Reply::bulk("v"));
+    assert_eq!(info_field(&mut other, "rdb_bgsave_in_progress"), "1");
+    wait_until(|| info_field(&mut other, "rdb_bgsave_in_progress") == "0");
+
+    // The file holds the dataset as it was when BGSAVE started.
+    let contents = std::fs::read(server.dir.join("dump.rdb")).unwrap();
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let data = runtime
+        .block_on(RdbReader::read_from(contents.as_slice(), true))
+        .unwrap();
+    assert_eq!(data.databases[&0].len(), KEYS);
+    assert!(matches!(
+        &data.databases[&0][&b"big:0"[..]],
+        altredis::store::Data::String(saved) if *saved == values[0]
+    ));
+}
+
+#[test]
+fn save_rule_triggers_once_enough_changes_were_made() {
+    let server = Server::start("save-rule", &["--save", "1 3"]);
+    let mut client = server.client();
+    let snapshot = server.dir.join("dump.rdb");
+    client.cmd(&["SET", "a", "1"]);
+    client.cmd(&["SET", "b", "1"]);
+    assert_eq!(info_field(&mut client, "rdb_changes_since_last_save"), "2");
+    thread::sleep(Duration::from_millis(1500));
+    assert!(!snapshot.exists());
+
+    client.cmd(&["DEL", "a", "b"]);
+    wait_until(|| snapshot.exists());
+    wait_until(|| info_field(&mut client, "rdb_changes_since_last_save") == "0");
+    assert_eq!(info_field(&mut client, "rdb_last_bgsave_status"), "ok");
+}
+
+#[test]
+fn config_set_save_replaces_the_rules() {
+    let server = Server::start("config-save", &[]);
+    let mut client = server.client();
+    assert_eq!(
+        client.cmd(&["CONFIG", "SET", "save", "10 5"]),
+        Reply::status("OK")
+    );
+    assert_eq!(
+        client.cmd(&["CONFIG", "GET", "save"]),
+        Reply::Array(vec![Reply::bulk("save"), Reply::bulk("10 5")])
+    );
+    assert!(client.cmd(&["CONFIG", "SET", "save", "10"]).is_error());
+}
+
+#[test]
+fn shutdown_saves_when_rules_are_set_or_asked_to() {
+    let mut server = Server::start("shutdown-default", &[]);
+    let mut client = server.client();
+    client.cmd(&["SET", "k", "v"]);
+    client.send(&[b"SHUTDOWN"]);
+    assert!(server.wait_exit());
+    let server = Server::start_in(server.stop(), &[]);
+    assert_eq!(server.client().cmd(&["GET", "k"]), Reply::bulk("v"));
+
+    // Without save rules nothing is written, unless SHUTDOWN SAVE asks for it.
+    let mut server = Server::start("shutdown-no-rules", &["--save", ""]);
+    server.client().cmd(&["SET", "k", "v"]);
+    server.client().send(&[b"SHUTDOWN"]);
+    assert!(server.wait_exit());
+    assert!(!server.dir.join("dump.rdb").exists());
+
+    let mut server = Server::start_in(server.stop(), &["--save", ""]);
+    server.client().send(&[b"SHUTDOWN", b"SAVE"]);
+    assert!(server.wait_exit());
+    assert!(server.dir.join("dump.rdb").exists());
+}
+
+#[test]
+fn shutdown_nosave_keeps_the_previous_snapshot() {
+    let mut server = Server::start("shutdown-nosave", &[]);
+    let mut client = server.client();
+    client.cmd(&["SET", "saved", "1"]);
+    client.cmd(&["SAVE"]);
+    client.cmd(&["SET", "unsaved", "1"]);
+    client.send(&[b"SHUTDOWN", b"NOSAVE"]);
+    assert!(server.wait_exit());
+
+    let server = Server::start_in(server.stop(), &[]);
+    let mut client = server.client();
+    assert_eq!(client.cmd(&["GET", "saved"]), Reply::bulk("1"));
+    assert_eq!(client.cmd(&["GET", "unsaved"]), Reply::Nil);
+}
@@ -0,0 +1,226 @@
+mod common;
+
+use common::{Reply, Server};
+use std::time::Duration;
+
+fn frame(parts: &[&str]) -> Reply {
+    Reply::Array(parts.iter().map(Reply::bulk).collect())
+}
+
+#[test]
+fn publish_reaches_channel_and_pattern_subscribers() {
+    let server = Server::start("publish", &[]);
+    let mut subscriber = server.client();
+    let mut publisher = server.client();
+    assert_eq!(
+        subscriber.cmd(&["SUBSCRIBE", "news.tech"]),
+        Reply::Array(vec![
+            Reply::bulk("subscribe"),
+            Reply::bulk("news.tech"),
+            Reply::Integer(1)
+        ])
+    );
+    assert_eq!(
+        subscriber.cmd(&["PSUBSCRIBE", "news.*"]),
+        Reply::Array(vec![
+            Reply::bulk("psubscribe"),
+            Reply::bulk("news.*"),
+            Reply::Integer(2)
+        ])
+    );
+
+    assert_eq!(
+        publisher.cmd(&["PUBLISH", "news.tech", "hello"]),
+        Reply::Integer(2)
+    );
+    assert_eq!(subscriber.read(), frame(&["message", "news.tech", "hello"]));
+    assert_eq!(
+        subscriber.read(),
+        frame(&["pmessage", "news.*", "news.tech", "hello"])
+    );
+
+    assert_eq!(
+        publisher.cmd(&["PUBLISH", "sports", "x"]),
+        Reply::Integer(0)
+    );
+    assert_eq!(subscriber.try_read(Duration::from_millis(200)), None);
+}
+
+#[test]
+fn subscriber_mode_only_allows_subscription_commands() {
+    let server = Server::start("subscriber-mode", &[]);
+    let mut client = server.client();
+    client.cmd(&["SUBSCRIBE", "a"]);
+    assert!(client.cmd(&["GET", "k"]).is_error());
+    assert_eq!(client.cmd(&["PING"]), frame(&["pong", ""]));
+    assert_eq!(
+        client.cmd(&["UNSUBSCRIBE", "a"]),
+        Reply::Array(vec![
+            Reply::bulk("unsubscribe"),
+            Reply::bulk("a"),
+            Reply::Integer(0)
+        ])
+    );
+    assert_eq!(client.cmd(&["GET", "k"]), Reply::Nil);
+}
+
+#[test]
+fn pubsub_introspection() {
+    let server = Server::start("pubsub-introspection", &[]);
+    let mut first = server.client();
+    let mut second = server.client();
+    first.cmd(&["SUBSCRIBE", "a"]);
+    first.cmd(&["SUBSCRIBE", "b"]);
+    second.cmd(&["SUBSCRIBE", "a"]);
+    second.cmd(&["PSUBSCRIBE", "x*"]);
+
+    let mut admin = server.client();
+    let mut channels = admin.cmd(&["PUBSUB", "CHANNELS"]).into_array();
+    channels.sort_by_key(|channel| format!("{:?}", channel));
+    assert_eq!(channels, [Reply::bulk("a"), Reply::bulk("b")]);
+    assert_eq!(
+        admin.cmd(&["PUBSUB", "CHANNELS", "b*"]),
+        Reply::Array(vec![Reply::bulk("b")])
+    );
+    assert_eq!(
+        admin.cmd(&["PUBSUB", "NUMSUB", "a", "b", "c"]),
+        Reply::Array(vec![
+            Reply::bulk("a"),
+            Reply::Integer(2),
+            Reply::bulk("b"),
+            Reply::Integer(1),
+            Reply::bulk("c"),
+            Reply::Integer(0)
+        ])
+    );
+    assert_eq!(admin.cmd(&["PUBSUB", "NUMPAT"]), Reply::Integer(1));
+
+    // Closing a connection drops its subscriptions.
+    drop(second);
+    common::wait_until(|| admin.cmd(&["PUBSUB", "NUMPAT"]) == Reply::Integer(0));
+}
+
+#[test]
+fn sharded_channels_are_separate_from_regular_ones() {
+    let server = Server::start("sharded", &[]);
+    let mut subscriber = server.client();
+    let mut publisher = server.client();
+    assert_eq!(
+        subscriber.cmd(&["SSUBSCRIBE", "orders"]),
+        Reply::Array(vec![
+            Reply::bulk("ssubscribe"),
+            Reply::bulk("orders"),
+            Reply::Integer(1)
+        ])
+    );
+
+    assert_eq!(
+        publisher.cmd(&["PUBLISH", "orders", "x"]),
+        Reply::Integer(0)
+    );
+    assert_eq!(
+        publisher.cmd(&["SPUBLISH", "orders", "new"]),
+        Reply::Integer(1)
+    );
+    assert_eq!(subscriber.read(), frame(&["smessage", "orders", "new"]));
+
+    assert_eq!(
+        publisher.cmd(&["PUBSUB", "SHARDCHANNELS"]),
+        Reply::Array(vec![Reply::bulk("orders")])
+    );
+    assert_eq!(
+        publisher.cmd(&["PUBSUB", "SHARDNUMSUB", "orders"]),
+        Reply::Array(vec![Reply::bulk("orders"), Reply::Integer(1)])
+    );
+    assert_eq!(publisher.cmd(&["PUBSUB", "CHANNELS"]), Reply::Array(vec![]));
+
+    // Shard subscriptions are counted on their own in (un)subscribe replies.
+    assert_eq!(
+        subscriber.cmd(&["SUBSCRIBE", "orders"]),
+        Reply::Array(vec![
+            Reply::bulk("subscribe"),
+            Reply::bulk("orders"),
+            Reply::Integer(1)
+        ])
+    );
+    assert_eq!(
+        subscriber.cmd(&["SUNSUBSCRIBE"]),
+        Reply::Array(vec![
+            Reply::bulk("sunsubscribe"),
+            Reply::bulk("orders"),
+            Reply::Integer(0)
+        ])
+    );
+    assert_eq!(
+        publisher.cmd(&["SPUBLISH", "orders", "gone"]),
+        Reply::Integer(0)
+    );
+}
+
+#[test]
+fn binary_channel_names_are_kept_as_sent() {
+    let server = Server::start("pubsub-binary", &[]);
+    let mut subscriber = server.client();
+    let mut publisher = server.client();
+    let channel: &[u8] = b"ch\xff\x00\xc3";
+    subscriber.call(&[b"SUBSCRIBE", channel]);
+    subscriber.call(&[b"PSUBSCRIBE", b"ch\xff*"]);
+
+    assert_eq!(
+        publisher.call(&[b"PUBLISH", channel, b"hi"]),
+        Reply::Integer(2)
+    );
+    assert_eq!(
+        subscriber.read(),
+        Reply::Array(vec![
+            Reply::bulk("message"),
+            Reply::bulk(channel),
+            Reply::bulk("hi")
+        ])
+    );
+    assert_eq!(
+        subscriber.read(),
+        Reply::Array(vec![
+            Reply::bulk("pmessage"),
+            Reply::bulk(b"ch\xff*"),
+            Reply::bulk(channel),
+            Reply::bulk("hi")
+        ])
+    );
+    assert_eq!(
+        publisher.cmd(&["PUBSUB", "CHANNELS"]),
+        Reply::Array(vec![Reply::bulk(channel)])
+    );
+}
+
+#[test]
+fn a_subscriber_that_stops_reading_does_not_hold_up_publishers() {
+    let server = Server::start("pubsub-slow-subscriber", &[]);
+    let mut stalled = server.client();
+    stalled.cmd(&["SUBSCRIBE", "firehose"]);
+
+    // Far more than the socket buffers hold, so writing to the subscriber inline would
+    // block the publisher, and every write behind it, until the subscriber reads.
+    let mut publisher = server.client();
+    let message = vec![b'x'; 64 * 1024];
+    for _ in 0..200 {
+        assert_eq!(
+            publisher.call(&[b"PUBLISH", b"firehose", &message]),
+            Reply::Integer(1)
+        );
+    }
+    let mut other = server.client();
+    assert_eq!(other.cmd(&["SET", "k", "v"]), Reply::bulk("OK"));
+
+    // The subscriber still gets everything once it reads again.
+    for _ in 0..200 {
+        assert_eq!(
+            stalled.read(),
+            Reply::Array(vec![
+                Reply::bulk("message"),
+                Reply::bulk("firehose"),
+                Reply::bulk(&message)
+            ])
+        );
+    }
+}
@@ -0,0 +1,254 @@
+mod common;
+
+use altredis::rdb::{RdbData, RdbReader};
+use altredis::store::Data;
+use common::{wait_until, Client, Reply, Server};
+use std::collections::HashSet;
+use std::thread;
+use std::time::Duration;
+
+/// Does the replica side of the handshake by hand and returns the FULLRESYNC reply
+/// and the decoded snapshot.
+fn full_resync(replica: &mut Client) -> (String, RdbData) {
+    replica.cmd(&["PING"]);
+    replica.cmd(&["REPLCONF", "listening-port", "6380"]);
+    replica.cmd(&["REPLCONF", "capa", "psync2"]);
+    replica.send(&[b"PSYNC", b"?", b"-1"]);
+    let fullresync = replica.read_line();
+    let len = replica.read_line();
+    let len = len.strip_prefix('$').expect("RDB bulk").parse().unwrap();
+    let payload = replica.read_raw(len);
+    assert!(payload.starts_with(b"REDIS"));
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let data = runtime
+        .block_on(RdbReader::read_from(payload.as_slice(), true))
+        .expect("valid snapshot");
+    (fullresync, data)
+}
+
+fn string(data: &RdbData, db: usize, key: &[u8]) -> Option<Vec<u8>> {
+    match data.databases.get(&db)?.get(key)? {
+        Data::String(value) => Some(value.clone()),
+        other => panic!("expected a string, got {:?}", other),
+    }
+}
+
+fn command(args: &[&[u8]]) -> Reply {
+    Reply::Array(args.iter().map(Reply::bulk).collect())
+}
+
+#[test]
+fn psync_sends_a_snapshot_of_the_dataset() {
+    let master = Server::start("psync-snapshot", &["--save", ""]);
+    let mut client = master.client();
+    client.call(&[b"SET", b"bin\xff", b"\x00\r\n"]);
+    client.cmd(&["SET", "expiring", "v", "PX", "3600000"]);
+    client.cmd(&["PFADD", "visitors", "a", "b"]);
+    client.cmd(&["SELECT", "4"]);
+    client.cmd(&["SET", "elsewhere", "4"]);
+
+    let mut replica = master.client();
+    let (fullresync, data) = full_resync(&mut replica);
+    let words = fullresync.split(' ').collect::<Vec<_>>();
+    assert_eq!(words[0], "+FULLRESYNC");
+    assert_eq!(words[1].len(), 40);
+    assert!(words[2].parse::<u64>().is_ok());
+
+    assert_eq!(data.databases[&0].len(), 3);
+    assert_eq!(string(&data, 0, b"bin\xff").unwrap(), b"\x00\r\n");
+    assert!(string(&data, 0, b"visitors").unwrap().starts_with(b"HYLL"));
+    assert!(data.expirations[&0].contains_key(&b"expiring"[..]));
+    assert_eq!(string(&data, 4, b"elsewhere").unwrap(), b"4");
+
+    // Afterwards the replica gets each write, starting with the database it applies to.
+    client.cmd(&["SET", "after", "1"]);
+    assert_eq!(replica.read(), command(&[b"SELECT", b"4"]));
+    assert_eq!(replica.read(), command(&[b"SET", b"after", b"1"]));
+}
+
+#[test]
+fn keys_expired_on_access_are_deleted_on_the_replica() {
+    let master = Server::start("psync-lazy-expire", &["--save", ""]);
+    let mut client = master.client();
+    let mut replica = master.client();
+    full_resync(&mut replica);
+    client.cmd(&["SET", "short", "v", "PX", "30"]);
+    assert_eq!(replica.read(), command(&[b"SELECT", b"0"]));
+    assert_eq!(replica.read().into_array()[0], Reply::bulk("SET"));
+    thread::sleep(Duration::from_millis(60));
+
+    assert_eq!(client.cmd(&["GET", "short"]), Reply::Nil);
+    client.cmd(&["SET", "after", "1"]);
+    assert_eq!(replica.read(), command(&[b"DEL", b"short"]));
+    assert_eq!(replica.read(), command(&[b"SET", b"after", b"1"]));
+}
+
+#[test]
+fn published_messages_reach_the_replica() {
+    let master = Server::start("psync-publish", &["--save", ""]);
+    let mut client = master.client();
+    let mut replica = master.client();
+    full_resync(&mut replica);
+    client.cmd(&["PUBLISH", "news", "hello"]);
+    assert_eq!(replica.read(), command(&[b"SELECT", b"0"]));
+    assert_eq!(replica.read(), command(&[b"PUBLISH", b"news", b"hello"]));
+}
+
+#[test]
+fn psync_of_an_empty_master_sends_an_empty_snapshot() {
+    let master = Server::start("psync-empty", &["--save", ""]);
+    let mut replica = master.client();
+    let (_, data) = full_resync(&mut replica);
+    assert!(data.databases.values().all(|db| db.is_empty()));
+}
+
+#[test]
+fn writes_during_the_transfer_reach_the_replica_once() {
+    const KEYS: usize = 3000;
+    let master = Server::start("psync-concurrent", &["--save", ""]);
+    let port = master.port;
+    let writer = thread::spawn(move || {
+        let mut client = Client::connect(port);
+        for i in 0..KEYS {
+            client.cmd(&["SET", &format!("key:{}", i), "v"]);
+        }
+    });
+    thread::sleep(Duration::from_millis(20));
+
+    let mut replica = master.client();
+    let (_, data) = full_resync(&mut replica);
+    let mut seen = data
+        .databases
+        .get(&0)
+        .map(|db| db.keys().cloned().collect::<HashSet<_>>())
+        .unwrap_or_default();
+    let in_snapshot = seen.len();
+    while seen.len() < KEYS {
+        let args = replica.read().into_array();
+        match &args[..] {
+            [Reply::Bulk(name), ..] if name == b"SELECT" => {}
+            [Reply::Bulk(name), Reply::Bulk(key), _] if name == b"SET" => {
+                assert!(seen.insert(key.clone()), "{:?} sent twice", key);
+            }
+            other => panic!("unexpected command {:?}", other),
+        }
+    }
+    writer.join().unwrap();
+    assert!(in_snapshot < KEYS, "the writes all finished before PSYNC");
+    assert_eq!(replica.try_read(Duration::from_millis(100)), None);
+}
+
+#[test]
+fn replica_loads_the_masters_dataset_then_follows_it() {
+    let master = Server::start("replica-load-master", &["--save", ""]);
+    let mut client = master.client();
+    client.call(&[b"SET", b"bin\xff", b"\x00\r\n"]);
+    client.cmd(&["PFADD", "visitors", "a", "b", "c"]);
+    client.cmd(&["SELECT", "3"]);
+    client.cmd(&["SET", "elsewhere", "3"]);
+
+    let port = master.port.to_string();
+    let replica = Server::start(
+        "replica-load-replica",
+        &["--save", "", "--replicaof", "127.0.0.1", &port],
+    );
+    let mut reader = replica.client();
+    wait_until(|| reader.call(&[b"GET", b"bin\xff"]) == Reply::bulk(b"\x00\r\n"));
+    assert_eq!(reader.cmd(&["PFCOUNT", "visitors"]), Reply::Integer(3));
+    reader.cmd(&["SELECT", "3"]);
+    assert_eq!(reader.cmd(&["GET", "elsewhere"]), Reply::bulk("3"));
+
+    // Writes that follow the snapshot, including a transaction, arrive in order.
+    client.cmd(&["SET", "elsewhere", "updated"]);
+    client.cmd(&["MULTI"]);
+    client.cmd(&["SET", "first", "1"]);
+    client.cmd(&["DEL", "elsewhere"]);
+    client.cmd(&["EXEC"]);
+    wait_until(|| reader.cmd(&["GET", "first"]) == Reply::bulk("1"));
+    assert_eq!(reader.cmd(&["GET", "elsewhere"]), Reply::Nil);
+}
+
+#[test]
+fn replica_replaces_its_own_data_with_the_masters() {
+    let stale = Server::start("replica-flush-replica", &["--save", ""]);
+    let mut client = stale.client();
+    client.cmd(&["SET", "stale", "old"]);
+    client.cmd(&["SET", "shared", "old"]);
+    assert_eq!(client.cmd(&["SAVE"]), Reply::status("OK"));
+    let dir = stale.stop();
+
+    let master = Server::start("replica-flush-master", &["--save", ""]);
+    master.client().cmd(&["SET", "shared", "new"]);
+    let port = master.port.to_string();
+    let replica = Server::start_in(dir, &["--save", "", "--replicaof", "127.0.0.1", &port]);
+    let mut reader = replica.client();
+    wait_until(|| reader.cmd(&["GET", "shared"]) == Reply::bulk("new"));
+    assert_eq!(reader.cmd(&["GET", "stale"]), Reply::Nil);
+    assert_eq!(
+        reader.cmd(&["KEYS", "*"]),
+        Reply::Array(vec![Reply::bulk("shared")])
+    );
+}
+
+#[test]
+fn replica_keeps_expiry_times_from_the_snapshot() {
+    let master = Server::start("replica-expiry-master", &["--save", ""]);
+    let mut client = master.client();
+    client.cmd(&["SET", "short", "v", "PX", "1500"]);
+    client.cmd(&["SET", "long", "v", "PX", "3600000"]);
+
+    let port = master.port.to_string();
+    let replica = Server::start(
+        "replica-expiry-replica",
+        &["--save", "", "--replicaof", "127.0.0.1", &port],
+    );
+    let mut reader = replica.client();
+    wait_until(|| reader.cmd(&["GET", "long"]) == Reply::bulk("v"));
+    assert_eq!(reader.cmd(&["GET", "short"]), Reply::bulk("v"));
+    wait_until(|| reader.cmd(&["GET", "short"]) == Reply::Nil);
+    assert_eq!(reader.cmd(&["GET", "long"]), Reply::bulk("v"));
+}
+
+#[test]
+fn replica_rewrites_its_aof_before_following_the_master() {
+    let stale = Server::start(
+        "replica-aof-replica",
+        &["--appendonly", "yes", "--save", ""],
+    );
+    stale.client().cmd(&["SET", "stale", "old"]);
+    let dir = stale.stop();
+
+    // Enough keys that writing the new base file takes a while.
+    let master = Server::start("replica-aof-master", &["--save", ""]);
+    let mut client = master.client();
+    for i in 0..20_000 {
+        client.send(&[b"SET", format!("key:{}", i).as_bytes(), b"v"]);
+    }
+    for _ in 0..20_000 {
+        client.read();
+    }
+    let port = master.port.to_string();
+    let replica = Server::start_in(
+        dir,
+        &[
+            "--appendonly",
+            "yes",
+            "--save",
+            "",
+            "--replicaof",
+            "127.0.0.1",
+            &port,
+        ],
+    );
+    client.cmd(&["SET", "after", "1"]);
+    let mut reader = replica.client();
+    wait_until(|| reader.cmd(&["GET", "after"]) == Reply::bulk("1"));
+
+    // Once the replica follows the master, its AOF no longer holds the old dataset.
+    let dir = replica.stop();
+    let restarted = Server::start_in(dir, &["--appendonly", "yes", "--save", ""]);
+    let mut reader = restarted.client();
+    assert_eq!(reader.cmd(&["GET", "stale"]), Reply::Nil);
+    assert_eq!(reader.cmd(&["GET", "key:19999"]), Reply::bulk("v"));
+    assert_eq!(reader.cmd(&["GET", "after"]), Reply::bulk("1"));
+}
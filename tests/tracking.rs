@@ -0,0 +1,263 @@
+mod common;
+
+use common::{Client, Reply, Server};
+use std::time::Duration;
+
+fn resp3_tracking(server: &Server, options: &[&str]) -> Client {
+    let mut client = server.client();
+    client.cmd(&["HELLO", "3"]);
+    let mut args = vec!["CLIENT", "TRACKING", "ON"];
+    args.extend_from_slice(options);
+    assert_eq!(client.cmd(&args), Reply::status("OK"));
+    client
+}
+
+fn invalidate(keys: &[&str]) -> Reply {
+    Reply::Push(vec![
+        Reply::bulk("invalidate"),
+        Reply::Array(keys.iter().map(Reply::bulk).collect()),
+    ])
+}
+
+fn no_push(client: &mut Client) {
+    assert_eq!(client.try_read(Duration::from_millis(200)), None);
+}
+
+#[test]
+fn default_mode_invalidates_keys_once_after_a_read() {
+    let server = Server::start("tracking-default", &[]);
+    let mut cached = resp3_tracking(&server, &[]);
+    let mut writer = server.client();
+
+    writer.cmd(&["SET", "unread", "1"]);
+    writer.cmd(&["SET", "k", "1"]);
+    cached.cmd(&["GET", "k"]);
+    writer.cmd(&["SET", "unread", "2"]);
+    writer.cmd(&["SET", "k", "2"]);
+    assert_eq!(cached.read(), invalidate(&["k"]));
+
+    // The key has to be read again before it is tracked again.
+    writer.cmd(&["SET", "k", "3"]);
+    no_push(&mut cached);
+    cached.cmd(&["GET", "k"]);
+    writer.cmd(&["DEL", "k"]);
+    assert_eq!(cached.read(), invalidate(&["k"]));
+}
+
+#[test]
+fn flushall_invalidates_everything() {
+    let server = Server::start("tracking-flush", &[]);
+    let mut cached = resp3_tracking(&server, &[]);
+    cached.cmd(&["GET", "k"]);
+    server.client().cmd(&["FLUSHALL"]);
+    assert_eq!(
+        cached.read(),
+        Reply::Push(vec![Reply::bulk("invalidate"), Reply::Nil])
+    );
+}
+
+#[test]
+fn bcast_mode_follows_prefixes_without_reads() {
+    let server = Server::start("tracking-bcast", &[]);
+    let mut cached = resp3_tracking(&server, &["BCAST", "PREFIX", "user:"]);
+    let mut writer = server.client();
+    writer.cmd(&["SET", "order:1", "x"]);
+    no_push(&mut cached);
+    writer.cmd(&["SET", "user:1", "x"]);
+    assert_eq!(cached.read(), invalidate(&["user:1"]));
+    writer.cmd(&["SET", "user:1", "y"]);
+    assert_eq!(cached.read(), invalidate(&["user:1"]));
+
+    assert!(server
+        .client()
+        .cmd(&["CLIENT", "TRACKING", "ON", "PREFIX", "user:"])
+        .is_error());
+}
+
+#[test]
+fn optin_tracks_only_reads_after_caching_yes() {
+    let server = Server::start("tracking-optin", &[]);
+    let mut cached = resp3_tracking(&server, &["OPTIN"]);
+    let mut writer = server.client();
+    cached.cmd(&["GET", "a"]);
+    assert_eq!(
+        cached.cmd(&["CLIENT", "CACHING", "YES"]),
+        Reply::status("OK")
+    );
+    cached.cmd(&["GET", "b"]);
+    // CACHING YES only covers the command right after it.
+    cached.cmd(&["GET", "c"]);
+    writer.cmd(&["SET", "a", "1"]);
+    writer.cmd(&["SET", "c", "1"]);
+    writer.cmd(&["SET", "b", "1"]);
+    assert_eq!(cached.read(), invalidate(&["b"]));
+    no_push(&mut cached);
+}
+
+#[test]
+fn noloop_skips_the_clients_own_writes() {
+    let server = Server::start("tracking-noloop", &[]);
+    let mut cached = resp3_tracking(&server, &["NOLOOP"]);
+    cached.cmd(&["GET", "k"]);
+    cached.cmd(&["SET", "k", "mine"]);
+    no_push(&mut cached);
+    cached.cmd(&["GET", "k"]);
+    server.client().cmd(&["SET", "k", "theirs"]);
+    assert_eq!(cached.read(), invalidate(&["k"]));
+}
+
+#[test]
+fn redirect_delivers_to_a_resp2_subscriber() {
+    let server = Server::start("tracking-redirect", &[]);
+    let mut receiver = server.client();
+    let Reply::Integer(receiver_id) = receiver.cmd(&["CLIENT", "ID"]) else {
+        panic!("CLIENT ID should reply with an integer");
+    };
+    receiver.cmd(&["SUBSCRIBE", "__redis__:invalidate"]);
+
+    let mut cached = server.client();
+    let redirect = receiver_id.to_string();
+    assert_eq!(
+        cached.cmd(&["CLIENT", "TRACKING", "ON", "REDIRECT", &redirect]),
+        Reply::status("OK")
+    );
+    assert_eq!(
+        cached.cmd(&["CLIENT", "GETREDIR"]),
+        Reply::Integer(receiver_id)
+    );
+    cached.cmd(&["GET", "k"]);
+    server.client().cmd(&["SET", "k", "1"]);
+    assert_eq!(
+        receiver.read(),
+        Reply::Array(vec![
+            Reply::bulk("message"),
+            Reply::bulk("__redis__:invalidate"),
+            Reply::Array(vec![Reply::bulk("k")]),
+        ])
+    );
+
+    assert!(cached
+        .cmd(&["CLIENT", "TRACKING", "ON", "REDIRECT", "999999"])
+        .is_error());
+}
+
+#[test]
+fn reenabling_bcast_replaces_the_prefixes() {
+    let server = Server::start("tracking-bcast-prefixes", &[]);
+    let mut cached = resp3_tracking(&server, &["BCAST", "PREFIX", "user:"]);
+    assert_eq!(
+        cached.cmd(&["CLIENT", "TRACKING", "ON", "BCAST", "PREFIX", "order:"]),
+        Reply::status("OK")
+    );
+    let mut writer = server.client();
+    writer.cmd(&["SET", "user:1", "x"]);
+    no_push(&mut cached);
+    writer.cmd(&["SET", "order:1", "x"]);
+    assert_eq!(cached.read(), invalidate(&["order:1"]));
+}
+
+#[test]
+fn bcast_prefixes_are_matched_as_bytes() {
+    let server = Server::start("tracking-bcast-bytes", &[]);
+    let mut cached = server.client();
+    cached.cmd(&["HELLO", "3"]);
+    let on: &[&[u8]] = &[b"CLIENT", b"TRACKING", b"ON", b"BCAST", b"PREFIX", b"\xff"];
+    assert_eq!(cached.call(on), Reply::status("OK"));
+    let mut writer = server.client();
+    // The lossy UTF-8 form of the prefix must not match.
+    writer.call(&[b"SET", "\u{fffd}1".as_bytes(), b"x"]);
+    no_push(&mut cached);
+    writer.call(&[b"SET", b"\xff\x001", b"x"]);
+    assert_eq!(
+        cached.read(),
+        Reply::Push(vec![
+            Reply::bulk("invalidate"),
+            Reply::Array(vec![Reply::bulk(b"\xff\x001")])
+        ])
+    );
+}
+
+#[test]
+fn disabling_tracking_forgets_the_keys_read() {
+    let server = Server::start("tracking-off", &[]);
+    let mut cached = resp3_tracking(&server, &[]);
+    cached.cmd(&["GET", "k"]);
+    assert_eq!(
+        cached.cmd(&["CLIENT", "TRACKING", "OFF"]),
+        Reply::status("OK")
+    );
+    assert_eq!(
+        cached.cmd(&["CLIENT", "TRACKING", "ON"]),
+        Reply::status("OK")
+    );
+    server.client().cmd(&["SET", "k", "1"]);
+    no_push(&mut cached);
+}
+
+/// A client-side cache over a RESP3 connection: it only reads keys it doesn't hold, and
+/// drops them when told to.
+struct Cache {
+    client: Client,
+    value: Option<Reply>,
+}
+
+impl Cache {
+    /// Sends a command and reads its reply, applying the invalidations that come first.
+    fn call(&mut self, args: &[&str]) -> Reply {
+        self.client.send(&args.iter().map(|arg| arg.as_bytes()).collect::<Vec<_>>());
+        loop {
+            match self.client.read() {
+                Reply::Push(push) => {
+                    assert_eq!(push[0], Reply::bulk("invalidate"));
+                    self.value = None;
+                }
+                reply => return reply,
+            }
+        }
+    }
+
+    fn get(&mut self) -> Reply {
+        if self.value.is_none() {
+            let value = self.call(&["GET", "k"]);
+            self.value = Some(value);
+        }
+        self.value.clone().unwrap()
+    }
+}
+
+#[test]
+fn a_write_racing_a_tracked_read_still_invalidates_it() {
+    let server = Server::start("tracking-race", &[]);
+    let port = server.port;
+    let writers = (0..4)
+        .map(|_| {
+            std::thread::spawn(move || {
+                let mut writer = Client::connect(port);
+                for i in 0..2000 {
+                    writer.cmd(&["SET", "k", &i.to_string()]);
+                }
+            })
+        })
+        .collect::<Vec<_>>();
+    let mut caches = (0..4)
+        .map(|_| Cache {
+            client: resp3_tracking(&server, &[]),
+            value: None,
+        })
+        .collect::<Vec<_>>();
+    while writers.iter().any(|writer| !writer.is_finished()) {
+        for cache in caches.iter_mut() {
+            cache.get();
+        }
+    }
+    for writer in writers {
+        writer.join().unwrap();
+    }
+
+    let current = server.client().cmd(&["GET", "k"]);
+    for cache in caches.iter_mut() {
+        // Invalidations for every write made so far arrive before this reply.
+        assert_eq!(cache.call(&["PING"]), Reply::bulk("PONG"));
+        assert_eq!(cache.get(), current);
+    }
+}
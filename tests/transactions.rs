@@ -0,0 +1,177 @@
+mod common;
+
+use common::{Reply, Server};
+
+#[test]
+fn exec_runs_queued_commands_in_order() {
+    let server = Server::start("exec-order", &[]);
+    let mut client = server.client();
+    // EXEC relays each reply exactly as the command gives it outside a transaction.
+    let set_ok = client.cmd(&["SET", "unrelated", "x"]);
+    assert_eq!(client.cmd(&["MULTI"]), Reply::status("OK"));
+    assert_eq!(client.cmd(&["SET", "a", "1"]), Reply::status("QUEUED"));
+    assert_eq!(client.cmd(&["GET", "a"]), Reply::status("QUEUED"));
+    assert_eq!(
+        client.cmd(&["SETBIT", "a", "7", "0"]),
+        Reply::status("QUEUED")
+    );
+
+    // Nothing runs before EXEC, so other clients don't see the write yet.
+    let mut other = server.client();
+    assert_eq!(other.cmd(&["GET", "a"]), Reply::Nil);
+
+    assert_eq!(
+        client.cmd(&["EXEC"]),
+        Reply::Array(vec![set_ok, Reply::bulk("1"), Reply::Integer(1)])
+    );
+    assert_eq!(other.cmd(&["GET", "a"]), Reply::bulk("0"));
+}
+
+#[test]
+fn discard_drops_the_queue() {
+    let server = Server::start("discard", &[]);
+    let mut client = server.client();
+    client.cmd(&["MULTI"]);
+    client.cmd(&["SET", "a", "1"]);
+    assert_eq!(client.cmd(&["DISCARD"]), Reply::status("OK"));
+    assert_eq!(client.cmd(&["GET", "a"]), Reply::Nil);
+    assert!(client.cmd(&["DISCARD"]).is_error());
+    assert!(client.cmd(&["EXEC"]).is_error());
+}
+
+#[test]
+fn nested_multi_is_rejected_without_ending_the_transaction() {
+    let server = Server::start("nested-multi", &[]);
+    let mut client = server.client();
+    client.cmd(&["MULTI"]);
+    assert!(client.cmd(&["MULTI"]).is_error());
+    client.cmd(&["GET", "a"]);
+    assert_eq!(client.cmd(&["EXEC"]), Reply::Array(vec![Reply::Nil]));
+}
+
+#[test]
+fn queueing_error_aborts_exec() {
+    let server = Server::start("execabort", &[]);
+    let mut client = server.client();
+    client.cmd(&["MULTI"]);
+    client.cmd(&["SET", "a", "1"]);
+    assert!(client
+        .cmd(&["SETBIT", "a", "not-an-offset", "1"])
+        .is_error());
+    match client.cmd(&["EXEC"]) {
+        Reply::Error(message) => assert!(message.starts_with("EXECABORT"), "{}", message),
+        other => panic!("expected EXECABORT, got {:?}", other),
+    }
+    assert_eq!(client.cmd(&["GET", "a"]), Reply::Nil);
+}
+
+#[test]
+fn runtime_errors_do_not_stop_the_other_commands() {
+    let server = Server::start("exec-runtime-error", &[]);
+    let mut client = server.client();
+    client.cmd(&["SET", "s", "text"]);
+    client.cmd(&["MULTI"]);
+    client.cmd(&["PFADD", "s", "x"]);
+    client.cmd(&["SET", "b", "2"]);
+    let replies = client.cmd(&["EXEC"]).into_array();
+    assert!(replies[0].is_error());
+    assert!(!replies[1].is_error());
+    assert_eq!(client.cmd(&["GET", "b"]), Reply::bulk("2"));
+}
+
+#[test]
+fn watched_key_written_by_another_client_aborts_exec() {
+    let server = Server::start("watch-touched", &[]);
+    let mut client = server.client();
+    let mut other = server.client();
+    client.cmd(&["SET", "balance", "10"]);
+    assert_eq!(client.cmd(&["WATCH", "balance"]), Reply::status("OK"));
+    other.cmd(&["SET", "balance", "20"]);
+
+    client.cmd(&["MULTI"]);
+    client.cmd(&["SET", "balance", "11"]);
+    assert_eq!(client.cmd(&["EXEC"]), Reply::Nil);
+    assert_eq!(client.cmd(&["GET", "balance"]), Reply::bulk("20"));
+
+    // EXEC unwatches everything, so the next transaction goes through.
+    other.cmd(&["SET", "balance", "30"]);
+    client.cmd(&["MULTI"]);
+    client.cmd(&["GET", "balance"]);
+    assert_eq!(client.cmd(&["EXEC"]), Reply::Array(vec![Reply::bulk("30")]));
+}
+
+#[test]
+fn aborted_exec_replies_with_a_resp3_null_after_hello_3() {
+    let server = Server::start("watch-resp3", &[]);
+    let mut client = server.client();
+    let mut other = server.client();
+    client.cmd(&["HELLO", "3"]);
+    client.cmd(&["WATCH", "balance"]);
+    other.cmd(&["SET", "balance", "20"]);
+
+    client.cmd(&["MULTI"]);
+    client.cmd(&["SET", "balance", "11"]);
+    client.send(&[b"EXEC"]);
+    assert_eq!(client.read_line(), "_");
+    assert_eq!(client.cmd(&["GET", "balance"]), Reply::bulk("20"));
+
+    // RESP2 clients still get the null array.
+    other.cmd(&["WATCH", "balance"]);
+    client.cmd(&["SET", "balance", "21"]);
+    other.cmd(&["MULTI"]);
+    other.cmd(&["SET", "balance", "22"]);
+    other.send(&[b"EXEC"]);
+    assert_eq!(other.read_line(), "*-1");
+}
+
+#[test]
+fn untouched_and_unwatched_keys_let_exec_run() {
+    let server = Server::start("watch-untouched", &[]);
+    let mut client = server.client();
+    let mut other = server.client();
+    client.cmd(&["WATCH", "a", "b"]);
+    other.cmd(&["SET", "c", "1"]);
+    // The same key name in another database is a different key.
+    other.cmd(&["SELECT", "1"]);
+    other.cmd(&["SET", "a", "1"]);
+    client.cmd(&["MULTI"]);
+    client.cmd(&["GET", "a"]);
+    assert_eq!(client.cmd(&["EXEC"]), Reply::Array(vec![Reply::Nil]));
+
+    client.cmd(&["WATCH", "a"]);
+    assert_eq!(client.cmd(&["UNWATCH"]), Reply::status("OK"));
+    other.cmd(&["SELECT", "0"]);
+    other.cmd(&["SET", "a", "2"]);
+    client.cmd(&["MULTI"]);
+    client.cmd(&["GET", "a"]);
+    assert_eq!(client.cmd(&["EXEC"]), Reply::Array(vec![Reply::bulk("2")]));
+}
+
+#[test]
+fn flush_and_expiry_count_as_modifications() {
+    let server = Server::start("watch-flush-expire", &[]);
+    let mut client = server.client();
+    let mut other = server.client();
+    client.cmd(&["SET", "a", "1"]);
+    client.cmd(&["WATCH", "a"]);
+    other.cmd(&["FLUSHALL"]);
+    client.cmd(&["MULTI"]);
+    client.cmd(&["GET", "a"]);
+    assert_eq!(client.cmd(&["EXEC"]), Reply::Nil);
+
+    client.cmd(&["SET", "a", "1", "PX", "100"]);
+    client.cmd(&["WATCH", "a"]);
+    std::thread::sleep(std::time::Duration::from_millis(200));
+    client.cmd(&["MULTI"]);
+    client.cmd(&["GET", "a"]);
+    assert_eq!(client.cmd(&["EXEC"]), Reply::Nil);
+}
+
+#[test]
+fn watch_inside_multi_is_rejected() {
+    let server = Server::start("watch-in-multi", &[]);
+    let mut client = server.client();
+    client.cmd(&["MULTI"]);
+    assert!(client.cmd(&["WATCH", "a"]).is_error());
+    assert_eq!(client.cmd(&["EXEC"]), Reply::Array(vec![]));
+}